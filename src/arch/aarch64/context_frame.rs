@@ -6,6 +6,8 @@ use cortex_a::registers::*;
 
 use crate::{msr, mrs};
 use crate::arch::gic::GicState;
+use crate::snapshot::{Decoder, Encoder};
+use crate::HyperResult;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -91,6 +93,23 @@ impl Aarch64ContextFrame {
             sp: 0,
         }
     }
+
+    /// Appends the registers to a snapshot.
+    pub fn encode(&self, out: &mut Encoder) {
+        out.put_u64s(&self.gpr);
+        out.put_u64s(&[self.sp, self.elr, self.spsr]);
+    }
+
+    /// Loads the registers written by `encode`.
+    pub fn decode(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        let mut gpr = [0; 31];
+        input.get_u64s(&mut gpr)?;
+        let mut regs = [0; 3];
+        input.get_u64s(&mut regs)?;
+        self.gpr = gpr;
+        [self.sp, self.elr, self.spsr] = regs;
+        Ok(())
+    }
 }
 
 #[repr(C)]
@@ -332,6 +351,114 @@ impl VmContext {
         self.gic_state.save_state();
     }
 
+    /// Appends the registers to a snapshot. The guest's virtual count is saved instead of
+    /// `CNTVOFF_EL2`, so that it goes on from where it stopped on another host.
+    pub fn encode(&self, out: &mut Encoder) {
+        let cntpct: u64;
+        mrs!(cntpct, CNTPCT_EL0);
+        out.put_u64(cntpct.wrapping_sub(self.cntvoff_el2));
+        out.put_u64s(&[
+            self.cntp_cval_el0,
+            self.cntv_cval_el0,
+            self.cntvct_el0,
+            self.vmpidr_el2,
+            self.sp_el0,
+            self.sp_el1,
+            self.elr_el1,
+            self.actlr_el1,
+            self.ttbr0_el1,
+            self.ttbr1_el1,
+            self.tcr_el1,
+            self.far_el1,
+            self.par_el1,
+            self.mair_el1,
+            self.amair_el1,
+            self.vbar_el1,
+            self.tpidr_el0,
+            self.tpidr_el1,
+            self.tpidrro_el0,
+            self.hcr_el2,
+            self.cptr_el2,
+            self.hstr_el2,
+            self.pmcr_el0,
+            self.vtcr_el2,
+            self.far_el2,
+            self.hpfar_el2,
+        ]);
+        out.put_u32s(&[
+            self.cntkctl_el1,
+            self.cntp_ctl_el0,
+            self.cntv_ctl_el0,
+            self.cntp_tval_el0,
+            self.cntv_tval_el0,
+            self.vpidr_el2,
+            self.spsr_el1,
+            self.sctlr_el1,
+            self.cpacr_el1,
+            self.esr_el1,
+            self.contextidr_el1,
+        ]);
+        self.gic_state.encode(out);
+    }
+
+    /// Loads the registers written by `encode`.
+    pub fn decode(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        let virtual_count = input.get_u64()?;
+        let mut regs64 = [0; 26];
+        input.get_u64s(&mut regs64)?;
+        let mut regs32 = [0; 11];
+        input.get_u32s(&mut regs32)?;
+        let mut gic_state = GicState::default();
+        gic_state.decode(input)?;
+
+        let cntpct: u64;
+        mrs!(cntpct, CNTPCT_EL0);
+        self.cntvoff_el2 = cntpct.wrapping_sub(virtual_count);
+        [
+            self.cntp_cval_el0,
+            self.cntv_cval_el0,
+            self.cntvct_el0,
+            self.vmpidr_el2,
+            self.sp_el0,
+            self.sp_el1,
+            self.elr_el1,
+            self.actlr_el1,
+            self.ttbr0_el1,
+            self.ttbr1_el1,
+            self.tcr_el1,
+            self.far_el1,
+            self.par_el1,
+            self.mair_el1,
+            self.amair_el1,
+            self.vbar_el1,
+            self.tpidr_el0,
+            self.tpidr_el1,
+            self.tpidrro_el0,
+            self.hcr_el2,
+            self.cptr_el2,
+            self.hstr_el2,
+            self.pmcr_el0,
+            self.vtcr_el2,
+            self.far_el2,
+            self.hpfar_el2,
+        ] = regs64;
+        [
+            self.cntkctl_el1,
+            self.cntp_ctl_el0,
+            self.cntv_ctl_el0,
+            self.cntp_tval_el0,
+            self.cntv_tval_el0,
+            self.vpidr_el2,
+            self.spsr_el1,
+            self.sctlr_el1,
+            self.cpacr_el1,
+            self.esr_el1,
+            self.contextidr_el1,
+        ] = regs32;
+        self.gic_state = gic_state;
+        Ok(())
+    }
+
     pub fn gic_restore_state(&self) {
         self.gic_state.restore_state();
     }
@@ -5,6 +5,8 @@ use arm_gic::gic_v2::{GicDistributor, GicHypervisorInterface, GicCpuInterface};
 use arm_gic::GIC_LIST_REGS_NUM;
 
 use crate::arch::utils::bit_extract;
+use crate::snapshot::{Decoder, Encoder};
+use crate::HyperResult;
 
 pub static GICD: Option<&SpinNoIrq<GicDistributor>> = None;
 pub static GICC: Option<&GicCpuInterface> = None;
@@ -87,6 +89,27 @@ impl GicState {
         }
     }
 
+    /// Appends the saved interface state to a snapshot.
+    pub fn encode(&self, out: &mut Encoder) {
+        out.put_u32(self.saved_hcr);
+        out.put_u32s(&self.saved_eisr);
+        out.put_u32s(&self.saved_elrsr);
+        out.put_u32(self.saved_apr);
+        out.put_u32s(&self.saved_lr);
+        out.put_u32(self.saved_ctlr);
+    }
+
+    /// Loads the state written by `encode`.
+    pub fn decode(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        self.saved_hcr = input.get_u32()?;
+        input.get_u32s(&mut self.saved_eisr)?;
+        input.get_u32s(&mut self.saved_elrsr)?;
+        self.saved_apr = input.get_u32()?;
+        input.get_u32s(&mut self.saved_lr)?;
+        self.saved_ctlr = input.get_u32()?;
+        Ok(())
+    }
+
 }
 
 /* 
@@ -0,0 +1,121 @@
+//! An emulated GICv2m MSI frame, turning the MSIs of emulated PCI functions into SPIs.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::devices::{MmioDevice, MsiMessage};
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+/// Size of the register frame.
+pub const GICV2M_FRAME_SIZE: usize = 0x1000;
+
+// Register offsets.
+const MSI_TYPER: usize = 0x008;
+const MSI_SETSPI_NS: usize = 0x040;
+
+// SPIs are INTIDs 32 to 1019.
+const SPI_FIRST: u32 = 32;
+const SPI_LAST: u32 = 1019;
+
+/// A GICv2m MSI frame, `arm,gic-v2m-frame` in the guest's device tree.
+///
+/// An MSI is a store of an SPI number to the frame's `MSI_SETSPI_NS` register. The frame latches
+/// the SPIs it was sent, for the VMM to inject into the guest's GIC as edge interrupts with
+/// [`take_pending`](Self::take_pending). MSIs of emulated devices, e.g. the ones taken from a
+/// [`PciHostBridge`](crate::devices::PciHostBridge), reach it through
+/// [`send_msi`](Self::send_msi).
+pub struct GicV2mFrame {
+    base: GuestPhysAddr,
+    spi_base: u32,
+    spi_count: u32,
+    // One bit per SPI of the frame.
+    pending: Vec<u64>,
+}
+
+impl GicV2mFrame {
+    /// Creates a frame at guest physical address `base` owning the `spi_count` SPIs from
+    /// `spi_base`.
+    pub fn new(base: GuestPhysAddr, spi_base: u32, spi_count: u32) -> HyperResult<Self> {
+        if spi_count == 0 || spi_base < SPI_FIRST || spi_base + spi_count - 1 > SPI_LAST {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(Self {
+            base,
+            spi_base,
+            spi_count,
+            pending: vec![0; (spi_count as usize).div_ceil(64)],
+        })
+    }
+
+    /// The address devices send their MSIs to.
+    pub fn msi_address(&self) -> u64 {
+        (self.base + MSI_SETSPI_NS) as u64
+    }
+
+    /// Latches the SPI an emulated device signaled with `msi`.
+    pub fn send_msi(&mut self, msi: &MsiMessage) -> HyperResult<()> {
+        if msi.address != self.msi_address() {
+            return Err(HyperError::InvalidParam);
+        }
+        self.set_spi(msi.data)
+    }
+
+    /// Calls `f` with every SPI signaled since the last call, and clears them.
+    pub fn take_pending(&mut self, mut f: impl FnMut(u32)) {
+        for (index, word) in self.pending.iter_mut().enumerate() {
+            while *word != 0 {
+                let bit = word.trailing_zeros();
+                *word &= !(1 << bit);
+                f(self.spi_base + index as u32 * 64 + bit);
+            }
+        }
+    }
+}
+
+// Private methods implementation
+impl GicV2mFrame {
+    fn set_spi(&mut self, spi: u32) -> HyperResult<()> {
+        let index = spi
+            .checked_sub(self.spi_base)
+            .filter(|&index| index < self.spi_count)
+            .ok_or(HyperError::OutOfRange)? as usize;
+        self.pending[index / 64] |= 1 << (index % 64);
+        Ok(())
+    }
+}
+
+impl MmioDevice for GicV2mFrame {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        self.base..self.base + GICV2M_FRAME_SIZE
+    }
+
+    fn mmio_read(&mut self, offset: usize, width: usize) -> HyperResult<u64> {
+        if width != 4 || offset % 4 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let val = match offset {
+            MSI_TYPER => (self.spi_base << 16) | self.spi_count,
+            // Including MSI_IIDR: not one of the implementations guests apply quirks to.
+            _ => 0,
+        };
+        Ok(val as u64)
+    }
+
+    fn mmio_write(&mut self, offset: usize, width: usize, val: u64) -> HyperResult<()> {
+        if offset == MSI_SETSPI_NS && matches!(width, 2 | 4) {
+            if let Err(err) = self.set_spi(val as u32) {
+                warn!("gicv2m: SPI {} is not in the frame: {:?}", val, err);
+            }
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, out: &mut Encoder) {
+        out.put_u64s(&self.pending);
+    }
+
+    fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        input.get_u64s(&mut self.pending)
+    }
+}
@@ -8,9 +8,11 @@ mod vcpu;
 mod vm;
 mod gic;
 mod ept;
+mod gicv2m;
 
 // pub use gic::{GICC, GICD, GICH, GICD_BASE};
 pub use ept::NestedPageTable;
+pub use gicv2m::{GicV2mFrame, GICV2M_FRAME_SIZE};
 pub use vcpu::VCpu;
 pub use vm::VM;
 pub use cpu::PerCpu;
@@ -19,6 +21,7 @@ pub use cpu::PerCpu;
 
 pub use page_table::PageSize;
 pub use exception::lower_aarch64_synchronous;
+pub use sync::set_dirty_fault_handler;
 
 type ContextFrame = crate::arch::context_frame::Aarch64ContextFrame;
 
@@ -14,9 +14,21 @@ use crate::arch::ContextFrame;
 use crate::traits::ContextFrameTrait;
 use crate::arch::vcpu::VmCpuRegisters;
 use crate::arch::hvc::{HVC_SYS, HVC_SYS_BOOT};
+use crate::GuestPhysAddr;
+use spin::Once;
 
 pub const HVC_RETURN_REG: usize = 0;
 
+/// Called with the faulting IPA on stage 2 write permission faults, returns true if it was a
+/// write to a page write-protected for dirty logging.
+static DIRTY_FAULT_HANDLER: Once<fn(GuestPhysAddr) -> bool> = Once::new();
+
+/// Sets the function stage 2 write permission faults are passed to. The embedder forwards them
+/// to `VM::handle_dirty_fault` of the VM running on this CPU.
+pub fn set_dirty_fault_handler(handler: fn(GuestPhysAddr) -> bool) {
+    DIRTY_FAULT_HANDLER.call_once(|| handler);
+}
+
 pub fn data_abort_handler(ctx: &mut ContextFrame) {
     /* 
     let emu_ctx = EmuContext {
@@ -32,6 +44,15 @@ pub fn data_abort_handler(ctx: &mut ContextFrame) {
         exception_fault_addr(), exception_esr());
     let elr = ctx.exception_pc();
 
+    if exception_data_abort_is_permission_fault() && exception_data_abort_access_is_write() {
+        if let Some(handler) = DIRTY_FAULT_HANDLER.get() {
+            if handler(exception_fault_addr()) {
+                // Retry the store, the page is writable now.
+                return;
+            }
+        }
+    }
+
     if !exception_data_abort_handleable() {
         panic!(
             "Data abort not handleable 0x{:x}, esr 0x{:x}",
@@ -22,7 +22,8 @@ use tock_registers::interfaces::*;
 use crate::arch::ContextFrame;
 use crate::arch::context_frame::VmContext;
 use crate::traits::ContextFrameTrait;
-use crate::HyperCraftHal;
+use crate::snapshot::{Decoder, Encoder};
+use crate::{HyperCraftHal, HyperResult};
 use crate::arch::hvc::run_guest_by_trap2el2;
 
 /// (v)CPU register state that must be saved or restored when entering/exiting a VM or switching
@@ -119,6 +120,21 @@ impl <H:HyperCraftHal> VCpu<H> {
         self.regs.guest_trap_context_regs.set_gpr(idx, val);
     }
 
+    /// Appends the guest's trap context and system registers to a snapshot.
+    pub fn save_state(&self, out: &mut Encoder) {
+        self.regs.guest_trap_context_regs.encode(out);
+        self.regs.vm_system_regs.encode(out);
+    }
+
+    /// Loads the state written by `save_state`.
+    pub fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        let mut regs = self.regs.clone();
+        regs.guest_trap_context_regs.decode(input)?;
+        regs.vm_system_regs.decode(input)?;
+        self.regs = regs;
+        Ok(())
+    }
+
     /// Init guest context. Also set some el2 register value.
     fn init_vm_context(&mut self) {
         self.regs.vm_system_regs.cntvoff_el2 = 0;
@@ -1,4 +1,16 @@
-use crate::{HyperCraftHal, GuestPageTableTrait, VmCpus, HyperResult};
+use alloc::vec::Vec;
+
+use crate::{HyperCraftHal, GuestPageTableTrait, GuestPhysAddr, VmCpus, HyperError, HyperResult};
+use crate::dirty_log::{DirtyBitmap, DirtyLog};
+use crate::snapshot::{
+    Decoder, Encoder, SnapshotReader, SnapshotSink, SnapshotSource, SnapshotWriter, RAM_TAG,
+    VCPU_TAG,
+};
+use crate::vmid::{VmidAllocator, VmidSlot};
+use super::PerCpu;
+
+/// 8-bit VMIDs, VTCR_EL2.VS is left clear.
+static VMID_ALLOCATOR: VmidAllocator = VmidAllocator::new(0xff);
 
 /// The guest VM
 #[repr(align(4096))]
@@ -9,6 +21,10 @@ pub struct VM<H: HyperCraftHal, G: GuestPageTableTrait> {
     gpt: G,
     /// VM id
     vm_id: usize,
+    /// The VMID tagging the VM's TLB entries
+    vmid: VmidSlot,
+    /// Pages written since the VMM last asked, while dirty logging is on
+    dirty_log: Option<DirtyLog>,
 }
 
 impl <H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
@@ -17,7 +33,9 @@ impl <H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
         Ok(Self { 
                 vcpus: vcpus, 
                 gpt: gpt, 
-                vm_id: id
+                vm_id: id,
+                vmid: VmidSlot::new(),
+                dirty_log: None,
             }
         )
     }
@@ -30,9 +48,134 @@ impl <H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
 
     /// Run this VM.
     pub fn run(&mut self, vcpu_id: usize) {
+        let (vmid, flush) = VMID_ALLOCATOR.get(&mut self.vmid, PerCpu::<H>::this_cpu().cpu_id);
+        if flush {
+            // VMIDs rolled over, drop the stage 1 and 2 entries of every VMID on this CPU.
+            unsafe { core::arch::asm!("tlbi alle1", "dsb nsh", "isb") };
+        }
         let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
-        let vttbr_token = (self.vm_id << 48) | self.gpt.token();
+        let vttbr_token = (vmid << 48) | self.gpt.token();
         debug!("vttbr_token: 0x{:X}", self.gpt.token());
         vcpu.run(vttbr_token);
     }
+
+    /// Starts logging the guest's writes to `regions` of guest RAM, given by start and size. All
+    /// their pages are write-protected in stage 2. The embedder routes stage-2 write permission
+    /// faults to `handle_dirty_fault` through `set_dirty_fault_handler`.
+    pub fn enable_dirty_log(&mut self, regions: &[(GuestPhysAddr, usize)]) -> HyperResult<()> {
+        if self.dirty_log.is_some() {
+            return Err(HyperError::BadState);
+        }
+        self.dirty_log = Some(DirtyLog::enable(&mut self.gpt, regions)?);
+        flush_stage2_tlb();
+        Ok(())
+    }
+
+    /// Stops logging writes and makes the logged regions writable again.
+    pub fn disable_dirty_log(&mut self) -> HyperResult<()> {
+        let log = self.dirty_log.take().ok_or(HyperError::BadState)?;
+        log.disable(&mut self.gpt)?;
+        flush_stage2_tlb();
+        Ok(())
+    }
+
+    /// Returns the pages written since dirty logging was enabled or since the previous call, one
+    /// bitmap per logged region, and write-protects them again.
+    pub fn get_and_clear_dirty_log(&mut self) -> HyperResult<Vec<DirtyBitmap>> {
+        let log = self.dirty_log.as_mut().ok_or(HyperError::BadState)?;
+        let dirty = log.get_and_clear(&mut self.gpt)?;
+        flush_stage2_tlb();
+        Ok(dirty)
+    }
+
+    /// Logs a guest write to `ipa` that faulted on a page write-protected for dirty logging and
+    /// makes the page writable again. Returns false if the fault has another cause. Must be
+    /// called on the CPU the fault was taken on, with the VM's stage 2 still loaded.
+    pub fn handle_dirty_fault(&mut self, ipa: GuestPhysAddr) -> bool {
+        let log = match self.dirty_log.as_mut() {
+            Some(log) => log,
+            None => return false,
+        };
+        match log.handle_write_fault(&mut self.gpt, ipa) {
+            Ok(true) => {
+                // Drop this CPU's read-only entry of the page.
+                unsafe {
+                    core::arch::asm!(
+                        "tlbi ipas2e1, {0}",
+                        "dsb nsh",
+                        "tlbi vmalle1",
+                        "dsb nsh",
+                        "isb",
+                        in(reg) ipa >> 12,
+                    )
+                };
+                true
+            }
+            Ok(false) => false,
+            Err(err) => {
+                warn!("dirty logging fault at {:#x} failed: {:?}", ipa, err);
+                false
+            }
+        }
+    }
+
+    /// Writes a snapshot of the VM to `sink`: the registers of every vCPU and the guest RAM in
+    /// `ram`. The hypervisor does not map guest RAM here, so the embedder passes its own view of
+    /// each RAM region along with the region's guest physical address.
+    pub fn snapshot(
+        &self,
+        sink: &mut dyn SnapshotSink,
+        ram: &[(GuestPhysAddr, &[u8])],
+    ) -> HyperResult<()> {
+        let mut writer = SnapshotWriter::new(sink)?;
+        for vcpu_id in self.vcpus.vcpu_ids() {
+            let mut out = Encoder::new();
+            out.put_u32(vcpu_id as u32);
+            self.vcpus.lock_vcpu(vcpu_id)?.save_state(&mut out);
+            writer.write_section(VCPU_TAG, 1, &out)?;
+        }
+        for (gpa, data) in ram {
+            writer.write_ram(*gpa, data)?;
+        }
+        writer.finish()
+    }
+
+    /// Restores the snapshot read from `source` into this VM, which must have the same vCPUs.
+    /// Each RAM section is copied into the region of `ram` that covers it.
+    pub fn restore(
+        &mut self,
+        source: &mut dyn SnapshotSource,
+        ram: &mut [(GuestPhysAddr, &mut [u8])],
+    ) -> HyperResult<()> {
+        let mut reader = SnapshotReader::new(source)?;
+        reader.check_arch()?;
+        while let Some(section) = reader.next_section()? {
+            match section.tag {
+                VCPU_TAG => {
+                    section.check_version(1)?;
+                    let payload = reader.read_payload()?;
+                    let mut input = Decoder::new(&payload);
+                    let vcpu_id = input.get_u32()? as usize;
+                    self.vcpus.get_vcpu(vcpu_id)?.restore_state(&mut input)?;
+                }
+                RAM_TAG => {
+                    section.check_version(1)?;
+                    let (gpa, len) = reader.read_ram_header()?;
+                    let region = ram
+                        .iter_mut()
+                        .find(|(start, data)| gpa >= *start && gpa + len <= *start + data.len())
+                        .ok_or(HyperError::OutOfRange)?;
+                    let offset = gpa - region.0;
+                    reader.read_raw(&mut region.1[offset..offset + len])?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Drops the stage 2 entries that may still grant write access to write-protected pages.
+fn flush_stage2_tlb() {
+    unsafe { core::arch::asm!("tlbi alle1is", "dsb ish", "isb") };
 }
@@ -0,0 +1,153 @@
+//! Microbenchmark of the ECALL exit round-trip: the time from a guest `ecall` through the exit
+//! handling in the hypervisor back to the next guest instruction.
+//!
+//! The guest side is [`ECALL_BENCH_GUEST`], a position independent program which the embedder
+//! copies to the entry point of the benchmarked vCPU. It asks for the number of iterations, times
+//! that many ECALLs to a no-op firmware extension with `rdtime`, reports the elapsed time and
+//! prints a newline, which hands control back to [`run_ecall_bench`].
+//!
+//! For the before/after comparison, building with feature `ecall-bench-baseline` puts back the
+//! GPR round trip every exit used to make: the registers copied out of the vCPU one index lookup
+//! at a time and written back the same way. Run the benchmark once with and once without it on
+//! the same host; the difference in [`EcallBenchResult::milliticks_per_round_trip`] is the cost
+//! the in-place exit handling saves.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "ecall-bench-baseline")]
+use super::{regs::GeneralPurposeRegisters, GprIndex, VCpu};
+use super::{vmm_trap::VmmTrap, SbiExtensionHandler, SbiReturn, VM};
+use crate::{GuestMemoryAccess, GuestPageTableTrait, HyperCraftHal, HyperError, HyperResult};
+
+/// The firmware-specific extension ID the benchmark guest calls.
+pub const ECALL_BENCH_EID: usize = 0x0a48_5000;
+
+const FID_NOP: usize = 0;
+const FID_REPORT: usize = 1;
+const FID_GET_ITERATIONS: usize = 2;
+
+/// Machine code of the benchmark guest, to be run in VS-mode with VS-stage translation off.
+pub const ECALL_BENCH_GUEST: [u32; 17] = [
+    0x0a48_58b7, // lui   a7, 0x0a485          (ECALL_BENCH_EID)
+    0x0020_0813, // li    a6, 2                (FID_GET_ITERATIONS)
+    0x0000_0073, // ecall
+    0x0005_8493, // mv    s1, a1
+    0x0000_0813, // li    a6, 0                (FID_NOP)
+    0xc010_2473, // rdtime s0
+    0x0000_0073, // 1: ecall
+    0xfff4_8493, // addi  s1, s1, -1
+    0xfe04_9ce3, // bnez  s1, 1b
+    0xc010_2573, // rdtime a0
+    0x4085_0533, // sub   a0, a0, s0
+    0x0010_0813, // li    a6, 1                (FID_REPORT)
+    0x0000_0073, // ecall
+    0x0010_0893, // li    a7, 1                (legacy console putchar)
+    0x00a0_0513, // li    a0, '\n'
+    0x0000_0073, // ecall
+    0x0000_006f, // j     .
+];
+
+/// Outcome of [`run_ecall_bench`].
+#[derive(Clone, Copy, Debug)]
+pub struct EcallBenchResult {
+    /// Number of timed ECALLs.
+    pub iterations: usize,
+    /// Time they took in total, in `time` CSR ticks.
+    pub ticks: u64,
+}
+
+impl EcallBenchResult {
+    /// Average round-trip time of one ECALL in `time` CSR ticks, scaled by 1000 to keep the
+    /// fraction of coarse timers.
+    pub fn milliticks_per_round_trip(&self) -> u64 {
+        self.ticks * 1000 / self.iterations.max(1) as u64
+    }
+}
+
+struct EcallBenchHandler {
+    iterations: usize,
+    ticks: Arc<AtomicU64>,
+}
+
+impl SbiExtensionHandler for EcallBenchHandler {
+    fn handle_ecall(
+        &mut self,
+        _eid: usize,
+        fid: usize,
+        args: &[usize],
+        _guest_mem: &dyn GuestMemoryAccess,
+    ) -> SbiReturn {
+        match fid {
+            FID_NOP => SbiReturn::success(0),
+            FID_GET_ITERATIONS => SbiReturn::success(self.iterations as i64),
+            FID_REPORT => {
+                self.ticks.store(args[0] as u64, Ordering::Release);
+                SbiReturn::success(0)
+            }
+            _ => SbiReturn::error(super::sbi::SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+}
+
+/// Runs the ECALL round-trip benchmark on vCPU `vcpu_id` of `vm`, which must start at a copy of
+/// [`ECALL_BENCH_GUEST`]. The vCPU is not usable for anything else afterwards. Other timers
+/// should be quiet while it runs, as their interrupts are counted in the round-trips.
+pub fn run_ecall_bench<H: HyperCraftHal, G: GuestPageTableTrait>(
+    vm: &mut VM<H, G>,
+    vcpu_id: usize,
+    iterations: usize,
+) -> HyperResult<EcallBenchResult> {
+    if iterations == 0 {
+        return Err(HyperError::InvalidParam);
+    }
+    let ticks = Arc::new(AtomicU64::new(u64::MAX));
+    vm.register_sbi_extension(
+        ECALL_BENCH_EID..=ECALL_BENCH_EID,
+        Box::new(EcallBenchHandler {
+            iterations,
+            ticks: ticks.clone(),
+        }),
+    )?;
+    loop {
+        match vm.run(vcpu_id) {
+            VmmTrap::ConsoleOutput => {
+                vm.drain_console_output(|_| {});
+                if ticks.load(Ordering::Acquire) != u64::MAX {
+                    break;
+                }
+            }
+            VmmTrap::TimerInterruptEmulation => {
+                sbi_rt::set_timer(u64::MAX);
+            }
+            VmmTrap::WrongHart(_) => {
+                vm.unregister_sbi_extension(ECALL_BENCH_EID);
+                return Err(HyperError::BadState);
+            }
+            _ => {}
+        }
+    }
+    vm.unregister_sbi_extension(ECALL_BENCH_EID);
+    Ok(EcallBenchResult {
+        iterations,
+        ticks: ticks.load(Ordering::Acquire),
+    })
+}
+
+/// The exit path's GPR handling before the rework, kept as the baseline of the benchmark: the
+/// guest's registers are saved to a copy and restored from it with a lookup per register.
+#[cfg(feature = "ecall-bench-baseline")]
+pub(super) fn copy_gprs_out_and_back<H: HyperCraftHal>(vcpu: &mut VCpu<H>) {
+    let mut gprs = GeneralPurposeRegisters::default();
+    for index in 0..32 {
+        let index = GprIndex::from_raw(index).unwrap();
+        gprs.set_reg(index, vcpu.gprs().reg(index));
+    }
+    // Keep the copy from being optimized away, the handlers used to work on it.
+    core::hint::black_box(&mut gprs);
+    for index in 0..32 {
+        let index = GprIndex::from_raw(index).unwrap();
+        vcpu.set_gpr(index, gprs.reg(index));
+    }
+}
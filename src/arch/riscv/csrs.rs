@@ -11,6 +11,11 @@ pub struct CSR {
     pub hideleg: ReadWriteCsr<hideleg::Register, CSR_HIDELEG>,
     pub hcounteren: ReadWriteCsr<hcounteren::Register, CSR_HCOUNTEREN>,
     pub hvip: ReadWriteCsr<hvip::Register, CSR_HVIP>,
+    pub henvcfg: ReadWriteCsr<henvcfg::Register, CSR_HENVCFG>,
+    pub hie: ReadWriteCsr<hie::Register, CSR_HIE>,
+    pub hgeie: ReadWriteCsr<hgeie::Register, CSR_HGEIE>,
+    pub hgeip: ReadWriteCsr<hgeip::Register, CSR_HGEIP>,
+    pub vstimecmp: ReadWriteCsr<vstimecmp::Register, CSR_VSTIMECMP>,
 }
 
 #[allow(clippy::identity_op, clippy::erasing_op)]
@@ -21,6 +26,11 @@ pub const CSR: &CSR = &CSR {
     hideleg: ReadWriteCsr::new(),
     hcounteren: ReadWriteCsr::new(),
     hvip: ReadWriteCsr::new(),
+    henvcfg: ReadWriteCsr::new(),
+    hie: ReadWriteCsr::new(),
+    hgeie: ReadWriteCsr::new(),
+    hgeip: ReadWriteCsr::new(),
+    vstimecmp: ReadWriteCsr::new(),
 };
 
 /// Trait defining the possible operations on a RISC-V CSR.
@@ -268,6 +278,46 @@ pub mod defs {
         vsext OFFSET(10) NUMBITS(1) [],
     ]
     ];
+
+    // Hypervisor guest external interrupt enable register, bit i enables guest interrupt file i.
+    register_bitfields![usize,
+    pub hgeie [
+        files OFFSET(1) NUMBITS(63) [],
+    ]
+    ];
+
+    // Hypervisor guest external interrupt pending register, bit i is set while guest interrupt
+    // file i has an enabled interrupt pending.
+    register_bitfields![usize,
+    pub hgeip [
+        files OFFSET(1) NUMBITS(63) [],
+    ]
+    ];
+
+    // Hypervisor environment configuration register.
+    register_bitfields![usize,
+    pub henvcfg [
+        // Fence of I/O implies memory.
+        fiom OFFSET(0) NUMBITS(1) [],
+        // Cache block invalidate instruction enable.
+        cbie OFFSET(4) NUMBITS(2) [],
+        // Cache block clean and flush instruction enable.
+        cbcfe OFFSET(6) NUMBITS(1) [],
+        // Cache block zero instruction enable.
+        cbze OFFSET(7) NUMBITS(1) [],
+        // Page-based memory types enable (Svpbmt).
+        pbmte OFFSET(62) NUMBITS(1) [],
+        // VS-level stimecmp enable (Sstc).
+        stce OFFSET(63) NUMBITS(1) [],
+    ]
+    ];
+
+    // Virtual supervisor timer compare register (Sstc).
+    register_bitfields![usize,
+    pub vstimecmp [
+        value OFFSET(0) NUMBITS(64) [],
+    ]
+    ];
 }
 
 pub mod traps {
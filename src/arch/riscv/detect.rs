@@ -1,5 +1,7 @@
 //! ref: https://github.com/luojia65/zihai/blob/main/zihai/src/detect.rs
 
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::vec::Vec;
 use core::arch::asm;
 use riscv::register::{
     scause::{Exception, Scause, Trap},
@@ -7,6 +9,88 @@ use riscv::register::{
     stvec::{self, Stvec, TrapMode},
 };
 
+use super::csrs::{
+    defs::{henvcfg, hstatus},
+    RiscvCsrTrait, CSR,
+};
+
+/// RISC-V ISA extensions and hypervisor features implemented by the host, used by the VMM to
+/// decide which features to enable and to advertise to guests.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HostCapabilities {
+    /// Supervisor-mode timer interrupts (`stimecmp`/`vstimecmp`).
+    pub sstc: bool,
+    /// Page-based memory types.
+    pub svpbmt: bool,
+    /// NAPOT translation contiguity, probed through a VS-stage test mapping.
+    pub svnapot: bool,
+    /// Fine-grained address-translation cache invalidation.
+    pub svinval: bool,
+    /// Cache-block management instructions.
+    pub zicbom: bool,
+    /// Cache-block zero instructions.
+    pub zicboz: bool,
+    /// Supervisor-level Advanced Interrupt Architecture. The M-level part (Smaia) is not visible
+    /// from S-mode.
+    pub ssaia: bool,
+    /// Sv39x4 G-stage translation.
+    pub sv39x4: bool,
+    /// Sv48x4 G-stage translation.
+    pub sv48x4: bool,
+    /// Sv57x4 G-stage translation.
+    pub sv57x4: bool,
+    /// Number of guest external interrupt files (GEILEN).
+    pub geilen: usize,
+    /// Number of implemented `hgatp.VMID` bits (VMIDLEN).
+    pub vmid_bits: usize,
+}
+
+impl HostCapabilities {
+    /// Probes the current hart. Must be called in HS-mode with the H extension present.
+    pub fn detect() -> Self {
+        let envcfg_writable = |bits: usize| unsafe { probe_henvcfg_bits(bits) };
+        Self {
+            sstc: detect_sstc_extension(),
+            svpbmt: envcfg_writable(henvcfg::pbmte::SET.value),
+            svnapot: unsafe { probe_svnapot() },
+            svinval: detect_svinval_extension(),
+            zicbom: envcfg_writable(henvcfg::cbcfe::SET.value),
+            zicboz: envcfg_writable(henvcfg::cbze::SET.value),
+            ssaia: detect_ssaia_extension(),
+            sv39x4: unsafe { probe_hgatp_mode(HGATP_MODE_SV39X4) },
+            sv48x4: unsafe { probe_hgatp_mode(HGATP_MODE_SV48X4) },
+            sv57x4: unsafe { probe_hgatp_mode(HGATP_MODE_SV57X4) },
+            geilen: unsafe { probe_geilen() },
+            vmid_bits: unsafe { probe_vmid_bits() },
+        }
+    }
+
+    /// Names of the detected extensions that can be passed through to guests, in the form used
+    /// by the `riscv,isa-extensions` device tree property.
+    pub fn guest_isa_extensions(&self) -> Vec<&'static str> {
+        [
+            (self.sstc, "sstc"),
+            (self.svpbmt, "svpbmt"),
+            (self.svnapot, "svnapot"),
+            (self.svinval, "svinval"),
+            (self.zicbom, "zicbom"),
+            (self.zicboz, "zicboz"),
+            (self.ssaia, "ssaia"),
+        ]
+        .iter()
+        .filter(|(present, _)| *present)
+        .map(|(_, name)| *name)
+        .collect()
+    }
+}
+
+const HGATP_MODE_SHIFT: usize = 60;
+pub(super) const HGATP_VMID_SHIFT: usize = 44;
+pub(super) const HGATP_VMID_MASK: usize = 0x3fff;
+const HGATP_MODE_SV39X4: usize = 8;
+const HGATP_MODE_SV48X4: usize = 9;
+const HGATP_MODE_SV57X4: usize = 10;
+
 // Detect if hypervisor extension exists on current hart environment
 //
 // This function tries to read hgatp and returns false if the read operation failed.
@@ -19,6 +103,153 @@ pub fn detect_h_extension() -> bool {
     ans != 2
 }
 
+// Detect if Sstc extension exists on current hart environment
+//
+// This function tries to read stimecmp and returns false if the read operation failed.
+pub fn detect_sstc_extension() -> bool {
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0x14d", out(reg) _, options(nomem, nostack)); // 0x14d => stimecmp
+    });
+    ans != 2
+}
+
+// Detect if Svinval extension exists on current hart environment
+//
+// This function tries to execute sfence.w.inval and returns false if it is an illegal instruction.
+pub fn detect_svinval_extension() -> bool {
+    let ans = with_detect_trap(0, || unsafe {
+        asm!(".word 0x18000073", options(nomem, nostack)); // sfence.w.inval
+    });
+    ans != 2
+}
+
+// Detect if Ssaia extension exists on current hart environment
+//
+// This function tries to read stopi and returns false if the read operation failed.
+pub fn detect_ssaia_extension() -> bool {
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0xdb0", out(reg) _, options(nomem, nostack)); // 0xdb0 => stopi
+    });
+    ans != 2
+}
+
+// henvcfg fields of unimplemented extensions are read-only zero, so an extension is present if its
+// enable bits stick after being written.
+unsafe fn probe_henvcfg_bits(bits: usize) -> bool {
+    let old = CSR.henvcfg.read_and_set_bits(bits);
+    let present = CSR.henvcfg.get_value() & bits == bits;
+    CSR.henvcfg.write_value(old);
+    present
+}
+
+// hgatp.MODE is WARL, writing an unsupported mode leaves it at a supported value.
+unsafe fn probe_hgatp_mode(mode: usize) -> bool {
+    let new: usize;
+    asm!(
+        "csrrw {old}, hgatp, {val}",
+        "csrr  {new}, hgatp",
+        "csrw  hgatp, {old}",
+        old = out(reg) _,
+        new = out(reg) new,
+        val = in(reg) mode << HGATP_MODE_SHIFT,
+        options(nomem, nostack),
+    );
+    new >> HGATP_MODE_SHIFT == mode
+}
+
+// Only the low VMIDLEN bits of hgatp.VMID are writable. hgatp is written in Bare mode, the
+// field is WARL regardless of the mode.
+unsafe fn probe_vmid_bits() -> usize {
+    let new: usize;
+    asm!(
+        "csrrw {old}, hgatp, {val}",
+        "csrr  {new}, hgatp",
+        "csrw  hgatp, {old}",
+        old = out(reg) _,
+        new = out(reg) new,
+        val = in(reg) HGATP_VMID_MASK << HGATP_VMID_SHIFT,
+        options(nomem, nostack),
+    );
+    ((new >> HGATP_VMID_SHIFT) & HGATP_VMID_MASK).count_ones() as usize
+}
+
+// Only the low GEILEN bits of hgeie are writable.
+unsafe fn probe_geilen() -> usize {
+    let new: usize;
+    asm!(
+        "csrrw {old}, hgeie, {val}",
+        "csrr  {new}, hgeie",
+        "csrw  hgeie, {old}",
+        old = out(reg) _,
+        new = out(reg) new,
+        val = in(reg) usize::MAX,
+        options(nomem, nostack),
+    );
+    new.count_ones() as usize
+}
+
+// Without Svnapot the N bit of a leaf PTE is reserved and an access through the PTE faults. The
+// probe loads a known value through a 64 KiB NAPOT mapping built as a VS-stage page table, with
+// HLV and the G-stage in Bare mode so that guest physical addresses are host physical ones.
+unsafe fn probe_svnapot() -> bool {
+    const PTE_V: u64 = 1 << 0;
+    const PTE_R: u64 = 1 << 1;
+    const PTE_A: u64 = 1 << 6;
+    const PTE_N: u64 = 1 << 63;
+    // The low 4 PPN bits of a 64 KiB NAPOT PTE.
+    const PPN_NAPOT_64K: u64 = 0b1000;
+    const NAPOT_SIZE: usize = 0x10000;
+    const VSATP_MODE_SV39: usize = 8 << 60;
+    const PROBE_VALUE: u64 = 0x5356_4e41_504f_5421;
+
+    // Root, level 1 and level 0 tables of an Sv39 mapping of guest virtual addresses 0 to 64 KiB.
+    let layout = Layout::from_size_align(3 * 4096, 4096).unwrap();
+    let tables = alloc_zeroed(layout) as *mut u64;
+    if tables.is_null() {
+        return false;
+    }
+    let table = |level: usize| tables.add(level * 512);
+    // Host memory is identity mapped.
+    let target = &PROBE_VALUE as *const u64 as usize;
+    *table(0) = ((table(1) as u64 >> 12) << 10) | PTE_V;
+    *table(1) = ((table(2) as u64 >> 12) << 10) | PTE_V;
+    let ppn = ((target & !(NAPOT_SIZE - 1)) >> 12) as u64 | PPN_NAPOT_64K;
+    for index in 0..NAPOT_SIZE / 4096 {
+        *table(2).add(index) = PTE_N | (ppn << 10) | PTE_A | PTE_R | PTE_V;
+    }
+
+    let old_hstatus = CSR.hstatus.read_and_set_bits(hstatus::spvp::Supervisor.value);
+    let old_hgatp: usize;
+    let old_vsatp: usize;
+    asm!(
+        "csrrw {old_hgatp}, hgatp, zero",
+        "csrrw {old_vsatp}, vsatp, {vsatp}",
+        "hfence.gvma zero, zero",
+        "hfence.vvma zero, zero",
+        old_hgatp = out(reg) old_hgatp,
+        old_vsatp = out(reg) old_vsatp,
+        vsatp = in(reg) VSATP_MODE_SV39 | (tables as usize >> 12),
+        options(nostack),
+    );
+    let mut value = target % NAPOT_SIZE;
+    let ans = with_detect_trap(0, || {
+        asm!(".word 0x6c054573", inout("a0") value, options(nostack)); // hlv.d a0, (a0)
+    });
+    asm!(
+        "hfence.vvma zero, zero",
+        "hfence.gvma zero, zero",
+        "csrw vsatp, {old_vsatp}",
+        "csrw hgatp, {old_hgatp}",
+        old_hgatp = in(reg) old_hgatp,
+        old_vsatp = in(reg) old_vsatp,
+        options(nostack),
+    );
+    CSR.hstatus.write_value(old_hstatus);
+    dealloc(tables as *mut u8, layout);
+    // An implementation ignoring the N bit reads from another page.
+    ans == 0 && value as u64 == PROBE_VALUE
+}
+
 // Tries to execute all instructions defined in clojure `f`.
 // If resulted in an exception, this function returns its exception id.
 //
@@ -49,6 +280,10 @@ extern "C" fn rust_detect_trap(trap_frame: &mut TrapFrame) {
             // skip current instruction
             trap_frame.sepc = trap_frame.sepc.wrapping_add(insn_bits);
         }
+        // Loads probing a mapping, all of them 32-bit instructions.
+        Trap::Exception(Exception::LoadPageFault) | Trap::Exception(Exception::LoadFault) => {
+            trap_frame.sepc = trap_frame.sepc.wrapping_add(4);
+        }
         Trap::Exception(_) => unreachable!(), // FIXME: unexpected instruction errors
         Trap::Interrupt(_) => unreachable!(), // filtered out for sie == false
     }
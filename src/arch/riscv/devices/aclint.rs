@@ -0,0 +1,139 @@
+use crate::snapshot::{Decoder, Encoder};
+use crate::{vcpus::VM_CPUS_MAX, GuestPhysAddr, HyperError, HyperResult};
+
+/// Size of the ACLINT MTIMER register region.
+pub const ACLINT_MTIMER_SIZE: usize = 0x8000;
+/// Size of the ACLINT SSWI register region.
+pub const ACLINT_SSWI_SIZE: usize = 0x4000;
+
+/// Offset of `mtime` in the MTIMER region, the `mtimecmp` registers start at offset 0.
+const MTIME_OFFSET: usize = 0x7ff8;
+
+/// An emulated ACLINT: an MTIMER device with a read-only view of the guest's time and one
+/// `mtimecmp` per vCPU, plus an SSWI device with one `setssip` register per vCPU. Both raise
+/// VS-level interrupts of the vCPU whose register was written.
+pub struct AclintState {
+    mtimer_base: GuestPhysAddr,
+    sswi_base: GuestPhysAddr,
+    mtimecmp: [u64; VM_CPUS_MAX],
+    // Bitmap of vCPUs with a supervisor software interrupt to inject.
+    ssip_pending: usize,
+    // Bitmap of vCPUs whose `mtimecmp` changed, until their timer is reprogrammed.
+    timer_dirty: usize,
+}
+
+impl AclintState {
+    /// Creates an ACLINT with its MTIMER at `mtimer_base` and its SSWI at `sswi_base`.
+    pub fn new(mtimer_base: GuestPhysAddr, sswi_base: GuestPhysAddr) -> Self {
+        Self {
+            mtimer_base,
+            sswi_base,
+            mtimecmp: [u64::MAX; VM_CPUS_MAX],
+            ssip_pending: 0,
+            timer_dirty: 0,
+        }
+    }
+
+    /// Returns true if `addr` falls in one of the two register regions.
+    pub fn contains(&self, addr: GuestPhysAddr) -> bool {
+        (self.mtimer_base..self.mtimer_base + ACLINT_MTIMER_SIZE).contains(&addr)
+            || (self.sswi_base..self.sswi_base + ACLINT_SSWI_SIZE).contains(&addr)
+    }
+
+    /// Handles a guest load of `width` bytes from `addr`. `now` is the current guest time.
+    pub fn read(&self, addr: GuestPhysAddr, width: usize, now: u64) -> HyperResult<u64> {
+        if (self.sswi_base..self.sswi_base + ACLINT_SSWI_SIZE).contains(&addr) {
+            // setssip always reads as zero.
+            return Ok(0);
+        }
+        let offset = addr - self.mtimer_base;
+        let (reg, shift) = if offset >= MTIME_OFFSET {
+            (now, (offset - MTIME_OFFSET) * 8)
+        } else {
+            let hart = offset / 8;
+            let reg = *self.mtimecmp.get(hart).ok_or(HyperError::InvalidParam)?;
+            (reg, (offset % 8) * 8)
+        };
+        match width {
+            8 if shift == 0 => Ok(reg),
+            4 if shift % 32 == 0 => Ok((reg >> shift) & 0xffff_ffff),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    /// Handles a guest store of the low `width` bytes of `val` to `addr`.
+    pub fn write(&mut self, addr: GuestPhysAddr, width: usize, val: u64) -> HyperResult<()> {
+        if (self.sswi_base..self.sswi_base + ACLINT_SSWI_SIZE).contains(&addr) {
+            let hart = (addr - self.sswi_base) / 4;
+            if hart >= VM_CPUS_MAX {
+                return Err(HyperError::InvalidParam);
+            }
+            if val & 1 != 0 {
+                self.ssip_pending |= 1 << hart;
+            }
+            return Ok(());
+        }
+        let offset = addr - self.mtimer_base;
+        if offset >= MTIME_OFFSET {
+            // mtime is read-only for the guest.
+            return Ok(());
+        }
+        let hart = offset / 8;
+        let cmp = self
+            .mtimecmp
+            .get_mut(hart)
+            .ok_or(HyperError::InvalidParam)?;
+        let shift = (offset % 8) * 8;
+        *cmp = match width {
+            8 if shift == 0 => val,
+            4 if shift % 32 == 0 => {
+                let mask = 0xffff_ffff_u64 << shift;
+                (*cmp & !mask) | ((val & 0xffff_ffff) << shift)
+            }
+            _ => return Err(HyperError::InvalidParam),
+        };
+        self.timer_dirty |= 1 << hart;
+        Ok(())
+    }
+
+    /// Returns the `mtimecmp` of vCPU `hart` if it changed since the last call for that vCPU.
+    pub fn take_timer_update(&mut self, hart: usize) -> Option<u64> {
+        if self.timer_dirty & (1 << hart) == 0 {
+            return None;
+        }
+        self.timer_dirty &= !(1 << hart);
+        Some(self.mtimecmp[hart])
+    }
+
+    /// Returns true if the `mtimecmp` of vCPU `hart` changed since the last `take_timer_update`.
+    pub fn has_timer_update(&self, hart: usize) -> bool {
+        self.timer_dirty & (1 << hart) != 0
+    }
+
+    /// Returns the `mtimecmp` of vCPU `hart`.
+    pub fn mtimecmp(&self, hart: usize) -> u64 {
+        self.mtimecmp[hart]
+    }
+
+    /// Appends the `mtimecmp` registers and pending software interrupts to a snapshot.
+    pub fn save_state(&self, out: &mut Encoder) {
+        out.put_u64s(&self.mtimecmp);
+        out.put_u64(self.ssip_pending as u64);
+    }
+
+    /// Loads the state written by `save_state`. The timer of each vCPU is reprogrammed for its
+    /// restored `mtimecmp` before the vCPU runs again.
+    pub fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        input.get_u64s(&mut self.mtimecmp)?;
+        self.ssip_pending = input.get_u64()? as usize;
+        self.timer_dirty = (1 << VM_CPUS_MAX) - 1;
+        Ok(())
+    }
+
+    /// Returns true, once, if a software interrupt was sent to vCPU `hart`.
+    pub fn take_soft_pending(&mut self, hart: usize) -> bool {
+        let pending = self.ssip_pending & (1 << hart) != 0;
+        self.ssip_pending &= !(1 << hart);
+        pending
+    }
+}
@@ -0,0 +1,323 @@
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+/// Size of the APLIC register region.
+pub const APLIC_SIZE: usize = 0x4000;
+/// Number of interrupt sources of the emulated APLIC, source 0 does not exist.
+pub const APLIC_NUM_SOURCES: usize = 1024;
+
+const DOMAINCFG: usize = 0x0000;
+const SOURCECFG_BASE: usize = 0x0004;
+const SMSIADDRCFG: usize = 0x1bc8;
+const SMSIADDRCFGH: usize = 0x1bcc;
+const SETIP_BASE: usize = 0x1c00;
+const SETIPNUM: usize = 0x1cdc;
+const IN_CLRIP_BASE: usize = 0x1d00;
+const CLRIPNUM: usize = 0x1ddc;
+const SETIE_BASE: usize = 0x1e00;
+const SETIENUM: usize = 0x1edc;
+const CLRIE_BASE: usize = 0x1f00;
+const CLRIENUM: usize = 0x1fdc;
+const SETIPNUM_LE: usize = 0x2000;
+const GENMSI: usize = 0x3000;
+const TARGET_BASE: usize = 0x3004;
+
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+// Bits 31:24 of domaincfg always read as 0x80.
+const DOMAINCFG_FIXED: u32 = 0x8000_0000;
+
+const SOURCECFG_D: u32 = 1 << 10;
+const SOURCECFG_SM_MASK: u32 = 0x7;
+
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_EIID_MASK: u32 = 0x7ff;
+const GENMSI_BUSY: u32 = 1 << 12;
+
+/// Source modes of an APLIC interrupt source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SourceMode {
+    Inactive,
+    Detached,
+    EdgeRising,
+    EdgeFalling,
+    LevelHigh,
+    LevelLow,
+}
+
+impl SourceMode {
+    fn from_sourcecfg(cfg: u32) -> Self {
+        if cfg & SOURCECFG_D != 0 {
+            return Self::Inactive;
+        }
+        match cfg & SOURCECFG_SM_MASK {
+            1 => Self::Detached,
+            4 => Self::EdgeRising,
+            5 => Self::EdgeFalling,
+            6 => Self::LevelHigh,
+            7 => Self::LevelLow,
+            _ => Self::Inactive,
+        }
+    }
+
+    fn is_level(&self) -> bool {
+        matches!(self, Self::LevelHigh | Self::LevelLow)
+    }
+}
+
+/// An emulated APLIC interrupt domain in MSI delivery mode. Wired interrupts are converted into
+/// MSIs towards the IMSIC guest interrupt file of the targeted vCPU.
+pub struct AplicState {
+    base: GuestPhysAddr,
+    domaincfg: u32,
+    sourcecfg: [u32; APLIC_NUM_SOURCES],
+    target: [u32; APLIC_NUM_SOURCES],
+    pending: [u32; APLIC_NUM_SOURCES / 32],
+    enabled: [u32; APLIC_NUM_SOURCES / 32],
+    // Raw (not rectified) input levels of the wires.
+    input: [u32; APLIC_NUM_SOURCES / 32],
+    msiaddrcfg: u64,
+    genmsi: u32,
+}
+
+impl AplicState {
+    /// Creates an APLIC with all sources inactive, located at guest physical address `base`.
+    pub fn new(base: GuestPhysAddr) -> Self {
+        Self {
+            base,
+            domaincfg: DOMAINCFG_DM,
+            sourcecfg: [0; APLIC_NUM_SOURCES],
+            target: [0; APLIC_NUM_SOURCES],
+            pending: [0; APLIC_NUM_SOURCES / 32],
+            enabled: [0; APLIC_NUM_SOURCES / 32],
+            input: [0; APLIC_NUM_SOURCES / 32],
+            msiaddrcfg: 0,
+            genmsi: 0,
+        }
+    }
+
+    /// Guest physical base address of the register region.
+    pub fn base(&self) -> GuestPhysAddr {
+        self.base
+    }
+
+    /// Returns true if `addr` falls in the register region.
+    pub fn contains(&self, addr: GuestPhysAddr) -> bool {
+        (self.base..self.base + APLIC_SIZE).contains(&addr)
+    }
+
+    /// Drives the wire of source `irq` to `level`.
+    pub fn set_irq_level(&mut self, irq: usize, level: bool) -> HyperResult<()> {
+        if irq == 0 || irq >= APLIC_NUM_SOURCES {
+            return Err(HyperError::InvalidParam);
+        }
+        let old = self.input_level(irq);
+        set_bit(&mut self.input, irq, level);
+        match SourceMode::from_sourcecfg(self.sourcecfg[irq]) {
+            SourceMode::EdgeRising if !old && level => self.set_pending(irq, true),
+            SourceMode::EdgeFalling if old && !level => self.set_pending(irq, true),
+            mode @ (SourceMode::LevelHigh | SourceMode::LevelLow) => {
+                // With MSI delivery only a rising edge of the rectified input sets the pending
+                // bit, a source still asserted once its MSI was forwarded is only sent again if
+                // the guest writes its number to setipnum (AIA, section 4.9.2).
+                let was_asserted = old != (mode == SourceMode::LevelLow);
+                let asserted = self.rectified_input(irq);
+                match (was_asserted, asserted) {
+                    (false, true) => self.set_pending(irq, true),
+                    (_, false) => self.set_pending(irq, false),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Handles a 32-bit guest load from `addr`.
+    pub fn read_u32(&self, addr: GuestPhysAddr) -> u32 {
+        let offset = addr - self.base;
+        match offset {
+            DOMAINCFG => DOMAINCFG_FIXED | self.domaincfg,
+            SOURCECFG_BASE..=0x0ffc => self.sourcecfg[source_index(offset, SOURCECFG_BASE)],
+            SMSIADDRCFG => self.msiaddrcfg as u32,
+            SMSIADDRCFGH => (self.msiaddrcfg >> 32) as u32,
+            SETIP_BASE..=0x1c7c => self.pending[(offset - SETIP_BASE) / 4],
+            IN_CLRIP_BASE..=0x1d7c => {
+                let word = (offset - IN_CLRIP_BASE) / 4;
+                (0..32).fold(0, |acc, bit| {
+                    let irq = word * 32 + bit;
+                    acc | ((irq != 0 && self.rectified_input(irq)) as u32) << bit
+                })
+            }
+            SETIE_BASE..=0x1e7c => self.enabled[(offset - SETIE_BASE) / 4],
+            GENMSI => self.genmsi,
+            TARGET_BASE..=0x3ffc => self.target[source_index(offset, TARGET_BASE)],
+            // setipnum/clripnum/setienum/clrienum/clrie and the rest read as zero.
+            _ => 0,
+        }
+    }
+
+    /// Handles a 32-bit guest store of `val` to `addr`. MSIs that become deliverable are handed
+    /// to `send_msi` as `(hart index, eiid)`.
+    pub fn write_u32(&mut self, addr: GuestPhysAddr, val: u32, send_msi: impl FnMut(usize, u32)) {
+        let offset = addr - self.base;
+        match offset {
+            DOMAINCFG => self.domaincfg = (val & DOMAINCFG_IE) | DOMAINCFG_DM,
+            SOURCECFG_BASE..=0x0ffc => {
+                let irq = source_index(offset, SOURCECFG_BASE);
+                self.sourcecfg[irq] = if val & SOURCECFG_D != 0 {
+                    // Delegation to child domains is not supported.
+                    0
+                } else {
+                    val & SOURCECFG_SM_MASK
+                };
+                if SourceMode::from_sourcecfg(self.sourcecfg[irq]) == SourceMode::Inactive {
+                    set_bit(&mut self.pending, irq, false);
+                    set_bit(&mut self.enabled, irq, false);
+                }
+            }
+            SMSIADDRCFG => self.msiaddrcfg = (self.msiaddrcfg & !0xffff_ffff) | val as u64,
+            SMSIADDRCFGH => self.msiaddrcfg = (self.msiaddrcfg & 0xffff_ffff) | (val as u64) << 32,
+            SETIP_BASE..=0x1c7c => {
+                let word = (offset - SETIP_BASE) / 4;
+                for bit in (0..32).filter(|bit| val & (1 << bit) != 0) {
+                    self.software_set_pending(word * 32 + bit);
+                }
+            }
+            SETIPNUM | SETIPNUM_LE => self.software_set_pending(val as usize),
+            IN_CLRIP_BASE..=0x1d7c => self.pending[(offset - IN_CLRIP_BASE) / 4] &= !val,
+            CLRIPNUM => self.clear_pending_num(val as usize),
+            SETIE_BASE..=0x1e7c => {
+                let word = (offset - SETIE_BASE) / 4;
+                self.enabled[word] |= val & self.active_mask(word);
+            }
+            SETIENUM => {
+                let irq = val as usize;
+                if self.is_active(irq) {
+                    set_bit(&mut self.enabled, irq, true);
+                }
+            }
+            CLRIE_BASE..=0x1f7c => self.enabled[(offset - CLRIE_BASE) / 4] &= !val,
+            CLRIENUM => {
+                if (val as usize) < APLIC_NUM_SOURCES {
+                    set_bit(&mut self.enabled, val as usize, false);
+                }
+            }
+            GENMSI => {
+                // Extempore MSIs are delivered synchronously, so the busy bit never reads as set.
+                self.genmsi = val & !GENMSI_BUSY;
+                let mut send_msi = send_msi;
+                send_msi((val >> TARGET_HART_SHIFT) as usize, val & TARGET_EIID_MASK);
+                return;
+            }
+            TARGET_BASE..=0x3ffc => {
+                let irq = source_index(offset, TARGET_BASE);
+                // The guest index field is ignored, MSIs always go to the vCPU's own file.
+                self.target[irq] = val & !(0x3f << 12) & !(1 << 11);
+            }
+            _ => {}
+        }
+        self.deliver(send_msi);
+    }
+
+    /// Forwards every pending and enabled source as an MSI, clearing its pending bit.
+    pub fn deliver(&mut self, mut send_msi: impl FnMut(usize, u32)) {
+        if self.domaincfg & DOMAINCFG_IE == 0 {
+            return;
+        }
+        for word in 0..self.pending.len() {
+            let mut ready = self.pending[word] & self.enabled[word];
+            while ready != 0 {
+                let bit = ready.trailing_zeros() as usize;
+                ready &= ready - 1;
+                let irq = word * 32 + bit;
+                self.pending[word] &= !(1 << bit);
+                let target = self.target[irq];
+                send_msi((target >> TARGET_HART_SHIFT) as usize, target & TARGET_EIID_MASK);
+            }
+        }
+    }
+
+    /// Appends the domain's registers and the levels of its wires to a snapshot.
+    pub fn save_state(&self, out: &mut Encoder) {
+        out.put_u32(self.domaincfg);
+        out.put_u32s(&self.sourcecfg);
+        out.put_u32s(&self.target);
+        out.put_u32s(&self.pending);
+        out.put_u32s(&self.enabled);
+        out.put_u32s(&self.input);
+        out.put_u64(self.msiaddrcfg);
+        out.put_u32(self.genmsi);
+    }
+
+    /// Loads the state written by `save_state`.
+    pub fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        self.domaincfg = input.get_u32()?;
+        input.get_u32s(&mut self.sourcecfg)?;
+        input.get_u32s(&mut self.target)?;
+        input.get_u32s(&mut self.pending)?;
+        input.get_u32s(&mut self.enabled)?;
+        input.get_u32s(&mut self.input)?;
+        self.msiaddrcfg = input.get_u64()?;
+        self.genmsi = input.get_u32()?;
+        Ok(())
+    }
+}
+
+// Private methods implementation
+impl AplicState {
+    fn input_level(&self, irq: usize) -> bool {
+        self.input[irq / 32] & (1 << (irq % 32)) != 0
+    }
+
+    fn rectified_input(&self, irq: usize) -> bool {
+        match SourceMode::from_sourcecfg(self.sourcecfg[irq]) {
+            SourceMode::EdgeRising | SourceMode::LevelHigh => self.input_level(irq),
+            SourceMode::EdgeFalling | SourceMode::LevelLow => !self.input_level(irq),
+            _ => false,
+        }
+    }
+
+    fn is_active(&self, irq: usize) -> bool {
+        irq != 0
+            && irq < APLIC_NUM_SOURCES
+            && SourceMode::from_sourcecfg(self.sourcecfg[irq]) != SourceMode::Inactive
+    }
+
+    fn active_mask(&self, word: usize) -> u32 {
+        (0..32).fold(0, |acc, bit| acc | (self.is_active(word * 32 + bit) as u32) << bit)
+    }
+
+    fn set_pending(&mut self, irq: usize, pending: bool) {
+        set_bit(&mut self.pending, irq, pending);
+    }
+
+    // A level-sensitive source may only be made pending by software while its input is asserted.
+    fn software_set_pending(&mut self, irq: usize) {
+        if !self.is_active(irq) {
+            return;
+        }
+        let mode = SourceMode::from_sourcecfg(self.sourcecfg[irq]);
+        if !mode.is_level() || self.rectified_input(irq) {
+            self.set_pending(irq, true);
+        }
+    }
+
+    fn clear_pending_num(&mut self, irq: usize) {
+        if irq < APLIC_NUM_SOURCES {
+            self.set_pending(irq, false);
+        }
+    }
+}
+
+fn source_index(offset: usize, base: usize) -> usize {
+    (offset - base) / 4 + 1
+}
+
+fn set_bit(bitmap: &mut [u32], index: usize, val: bool) {
+    if val {
+        bitmap[index / 32] |= 1 << (index % 32);
+    } else {
+        bitmap[index / 32] &= !(1 << (index % 32));
+    }
+}
@@ -0,0 +1,83 @@
+use spin::{Mutex, Once};
+
+use crate::{vcpus::MAX_CPUS, HostPhysAddr, HyperError, HyperResult};
+
+/// Size of one IMSIC interrupt file.
+pub const IMSIC_FILE_SIZE: usize = 0x1000;
+
+/// Offset of the little-endian `seteipnum` register in an interrupt file.
+const IMSIC_SETEIPNUM_LE: usize = 0x0;
+
+/// Location of the host's S-level IMSICs, as described by the host device tree. The interrupt
+/// files of hart `i` start at `base + i * hart_stride`: first the S-level file, then the
+/// guest interrupt files 1..=GEILEN.
+#[derive(Clone, Copy, Debug)]
+pub struct ImsicGeometry {
+    /// Base address of hart 0's interrupt files.
+    pub base: HostPhysAddr,
+    /// Distance between the interrupt files of two consecutive harts.
+    pub hart_stride: usize,
+    /// Number of guest interrupt files per hart.
+    pub geilen: usize,
+}
+
+static IMSIC_GEOMETRY: Once<ImsicGeometry> = Once::new();
+
+/// Guest interrupt files in use, one bitmap per physical hart. Bit `i` stands for guest file `i`.
+static GUEST_FILES_USED: Mutex<[usize; MAX_CPUS]> = Mutex::new([0; MAX_CPUS]);
+
+/// Registers the host IMSIC layout. Must be called once before any VM enables AIA.
+pub fn init_imsic(geometry: ImsicGeometry) {
+    IMSIC_GEOMETRY.call_once(|| geometry);
+}
+
+/// A guest interrupt file of a physical hart, owned by one vCPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImsicGuestFile {
+    hart_id: usize,
+    index: usize,
+}
+
+impl ImsicGuestFile {
+    /// Allocates a free guest interrupt file on physical hart `hart_id`.
+    pub fn alloc(hart_id: usize) -> HyperResult<Self> {
+        let geometry = IMSIC_GEOMETRY.get().ok_or(HyperError::BadState)?;
+        let mut used = GUEST_FILES_USED.lock();
+        let bitmap = used.get_mut(hart_id).ok_or(HyperError::InvalidParam)?;
+        let index = (1..=geometry.geilen)
+            .find(|i| *bitmap & (1 << i) == 0)
+            .ok_or(HyperError::NoMemory)?;
+        *bitmap |= 1 << index;
+        Ok(Self { hart_id, index })
+    }
+
+    /// Gives the file back to the allocator.
+    pub fn free(self) {
+        GUEST_FILES_USED.lock()[self.hart_id] &= !(1 << self.index);
+    }
+
+    /// The physical hart the file belongs to.
+    pub fn hart_id(&self) -> usize {
+        self.hart_id
+    }
+
+    /// The guest external interrupt number of the file, i.e. the value for `hstatus.VGEIN` and
+    /// the bit in `hgeie`/`hgeip`.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Host physical address of the file.
+    pub fn host_addr(&self) -> HostPhysAddr {
+        let geometry = IMSIC_GEOMETRY.get().unwrap();
+        geometry.base + self.hart_id * geometry.hart_stride + self.index * IMSIC_FILE_SIZE
+    }
+
+    /// Makes external interrupt identity `eiid` pending in the file.
+    pub fn send_msi(&self, eiid: u32) {
+        let addr = self.host_addr() + IMSIC_SETEIPNUM_LE;
+        unsafe {
+            core::ptr::write_volatile(addr as *mut u32, eiid);
+        }
+    }
+}
@@ -1,16 +1,32 @@
-use crate::{
-    arch::csrs::{traps, RiscvCsrTrait, CSR},
-    vcpus::MAX_CPUS,
-};
+use crate::snapshot::{Decoder, Encoder};
+use crate::vcpus::MAX_CPUS;
+use crate::{HyperError, HyperResult};
 
 /// Number of contexts for the PLIC. Value is twice the max number of harts because each hart will
 /// have one M-mode context and one S-mode context.
 pub const MAX_CONTEXTS: usize = 2 * MAX_CPUS;
 
+/// Number of interrupt sources, including the reserved source 0.
+const MAX_SOURCES: usize = 512;
+
+/// The S-mode context of `hart`.
+pub fn supervisor_context(hart: usize) -> usize {
+    2 * hart + 1
+}
+
+/// The PLIC of a guest, passing the interrupts of the host PLIC through.
+///
+/// Sources driven by emulated devices with [`set_irq_level`](Self::set_irq_level) are virtual:
+/// their priority and enable bits are the ones the guest programmed in the host PLIC, but they
+/// are claimed and completed here without reaching the host. They must not be wired to a host
+/// device.
 pub struct PlicState {
     base: usize,
     source_priority: [u32; 512],
+    // Pending virtual sources.
     pending: [u32; 16],
+    // Sources driven by emulated devices. Rebuilt from the device lines before the VM runs.
+    virtual_sources: [u32; 16],
     enable: [[u32; 32]; MAX_CONTEXTS],
     thresholds: [u32; MAX_CONTEXTS],
     pub claim_complete: [u32; MAX_CONTEXTS],
@@ -22,6 +38,7 @@ impl PlicState {
             base,
             source_priority: [0; 512],
             pending: [0; 16],
+            virtual_sources: [0; 16],
             enable: [[0; 32]; MAX_CONTEXTS],
             thresholds: [0; MAX_CONTEXTS],
             claim_complete: [0; MAX_CONTEXTS],
@@ -32,6 +49,51 @@ impl PlicState {
         self.base
     }
 
+    /// Appends the emulated registers to a snapshot.
+    pub fn save_state(&self, out: &mut Encoder) {
+        out.put_u32s(&self.source_priority);
+        out.put_u32s(&self.pending);
+        for enable in self.enable.iter() {
+            out.put_u32s(enable);
+        }
+        out.put_u32s(&self.thresholds);
+        out.put_u32s(&self.claim_complete);
+    }
+
+    /// Loads the registers written by `save_state`. Only the emulated state is restored, the
+    /// thresholds of the host PLIC are updated by the guest's next write.
+    pub fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        input.get_u32s(&mut self.source_priority)?;
+        input.get_u32s(&mut self.pending)?;
+        for enable in self.enable.iter_mut() {
+            input.get_u32s(enable)?;
+        }
+        input.get_u32s(&mut self.thresholds)?;
+        input.get_u32s(&mut self.claim_complete)
+    }
+
+    /// Drives virtual source `irq` to `level`. A level-triggered source is pending while its
+    /// line is high, except between its claim and its completion.
+    pub fn set_irq_level(&mut self, irq: usize, level: bool) -> HyperResult<()> {
+        if irq == 0 || irq >= MAX_SOURCES {
+            return Err(HyperError::OutOfRange);
+        }
+        let (word, bit) = (irq / 32, 1 << (irq % 32));
+        self.virtual_sources[word] |= bit;
+        if level && !self.claim_complete.contains(&(irq as u32)) {
+            self.pending[word] |= bit;
+        } else {
+            self.pending[word] &= !bit;
+        }
+        Ok(())
+    }
+
+    /// Returns true if a virtual source is pending for `context`, i.e. enabled with a priority
+    /// above the context's threshold.
+    pub fn has_pending(&self, context: usize) -> bool {
+        self.best_pending(context).is_some()
+    }
+
     pub fn read_u32(&mut self, addr: usize) -> u32 {
         let offset = addr.wrapping_sub(self.base);
         if (0x20_0000..0x20_0000 + 0x1000 * MAX_CONTEXTS).contains(&offset) {
@@ -40,6 +102,12 @@ impl PlicState {
             let index = ((offset - 0x200000) & 0xfff) >> 2;
             if index == 1 {
                 // debug!("PLIC read@{:#x} -> {:#x}", addr, self.claim_complete[hart]);
+                if self.claim_complete[hart] == 0 {
+                    if let Some(irq) = self.best_pending(hart) {
+                        self.pending[irq / 32] &= !(1 << (irq % 32));
+                        self.claim_complete[hart] = irq as u32;
+                    }
+                }
                 return self.claim_complete[hart];
             }
             todo!()
@@ -47,7 +115,9 @@ impl PlicState {
         todo!()
     }
 
-    pub fn write_u32(&mut self, addr: usize, val: u32) {
+    /// Returns true if the write completed the interrupt claimed by a context, which must then
+    /// stop being injected into the guest.
+    pub fn write_u32(&mut self, addr: usize, val: u32) -> bool {
         // debug!("PLIC write@{:#x} -> {:#x}", addr, val);
         let offset = addr.wrapping_sub(self.base);
         // threshold/claim/complete
@@ -62,16 +132,52 @@ impl PlicState {
                 }
             } else if index == 1 {
                 // claim
-                unsafe {
-                    core::ptr::write_volatile(addr as *mut u32, val);
+                if !self.is_virtual(val as usize) {
+                    unsafe {
+                        core::ptr::write_volatile(addr as *mut u32, val);
+                    }
                 }
                 self.claim_complete[hart] = 0;
-                // Send Interrupt to the hart
-                CSR.hvip
-                    .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+                return true;
             }
         } else {
             todo!()
         }
+        false
+    }
+}
+
+// Private methods implementation
+impl PlicState {
+    fn is_virtual(&self, irq: usize) -> bool {
+        irq < MAX_SOURCES && self.virtual_sources[irq / 32] & (1 << (irq % 32)) != 0
+    }
+
+    /// The pending virtual source with the highest priority that `context` takes, the one with
+    /// the lowest ID among equals.
+    fn best_pending(&self, context: usize) -> Option<usize> {
+        let mut best: Option<(usize, u32)> = None;
+        for (word, &pending) in self.pending.iter().enumerate() {
+            let mut bits = pending & self.host_enable(context, word);
+            while bits != 0 {
+                let irq = word * 32 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                let priority = self.host_priority(irq);
+                if priority > self.thresholds[context] && best.map_or(true, |(_, p)| priority > p) {
+                    best = Some((irq, priority));
+                }
+            }
+        }
+        best.map(|(irq, _)| irq)
+    }
+
+    // The guest programs priorities and enable bits in the host PLIC directly.
+    fn host_priority(&self, irq: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + 4 * irq) as *const u32) }
+    }
+
+    fn host_enable(&self, context: usize, word: usize) -> u32 {
+        let addr = self.base + 0x2000 + 0x80 * context + 4 * word;
+        unsafe { core::ptr::read_volatile(addr as *const u32) }
     }
 }
@@ -1,5 +1,8 @@
-use page_table::{PageTable64, PagingMetaData};
-use page_table_entry::riscv::Rv64PTE;
+use memory_addr::VirtAddr;
+use page_table::{PageTable64, PagingIf, PagingMetaData};
+use page_table_entry::{riscv::Rv64PTE, MappingFlags};
+
+use crate::{dirty_log::DirtyLogPageTable, GuestPhysAddr, HyperError, HyperResult};
 
 pub struct Sv39GuestMetaData;
 
@@ -12,3 +15,18 @@ impl PagingMetaData for Sv39GuestMetaData {
 
 /// Nested page table define.
 pub type NestedPageTable<I> = PageTable64<Sv39GuestMetaData, Rv64PTE, I>;
+
+impl<I: PagingIf> DirtyLogPageTable for NestedPageTable<I> {
+    fn set_writable(&mut self, gpa: GuestPhysAddr, writable: bool) -> HyperResult<usize> {
+        let vaddr = VirtAddr::from(gpa);
+        let (_, flags, page_size) = self.query(vaddr).map_err(|_| HyperError::NotFound)?;
+        let flags = if writable {
+            flags | MappingFlags::WRITE
+        } else {
+            flags - MappingFlags::WRITE
+        };
+        self.update(vaddr, None, Some(flags))
+            .map_err(|_| HyperError::Internal)?;
+        Ok(page_size as usize)
+    }
+}
@@ -1,3 +1,4 @@
+mod bench;
 mod csrs;
 mod detect;
 mod devices;
@@ -12,26 +13,61 @@ mod vmexit;
 mod vmm;
 mod vmm_trap;
 
+pub use bench::{run_ecall_bench, EcallBenchResult, ECALL_BENCH_EID, ECALL_BENCH_GUEST};
 pub use ept::NestedPageTable;
 pub use regs::GprIndex;
 pub use sbi::SbiMessage as HyperCallMsg;
+pub use sbi::{SbiExtensionHandler, SbiReturn};
 pub use smp::PerCpu;
 pub use vcpu::VCpu;
 pub use vm::VM;
 pub use vmexit::VmExitInfo;
-pub use vmm::VMM;
+pub use vmm::{SbiConsole, VMM};
 
 use self::csrs::{traps, ReadWriteCsr, RiscvCsrTrait, CSR};
 use self::detect::detect_h_extension;
+pub use self::detect::HostCapabilities;
+pub use self::devices::imsic::{init_imsic, ImsicGeometry};
 use self::devices::plic::PlicState;
 use self::vcpu::VmCpuRegisters;
 use sbi::BaseFunction;
+use spin::Once;
+
+use crate::vmid::VmidAllocator;
+
+/// The host's capabilities, detected in `init_hv_runtime`.
+static HOST_CAPS: Once<HostCapabilities> = Once::new();
+
+/// Returns the capabilities of the host detected by `init_hv_runtime`.
+pub fn host_capabilities() -> &'static HostCapabilities {
+    HOST_CAPS
+        .get()
+        .expect("host capabilities queried before init_hv_runtime")
+}
+
+/// The allocator of `hgatp.VMID`s, set up in `init_hv_runtime`.
+static VMID_ALLOCATOR: Once<VmidAllocator> = Once::new();
+
+/// Returns the allocator of `hgatp.VMID`s shared by all harts.
+pub(crate) fn vmid_allocator() -> &'static VmidAllocator {
+    VMID_ALLOCATOR
+        .get()
+        .expect("VMID allocator used before init_hv_runtime")
+}
+
+/// Returns true if guest timers are handled by hardware through `vstimecmp`.
+pub(crate) fn has_sstc() -> bool {
+    HOST_CAPS.get().map_or(false, |caps| caps.sstc)
+}
 
 /// Initialize the hypervisor runtime.
 pub fn init_hv_runtime() {
     if !detect_h_extension() {
         panic!("H Extension not supported.")
     }
+    let caps = HOST_CAPS.call_once(HostCapabilities::detect);
+    info!("host capabilities: {:?}", caps);
+    VMID_ALLOCATOR.call_once(|| VmidAllocator::new((1 << caps.vmid_bits) - 1));
 
     unsafe {
         setup_csrs();
@@ -68,6 +104,38 @@ unsafe fn setup_csrs() {
     // clear all interrupts.
     CSR.hcounteren.write_value(0xffff_ffff);
 
+    // HLV/HSV from HS-mode access guest memory with VS-mode privilege. hstatus is swapped on
+    // guest entry and exit, so this is the value in effect while exits are handled.
+    CSR.hstatus
+        .read_and_set_bits(csrs::defs::hstatus::spvp::Supervisor.value);
+
+    // Enable the VS-level features the host implements. With Sstc guests program vstimecmp, so
+    // their timer interrupts no longer need the SBI SetTimer emulation path.
+    use csrs::defs::henvcfg;
+    let caps = host_capabilities();
+    let mut envcfg = 0;
+    if caps.sstc {
+        envcfg |= henvcfg::stce::SET.value;
+    }
+    if caps.svpbmt {
+        envcfg |= henvcfg::pbmte::SET.value;
+    }
+    if caps.zicbom {
+        envcfg |= henvcfg::cbie::SET.value | henvcfg::cbcfe::SET.value;
+    }
+    if caps.zicboz {
+        envcfg |= henvcfg::cbze::SET.value;
+    }
+    CSR.henvcfg.read_and_set_bits(envcfg);
+
+    // Get notified about interrupts arriving in the IMSIC guest interrupt files of vCPUs that
+    // are not running. Which files may raise it is controlled through hgeie.
+    if caps.ssaia && caps.geilen > 0 {
+        CSR.hgeie.write_value(0);
+        CSR.hie
+            .read_and_set_bits(traps::interrupt::SUPERVISOR_GUEST_EXTERNEL);
+    }
+
     // enable interrupt
     CSR.sie.write_value(
         traps::interrupt::SUPERVISOR_EXTERNAL
@@ -1,4 +1,4 @@
-#[derive(Default)]
+#[derive(Clone, Default)]
 #[repr(C)]
 pub struct GeneralPurposeRegisters([usize; 32]);
 
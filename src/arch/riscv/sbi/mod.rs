@@ -3,6 +3,7 @@ mod dbcn;
 mod pmu;
 mod rfnc;
 mod srst;
+mod vendor;
 
 use crate::{HyperError, HyperResult};
 pub use base::BaseFunction;
@@ -11,6 +12,7 @@ pub use pmu::PmuFunction;
 pub use rfnc::RemoteFenceFunction;
 use sbi_spec;
 pub use srst::ResetFunction;
+pub use vendor::{is_vendor_extension, SbiExtensionHandler, SbiExtensionRegistry};
 
 pub const SBI_SUCCESS: usize = 0;
 pub const SBI_ERR_FAILUER: isize = -1;
@@ -29,6 +31,24 @@ pub struct SbiReturn {
     pub return_value: i64,
 }
 
+impl SbiReturn {
+    /// Creates a successful return carrying `value`.
+    pub fn success(value: i64) -> Self {
+        Self {
+            error_code: SBI_SUCCESS as i64,
+            return_value: value,
+        }
+    }
+
+    /// Creates a failed return with the given SBI error code.
+    pub fn error(error_code: isize) -> Self {
+        Self {
+            error_code: error_code as i64,
+            return_value: 0,
+        }
+    }
+}
+
 /// SBI return value conventions
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SbiReturnTyoe {
@@ -58,6 +78,14 @@ pub enum SbiMessage {
     RemoteFence(RemoteFenceFunction),
     /// The PMU Extension
     PMU(PmuFunction),
+    /// A vendor or firmware specific extension, handled by the embedder through
+    /// `SbiExtensionRegistry`.
+    VendorExtension {
+        /// The extension ID in A7.
+        eid: usize,
+        /// The function ID in A6.
+        fid: usize,
+    },
 }
 
 impl SbiMessage {
@@ -76,6 +104,7 @@ impl SbiMessage {
                 RemoteFenceFunction::from_args(args).map(SbiMessage::RemoteFence)
             }
             sbi_spec::pmu::EID_PMU => PmuFunction::from_regs(args).map(SbiMessage::PMU),
+            eid if is_vendor_extension(eid) => Ok(SbiMessage::VendorExtension { eid, fid: args[6] }),
             _ => {
                 error!("args: {:?}", args);
                 error!("args[7]: {:#x}", args[7]);
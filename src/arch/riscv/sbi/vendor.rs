@@ -0,0 +1,97 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use super::SbiReturn;
+use crate::{GuestMemoryAccess, HyperError, HyperResult};
+
+/// Extension IDs reserved by the SBI specification for vendor-specific extensions.
+pub const EID_VENDOR_RANGE: RangeInclusive<usize> = 0x0900_0000..=0x09ff_ffff;
+/// Extension IDs reserved by the SBI specification for firmware-specific extensions.
+pub const EID_FIRMWARE_RANGE: RangeInclusive<usize> = 0x0a00_0000..=0x0aff_ffff;
+
+/// Returns true if `eid` lies in the vendor or firmware specific extension space.
+pub fn is_vendor_extension(eid: usize) -> bool {
+    EID_VENDOR_RANGE.contains(&eid) || EID_FIRMWARE_RANGE.contains(&eid)
+}
+
+/// An SBI extension implemented by the embedder instead of hypercraft, e.g. a hypercall interface
+/// for guests to query their VM ID or to exchange messages with other VMs.
+pub trait SbiExtensionHandler: Send {
+    /// Handles function `fid` of extension `eid`. `args` holds the caller's a0-a7 and
+    /// `guest_mem` gives access to the calling VM's memory. The returned value is written back to
+    /// a0/a1 of the caller.
+    fn handle_ecall(
+        &mut self,
+        eid: usize,
+        fid: usize,
+        args: &[usize],
+        guest_mem: &dyn GuestMemoryAccess,
+    ) -> SbiReturn;
+}
+
+struct SbiExtensionEntry {
+    eids: RangeInclusive<usize>,
+    handler: Box<dyn SbiExtensionHandler>,
+}
+
+/// The vendor and firmware SBI extensions registered for a VM.
+#[derive(Default)]
+pub struct SbiExtensionRegistry {
+    entries: Vec<SbiExtensionEntry>,
+}
+
+impl SbiExtensionRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Installs `handler` for the extension IDs in `eids`. The range must lie entirely in the
+    /// vendor or in the firmware extension space and must not overlap an already registered one.
+    pub fn register(
+        &mut self,
+        eids: RangeInclusive<usize>,
+        handler: Box<dyn SbiExtensionHandler>,
+    ) -> HyperResult<()> {
+        let (start, end) = (*eids.start(), *eids.end());
+        let in_space = |space: &RangeInclusive<usize>| space.contains(&start) && space.contains(&end);
+        if start > end || !(in_space(&EID_VENDOR_RANGE) || in_space(&EID_FIRMWARE_RANGE)) {
+            return Err(HyperError::InvalidParam);
+        }
+        if self
+            .entries
+            .iter()
+            .any(|e| start <= *e.eids.end() && *e.eids.start() <= end)
+        {
+            return Err(HyperError::BadState);
+        }
+        self.entries.push(SbiExtensionEntry { eids, handler });
+        Ok(())
+    }
+
+    /// Removes the handler registered for `eid`, returning it if there was one.
+    pub fn unregister(&mut self, eid: usize) -> Option<Box<dyn SbiExtensionHandler>> {
+        let index = self.entries.iter().position(|e| e.eids.contains(&eid))?;
+        Some(self.entries.swap_remove(index).handler)
+    }
+
+    /// Returns true if a handler is registered for `eid`.
+    pub fn contains(&self, eid: usize) -> bool {
+        self.entries.iter().any(|e| e.eids.contains(&eid))
+    }
+
+    /// Dispatches the call to the handler registered for `eid`. Returns `None` if there is none.
+    pub fn handle_ecall(
+        &mut self,
+        eid: usize,
+        fid: usize,
+        args: &[usize],
+        guest_mem: &dyn GuestMemoryAccess,
+    ) -> Option<SbiReturn> {
+        let entry = self.entries.iter_mut().find(|e| e.eids.contains(&eid))?;
+        Some(entry.handler.handle_ecall(eid, fid, args, guest_mem))
+    }
+}
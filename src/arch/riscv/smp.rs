@@ -96,6 +96,11 @@ impl<H: HyperCraftHal> PerCpu<H> {
         pcpu
     }
 
+    /// The id of the hart this structure belongs to.
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id
+    }
+
     /// Get stack top addr.
     pub fn stack_top_addr(&self) -> HostVirtAddr {
         self.stack_top_addr
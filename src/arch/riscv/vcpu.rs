@@ -7,21 +7,33 @@ use tock_registers::LocalRegisterCopy;
 
 // use alloc::sync::Arc;
 use riscv::register::{
-    htimedelta, htinst, htval, hvip, scause, sstatus, stval, vsatp, vscause, vsepc, vsie,
+    htimedelta, htinst, htval, hvip, scause, sstatus, stval, time, vsatp, vscause, vsepc, vsie,
     vsscratch, vsstatus, vstval, vstvec,
 };
 
 use crate::arch::vmexit::PrivilegeLevel;
-use crate::arch::{traps, RiscvCsrTrait, CSR};
+use crate::arch::{has_sstc, traps, RiscvCsrTrait, CSR};
+use crate::snapshot::{Decoder, Encoder};
 use crate::{
     arch::sbi::SbiMessage, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPhysAddr,
-    HyperCraftHal, VmExitInfo,
+    HyperCraftHal, HyperResult, VmExitInfo,
 };
 
 use super::csrs::defs::hstatus;
+use super::detect::{HGATP_VMID_MASK, HGATP_VMID_SHIFT};
 use super::regs::{GeneralPurposeRegisters, GprIndex};
 // use super::Guest;
 
+/// The `hvip` bits injecting VS-level software, timer and external interrupts.
+const HVIP_VS_INTERRUPTS: usize = traps::interrupt::VIRTUAL_SUPERVISOR_SOFT
+    | traps::interrupt::VIRTUAL_SUPERVISOR_TIMER
+    | traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL;
+
+// The `sstatus`/`vsstatus` bits a trap into S-mode updates.
+const SSTATUS_SIE: usize = 1 << 1;
+const SSTATUS_SPIE: usize = 1 << 5;
+const SSTATUS_SPP: usize = 1 << 8;
+
 /// Hypervisor GPR and CSR state which must be saved/restored when entering/exiting virtualization.
 #[derive(Default)]
 #[repr(C)]
@@ -67,7 +79,9 @@ pub struct GuestVsCsrs {
 #[derive(Default)]
 #[repr(C)]
 pub struct GuestVirtualHsCsrs {
+    // The VS-level interrupt enables, which the guest sees as `vsie`.
     hie: usize,
+    // The bit of the vCPU's IMSIC guest interrupt file, enabled while the vCPU is switched out.
     hgeie: usize,
     hgatp: usize,
     hvip: usize,
@@ -206,6 +220,9 @@ extern "C" {
     fn _run_guest(state: *mut VmCpuRegisters);
 }
 
+/// Interrupt code of the supervisor guest external interrupt (SGEI).
+const IRQ_S_GUEST_EXTERNAL: usize = 12;
+
 pub enum VmCpuStatus {
     /// The vCPU is not powered on.
     PoweredOff,
@@ -220,6 +237,8 @@ pub enum VmCpuStatus {
 pub struct VCpu<H: HyperCraftHal> {
     vcpu_id: usize,
     regs: VmCpuRegisters,
+    // Host time of the next guest timer event, when it is not handled through `vstimecmp`.
+    timer: u64,
     // gpt: G,
     // pub guest: Arc<Guest>,
     marker: PhantomData<H>,
@@ -229,14 +248,14 @@ impl<H: HyperCraftHal> VCpu<H> {
     /// Create a new vCPU
     pub fn new(vcpu_id: usize, entry: GuestPhysAddr) -> Self {
         let mut regs = VmCpuRegisters::default();
-        // Set hstatus
+        // Set hstatus, it is only loaded into the CSR when entering the guest.
         let mut hstatus = LocalRegisterCopy::<usize, hstatus::Register>::new(
             riscv::register::hstatus::read().bits(),
         );
         hstatus.modify(hstatus::spv::Supervisor);
-        // Set SPVP bit in order to accessing VS-mode memory from HS-mode.
         hstatus.modify(hstatus::spvp::Supervisor);
-        CSR.hstatus.write_value(hstatus.get());
+        // Trap WFI, so that the VMM can run other vCPUs while this one waits for an interrupt.
+        hstatus.modify(hstatus::vtw.val(1));
         regs.guest_regs.hstatus = hstatus.get();
 
         // Set sstatus
@@ -247,11 +266,15 @@ impl<H: HyperCraftHal> VCpu<H> {
         regs.guest_regs.gprs.set_reg(GprIndex::A0, 0);
         regs.guest_regs.gprs.set_reg(GprIndex::A1, 0x9000_0000);
 
+        // No guest timer is armed until the guest programs one.
+        regs.vs_csrs.vstimecmp = usize::MAX;
+
         // Set entry
         regs.guest_regs.sepc = entry;
         Self {
             vcpu_id,
             regs,
+            timer: u64::MAX,
             // gpt,
             marker: PhantomData,
         }
@@ -264,6 +287,24 @@ impl<H: HyperCraftHal> VCpu<H> {
         self.regs.virtual_hs_csrs.hgatp = token;
     }
 
+    /// Tags the vCPU's guest TLB entries with `vmid`. Takes effect on the next
+    /// `restore_virtual_hs_csrs`.
+    pub fn set_vmid(&mut self, vmid: usize) {
+        let hgatp = &mut self.regs.virtual_hs_csrs.hgatp;
+        *hgatp = (*hgatp & !(HGATP_VMID_MASK << HGATP_VMID_SHIFT))
+            | (vmid & HGATP_VMID_MASK) << HGATP_VMID_SHIFT;
+    }
+
+    /// Routes the IMSIC guest interrupt file `vgein` to this vCPU as its VS-level external
+    /// interrupt source. Takes effect on the next entry into the guest.
+    pub fn set_vgein(&mut self, vgein: usize) {
+        let mut hstatus =
+            LocalRegisterCopy::<usize, hstatus::Register>::new(self.regs.guest_regs.hstatus);
+        hstatus.modify(hstatus::vgein.val(vgein));
+        self.regs.guest_regs.hstatus = hstatus.get();
+        self.regs.virtual_hs_csrs.hgeie = if vgein == 0 { 0 } else { 1 << vgein };
+    }
+
     /// 恢復該虛擬機對應的 vs 系統暫存器
     pub fn restore_vs_csrs(&mut self) {
         unsafe {
@@ -278,7 +319,6 @@ impl<H: HyperCraftHal> VCpu<H> {
                 "csrw vscause, {vscause}",
                 "csrw vstval, {vstval}",
                 "csrw vsatp, {vsatp}",
-                // "csrw vstimecmp, {vstimecmp}",
                 htimedelta = in(reg) self.regs.vs_csrs.htimedelta,
                 vsstatus = in(reg) self.regs.vs_csrs.vsstatus,
                 vsie = in(reg) self.regs.vs_csrs.vsie,
@@ -288,9 +328,10 @@ impl<H: HyperCraftHal> VCpu<H> {
                 vscause = in(reg) self.regs.vs_csrs.vscause,
                 vstval = in(reg) self.regs.vs_csrs.vstval,
                 vsatp = in(reg) self.regs.vs_csrs.vsatp,
-                // vstimecmp = in(reg) self.regs.vs_csrs.vstimecmp
             );
-            core::arch::riscv64::hfence_gvma_all();
+        }
+        if has_sstc() {
+            CSR.vstimecmp.write_value(self.regs.vs_csrs.vstimecmp);
         }
     }
 
@@ -305,48 +346,99 @@ impl<H: HyperCraftHal> VCpu<H> {
         self.regs.vs_csrs.vscause = vscause::read().bits();
         self.regs.vs_csrs.vstval = vstval::read();
         self.regs.vs_csrs.vsatp = vsatp::read().bits();
+        if has_sstc() {
+            self.regs.vs_csrs.vstimecmp = CSR.vstimecmp.get_value();
+        }
+    }
+
+    /// Arms the guest timer through `vstimecmp`, only meaningful when the host supports Sstc.
+    /// The new value takes effect on the next `restore_vs_csrs`.
+    pub fn set_vstimecmp(&mut self, stime_value: u64) {
+        self.regs.vs_csrs.vstimecmp = stime_value as usize;
+    }
 
-        // vstimecmp 是 sstc 擴展中加入的，暫時不理會
-        // self.regs.vs_csrs.vstimecmp = ???
+    /// The guest time of the next timer event programmed in `vstimecmp`.
+    pub fn vstimecmp(&self) -> u64 {
+        self.regs.vs_csrs.vstimecmp as u64
     }
 
-    /// 恢復該虛擬機對應的 hgatp, hvip, ...
+    /// Records the host time of the guest's next timer event, for VMMs emulating the guest
+    /// timer without Sstc.
+    pub fn set_timer(&mut self, timer: u64) {
+        self.timer = timer;
+    }
+
+    /// The host time of the guest's next timer event, see `set_timer`.
+    pub fn timer(&self) -> u64 {
+        self.timer
+    }
+
+    /// Converts `guest_time`, a value of the guest's `time` CSR such as a timer deadline, to the
+    /// host's time. `u64::MAX`, a timer that never fires, is kept as is.
+    pub fn guest_to_host_time(&self, guest_time: u64) -> u64 {
+        match guest_time {
+            u64::MAX => u64::MAX,
+            time => time.wrapping_sub(self.regs.vs_csrs.htimedelta as u64),
+        }
+    }
+
+    /// 恢復該虛擬機對應的 hgatp, hvip, hie, hgeie
+    ///
+    /// The host's own bits of `hie` and the bits of other vCPUs' guest interrupt files in
+    /// `hgeie` are left alone. The vCPU's own file stops raising SGEIs, its interrupts go
+    /// straight to the guest while it runs.
     pub fn restore_virtual_hs_csrs(&mut self) {
+        let hs = &self.regs.virtual_hs_csrs;
         unsafe {
-            // TODO: 恢復 vsstatus, vsie, vsatp 等等虛擬機的 csr
             core::arch::asm!(
                 "csrw hgatp, {hgatp}",
-                hgatp = in(reg) self.regs.virtual_hs_csrs.hgatp,
+                hgatp = in(reg) hs.hgatp,
             );
-            core::arch::riscv64::hfence_gvma_all();
         }
+        CSR.hvip.write_value(hs.hvip);
+        CSR.hie.read_and_clear_bits(HVIP_VS_INTERRUPTS & !hs.hie);
+        CSR.hie.read_and_set_bits(hs.hie);
+        CSR.hgeie.read_and_clear_bits(hs.hgeie);
+    }
+
+    /// 儲存該虛擬機對應的 hvip, hie, 並讓 hgeie 接手它的 guest interrupt file
+    ///
+    /// The guest acknowledges its software interrupt by clearing `sip.SSIP`, which aliases
+    /// `hvip.VSSIP`, and changes its enables through `vsie`, which aliases the VS-level bits of
+    /// `hie`, so both have to be read back. An interrupt arriving in the vCPU's guest interrupt
+    /// file while it is switched out raises an SGEI.
+    pub fn save_virtual_hs_csrs(&mut self) {
+        let hs = &mut self.regs.virtual_hs_csrs;
+        hs.hvip = CSR.hvip.get_value() & HVIP_VS_INTERRUPTS;
+        hs.hie = CSR.hie.get_value() & HVIP_VS_INTERRUPTS;
+        CSR.hgeie.read_and_set_bits(hs.hgeie);
+    }
+
+    /// Makes the VS-level interrupts in `mask`, a set of `hvip` bits, pending for this vCPU.
+    /// Takes effect on the next entry into the guest.
+    pub fn set_pending_interrupts(&mut self, mask: usize) {
+        self.regs.virtual_hs_csrs.hvip |= mask & HVIP_VS_INTERRUPTS;
+    }
+
+    /// Withdraws the pending VS-level interrupts in `mask`.
+    pub fn clear_pending_interrupts(&mut self, mask: usize) {
+        self.regs.virtual_hs_csrs.hvip &= !mask;
     }
 
-    /// 儲存該虛擬機對應的 hgatp, hvip
-    /// 有需要嗎？虛擬機內部能修改到這些暫存器？
-    pub fn save_virtual_hs_csrs(&mut self) {}
+    /// The VS-level interrupts pending for this vCPU, as `hvip` bits.
+    pub fn pending_interrupts(&self) -> usize {
+        self.regs.virtual_hs_csrs.hvip
+    }
 
-    /// Restore vCPU registers from the guest's GPRs
+    /// Restore vCPU registers from the guest's GPRs. Exit handlers don't need this, they work on
+    /// the registers in place through `gprs_mut`.
     pub fn restore_gprs(&mut self, gprs: &GeneralPurposeRegisters) {
-        for index in 0..32 {
-            self.regs.guest_regs.gprs.set_reg(
-                GprIndex::from_raw(index).unwrap(),
-                gprs.reg(GprIndex::from_raw(index).unwrap()),
-            )
-        }
+        self.regs.guest_regs.gprs.clone_from(gprs);
     }
 
     /// Save vCPU registers to the guest's GPRs
     pub fn save_gprs(&self, gprs: &mut GeneralPurposeRegisters) {
-        for index in 0..32 {
-            gprs.set_reg(
-                GprIndex::from_raw(index).unwrap(),
-                self.regs
-                    .guest_regs
-                    .gprs
-                    .reg(GprIndex::from_raw(index).unwrap()),
-            );
-        }
+        gprs.clone_from(&self.regs.guest_regs.gprs);
     }
 
     /// Runs this vCPU until traps.
@@ -364,12 +456,20 @@ impl<H: HyperCraftHal> VCpu<H> {
         regs.trap_csrs.htinst = htinst::read();
 
         let scause = scause::read();
+        if scause.is_interrupt() && scause.code() == IRQ_S_GUEST_EXTERNAL {
+            return VmExitInfo::GuestExternalInterrupt;
+        }
         use scause::{Exception, Interrupt, Trap};
         match scause.cause() {
             Trap::Exception(Exception::VirtualSupervisorEnvCall) => {
                 let sbi_msg = SbiMessage::from_regs(regs.guest_regs.gprs.a_regs()).ok();
                 VmExitInfo::Ecall(sbi_msg)
             }
+            Trap::Exception(Exception::VirtualInstruction) => VmExitInfo::VirtualInstruction {
+                fault_pc: regs.guest_regs.sepc,
+                inst: regs.trap_csrs.stval as u32,
+                priv_level: PrivilegeLevel::from_hstatus(regs.guest_regs.hstatus),
+            },
             Trap::Interrupt(Interrupt::SupervisorTimer) => VmExitInfo::TimerInterruptEmulation,
             Trap::Interrupt(Interrupt::SupervisorExternal) => {
                 VmExitInfo::ExternalInterruptEmulation
@@ -395,6 +495,10 @@ impl<H: HyperCraftHal> VCpu<H> {
                     // mode into account.
                     falut_pc: regs.guest_regs.sepc,
                     inst: regs.trap_csrs.htinst as u32,
+                    is_store: matches!(
+                        scause.cause(),
+                        Trap::Exception(Exception::StoreGuestPageFault)
+                    ),
                     priv_level: PrivilegeLevel::from_hstatus(regs.guest_regs.hstatus),
                 }
             }
@@ -419,11 +523,41 @@ impl<H: HyperCraftHal> VCpu<H> {
         self.regs.guest_regs.gprs.set_reg(index, val);
     }
 
+    /// The vCPU's general purpose registers.
+    pub fn gprs(&self) -> &GeneralPurposeRegisters {
+        &self.regs.guest_regs.gprs
+    }
+
+    /// The vCPU's general purpose registers, for exit handlers to read arguments from and write
+    /// results to while the vCPU is stopped.
+    pub fn gprs_mut(&mut self) -> &mut GeneralPurposeRegisters {
+        &mut self.regs.guest_regs.gprs
+    }
+
     /// Advance guest pc by `instr_len` bytes
     pub fn advance_pc(&mut self, instr_len: usize) {
         self.regs.guest_regs.sepc += instr_len
     }
 
+    /// Delivers exception `cause` with trap value `tval` to the guest at the instruction that
+    /// caused the current exit, as if the hardware had taken it in VS-mode. Takes effect on the
+    /// next entry into the guest.
+    pub fn inject_exception(&mut self, cause: usize, tval: usize) {
+        let guest = &mut self.regs.guest_regs;
+        let vs = &mut self.regs.vs_csrs;
+        vs.vsepc = guest.sepc;
+        vs.vscause = cause;
+        vs.vstval = tval;
+        // SPP records the privilege the guest trapped from, SPIE the interrupt enable it had.
+        let sie = vs.vsstatus & SSTATUS_SIE;
+        vs.vsstatus = (vs.vsstatus & !(SSTATUS_SIE | SSTATUS_SPIE | SSTATUS_SPP))
+            | (guest.sstatus & SSTATUS_SPP)
+            | if sie != 0 { SSTATUS_SPIE } else { 0 };
+        // The guest resumes in VS-mode at its trap vector, which takes exceptions in both modes.
+        guest.sstatus |= SSTATUS_SPP;
+        guest.sepc = vs.vstvec & !0x3;
+    }
+
     /// Gets the vCPU's id.
     pub fn vcpu_id(&self) -> usize {
         self.vcpu_id
@@ -433,22 +567,96 @@ impl<H: HyperCraftHal> VCpu<H> {
     pub fn regs(&mut self) -> &mut VmCpuRegisters {
         &mut self.regs
     }
-}
 
-// Private methods implements
-impl<H: HyperCraftHal> VCpu<H> {
-    /// Delivers the given exception to the vCPU, setting its register state
-    /// to handle the trap the next time it is run.
-    fn inject_exception(&mut self) {
-        unsafe {
-            core::arch::asm!(
-                "csrw vsepc, {hyp_sepc}",
-                "csrw vscause, {hyp_scause}",
-                "csrr {guest_sepc}, vstvec",
-                hyp_sepc = in(reg) self.regs.guest_regs.sepc,
-                hyp_scause = in(reg) self.regs.trap_csrs.scause,
-                guest_sepc = out(reg) self.regs.guest_regs.sepc,
-            );
+    /// Appends the guest's registers, its pending interrupts and its timer to a snapshot. The
+    /// guest's time is saved rather than `htimedelta`, so that it goes on from where it stopped
+    /// on another host. The vCPU must not be running.
+    pub fn save_state(&self, out: &mut Encoder) {
+        let guest = &self.regs.guest_regs;
+        for index in 0..32 {
+            out.put_u64(guest.gprs.reg(GprIndex::from_raw(index).unwrap()) as u64);
+        }
+        let vs = &self.regs.vs_csrs;
+        for csr in [
+            guest.sstatus,
+            guest.hstatus,
+            guest.scounteren,
+            guest.sepc,
+            vs.vsstatus,
+            vs.vsie,
+            vs.vstvec,
+            vs.vsscratch,
+            vs.vsepc,
+            vs.vscause,
+            vs.vstval,
+            vs.vsatp,
+            vs.vstimecmp,
+        ] {
+            out.put_u64(csr as u64);
+        }
+        let htimedelta = vs.htimedelta as u64;
+        out.put_u64((time::read() as u64).wrapping_add(htimedelta));
+        let hs = &self.regs.virtual_hs_csrs;
+        out.put_u64(hs.hie as u64);
+        out.put_u64(hs.hgeie as u64);
+        out.put_u64(hs.hvip as u64);
+        out.put_u64(match self.timer {
+            u64::MAX => u64::MAX,
+            timer => timer.wrapping_add(htimedelta),
+        });
+    }
+
+    /// Loads the state written by `save_state`. The guest page table, VMID and IMSIC guest
+    /// interrupt file of the vCPU are kept, they belong to the VM being restored into.
+    pub fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        let mut guest = GuestCpuState::default();
+        for index in 0..32 {
+            let val = input.get_u64()? as usize;
+            guest.gprs.set_reg(GprIndex::from_raw(index).unwrap(), val);
+        }
+        for csr in [
+            &mut guest.sstatus,
+            &mut guest.hstatus,
+            &mut guest.scounteren,
+            &mut guest.sepc,
+        ] {
+            *csr = input.get_u64()? as usize;
+        }
+        let mut vs = GuestVsCsrs::default();
+        for csr in [
+            &mut vs.vsstatus,
+            &mut vs.vsie,
+            &mut vs.vstvec,
+            &mut vs.vsscratch,
+            &mut vs.vsepc,
+            &mut vs.vscause,
+            &mut vs.vstval,
+            &mut vs.vsatp,
+            &mut vs.vstimecmp,
+        ] {
+            *csr = input.get_u64()? as usize;
         }
+        let htimedelta = input.get_u64()?.wrapping_sub(time::read() as u64);
+        vs.htimedelta = htimedelta as usize;
+        let mut hs = [0; 3];
+        input.get_u64s(&mut hs)?;
+        let timer = input.get_u64()?;
+
+        let vgein = LocalRegisterCopy::<usize, hstatus::Register>::new(
+            self.regs.guest_regs.hstatus,
+        )
+        .read(hstatus::vgein);
+        self.regs.guest_regs = guest;
+        self.set_vgein(vgein);
+        self.regs.vs_csrs = vs;
+        let virtual_hs = &mut self.regs.virtual_hs_csrs;
+        virtual_hs.hie = hs[0] as usize & HVIP_VS_INTERRUPTS;
+        // hs[1], the `hgeie` bit of the guest interrupt file, belongs to the saved VM.
+        virtual_hs.hvip = hs[2] as usize & HVIP_VS_INTERRUPTS;
+        self.timer = match timer {
+            u64::MAX => u64::MAX,
+            timer => timer.wrapping_sub(htimedelta),
+        };
+        Ok(())
     }
 }
@@ -1,50 +1,312 @@
 use core::panic;
 
 use super::{
-    devices::plic::{PlicState, MAX_CONTEXTS},
+    devices::{
+        aclint::AclintState,
+        aplic::AplicState,
+        imsic::ImsicGuestFile,
+        plic::{supervisor_context, PlicState, MAX_CONTEXTS},
+    },
+    detect::{HGATP_VMID_MASK, HGATP_VMID_SHIFT},
+    sbi::{
+        BaseFunction, PmuFunction, RemoteFenceFunction, SbiExtensionHandler,
+        SbiExtensionRegistry, SbiReturn,
+    },
     regs::GeneralPurposeRegisters,
-    sbi::{BaseFunction, PmuFunction, RemoteFenceFunction},
     traps,
     vcpu::{self, VmCpuRegisters},
     vm_pages::VmPages,
     vmm_trap::VmmTrap,
-    HyperCallMsg, RiscvCsrTrait, CSR,
+    has_sstc, host_capabilities, vmid_allocator, HyperCallMsg, PerCpu, RiscvCsrTrait, CSR,
 };
 use crate::{
-    arch::sbi::SBI_ERR_NOT_SUPPORTED, vcpus::VM_CPUS_MAX, GprIndex, GuestPageTableTrait,
+    arch::sbi::SBI_ERR_NOT_SUPPORTED,
+    devices::{
+        virtio::{VirtioBalloon, VirtioConsole, VirtioMmio},
+        MmioBus, MmioDevice, PciHostBridge, Uart16550,
+    },
+    dirty_log::{DirtyBitmap, DirtyLog},
+    memory::PAGE_SIZE_4K,
+    migration::{MigrationSource, MigrationTarget},
+    snapshot::{
+        Decoder, Encoder, SectionHeader, SnapshotReader, SnapshotSink, SnapshotSource,
+        SnapshotWriter, ACLINT_TAG, APLIC_TAG, CONSOLE_TAG, MMIO_DEVICE_TAG, PLIC_TAG, RAM_TAG,
+        VCPU_TAG,
+    },
+    vcpus::{MAX_CPUS, VM_CPUS_MAX},
+    vmid::VmidSlot,
+    GprIndex, GuestMemoryAccess, GuestPageTableTrait,
     GuestPhysAddr, GuestVirtAddr, HyperCraftHal, HyperError, HyperResult, VCpu, VmCpus, VmExitInfo,
 };
-use alloc::collections::VecDeque;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::ops::RangeInclusive;
+use page_table_entry::MappingFlags;
+use riscv::register::{htimedelta, time};
 use riscv_decode::Instruction;
 use sbi_rt::{pmu_counter_get_info, pmu_counter_stop};
+use spin::Mutex;
 
-// 可供外部 （VMM）修改的一些 cpu 狀態，在重新載入 vcpu 時會把這些狀態設進 vcpu 裡
-// vcpu 仍需把狀態切換進真實的 cpu 裡
-struct VMState {
-    pub general_purpose_registers: GeneralPurposeRegisters,
-    pub advance_pc: bool,
-    pub instruction_length: usize,
-}
+/// Bytes of SBI console output buffered before they are handed to the VMM without a newline.
+const CONSOLE_OUTPUT_FLUSH_SIZE: usize = 256;
 
-impl VMState {
-    fn new() -> Self {
-        VMState {
-            general_purpose_registers: GeneralPurposeRegisters::default(),
-            advance_pc: false,
-            instruction_length: 4,
-        }
-    }
-}
+/// Encoding of the `wfi` instruction.
+const WFI_INSTRUCTION: u32 = 0x1050_0073;
+/// `scause` code of an illegal instruction exception.
+const ILLEGAL_INSTRUCTION: usize = 2;
 
 /// A VM that is being run.
+///
+/// Each vCPU keeps its own registers and pending interrupts, and is locked by the hart running
+/// it. The devices and the guest page table are shared by all vCPUs and only locked while an
+/// exit is handled, so several vCPUs of one VM can run concurrently on different harts.
 pub struct VM<H: HyperCraftHal, G: GuestPageTableTrait> {
     vcpus: VmCpus<H>,
+    shared: Mutex<VmShared<G>>,
+}
+
+/// The state of a VM shared by its vCPUs.
+struct VmShared<G: GuestPageTableTrait> {
     gpt: G,
     vm_pages: VmPages,
     plic: PlicState,
-    state: VMState,
-    timer: u64,
     input_buffer: VecDeque<usize>,
+    console_output: VecDeque<u8>,
+    console_uart: Option<Arc<Mutex<Uart16550>>>,
+    console_virtio: Option<Arc<Mutex<VirtioMmio<VirtioConsole>>>>,
+    balloon: Option<Arc<Mutex<VirtioMmio<VirtioBalloon>>>>,
+    sbi_extensions: SbiExtensionRegistry,
+    aplic: Option<AplicState>,
+    aclint: Option<AclintState>,
+    mmio_bus: MmioBus,
+    imsic_files: [Option<ImsicGuestFile>; VM_CPUS_MAX],
+    // Where each vCPU's IMSIC file is mapped, the address device MSIs to the vCPU are sent to.
+    imsic_gpas: [Option<GuestPhysAddr>; VM_CPUS_MAX],
+    vmid: VmidSlot,
+    // The vCPU that last ran on each hart, and the VMID it ran with.
+    last_vcpu_on_hart: [Option<(usize, usize)>; MAX_CPUS],
+    // Start and size of the guest RAM regions, saved in snapshots.
+    ram_regions: Vec<(GuestPhysAddr, usize)>,
+    // Pages of the RAM regions written since the VMM last asked, while dirty logging is on.
+    dirty_log: Option<DirtyLog>,
+    // RAM pages whose frames were given back to the host, e.g. by the balloon.
+    discarded_pages: BTreeSet<GuestPhysAddr>,
+    // RAM pages mapped to frames hypercraft allocated when they were populated again.
+    owned_pages: BTreeSet<GuestPhysAddr>,
+}
+
+/// A trapped guest load or store to an emulated device.
+#[derive(Clone, Copy, Debug)]
+enum MmioAccess {
+    /// Load `width` bytes into `rd`.
+    Load {
+        rd: GprIndex,
+        width: usize,
+        signed: bool,
+    },
+    /// Store the low `width` bytes of `rs2`.
+    Store { rs2: GprIndex, width: usize },
+}
+
+/// Sends an MSI with identity `eiid` to the guest interrupt file of guest hart `hart_index`.
+fn send_msi(files: &[Option<ImsicGuestFile>], hart_index: usize, eiid: u32) {
+    match files.get(hart_index) {
+        Some(Some(file)) => file.send_msi(eiid),
+        _ => warn!("MSI {} to hart {} without an IMSIC file dropped", eiid, hart_index),
+    }
+}
+
+/// Arms the timer of `vcpu` for `deadline`, a time of the guest as programmed through SBI
+/// `SetTimer` or an ACLINT `mtimecmp`. Returns the trap asking the VMM to arm the host timer,
+/// unless Sstc lets the hardware handle it.
+fn set_guest_timer<H: HyperCraftHal>(vcpu: &mut VCpu<H>, deadline: u64) -> Option<VmmTrap> {
+    if has_sstc() {
+        // The hardware compares `vstimecmp` against the guest's time and raises VSTIP by
+        // itself, the VMM is not involved.
+        vcpu.set_vstimecmp(deadline);
+        return None;
+    }
+    // As per the SBI spec, programming the next event clears the pending timer interrupt.
+    vcpu.clear_pending_interrupts(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
+    // The VMM's timers run on the host's time.
+    let deadline = vcpu.guest_to_host_time(deadline);
+    vcpu.set_timer(deadline);
+    Some(VmmTrap::SetTimer(deadline))
+}
+
+/// Runs `f` with the guest physical address space of the page table `token` loaded on this
+/// hart, so that `VmPages` reaches the VM's RAM while none of its vCPUs runs here.
+fn with_guest_hgatp<R>(token: usize, f: impl FnOnce() -> R) -> R {
+    // VMID 0 is never given to a guest, its entries are dropped before and after the access.
+    let hgatp = token & !(HGATP_VMID_MASK << HGATP_VMID_SHIFT);
+    let old_hgatp: usize;
+    unsafe {
+        core::arch::asm!("csrrw {0}, hgatp, {1}", out(reg) old_hgatp, in(reg) hgatp);
+        core::arch::riscv64::hfence_gvma_vmid(0);
+    }
+    let ret = f();
+    unsafe {
+        core::arch::riscv64::hfence_gvma_vmid(0);
+        core::arch::asm!("csrw hgatp, {0}", in(reg) old_hgatp);
+    }
+    ret
+}
+
+/// Invalidates the G-stage TLB entries of VMID `vmid` for the `size` bytes at `gpa` on all
+/// harts. A `size` of 0 invalidates all entries of the VMID.
+fn flush_guest_tlb(vmid: usize, gpa: GuestPhysAddr, size: usize) {
+    // A hart mask base of -1 selects all harts.
+    let ret = sbi_rt::remote_hfence_gvma_vmid(0, usize::MAX, gpa, size, vmid);
+    if ret.error != 0 {
+        // Without the RFENCE extension only the local hart can be fenced.
+        unsafe {
+            match size {
+                0 => core::arch::riscv64::hfence_gvma_vmid(vmid),
+                _ => core::arch::riscv64::hfence_gvma(gpa >> 2, vmid),
+            }
+        }
+    }
+}
+
+/// The parts of a VM that back its RAM, borrowed from `VmShared` apart from the rest of it.
+struct GuestRam<'a, G: GuestPageTableTrait> {
+    gpt: &'a mut G,
+    dirty_log: Option<&'a mut DirtyLog>,
+    regions: &'a [(GuestPhysAddr, usize)],
+    discarded: &'a mut BTreeSet<GuestPhysAddr>,
+    owned: &'a mut BTreeSet<GuestPhysAddr>,
+    vmid: usize,
+}
+
+/// Borrows the [`GuestRam`] of a `VmShared`, leaving its other fields free.
+macro_rules! guest_ram {
+    ($shared:expr) => {
+        GuestRam {
+            gpt: &mut $shared.gpt,
+            dirty_log: $shared.dirty_log.as_mut(),
+            regions: &$shared.ram_regions,
+            discarded: &mut $shared.discarded_pages,
+            owned: &mut $shared.owned_pages,
+            vmid: $shared.vmid.vmid(),
+        }
+    };
+}
+
+impl<G: GuestPageTableTrait> GuestRam<'_, G> {
+    /// Unmaps the RAM page at `gpa` and gives its frame back, with `dealloc_page` if hypercraft
+    /// allocated it and to the embedder through `release_guest_frame` otherwise. Pages of huge
+    /// mappings are kept, unmapping one would take the whole huge page from the guest.
+    fn discard<H: HyperCraftHal>(&mut self, gpa: GuestPhysAddr) -> HyperResult<()> {
+        let in_ram = self
+            .regions
+            .iter()
+            .any(|&(start, size)| gpa >= start && gpa - start < size);
+        if gpa % PAGE_SIZE_4K != 0 || !in_ram {
+            return Err(HyperError::OutOfRange);
+        }
+        if self.discarded.contains(&gpa) {
+            return Ok(());
+        }
+        if self.gpt.mapping_size(gpa)? != PAGE_SIZE_4K {
+            return Err(HyperError::NotSupported);
+        }
+        let frame = self.gpt.translate(gpa)?;
+        self.gpt.unmap(gpa)?;
+        // No hart may reach the frame once it is freed.
+        flush_guest_tlb(self.vmid, gpa, PAGE_SIZE_4K);
+        if self.owned.remove(&gpa) {
+            // Host memory is identity mapped.
+            H::dealloc_page(frame);
+        } else {
+            H::release_guest_frame(frame);
+        }
+        self.discarded.insert(gpa);
+        // The page reads as zeros now.
+        if let Some(log) = self.dirty_log.as_mut() {
+            log.mark(gpa, PAGE_SIZE_4K);
+        }
+        Ok(())
+    }
+
+    /// Maps a zeroed frame at the RAM page `gpa` if it was discarded. Returns whether it was.
+    fn populate<H: HyperCraftHal>(&mut self, gpa: GuestPhysAddr) -> HyperResult<bool> {
+        let gpa = gpa & !(PAGE_SIZE_4K - 1);
+        if !self.discarded.contains(&gpa) {
+            return Ok(false);
+        }
+        let frame = H::alloc_page().ok_or(HyperError::NoMemory)?;
+        unsafe { core::ptr::write_bytes(frame as *mut u8, 0, PAGE_SIZE_4K) };
+        let flags = MappingFlags::READ
+            | MappingFlags::WRITE
+            | MappingFlags::EXECUTE
+            | MappingFlags::USER;
+        if let Err(err) = self.gpt.map(gpa, frame, flags) {
+            H::dealloc_page(frame);
+            return Err(err);
+        }
+        self.discarded.remove(&gpa);
+        self.owned.insert(gpa);
+        // Mapped writable, the next `get_and_clear_dirty_log` write-protects it again.
+        if let Some(log) = self.dirty_log.as_mut() {
+            log.mark(gpa, PAGE_SIZE_4K);
+        }
+        Ok(true)
+    }
+}
+
+/// Guest memory as reached by emulated devices and hypercall handlers. While dirty logging is
+/// on, their writes are logged like the guest's own, instead of faulting on the write-protected
+/// pages. Devices may discard RAM pages, e.g. for the balloon.
+struct DeviceMemory<'a, H: HyperCraftHal, G: GuestPageTableTrait> {
+    vm_pages: &'a VmPages,
+    ram: RefCell<GuestRam<'a, G>>,
+    _hal: PhantomData<H>,
+}
+
+impl<'a, H: HyperCraftHal, G: GuestPageTableTrait> DeviceMemory<'a, H, G> {
+    fn new(vm_pages: &'a VmPages, ram: GuestRam<'a, G>) -> Self {
+        Self {
+            vm_pages,
+            ram: RefCell::new(ram),
+            _hal: PhantomData,
+        }
+    }
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> GuestMemoryAccess for DeviceMemory<'_, H, G> {
+    fn read_guest(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()> {
+        self.vm_pages.read_guest(gpa, buf)
+    }
+
+    fn write_guest(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HyperResult<()> {
+        let ram = &mut *self.ram.borrow_mut();
+        let end = gpa + buf.len();
+        let mut page = gpa & !(PAGE_SIZE_4K - 1);
+        while page < end {
+            // A buffer the driver placed in a page it gave up earlier.
+            ram.populate::<H>(page)?;
+            if let Some(log) = ram.dirty_log.as_mut() {
+                if log.handle_write_fault(&mut *ram.gpt, page)? {
+                    // Drop this hart's read-only entry, whatever the VMID loaded.
+                    unsafe { core::arch::riscv64::hfence_gvma_gaddr(page >> 2) };
+                }
+            }
+            page += PAGE_SIZE_4K;
+        }
+        self.vm_pages.write_guest(gpa, buf)
+    }
+
+    fn discard_page(&self, gpa: GuestPhysAddr) -> HyperResult<()> {
+        self.ram.borrow_mut().discard::<H>(gpa)
+    }
+
+    fn populate_page(&self, gpa: GuestPhysAddr) -> HyperResult<()> {
+        self.ram.borrow_mut().populate::<H>(gpa).map(|_| ())
+    }
 }
 
 impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
@@ -52,188 +314,722 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
     pub fn new(vcpus: VmCpus<H>, gpt: G) -> HyperResult<Self> {
         Ok(Self {
             vcpus,
-            gpt,
-            vm_pages: VmPages::default(),
-            plic: PlicState::new(0xC00_0000),
-            state: VMState::new(),
-            timer: u64::MAX,
-            input_buffer: VecDeque::new(),
+            shared: Mutex::new(VmShared {
+                gpt,
+                vm_pages: VmPages::default(),
+                plic: PlicState::new(0xC00_0000),
+                input_buffer: VecDeque::new(),
+                console_output: VecDeque::new(),
+                console_uart: None,
+                console_virtio: None,
+                balloon: None,
+                sbi_extensions: SbiExtensionRegistry::new(),
+                aplic: None,
+                aclint: None,
+                mmio_bus: MmioBus::new(),
+                imsic_files: [None; VM_CPUS_MAX],
+                imsic_gpas: [None; VM_CPUS_MAX],
+                vmid: VmidSlot::new(),
+                last_vcpu_on_hart: [None; MAX_CPUS],
+                ram_regions: Vec::new(),
+                dirty_log: None,
+                discarded_pages: BTreeSet::new(),
+                owned_pages: BTreeSet::new(),
+            }),
         })
     }
 
+    /// Gives vCPU `vcpu_id` an IMSIC guest interrupt file of physical hart `hart_id`, the hart the
+    /// vCPU is going to run on, and maps the file at `imsic_gpa`, the address of the vCPU's
+    /// S-level IMSIC in the guest's device tree. From then on `run` refuses to enter the vCPU on
+    /// other harts.
+    pub fn attach_imsic(
+        &mut self,
+        vcpu_id: usize,
+        hart_id: usize,
+        imsic_gpa: GuestPhysAddr,
+    ) -> HyperResult<()> {
+        if !host_capabilities().ssaia {
+            return Err(HyperError::NotSupported);
+        }
+        let shared = self.shared.get_mut();
+        let slot = shared
+            .imsic_files
+            .get_mut(vcpu_id)
+            .ok_or(HyperError::InvalidParam)?;
+        if slot.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+        let file = ImsicGuestFile::alloc(hart_id)?;
+        let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER;
+        if let Err(err) = shared.gpt.map(imsic_gpa, file.host_addr(), flags) {
+            file.free();
+            return Err(err);
+        }
+        vcpu.set_vgein(file.index());
+        *slot = Some(file);
+        shared.imsic_gpas[vcpu_id] = Some(imsic_gpa);
+        Ok(())
+    }
+
+    /// Removes the mapping of guest physical page `gpa` and invalidates its G-stage TLB entries
+    /// on all harts.
+    pub fn unmap_guest_page(&self, gpa: GuestPhysAddr) -> HyperResult<()> {
+        let mut shared = self.shared.lock();
+        shared.gpt.unmap(gpa)?;
+        shared.flush_guest_tlb(gpa, PAGE_SIZE_4K);
+        Ok(())
+    }
+
+    /// Starts logging the guest's writes to the RAM regions declared with `add_ram_region`. All
+    /// their pages are write-protected, the first write to a page marks it dirty and makes it
+    /// writable again. The guest page table must support `set_writable`.
+    pub fn enable_dirty_log(&self) -> HyperResult<()> {
+        let mut shared = self.shared.lock();
+        let shared = &mut *shared;
+        if shared.dirty_log.is_some() || shared.ram_regions.is_empty() {
+            return Err(HyperError::BadState);
+        }
+        let log = DirtyLog::enable(&mut shared.gpt, &shared.ram_regions)?;
+        shared.dirty_log = Some(log);
+        shared.flush_guest_tlb(0, 0);
+        Ok(())
+    }
+
+    /// Stops logging writes and makes the RAM regions writable again.
+    pub fn disable_dirty_log(&self) -> HyperResult<()> {
+        let mut shared = self.shared.lock();
+        let log = shared.dirty_log.take().ok_or(HyperError::BadState)?;
+        log.disable(&mut shared.gpt)?;
+        // Harts may still hold read-only entries, stores through them must not fault anymore.
+        shared.flush_guest_tlb(0, 0);
+        Ok(())
+    }
+
+    /// Returns the pages written since dirty logging was enabled or since the previous call, one
+    /// bitmap per RAM region, and write-protects them again. Writes the guest makes after this
+    /// returns show up in the next call.
+    pub fn get_and_clear_dirty_log(&self) -> HyperResult<Vec<DirtyBitmap>> {
+        let mut shared = self.shared.lock();
+        let shared = &mut *shared;
+        let log = shared.dirty_log.as_mut().ok_or(HyperError::BadState)?;
+        let dirty = log.get_and_clear(&mut shared.gpt)?;
+        if dirty.iter().any(|bitmap| bitmap.dirty_count() != 0) {
+            shared.flush_guest_tlb(0, 0);
+        }
+        Ok(dirty)
+    }
+
+    /// Declares the `size` bytes at `gpa` as guest RAM. The region must already be mapped in the
+    /// guest page table. Guest RAM is saved in snapshots.
+    pub fn add_ram_region(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<()> {
+        if size == 0 || gpa % PAGE_SIZE_4K != 0 || size % PAGE_SIZE_4K != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let regions = &mut self.shared.get_mut().ram_regions;
+        if regions
+            .iter()
+            .any(|&(start, len)| gpa < start + len && start < gpa + size)
+        {
+            return Err(HyperError::BadState);
+        }
+        regions.push((gpa, size));
+        Ok(())
+    }
+
+    /// Emulates an APLIC in MSI delivery mode at guest physical address `base`. Its wired
+    /// interrupts are forwarded to the IMSIC files given to the vCPUs by `attach_imsic`.
+    pub fn enable_aplic(&mut self, base: GuestPhysAddr) {
+        self.shared.get_mut().aplic = Some(AplicState::new(base));
+    }
+
+    /// Emulates an ACLINT with its MTIMER device at `mtimer_base` and its SSWI device at
+    /// `sswi_base`, for guests that program timers and IPIs through MMIO instead of SBI calls.
+    pub fn enable_aclint(&mut self, mtimer_base: GuestPhysAddr, sswi_base: GuestPhysAddr) {
+        self.shared.get_mut().aclint = Some(AclintState::new(mtimer_base, sswi_base));
+    }
+
+    /// Adds an emulated device to the VM's MMIO bus. The VMM keeps its own handle to the device,
+    /// e.g. to exchange data with a UART. Devices with an interrupt line are wired to the APLIC,
+    /// or to the PLIC if the VM has none.
+    pub fn register_mmio_device(&mut self, device: Arc<Mutex<dyn MmioDevice>>) -> HyperResult<()> {
+        self.shared.get_mut().mmio_bus.register(device)
+    }
+
+    /// Adds the ECAM window and the memory BAR window of `bridge` to the VM's MMIO bus. Its INTx
+    /// lines are wired to the APLIC, or to the PLIC if the VM has none.
+    pub fn attach_pci_host_bridge(&mut self, bridge: &PciHostBridge) -> HyperResult<()> {
+        let bus = &mut self.shared.get_mut().mmio_bus;
+        bus.register(Arc::new(Mutex::new(bridge.ecam())))?;
+        if let Err(err) = bus.register(Arc::new(Mutex::new(bridge.mmio_window()))) {
+            bus.unregister(bridge.ecam_range().start);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Drives wired interrupt `irq` of the VM's APLIC to `level`. Without an APLIC, it drives a
+    /// virtual source of the PLIC, which the vCPUs take on their next entry.
+    pub fn set_irq_level(&self, irq: usize, level: bool) -> HyperResult<()> {
+        let mut shared = self.shared.lock();
+        let shared = &mut *shared;
+        let aplic = match shared.aplic.as_mut() {
+            Some(aplic) => aplic,
+            None => return shared.plic.set_irq_level(irq, level),
+        };
+        aplic.set_irq_level(irq, level)?;
+        let files = &shared.imsic_files;
+        aplic.deliver(|hart, eiid| send_msi(files, hart, eiid));
+        Ok(())
+    }
+
+    /// Installs `handler` for the vendor or firmware specific SBI extension IDs in `eids`. Guest
+    /// ECALLs with an extension ID in `eids` are forwarded to `handler`.
+    pub fn register_sbi_extension(
+        &mut self,
+        eids: RangeInclusive<usize>,
+        handler: Box<dyn SbiExtensionHandler>,
+    ) -> HyperResult<()> {
+        self.shared.get_mut().sbi_extensions.register(eids, handler)
+    }
+
+    /// Removes the SBI extension handler registered for `eid`.
+    pub fn unregister_sbi_extension(&mut self, eid: usize) -> Option<Box<dyn SbiExtensionHandler>> {
+        self.shared.get_mut().sbi_extensions.unregister(eid)
+    }
+
+    /// Makes `uart` the VM's console: it is added to the MMIO bus, console input goes to its RX
+    /// buffer instead of the SBI `GetChar` queue and its output is collected along with the one
+    /// of SBI `PutChar`.
+    pub fn set_console_uart(&mut self, uart: Arc<Mutex<Uart16550>>) -> HyperResult<()> {
+        let shared = self.shared.get_mut();
+        shared.mmio_bus.register(uart.clone())?;
+        shared.console_uart = Some(uart);
+        Ok(())
+    }
+
+    /// Makes port 0 of the virtio console `console` the VM's console: it is added to the MMIO
+    /// bus, console input goes to the port and its output is collected along with the one of SBI
+    /// `PutChar`. Takes precedence over a console UART. The VMM reaches the other ports through
+    /// its own handle.
+    pub fn set_console_virtio(
+        &mut self,
+        console: Arc<Mutex<VirtioMmio<VirtioConsole>>>,
+    ) -> HyperResult<()> {
+        let shared = self.shared.get_mut();
+        shared.mmio_bus.register(console.clone())?;
+        shared.console_virtio = Some(console);
+        Ok(())
+    }
+
+    /// Gives the VM the memory balloon `balloon` and adds it to the MMIO bus. The VMM resizes it
+    /// with `set_balloon_target`.
+    pub fn set_balloon(
+        &mut self,
+        balloon: Arc<Mutex<VirtioMmio<VirtioBalloon>>>,
+    ) -> HyperResult<()> {
+        let shared = self.shared.get_mut();
+        if shared.balloon.is_some() {
+            return Err(HyperError::BadState);
+        }
+        shared.mmio_bus.register(balloon.clone())?;
+        shared.balloon = Some(balloon);
+        Ok(())
+    }
+
+    /// Asks the guest to give up `pages` 4K pages of its RAM through the balloon. Frames the VM
+    /// allocated itself when the guest touched a page again are freed with
+    /// `HyperCraftHal::dealloc_page`, frames the embedder mapped go back to it through
+    /// `HyperCraftHal::release_guest_frame`. Pages in larger mappings are not given up.
+    pub fn set_balloon_target(&self, pages: u32) -> HyperResult<()> {
+        let shared = self.shared.lock();
+        let balloon = shared.balloon.as_ref().ok_or(HyperError::NotFound)?;
+        balloon.lock().device_mut().set_target(pages);
+        Ok(())
+    }
+
+    /// Returns the number of pages the balloon asks for and the number of pages it holds.
+    pub fn balloon_size(&self) -> HyperResult<(u32, usize)> {
+        let shared = self.shared.lock();
+        let balloon = shared.balloon.as_ref().ok_or(HyperError::NotFound)?;
+        let balloon = balloon.lock();
+        Ok((balloon.device().target(), balloon.device().inflated_pages()))
+    }
+
     /// 給虛擬機的 input_buffer 加入
-    pub fn add_char_to_input_buffer(&mut self, c: usize) {
-        self.input_buffer.push_back(c);
+    pub fn add_char_to_input_buffer(&self, c: usize) {
+        let mut shared = self.shared.lock();
+        let shared = &mut *shared;
+        if let Some(console) = &shared.console_virtio {
+            console.lock().device_mut().push_rx(0, &[c as u8]);
+            return;
+        }
+        match &shared.console_uart {
+            Some(uart) => {
+                uart.lock().push_rx(&[c as u8]);
+            }
+            None => shared.input_buffer.push_back(c),
+        }
     }
 
-    fn read_from_input_buffer(&mut self) -> usize {
-        if let Some(c) = self.input_buffer.pop_front() {
-            return c;
+    /// Passes the console output the guest produced since the last call to `f`.
+    pub fn drain_console_output(&self, mut f: impl FnMut(&[u8])) {
+        let mut shared = self.shared.lock();
+        let (front, back) = shared.console_output.as_slices();
+        f(front);
+        f(back);
+        shared.console_output.clear();
+        if let Some(uart) = &shared.console_uart {
+            let mut buf = [0; 64];
+            let mut uart = uart.lock();
+            while uart.has_tx() {
+                let len = uart.take_tx(&mut buf);
+                f(&buf[..len]);
+            }
+        }
+        if let Some(console) = &shared.console_virtio {
+            let mut buf = [0; 256];
+            let mut console = console.lock();
+            let console = console.device_mut();
+            while console.has_tx(0) {
+                let len = console.take_tx(0, &mut buf);
+                f(&buf[..len]);
+            }
         }
-        return usize::MAX;
     }
 
     /// Initialize `VCpu` by `vcpu_id`.
     pub fn init_vcpu(&mut self, vcpu_id: usize) {
+        let token = self.shared.get_mut().gpt.token();
         let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
-        vcpu.init_page_map(self.gpt.token());
+        vcpu.init_page_map(token);
+    }
+
+    /// Raises the timer interrupt of vCPU `vcpu_id`, whose guest timer expired. It stays pending
+    /// until the guest programs its next timer event. Waits for the vCPU to return to its VMM if
+    /// it is running.
+    pub fn inject_timer_interrupt(&self, vcpu_id: usize) -> HyperResult<()> {
+        let mut vcpu = self.vcpus.lock_vcpu(vcpu_id)?;
+        vcpu.set_pending_interrupts(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
+        Ok(())
+    }
+
+    /// Returns true if vCPU `vcpu_id`, waiting after a [`VmmTrap::Wfi`], has an interrupt to take
+    /// and must run again. The interrupt lines of the devices are propagated to it first, as
+    /// before an entry. Must be called on the hart the vCPU runs on.
+    pub fn has_pending_interrupts(&self, vcpu_id: usize) -> HyperResult<bool> {
+        let mut vcpu = self.vcpus.lock_vcpu(vcpu_id)?;
+        let mut shared = self.shared.lock();
+        shared.inject_aclint_interrupts(&mut vcpu);
+        shared.sync_device_irqs(&mut vcpu);
+        Ok(shared.has_pending_interrupts(&vcpu))
+    }
+
+    /// Returns the ids of the VM's vCPUs.
+    pub fn vcpu_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.vcpus.vcpu_ids()
+    }
 
-        // vcpu 初始化完成後，立刻儲存通用暫存器
-        vcpu.save_gprs(&mut self.state.general_purpose_registers);
+    /// 取得 vCPU 的 timer
+    pub fn get_timer(&self, vcpu_id: usize) -> HyperResult<u64> {
+        Ok(self.vcpus.lock_vcpu(vcpu_id)?.timer())
     }
 
-    /// 取得 VM 的 timer
-    pub fn get_timer(&self) -> u64 {
-        self.timer
+    /// Writes a snapshot of the VM to `sink`: the registers of every vCPU, the state of the
+    /// interrupt controllers, of the devices on the MMIO bus and of the SBI console, and the RAM
+    /// declared with `add_ram_region`. Waits for the vCPUs running on other harts to return to
+    /// their VMM, and keeps them from running again until the snapshot is written.
+    ///
+    /// The interrupts pending in IMSIC guest interrupt files are held by the hardware and are
+    /// not saved.
+    pub fn snapshot(&self, sink: &mut dyn SnapshotSink) -> HyperResult<()> {
+        let mut writer = SnapshotWriter::new(sink)?;
+        self.write_sections(&mut writer, true)?;
+        writer.finish()
+    }
+
+    /// Writes the sections of a snapshot, with or without the RAM.
+    fn write_sections(&self, writer: &mut SnapshotWriter, with_ram: bool) -> HyperResult<()> {
+        let vcpus = self
+            .vcpus
+            .vcpu_ids()
+            .map(|vcpu_id| self.vcpus.lock_vcpu(vcpu_id))
+            .collect::<HyperResult<Vec<_>>>()?;
+        let shared = self.shared.lock();
+        for vcpu in vcpus.iter() {
+            let mut out = Encoder::new();
+            out.put_u32(vcpu.vcpu_id() as u32);
+            vcpu.save_state(&mut out);
+            writer.write_section(VCPU_TAG, 1, &out)?;
+        }
+        shared.save_devices(writer)?;
+        if with_ram {
+            shared.save_ram(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Restores the snapshot read from `source`. The VM must be set up like the one the snapshot
+    /// was taken of: same vCPUs and RAM regions, and the same devices, registered on the MMIO bus
+    /// in the same order. Sections unknown to this version are skipped.
+    ///
+    /// Guest timers are restored relative to the guest's time, the VMM must re-arm the host
+    /// timer of each vCPU from `get_timer` before running it. Dirty logging must be off, the
+    /// hypervisor's own stores to write-protected RAM would fault.
+    pub fn restore(&mut self, source: &mut dyn SnapshotSource) -> HyperResult<()> {
+        if self.shared.get_mut().dirty_log.is_some() {
+            return Err(HyperError::BadState);
+        }
+        let mut reader = SnapshotReader::new(source)?;
+        reader.check_arch()?;
+        let shared = self.shared.get_mut();
+        while let Some(section) = reader.next_section()? {
+            match section.tag {
+                VCPU_TAG => {
+                    section.check_version(1)?;
+                    let payload = reader.read_payload()?;
+                    let mut input = Decoder::new(&payload);
+                    let vcpu_id = input.get_u32()? as usize;
+                    self.vcpus.get_vcpu(vcpu_id)?.restore_state(&mut input)?;
+                }
+                RAM_TAG => {
+                    section.check_version(1)?;
+                    shared.restore_ram::<H>(&mut reader)?;
+                }
+                _ => shared.restore_device(section, &mut reader)?,
+            }
+        }
+        // Guest code may have been overwritten.
+        unsafe { core::arch::asm!("fence.i") };
+        Ok(())
     }
 
     #[allow(unused_variables, deprecated)]
-    /// Run the host VM's vCPU with ID `vcpu_id`. Does not return.
-    pub fn run(&mut self, vcpu_id: usize) -> VmmTrap {
-        let mut vm_exit_info: VmExitInfo;
+    /// Run vCPU `vcpu_id` on this hart until it needs the VMM. Other vCPUs of the VM may run on
+    /// other harts at the same time. A vCPU with an IMSIC guest interrupt file only runs on the
+    /// hart of the file, elsewhere `hstatus.VGEIN` would select a file of another vCPU.
+    pub fn run(&self, vcpu_id: usize) -> VmmTrap {
+        let mut vcpu = self.vcpus.lock_vcpu(vcpu_id).unwrap();
+        let hart_id = PerCpu::<H>::this_cpu().cpu_id();
+        if let Some(file) = self.shared.lock().imsic_files[vcpu_id] {
+            if file.hart_id() != hart_id {
+                return VmmTrap::WrongHart(file.hart_id());
+            }
+        }
         // VMM 設定時鐘中斷，使得 vm 能定時脫出 loop
         loop {
-            // 第一次執行時，其實不需要 restore
-            self.restore_state(vcpu_id);
-
-            self.state.advance_pc = false;
-            self.state.instruction_length = 4;
+            {
+                let mut shared = self.shared.lock();
+                // Another vCPU may have written this vCPU's mtimecmp, the VMM arms the host timer
+                // before it runs.
+                if let Some(trap) = shared.update_aclint_timer(&mut vcpu) {
+                    return trap;
+                }
+                shared.inject_aclint_interrupts(&mut vcpu);
+                // The VMM may have fed input to a device since the last exit.
+                if shared.mmio_bus.has_pending_work() {
+                    let token = shared.gpt.token();
+                    with_guest_hgatp(token, || shared.poll_devices::<H>());
+                }
+                shared.sync_device_irqs(&mut vcpu);
+                let flush_all = shared.assign_vmid(&mut vcpu, hart_id);
+                // 第一次執行時，其實不需要 restore
+                vcpu.restore_vs_csrs();
+                vcpu.restore_virtual_hs_csrs();
+                shared.flush_stale_tlb(vcpu_id, hart_id, flush_all);
+            }
 
-            let vm_exit_info = self.run_and_save_state(vcpu_id);
+            let vm_exit_info = vcpu.run();
+            vcpu.save_virtual_hs_csrs();
+            vcpu.save_vs_csrs();
+            #[cfg(feature = "ecall-bench-baseline")]
+            super::bench::copy_gprs_out_and_back(&mut vcpu);
             // debug!("處理中斷");
 
-            match vm_exit_info {
-                VmExitInfo::Ecall(sbi_msg) => {
-                    if let Some(sbi_msg) = sbi_msg {
-                        self.state.advance_pc = true;
-                        match sbi_msg {
-                            HyperCallMsg::Base(base) => {
-                                self.handle_base_function(base).unwrap();
-                            }
-                            HyperCallMsg::GetChar => {
-                                // let c = sbi_rt::legacy::console_getchar();
-                                let c = self.read_from_input_buffer();
-                                // debug!("sbi call GetChar, c = {}", c);
-                                self.state
-                                    .general_purpose_registers
-                                    .set_reg(GprIndex::A0, c);
-                            }
-                            HyperCallMsg::PutChar(c) => {
-                                sbi_rt::legacy::console_putchar(c);
-                            }
-                            HyperCallMsg::SetTimer(timer) => {
-                                // Clear guest timer interrupt
-                                // CSR.hvip.read_and_clear_bits(
-                                //     traps::interrupt::VIRTUAL_SUPERVISOR_TIMER,
-                                // );
-                                //  Enable host timer interrupt
-                                self.set_timer(timer as u64);
-                                // TODO: 清除 guest 的 hvip 的 VSTIP bit
-                                return VmmTrap::SetTimer(timer as u64);
-                            }
-                            HyperCallMsg::Reset(_) => {
-                                sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
-                            }
-                            HyperCallMsg::RemoteFence(rfnc) => {
-                                self.handle_rfnc_function(rfnc).unwrap();
+            let mut shared = self.shared.lock();
+            if let Some(trap) = shared.handle_exit(&mut vcpu, vm_exit_info) {
+                return trap;
+            }
+        }
+    }
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> MigrationSource for VM<H, G> {
+    fn ram_regions(&self) -> Vec<(GuestPhysAddr, usize)> {
+        self.shared.lock().ram_regions.clone()
+    }
+
+    fn enable_dirty_log(&self) -> HyperResult<()> {
+        VM::enable_dirty_log(self)
+    }
+
+    fn get_and_clear_dirty_log(&self) -> HyperResult<Vec<DirtyBitmap>> {
+        VM::get_and_clear_dirty_log(self)
+    }
+
+    fn disable_dirty_log(&self) -> HyperResult<()> {
+        VM::disable_dirty_log(self)
+    }
+
+    fn read_ram(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()> {
+        let shared = self.shared.lock();
+        shared.with_guest_memory(|vm_pages| {
+            let mut offset = 0;
+            while offset < buf.len() {
+                let addr = gpa + offset;
+                let len = (PAGE_SIZE_4K - addr % PAGE_SIZE_4K).min(buf.len() - offset);
+                shared.read_ram_page(vm_pages, addr, &mut buf[offset..offset + len])?;
+                offset += len;
+            }
+            Ok(())
+        })
+    }
+
+    fn save_state(&self, writer: &mut SnapshotWriter) -> HyperResult<()> {
+        self.write_sections(writer, false)
+    }
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> MigrationTarget for VM<H, G> {
+    fn restore(&mut self, source: &mut dyn SnapshotSource) -> HyperResult<()> {
+        VM::restore(self, source)
+    }
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> Drop for VM<H, G> {
+    fn drop(&mut self) {
+        // Guest RAM is the embedder's, apart from the frames of pages populated again after
+        // they were discarded.
+        let shared = self.shared.get_mut();
+        for &gpa in shared.owned_pages.iter() {
+            if let Ok(frame) = shared.gpt.translate(gpa) {
+                H::dealloc_page(frame);
+            }
+        }
+    }
+}
+
+// Privaie methods implementation
+impl<G: GuestPageTableTrait> VmShared<G> {
+    /// Handles an exit of `vcpu`. Returns the trap to hand to the VMM, or `None` to resume the
+    /// guest.
+    fn handle_exit<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &mut VCpu<H>,
+        vm_exit_info: VmExitInfo,
+    ) -> Option<VmmTrap> {
+        let vcpu_id = vcpu.vcpu_id();
+        match vm_exit_info {
+            VmExitInfo::Ecall(sbi_msg) => {
+                if let Some(sbi_msg) = sbi_msg {
+                    vcpu.advance_pc(4);
+                    match sbi_msg {
+                        HyperCallMsg::Base(base) => {
+                            self.handle_base_function(vcpu.gprs_mut(), base).unwrap();
+                        }
+                        HyperCallMsg::GetChar => {
+                            // let c = sbi_rt::legacy::console_getchar();
+                            let c = self.read_from_input_buffer();
+                            // debug!("sbi call GetChar, c = {}", c);
+                            vcpu.set_gpr(GprIndex::A0, c);
+                        }
+                        HyperCallMsg::PutChar(c) => {
+                            self.console_output.push_back(c as u8);
+                            // Output is handed to the VMM line by line.
+                            if c == b'\n' as usize
+                                || self.console_output.len() >= CONSOLE_OUTPUT_FLUSH_SIZE
+                            {
+                                return Some(VmmTrap::ConsoleOutput);
                             }
-                            HyperCallMsg::PMU(pmu) => {
-                                self.handle_pmu_function(pmu).unwrap();
+                        }
+                        HyperCallMsg::SetTimer(timer) => {
+                            if let Some(trap) = set_guest_timer(vcpu, timer as u64) {
+                                return Some(trap);
                             }
-                            _ => todo!(),
                         }
-                    } else {
-                        panic!()
+                        HyperCallMsg::Reset(_) => {
+                            sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
+                        }
+                        HyperCallMsg::RemoteFence(rfnc) => {
+                            self.handle_rfnc_function(vcpu.gprs_mut(), rfnc).unwrap();
+                        }
+                        HyperCallMsg::PMU(pmu) => {
+                            self.handle_pmu_function(vcpu.gprs_mut(), pmu).unwrap();
+                        }
+                        HyperCallMsg::VendorExtension { eid, fid } => {
+                            self.handle_vendor_extension::<H>(vcpu.gprs_mut(), eid, fid).unwrap();
+                        }
+                        _ => todo!(),
                     }
+                } else {
+                    panic!()
                 }
-                VmExitInfo::PageFault {
-                    fault_addr,
-                    falut_pc,
-                    inst,
-                    priv_level,
-                } => match priv_level {
-                    super::vmexit::PrivilegeLevel::Supervisor => {
-                        match self.handle_page_fault(falut_pc, inst, fault_addr) {
-                            Ok(inst_len) => {
-                                self.state.instruction_length = inst_len;
-                            }
-                            Err(err) => {
-                                panic!(
-                                    "Page fault at {:#x} addr@{:#x} with error {:?}",
-                                    falut_pc, fault_addr, err
-                                )
-                            }
+            }
+            VmExitInfo::PageFault { fault_addr, .. }
+                if self.handle_discarded_fault::<H>(fault_addr) =>
+            {
+                // The access is retried now that the page has a frame again.
+            }
+            VmExitInfo::PageFault {
+                fault_addr,
+                is_store: true,
+                ..
+            } if self.handle_dirty_fault(fault_addr) => {
+                // The store is retried now that the page is writable.
+            }
+            VmExitInfo::PageFault {
+                fault_addr,
+                falut_pc,
+                inst,
+                priv_level,
+                ..
+            } => match priv_level {
+                super::vmexit::PrivilegeLevel::Supervisor => {
+                    match self.handle_page_fault(vcpu, falut_pc, inst, fault_addr) {
+                        Ok(inst_len) => {
+                            vcpu.advance_pc(inst_len);
+                        }
+                        Err(err) => {
+                            panic!(
+                                "Page fault at {:#x} addr@{:#x} with error {:?}",
+                                falut_pc, fault_addr, err
+                            )
                         }
-                        self.state.advance_pc = true;
-                    }
-                    super::vmexit::PrivilegeLevel::User => {
-                        panic!("User page fault")
                     }
-                },
-                VmExitInfo::TimerInterruptEmulation => {
-                    // debug!("timer irq emulation");
-                    // Enable guest timer interrupt
-                    // CSR.hvip
-                    //     .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
-                    return VmmTrap::TimerInterruptEmulation;
                 }
-                VmExitInfo::ExternalInterruptEmulation => self.handle_irq(),
-                _ => {}
+                super::vmexit::PrivilegeLevel::User => {
+                    panic!("User page fault")
+                }
+            },
+            VmExitInfo::TimerInterruptEmulation => {
+                // debug!("timer irq emulation");
+                return Some(VmmTrap::TimerInterruptEmulation);
+            }
+            VmExitInfo::ExternalInterruptEmulation => self.handle_irq(vcpu),
+            VmExitInfo::VirtualInstruction { fault_pc, inst, .. } => {
+                return self.handle_virtual_instruction(vcpu, fault_pc, inst);
             }
+            VmExitInfo::GuestExternalInterrupt => {
+                return Some(self.handle_guest_external_interrupt());
+            }
+            _ => {}
         }
+
+        self.update_aclint_timer(vcpu)
     }
-}
 
-// Privaie methods implementation
-impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
-    fn set_timer(&mut self, timer: u64) {
-        self.timer = timer;
+    fn read_from_input_buffer(&mut self) -> usize {
+        if let Some(c) = self.input_buffer.pop_front() {
+            return c;
+        }
+        return usize::MAX;
     }
-    fn run_and_save_state(&mut self, vcpu_id: usize) -> VmExitInfo {
-        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
 
-        let vm_exit_info = vcpu.run();
+    /// Gives `vcpu` the VM's current VMID. Returns true if the hart must first flush the guest
+    /// TLB entries of all VMIDs, because they rolled over.
+    fn assign_vmid<H: HyperCraftHal>(&mut self, vcpu: &mut VCpu<H>, hart_id: usize) -> bool {
+        let (vmid, flush_all) = vmid_allocator().get(&mut self.vmid, hart_id);
+        vcpu.set_vmid(vmid);
+        flush_all
+    }
+    /// Flushes the guest TLB entries this hart may still hold for another vCPU of the VM, or for
+    /// another VM that had the same VMID. Must run after `hgatp` was loaded.
+    fn flush_stale_tlb(&mut self, vcpu_id: usize, hart_id: usize, flush_all: bool) {
+        let current = Some((vcpu_id, self.vmid.vmid()));
+        unsafe {
+            if flush_all {
+                core::arch::riscv64::hfence_gvma_all();
+            }
+            // VS-stage entries are tagged with the VMID only, not with the vCPU.
+            if flush_all || self.last_vcpu_on_hart[hart_id] != current {
+                core::arch::riscv64::hfence_vvma_all();
+            }
+        }
+        self.last_vcpu_on_hart[hart_id] = current;
+    }
 
-        vcpu.save_gprs(&mut self.state.general_purpose_registers);
-        vcpu.save_virtual_hs_csrs();
-        vcpu.save_vs_csrs();
+    /// Invalidates the G-stage TLB entries of the `size` bytes at `gpa` on all harts. A `size` of
+    /// 0 invalidates all entries of the VM.
+    fn flush_guest_tlb(&self, gpa: GuestPhysAddr, size: usize) {
+        flush_guest_tlb(self.vmid.vmid(), gpa, size);
+    }
 
-        return vm_exit_info;
+    /// Logs a store to a write-protected page of guest RAM while dirty logging is on. Returns
+    /// false if the fault has another cause.
+    fn handle_dirty_fault(&mut self, fault_addr: GuestPhysAddr) -> bool {
+        let log = match self.dirty_log.as_mut() {
+            Some(log) => log,
+            None => return false,
+        };
+        match log.handle_write_fault(&mut self.gpt, fault_addr) {
+            Ok(true) => {
+                // Drop this hart's read-only entry. Other harts drop theirs on their own fault.
+                unsafe { core::arch::riscv64::hfence_gvma(fault_addr >> 2, self.vmid.vmid()) };
+                true
+            }
+            Ok(false) => false,
+            Err(err) => {
+                warn!("dirty logging fault at {:#x} failed: {:?}", fault_addr, err);
+                false
+            }
+        }
     }
 
-    fn restore_state(&mut self, vcpu_id: usize) {
-        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
-        vcpu.restore_gprs(&self.state.general_purpose_registers);
-        vcpu.restore_vs_csrs();
-        vcpu.restore_virtual_hs_csrs();
-        if self.state.advance_pc {
-            vcpu.advance_pc(self.state.instruction_length);
+    /// Gives a discarded RAM page a frame again when the guest touches it, e.g. after reusing a
+    /// page it reported as free. Returns false if the fault has another cause.
+    fn handle_discarded_fault<H: HyperCraftHal>(&mut self, fault_addr: GuestPhysAddr) -> bool {
+        let vmid = self.vmid.vmid();
+        match guest_ram!(self).populate::<H>(fault_addr) {
+            Ok(true) => {
+                // Drop a cached invalid entry, should this hart hold one.
+                unsafe { core::arch::riscv64::hfence_gvma(fault_addr >> 2, vmid) };
+                true
+            }
+            Ok(false) => false,
+            Err(err) => {
+                warn!("no frame for discarded page {:#x}: {:?}", fault_addr, err);
+                false
+            }
         }
     }
 
-    fn handle_page_fault(
+    fn handle_page_fault<H: HyperCraftHal>(
         &mut self,
+        vcpu: &mut VCpu<H>,
         inst_addr: GuestVirtAddr,
         inst: u32,
         fault_addr: GuestPhysAddr,
     ) -> HyperResult<usize> {
-        //  plic
-        if fault_addr >= self.plic.base() && fault_addr < self.plic.base() + 0x0400_0000 {
-            self.handle_plic(inst_addr, inst, fault_addr)
-        } else {
+        let is_plic = fault_addr >= self.plic.base() && fault_addr < self.plic.base() + 0x0400_0000;
+        let is_aplic = self.aplic.as_ref().map_or(false, |aplic| aplic.contains(fault_addr));
+        let is_aclint = self.aclint.as_ref().map_or(false, |aclint| aclint.contains(fault_addr));
+        let is_bus = self.mmio_bus.contains(fault_addr);
+        if !is_plic && !is_aplic && !is_aclint && !is_bus {
             error!("inst_addr: {:#x}, fault_addr: {:#x}", inst_addr, fault_addr);
-            Err(HyperError::PageFault)
+            return Err(HyperError::PageFault);
         }
+
+        let (access, len) = self.decode_mmio_access(inst_addr, inst)?;
+        if is_plic {
+            self.handle_plic(vcpu, fault_addr, access)?;
+        } else if is_aplic {
+            self.handle_aplic(vcpu, fault_addr, access)?;
+        } else if is_aclint {
+            self.handle_aclint(vcpu, fault_addr, access)?;
+        } else {
+            self.handle_mmio_bus(vcpu, fault_addr, access)?;
+        }
+        Ok(len)
     }
 
-    #[allow(clippy::needless_late_init)]
-    fn handle_plic(
-        &mut self,
+    /// Decodes the load or store that trapped at `inst_addr`, returning it along with the length
+    /// of the instruction.
+    fn decode_mmio_access(
+        &self,
         inst_addr: GuestVirtAddr,
         mut inst: u32,
-        fault_addr: GuestPhysAddr,
-    ) -> HyperResult<usize> {
-        let gprs = &mut self.state.general_purpose_registers;
+    ) -> HyperResult<(MmioAccess, usize)> {
         if inst == 0 {
             // If hinst does not provide information about trap,
             // we must read the instruction from guest's memory maunally.
@@ -246,35 +1042,440 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
             4 => inst,
             _ => unreachable!(),
         };
-        // assert!(len == 4);
         let decode_inst = riscv_decode::decode(inst).map_err(|_| HyperError::DecodeError)?;
-        match decode_inst {
-            Instruction::Sw(i) => {
-                let val = gprs.reg(GprIndex::from_raw(i.rs2()).unwrap()) as u32;
-                self.plic.write_u32(fault_addr, val)
+        let gpr = |raw: u32| GprIndex::from_raw(raw).ok_or(HyperError::DecodeError);
+        let load = |rd: u32, width: usize, signed: bool| -> HyperResult<MmioAccess> {
+            Ok(MmioAccess::Load {
+                rd: gpr(rd)?,
+                width,
+                signed,
+            })
+        };
+        let store = |rs2: u32, width: usize| -> HyperResult<MmioAccess> {
+            Ok(MmioAccess::Store {
+                rs2: gpr(rs2)?,
+                width,
+            })
+        };
+        let access = match decode_inst {
+            Instruction::Lb(i) => load(i.rd(), 1, true)?,
+            Instruction::Lbu(i) => load(i.rd(), 1, false)?,
+            Instruction::Lh(i) => load(i.rd(), 2, true)?,
+            Instruction::Lhu(i) => load(i.rd(), 2, false)?,
+            Instruction::Lw(i) => load(i.rd(), 4, true)?,
+            Instruction::Lwu(i) => load(i.rd(), 4, false)?,
+            Instruction::Ld(i) => load(i.rd(), 8, false)?,
+            Instruction::Sb(i) => store(i.rs2(), 1)?,
+            Instruction::Sh(i) => store(i.rs2(), 2)?,
+            Instruction::Sw(i) => store(i.rs2(), 4)?,
+            Instruction::Sd(i) => store(i.rs2(), 8)?,
+            _ => return Err(HyperError::InvalidInstruction),
+        };
+        Ok((access, len))
+    }
+
+    /// Performs `access` on behalf of `vcpu`, calling `read` or `write` with the access width
+    /// and returning the loaded value in the destination register.
+    fn emulate_mmio_access<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &mut VCpu<H>,
+        access: MmioAccess,
+        read: impl FnOnce(&mut Self, usize) -> HyperResult<u64>,
+        write: impl FnOnce(&mut Self, usize, u64) -> HyperResult<()>,
+    ) -> HyperResult<()> {
+        match access {
+            MmioAccess::Load { rd, width, signed } => {
+                let val = read(self, width)?;
+                let val = match (width, signed) {
+                    (1, true) => val as i8 as usize,
+                    (2, true) => val as i16 as usize,
+                    (4, true) => val as i32 as usize,
+                    _ => val as usize,
+                };
+                vcpu.set_gpr(rd, val);
             }
-            Instruction::Lw(i) => {
-                let val = self.plic.read_u32(fault_addr);
-                gprs.set_reg(GprIndex::from_raw(i.rd()).unwrap(), val as usize)
+            MmioAccess::Store { rs2, width } => {
+                let val = vcpu.get_gpr(rs2) as u64;
+                let val = if width < 8 {
+                    val & ((1 << (width * 8)) - 1)
+                } else {
+                    val
+                };
+                write(self, width, val)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Performs `access` on a device that only supports 32-bit registers.
+    fn emulate_u32_access<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &mut VCpu<H>,
+        access: MmioAccess,
+        read: impl FnOnce(&mut Self) -> u32,
+        write: impl FnOnce(&mut Self, u32),
+    ) -> HyperResult<()> {
+        self.emulate_mmio_access(
+            vcpu,
+            access,
+            |vm, width| match width {
+                4 => Ok(read(vm) as u64),
+                _ => Err(HyperError::InvalidInstruction),
+            },
+            |vm, width, val| match width {
+                4 => Ok(write(vm, val as u32)),
+                _ => Err(HyperError::InvalidInstruction),
+            },
+        )
+    }
+
+    fn handle_plic<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &mut VCpu<H>,
+        fault_addr: GuestPhysAddr,
+        access: MmioAccess,
+    ) -> HyperResult<()> {
+        let mut completed = false;
+        self.emulate_u32_access(
+            vcpu,
+            access,
+            |vm| vm.plic.read_u32(fault_addr),
+            |vm, val| completed = vm.plic.write_u32(fault_addr, val),
+        )?;
+        if completed {
+            vcpu.clear_pending_interrupts(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        }
+        Ok(())
+    }
+
+    fn handle_aclint<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &mut VCpu<H>,
+        fault_addr: GuestPhysAddr,
+        access: MmioAccess,
+    ) -> HyperResult<()> {
+        self.emulate_mmio_access(
+            vcpu,
+            access,
+            |vm, width| {
+                let now = (time::read() as u64).wrapping_add(htimedelta::read() as u64);
+                vm.aclint.as_ref().unwrap().read(fault_addr, width, now)
+            },
+            |vm, width, val| vm.aclint.as_mut().unwrap().write(fault_addr, width, val),
+        )
+    }
+
+    fn handle_mmio_bus<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &mut VCpu<H>,
+        fault_addr: GuestPhysAddr,
+        access: MmioAccess,
+    ) -> HyperResult<()> {
+        self.emulate_mmio_access(
+            vcpu,
+            access,
+            |vm, width| vm.mmio_bus.read(fault_addr, width),
+            |vm, width, val| vm.mmio_bus.write(fault_addr, width, val),
+        )?;
+        // The store may have been a queue notification.
+        self.poll_devices::<H>();
+        self.sync_device_irqs(vcpu);
+        Ok(())
+    }
+
+    /// Lets the devices on the MMIO bus do their pending work. The VM's guest physical address
+    /// space must be loaded on this hart.
+    fn poll_devices<H: HyperCraftHal>(&mut self) {
+        let mem = DeviceMemory::<H, G>::new(&self.vm_pages, guest_ram!(self));
+        if let Err(err) = self.mmio_bus.poll(&mem) {
+            warn!("device poll failed: {:?}", err);
+        }
+    }
+
+    /// Propagates the interrupt lines of the devices on the MMIO bus to the APLIC, or to the
+    /// PLIC if the VM has none, and delivers the MSIs they sent to the IMSIC files of the vCPUs.
+    fn sync_device_irqs<H: HyperCraftHal>(&mut self, vcpu: &mut VCpu<H>) {
+        let (files, gpas) = (&self.imsic_files, &self.imsic_gpas);
+        self.mmio_bus.take_msis(|msi| {
+            // MSIs are written to the `seteipnum_le` register at the start of a file's page.
+            let hart = gpas.iter().position(|gpa| gpa.map(|gpa| gpa as u64) == Some(msi.address));
+            match hart {
+                Some(hart) => send_msi(files, hart, msi.data),
+                None => warn!("MSI to {:#x} does not target an IMSIC file", msi.address),
+            }
+        });
+        let aplic = match self.aplic.as_mut() {
+            Some(aplic) => aplic,
+            None => return self.sync_plic_irqs(vcpu),
+        };
+        self.mmio_bus.for_each_irq(|irq, level| {
+            if let Err(err) = aplic.set_irq_level(irq, level) {
+                warn!("device irq {} is not routable: {:?}", irq, err);
+            }
+        });
+        let files = &self.imsic_files;
+        aplic.deliver(|hart, eiid| send_msi(files, hart, eiid));
+    }
+
+    /// Drives the virtual PLIC sources from the device lines, and signals the ones `vcpu` takes
+    /// with its virtual supervisor external interrupt.
+    fn sync_plic_irqs<H: HyperCraftHal>(&mut self, vcpu: &mut VCpu<H>) {
+        let plic = &mut self.plic;
+        self.mmio_bus.for_each_irq(|irq, level| {
+            if let Err(err) = plic.set_irq_level(irq, level) {
+                warn!("device irq {} is not routable: {:?}", irq, err);
+            }
+        });
+        let context = supervisor_context(vcpu.vcpu_id());
+        if plic.has_pending(context) {
+            vcpu.set_pending_interrupts(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        } else if plic.claim_complete[context] == 0 {
+            // The line dropped before the guest claimed the source.
+            vcpu.clear_pending_interrupts(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        }
+    }
+
+    /// Arms the timer of `vcpu` if its ACLINT `mtimecmp` was written since the last call, which
+    /// takes the same path as an SBI `SetTimer` call. Another vCPU may have written it, in which
+    /// case the timer is armed before `vcpu` runs next.
+    fn update_aclint_timer<H: HyperCraftHal>(&mut self, vcpu: &mut VCpu<H>) -> Option<VmmTrap> {
+        let deadline = self.aclint.as_mut()?.take_timer_update(vcpu.vcpu_id())?;
+        set_guest_timer(vcpu, deadline)
+    }
+
+    /// Emulates the instruction the guest is not allowed to execute in VS-mode, a trapped WFI.
+    /// Returns the trap blocking the vCPU if it has no interrupt to take. Any other instruction
+    /// raises an illegal instruction exception in the guest.
+    fn handle_virtual_instruction<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &mut VCpu<H>,
+        fault_pc: GuestVirtAddr,
+        inst: u32,
+    ) -> Option<VmmTrap> {
+        let inst = match inst {
+            0 => self.vm_pages.fetch_guest_instruction(fault_pc).unwrap_or(0),
+            inst => inst,
+        };
+        if inst != WFI_INSTRUCTION {
+            // The guest may not execute the instruction in its mode, or it could not be fetched
+            // (`inst` is 0 then). Either way it is the guest's fault.
+            debug!("virtual instruction {:#x} at {:#x} is illegal", inst, fault_pc);
+            vcpu.inject_exception(ILLEGAL_INSTRUCTION, inst as usize);
+            return None;
+        }
+        vcpu.advance_pc(4);
+        if self.has_pending_interrupts(vcpu) {
+            return None;
+        }
+        // The hardware raises the Sstc timer only while the vCPU runs, so the VMM has to wake it.
+        let deadline = if has_sstc() {
+            vcpu.guest_to_host_time(vcpu.vstimecmp())
+        } else {
+            u64::MAX
+        };
+        Some(VmmTrap::Wfi(deadline))
+    }
+
+    /// Returns true if `vcpu` has an interrupt that ends a WFI: a pending VS-level interrupt, one
+    /// in its IMSIC guest interrupt file, an expired Sstc timer, or an ACLINT timer to arm.
+    fn has_pending_interrupts<H: HyperCraftHal>(&self, vcpu: &VCpu<H>) -> bool {
+        let vcpu_id = vcpu.vcpu_id();
+        let file_pending = self.imsic_files[vcpu_id]
+            .map_or(false, |file| CSR.hgeip.get_value() & (1 << file.index()) != 0);
+        let timer_expired =
+            has_sstc() && vcpu.guest_to_host_time(vcpu.vstimecmp()) <= time::read() as u64;
+        let timer_update = self
+            .aclint
+            .as_ref()
+            .map_or(false, |aclint| aclint.has_timer_update(vcpu_id));
+        vcpu.pending_interrupts() != 0 || file_pending || timer_expired || timer_update
+    }
+
+    /// Injects the ACLINT software interrupt sent to `vcpu`, if any.
+    fn inject_aclint_interrupts<H: HyperCraftHal>(&mut self, vcpu: &mut VCpu<H>) {
+        if let Some(aclint) = self.aclint.as_mut() {
+            if aclint.take_soft_pending(vcpu.vcpu_id()) {
+                vcpu.set_pending_interrupts(traps::interrupt::VIRTUAL_SUPERVISOR_SOFT);
+            }
+        }
+    }
+
+    fn handle_aplic<H: HyperCraftHal>(
+        &mut self,
+        vcpu: &mut VCpu<H>,
+        fault_addr: GuestPhysAddr,
+        access: MmioAccess,
+    ) -> HyperResult<()> {
+        self.emulate_u32_access(
+            vcpu,
+            access,
+            |vm| vm.aplic.as_ref().unwrap().read_u32(fault_addr),
+            |vm, val| {
+                let files = &vm.imsic_files;
+                vm.aplic
+                    .as_mut()
+                    .unwrap()
+                    .write_u32(fault_addr, val, |hart, eiid| send_msi(files, hart, eiid));
+            },
+        )
+    }
+
+    /// Runs `f` with the VM's guest physical address space loaded on this hart, so that
+    /// `vm_pages` reaches the VM's RAM while none of its vCPUs runs here.
+    fn with_guest_memory<R>(&self, f: impl FnOnce(&VmPages) -> R) -> R {
+        with_guest_hgatp(self.gpt.token(), || f(&self.vm_pages))
+    }
+
+    /// Writes the state of the interrupt controllers, of the devices on the MMIO bus and of the
+    /// SBI console as snapshot sections.
+    fn save_devices(&self, writer: &mut SnapshotWriter) -> HyperResult<()> {
+        let mut out = Encoder::new();
+        self.plic.save_state(&mut out);
+        writer.write_section(PLIC_TAG, 1, &out)?;
+        if let Some(aplic) = &self.aplic {
+            let mut out = Encoder::new();
+            aplic.save_state(&mut out);
+            writer.write_section(APLIC_TAG, 1, &out)?;
+        }
+        if let Some(aclint) = &self.aclint {
+            let mut out = Encoder::new();
+            aclint.save_state(&mut out);
+            writer.write_section(ACLINT_TAG, 1, &out)?;
+        }
+        self.mmio_bus.save_states(|index, state| {
+            let mut out = Encoder::new();
+            out.put_u32(index as u32);
+            out.put_bytes(state.as_bytes());
+            writer.write_section(MMIO_DEVICE_TAG, 1, &out)
+        })?;
+        let mut out = Encoder::new();
+        out.put_u64(self.input_buffer.len() as u64);
+        self.input_buffer.iter().for_each(|c| out.put_u64(*c as u64));
+        out.put_bytes(&self.console_output.iter().copied().collect::<Vec<u8>>());
+        writer.write_section(CONSOLE_TAG, 1, &out)
+    }
+
+    /// Loads the device state in `section`. Sections of devices the VM does not have are an
+    /// error, unknown sections are skipped.
+    fn restore_device(
+        &mut self,
+        section: SectionHeader,
+        reader: &mut SnapshotReader,
+    ) -> HyperResult<()> {
+        if ![PLIC_TAG, APLIC_TAG, ACLINT_TAG, MMIO_DEVICE_TAG, CONSOLE_TAG].contains(&section.tag) {
+            return Ok(());
+        }
+        section.check_version(1)?;
+        let payload = reader.read_payload()?;
+        let mut input = Decoder::new(&payload);
+        match section.tag {
+            PLIC_TAG => self.plic.restore_state(&mut input),
+            APLIC_TAG => self
+                .aplic
+                .as_mut()
+                .ok_or(HyperError::BadState)?
+                .restore_state(&mut input),
+            ACLINT_TAG => self
+                .aclint
+                .as_mut()
+                .ok_or(HyperError::BadState)?
+                .restore_state(&mut input),
+            MMIO_DEVICE_TAG => {
+                let index = input.get_u32()? as usize;
+                let state = input.get_bytes()?;
+                self.mmio_bus.restore_state(index, &mut Decoder::new(state))
+            }
+            _ => {
+                let len = input.get_u64()?;
+                self.input_buffer.clear();
+                for _ in 0..len {
+                    self.input_buffer.push_back(input.get_u64()? as usize);
+                }
+                self.console_output = input.get_bytes()?.iter().copied().collect();
+                Ok(())
             }
-            _ => return Err(HyperError::InvalidInstruction),
         }
-        Ok(len)
     }
 
-    fn handle_irq(&mut self) {
+    /// Writes every RAM region of the VM as a snapshot section.
+    fn save_ram(&self, writer: &mut SnapshotWriter) -> HyperResult<()> {
+        let mut page = vec![0; PAGE_SIZE_4K];
+        self.with_guest_memory(|vm_pages| {
+            for &(gpa, size) in self.ram_regions.iter() {
+                writer.begin_ram(gpa, size)?;
+                for offset in (0..size).step_by(PAGE_SIZE_4K) {
+                    self.read_ram_page(vm_pages, gpa + offset, &mut page)?;
+                    writer.write_raw(&page)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Reads the RAM at `gpa` into `buf`, without crossing a page. Discarded pages read as zeros.
+    fn read_ram_page(
+        &self,
+        vm_pages: &VmPages,
+        gpa: GuestPhysAddr,
+        buf: &mut [u8],
+    ) -> HyperResult<()> {
+        if self.discarded_pages.contains(&(gpa & !(PAGE_SIZE_4K - 1))) {
+            buf.fill(0);
+            return Ok(());
+        }
+        vm_pages.read_guest(gpa, buf)
+    }
+
+    /// Copies the RAM section `reader` is at into guest memory. It must lie within a RAM region
+    /// of the VM.
+    fn restore_ram<H: HyperCraftHal>(&mut self, reader: &mut SnapshotReader) -> HyperResult<()> {
+        let (gpa, len) = reader.read_ram_header()?;
+        let covered = self
+            .ram_regions
+            .iter()
+            .any(|&(start, size)| gpa >= start && gpa.saturating_add(len) <= start + size);
+        if !covered {
+            return Err(HyperError::OutOfRange);
+        }
+        // Pages discarded since the snapshot was taken are written to.
+        let mut ram = guest_ram!(self);
+        let first = gpa & !(PAGE_SIZE_4K - 1);
+        for page in (first..gpa + len).step_by(PAGE_SIZE_4K) {
+            ram.populate::<H>(page)?;
+        }
+        let mut page = vec![0; PAGE_SIZE_4K];
+        self.with_guest_memory(|vm_pages| {
+            for offset in (0..len).step_by(PAGE_SIZE_4K) {
+                let chunk = &mut page[..PAGE_SIZE_4K.min(len - offset)];
+                reader.read_raw(chunk)?;
+                vm_pages.write_guest(gpa + offset, chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn handle_guest_external_interrupt(&mut self) -> VmmTrap {
+        let hgeip = CSR.hgeip.get_value() & CSR.hgeie.get_value();
+        // The interrupts stay pending in the files and are taken once their vCPUs run again.
+        CSR.hgeie.read_and_clear_bits(hgeip);
+        VmmTrap::GuestExternalInterrupt(hgeip)
+    }
+
+    fn handle_irq<H: HyperCraftHal>(&mut self, vcpu: &mut VCpu<H>) {
         let context_id = 1;
         let claim_and_complete_addr = self.plic.base() + 0x0020_0004 + 0x1000 * context_id;
         let irq = unsafe { core::ptr::read_volatile(claim_and_complete_addr as *const u32) };
         assert!(irq != 0);
         self.plic.claim_complete[context_id] = irq;
 
-        CSR.hvip
-            .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        vcpu.set_pending_interrupts(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
     }
 
-    fn handle_base_function(&mut self, base: BaseFunction) -> HyperResult<()> {
-        let gprs = &mut self.state.general_purpose_registers;
+    fn handle_base_function(
+        &mut self,
+        gprs: &mut GeneralPurposeRegisters,
+        base: BaseFunction,
+    ) -> HyperResult<()> {
         match base {
             BaseFunction::GetSepcificationVersion => {
                 let version = sbi_rt::get_spec_version();
@@ -293,7 +1494,11 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
                 gprs.set_reg(GprIndex::A1, impl_version);
             }
             BaseFunction::ProbeSbiExtension(extension) => {
-                let extension = sbi_rt::probe_extension(extension as usize).raw;
+                let extension = if self.sbi_extensions.contains(extension as usize) {
+                    1
+                } else {
+                    sbi_rt::probe_extension(extension as usize).raw
+                };
                 gprs.set_reg(GprIndex::A1, extension);
             }
             BaseFunction::GetMachineVendorID => {
@@ -313,8 +1518,11 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
         Ok(())
     }
 
-    fn handle_pmu_function(&mut self, pmu: PmuFunction) -> HyperResult<()> {
-        let gprs = &mut self.state.general_purpose_registers;
+    fn handle_pmu_function(
+        &mut self,
+        gprs: &mut GeneralPurposeRegisters,
+        pmu: PmuFunction,
+    ) -> HyperResult<()> {
         gprs.set_reg(GprIndex::A0, 0);
         match pmu {
             PmuFunction::GetNumCounters => gprs.set_reg(GprIndex::A1, sbi_rt::pmu_num_counters()),
@@ -340,8 +1548,27 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
         Ok(())
     }
 
-    fn handle_rfnc_function(&mut self, rfnc: RemoteFenceFunction) -> HyperResult<()> {
-        let gprs = &mut self.state.general_purpose_registers;
+    fn handle_vendor_extension<H: HyperCraftHal>(
+        &mut self,
+        gprs: &mut GeneralPurposeRegisters,
+        eid: usize,
+        fid: usize,
+    ) -> HyperResult<()> {
+        let mem = DeviceMemory::<H, G>::new(&self.vm_pages, guest_ram!(self));
+        let sbi_ret = self
+            .sbi_extensions
+            .handle_ecall(eid, fid, gprs.a_regs(), &mem)
+            .unwrap_or_else(|| SbiReturn::error(SBI_ERR_NOT_SUPPORTED));
+        gprs.set_reg(GprIndex::A0, sbi_ret.error_code as usize);
+        gprs.set_reg(GprIndex::A1, sbi_ret.return_value as usize);
+        Ok(())
+    }
+
+    fn handle_rfnc_function(
+        &mut self,
+        gprs: &mut GeneralPurposeRegisters,
+        rfnc: RemoteFenceFunction,
+    ) -> HyperResult<()> {
         gprs.set_reg(GprIndex::A0, 0);
         match rfnc {
             RemoteFenceFunction::FenceI {
@@ -371,3 +1598,11 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
         Ok(())
     }
 }
+
+impl<G: GuestPageTableTrait> Drop for VmShared<G> {
+    fn drop(&mut self) {
+        for file in self.imsic_files.iter_mut().filter_map(Option::take) {
+            file.free();
+        }
+    }
+}
@@ -3,7 +3,7 @@ use core::arch::global_asm;
 use arrayvec::ArrayVec;
 use riscv_decode::Instruction;
 
-use crate::{GuestPhysAddr, HyperError, HyperResult};
+use crate::{GuestMemoryAccess, GuestPhysAddr, HyperError, HyperResult};
 global_asm!(include_str!("mem_extable.S"));
 
 extern "C" {
@@ -67,3 +67,41 @@ impl VmPages {
         Ok(raw_inst)
     }
 }
+
+/// Runs `f` with VS-stage translation disabled, so that HLV/HSV treat their operands as guest
+/// physical addresses instead of guest virtual ones.
+fn with_bare_vsatp<R>(f: impl FnOnce() -> R) -> R {
+    let vsatp: usize;
+    unsafe {
+        core::arch::asm!("csrrw {0}, vsatp, zero", out(reg) vsatp);
+    }
+    let ret = f();
+    unsafe {
+        core::arch::asm!("csrw vsatp, {0}", in(reg) vsatp);
+    }
+    ret
+}
+
+impl GuestMemoryAccess for VmPages {
+    fn read_guest(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()> {
+        let len = buf.len();
+        // Safety: _copy_from_guest internally detects and handles an invalid guest physical
+        // address and will only write up to `len` bytes to `buf`.
+        let copied = with_bare_vsatp(|| unsafe { _copy_from_guest(buf.as_mut_ptr(), gpa, len) });
+        if copied != len {
+            return Err(HyperError::PageFault);
+        }
+        Ok(())
+    }
+
+    fn write_guest(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HyperResult<()> {
+        let len = buf.len();
+        // Safety: _copy_to_guest internally detects and handles an invalid guest physical address
+        // and will only read up to `len` bytes from `buf`.
+        let copied = with_bare_vsatp(|| unsafe { _copy_to_guest(gpa, buf.as_ptr(), len) });
+        if copied != len {
+            return Err(HyperError::PageFault);
+        }
+        Ok(())
+    }
+}
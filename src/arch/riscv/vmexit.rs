@@ -37,6 +37,8 @@ pub enum VmExitInfo {
         falut_pc: GuestVirtAddr,
         /// Page fault inst.
         inst: u32,
+        /// Whether the fault was caused by a store or AMO.
+        is_store: bool,
         /// Page fault privilege level.
         priv_level: PrivilegeLevel,
     },
@@ -44,6 +46,8 @@ pub enum VmExitInfo {
     VirtualInstruction {
         /// Virtual instruction addr.
         fault_pc: GuestVirtAddr,
+        /// The instruction, or 0 if the hart does not report it in `stval`.
+        inst: u32,
         /// Virtual instruction privilege level.
         priv_level: PrivilegeLevel,
     },
@@ -54,4 +58,6 @@ pub enum VmExitInfo {
     TimerInterruptEmulation,
     /// An external interrupt for the running vCPU that can't be delegated and must be injected.
     ExternalInterruptEmulation,
+    /// An IMSIC guest interrupt file enabled in `hgeie` has an interrupt pending.
+    GuestExternalInterrupt,
 }
@@ -1,3 +1,5 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use riscv::register::time;
 
@@ -6,40 +8,189 @@ use crate::{
         csrs::{traps, RiscvCsrTrait, CSR},
         vmm_trap::VmmTrap,
     },
+    console::{ConsoleMux, ConsoleMuxConfig, HostConsole},
+    sched::{RoundRobinScheduler, SchedParams, Scheduler, VcpuHandle, DEFAULT_TIME_SLICE},
+    timer::TimerQueue,
     GuestPageTableTrait, HyperCraftHal,
 };
 
 use super::VM;
 /// virtual machine manager
 pub struct VMM<H: HyperCraftHal, G: GuestPageTableTrait> {
-    vm_list: Vec<VM<H, G>>,
-    switch_vm_timer: u64,
+    vm_list: Vec<Arc<VM<H, G>>>,
+    vm_params: Vec<SchedParams>,
+    // The vCPUs of each VM scheduled by this VMM.
+    vm_vcpus: Vec<Vec<usize>>,
+    scheduler: Box<dyn Scheduler>,
+    // The vCPUs waiting for an interrupt after a WFI.
+    blocked: Vec<VcpuHandle>,
+    timers: TimerQueue<VmmTimer>,
+    console: ConsoleMux,
+    host_console: Box<dyn HostConsole>,
+}
+
+/// Deadlines tracked in the VMM's timer queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum VmmTimer {
+    /// The timer a vCPU programmed through SBI `SetTimer` or its ACLINT.
+    Guest(VcpuHandle),
+    /// The Sstc timer of a blocked vCPU, which the hardware only raises while the vCPU runs.
+    Wake(VcpuHandle),
+    /// The end of the running vCPU's time slice.
+    SliceEnd,
+}
+
+/// The host console reached through the legacy SBI console calls.
+pub struct SbiConsole;
+
+impl HostConsole for SbiConsole {
+    fn getchar(&mut self) -> Option<u8> {
+        let c = sbi_rt::legacy::console_getchar();
+        if c == usize::MAX {
+            None
+        } else {
+            Some(c as u8)
+        }
+    }
+
+    fn putchar(&mut self, c: u8) {
+        sbi_rt::legacy::console_putchar(c as usize);
+    }
 }
 
 fn get_time() -> u64 {
     time::read() as u64
 }
 
-// TODO: 確定 qemu 的時鐘頻率
-const TIME_SLICE: u64 = 20_0000;
-
 impl<H: HyperCraftHal, G: GuestPageTableTrait> VMM<H, G> {
     /// 創建新的 VMM ，底下無任何虛擬機
     pub fn new() -> Self {
+        Self::with_console(Box::new(SbiConsole), ConsoleMuxConfig::default())
+    }
+    /// Creates a VMM without VMs, whose guests share `host_console` as configured by `config`.
+    pub fn with_console(host_console: Box<dyn HostConsole>, config: ConsoleMuxConfig) -> Self {
         VMM {
             vm_list: vec![],
-            switch_vm_timer: u64::MAX,
+            vm_params: vec![],
+            vm_vcpus: vec![],
+            scheduler: Box::new(RoundRobinScheduler::new()),
+            blocked: vec![],
+            timers: TimerQueue::new(),
+            console: ConsoleMux::new(config),
+            host_console,
         }
     }
     /// 將虛擬機加入 VMM
     pub fn add_vm(&mut self, vm: VM<H, G>) {
+        self.add_vm_with_params(vm, SchedParams::default());
+    }
+    /// Adds `vm` to the VMM. Each of its vCPUs is scheduled with `params`.
+    pub fn add_vm_with_params(&mut self, vm: VM<H, G>, params: SchedParams) {
+        let vcpus = vm.vcpu_ids().collect();
+        self.add_shared_vm(Arc::new(vm), params, vcpus);
+    }
+    /// Adds `vm`, which may be shared with the VMMs of other harts, and schedules only its
+    /// vCPUs in `vcpus` on this hart, each with `params`.
+    pub fn add_shared_vm(&mut self, vm: Arc<VM<H, G>>, params: SchedParams, vcpus: Vec<usize>) {
+        // VMs are identified by their index in `vm_list`, on the console and by the scheduler.
+        let vm_id = self.vm_list.len();
+        self.console.add_vm(vm_id).unwrap();
+        for &vcpu_id in vcpus.iter() {
+            self.scheduler
+                .add_vcpu(VcpuHandle::new(vm_id, vcpu_id), params);
+        }
         self.vm_list.push(vm);
+        self.vm_params.push(params);
+        self.vm_vcpus.push(vcpus);
     }
-    fn set_switch_vm_timer(&mut self) {
-        self.switch_vm_timer = get_time() + TIME_SLICE;
-        CSR.sie
-            .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
-        sbi_rt::set_timer(self.switch_vm_timer);
+    /// Replaces the scheduling policy, the default one is round-robin. The vCPUs of the VMs
+    /// already added are handed over to `scheduler`.
+    pub fn set_scheduler(&mut self, mut scheduler: Box<dyn Scheduler>) {
+        for (vm_id, vcpus) in self.vm_vcpus.iter().enumerate() {
+            for &vcpu_id in vcpus.iter() {
+                scheduler.add_vcpu(VcpuHandle::new(vm_id, vcpu_id), self.vm_params[vm_id]);
+            }
+        }
+        self.scheduler = scheduler;
+    }
+    /// The console multiplexer shared by the VMs.
+    pub fn console(&mut self) -> &mut ConsoleMux {
+        &mut self.console
+    }
+    /// Echoes the output VM `id` wrote since the last call.
+    fn flush_console_output(&mut self, id: usize) {
+        let console = &mut self.console;
+        let host = self.host_console.as_mut();
+        self.vm_list[id].drain_console_output(|data| console.write_output(host, id, data));
+    }
+    /// Hands the host console input to the VMs. Reading the SBI console is a firmware call, so
+    /// it is done once per time slice rather than on every VM exit.
+    fn poll_console_input(&mut self) {
+        let vm_list = &self.vm_list;
+        self.console.poll_input(self.host_console.as_mut(), |vm_id, c| {
+            vm_list[vm_id].add_char_to_input_buffer(c as usize)
+        });
+    }
+    /// Programs the host timer for the earliest pending deadline.
+    fn program_timer(&mut self) {
+        sbi_rt::set_timer(self.timers.next_deadline().unwrap_or(u64::MAX));
+    }
+    /// Handles the timers expired at `time`. Returns true if the time slice ended.
+    fn handle_expired_timers(&mut self, time: u64) -> bool {
+        let mut slice_expired = false;
+        while let Some(timer) = self.timers.pop_expired(time) {
+            match timer {
+                VmmTimer::Guest(vcpu) => {
+                    self.vm_list[vcpu.vm_id]
+                        .inject_timer_interrupt(vcpu.vcpu_id)
+                        .unwrap();
+                    self.wake(vcpu);
+                }
+                VmmTimer::Wake(vcpu) => self.wake(vcpu),
+                VmmTimer::SliceEnd => slice_expired = true,
+            }
+        }
+        slice_expired
+    }
+    /// Makes `vcpu` runnable again if it is blocked.
+    fn wake(&mut self, vcpu: VcpuHandle) {
+        if let Some(index) = self.blocked.iter().position(|v| *v == vcpu) {
+            self.blocked.swap_remove(index);
+            self.timers.cancel(&VmmTimer::Wake(vcpu));
+            self.scheduler.on_wake(vcpu);
+        }
+    }
+    /// Wakes the blocked vCPUs an interrupt reached, e.g. from a device or an IMSIC file.
+    fn wake_interrupted(&mut self) {
+        let vm_list = &self.vm_list;
+        let woken: Vec<VcpuHandle> = self
+            .blocked
+            .iter()
+            .copied()
+            .filter(|v| vm_list[v.vm_id].has_pending_interrupts(v.vcpu_id).unwrap_or(true))
+            .collect();
+        for vcpu in woken {
+            self.wake(vcpu);
+        }
+    }
+    /// Picks the vCPU to run next and starts its time slice, returning the vCPU and the start
+    /// time. While no vCPU is runnable, the hart waits for the blocked ones to be woken.
+    fn start_next_slice(&mut self) -> (VcpuHandle, u64) {
+        loop {
+            if let Some((next, time_slice)) = self.scheduler.pick_next() {
+                let time = get_time();
+                self.timers.set(VmmTimer::SliceEnd, time + time_slice);
+                return (next, time);
+            }
+            // Interrupts from devices are checked for at least once per default time slice.
+            self.timers
+                .set(VmmTimer::SliceEnd, get_time() + DEFAULT_TIME_SLICE);
+            self.program_timer();
+            unsafe { riscv::asm::wfi() };
+            self.handle_expired_timers(get_time());
+            self.poll_console_input();
+            self.wake_interrupted();
+        }
     }
     /// 在 hart_id 上執行 VMM 管理的所有虛擬機
     pub fn run(&mut self, hart_id: usize) {
@@ -49,88 +200,63 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait> VMM<H, G> {
 
         info!("vmm run cpu{}", hart_id);
 
+        // The timer queue always holds the end of the current time slice, so the host timer
+        // interrupt can stay enabled and preemption always works.
         CSR.sie
             .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
 
-        let mut id = 0;
-        let mut selected_vm_id_for_input = 0;
-        info!("由虛擬機 {} 控制獲取輸入", selected_vm_id_for_input);
-        self.set_switch_vm_timer();
+        let (mut current, mut slice_start) = self.start_next_slice();
+        self.program_timer();
         loop {
             // debug!("執行虛擬機 {}", id);
 
-            let vmm_trap = self.vm_list[id].run(0);
+            let id = current.vm_id;
+            let vmm_trap = self.vm_list[id].run(current.vcpu_id);
+            self.flush_console_output(id);
 
             match vmm_trap {
                 VmmTrap::SetTimer(timer) => {
-                    CSR.sie
-                        .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
-
-                    // let time = get_time();
-                    // debug!("虛擬機設定時鐘 {}", timer);
-                    // debug!("現在時鍾 {}", time);
-                    // TODO: 僅清除該 vm 的 hvip bit
-                    CSR.hvip
-                        .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
-                    sbi_rt::set_timer(timer);
+                    self.timers.set(VmmTimer::Guest(current), timer);
+                    self.program_timer();
+                }
+                VmmTrap::ConsoleOutput => {}
+                VmmTrap::GuestExternalInterrupt(hgeip) => {
+                    // The interrupts wait in their guest interrupt files until the owning vCPUs
+                    // get their next time slice. Blocked ones become runnable.
+                    trace!("guest external interrupts pending: {:#x}", hgeip);
+                    self.wake_interrupted();
+                }
+                VmmTrap::Wfi(deadline) => {
+                    if deadline != u64::MAX {
+                        self.timers.set(VmmTimer::Wake(current), deadline);
+                    }
+                    self.scheduler.on_block(current, get_time() - slice_start);
+                    self.blocked.push(current);
+                    (current, slice_start) = self.start_next_slice();
+                    self.program_timer();
+                }
+                VmmTrap::WrongHart(owner) => {
+                    error!(
+                        "vCPU {} of VM {} is bound to hart {}, not scheduling it on hart {}",
+                        current.vcpu_id, id, owner, hart_id
+                    );
+                    self.scheduler.remove_vcpu(current);
+                    self.vm_vcpus[id].retain(|&vcpu_id| vcpu_id != current.vcpu_id);
+                    self.timers.cancel(&VmmTimer::Guest(current));
+                    (current, slice_start) = self.start_next_slice();
+                    self.program_timer();
                 }
                 VmmTrap::TimerInterruptEmulation => {
-                    // XXX: 爲什麼需要阻斷 host 的 sie ？
-                    // nimbos 的時鐘中斷處理器也會 setTimer ，但並不是根據當前時間加上一個時間片
-                    // 而是根據上一次 setTimer(timer) 的 timer 加上一個時間片
-                    // 如果它 timer 加上時間片的增長速度比真實時間增長得還要慢，會發生以下循環：
-                    // 1.  虛擬機呼叫 SetTimer ，hypervisor 呼叫的 sbi_rt::set_timer 的時間點會比當下時間還要早
-                    // 2.  若不阻斷 sie.STIE ，一進入虛擬機，hypervisor 馬上被時鐘中斷
-                    // 3.  掉入 VmmTrap::TimerInterruptEmultaion ，也就是當前代碼
-                    // 4.  由於當前時間比虛擬機 timer 晚，注入 hvip.STIP
-                    // 5.a 若 hypervisor 時間片還沒到，timer 沒有重設，回到 2.
-                    // 5.b 若 hypervisor 時間片到了，timer 終於被重設到未來的時間點，進入虛擬機之後不再立刻觸發 hypervisor 的中斷
-                    //     但由於剛注入過 hvip.STIP ，虛擬機進入其時鐘中斷處理器，重新呼叫 setTimer ，回到 1.
-                    // 在整個循環中，虛擬機都沒辦法做到事。
-                    //
-                    // 若阻斷 sie.STIE ，則 nimbos 虛擬機不會頻繁跳入 hypervisor ，其 timer 追上真實時間的機會就增加了。
-                    //
-                    // 那總是在進入虛擬機之前關閉 sie.STIE 有沒有壞處呢？有的，如果虛擬機本身不會主動 SetTimer
-                    // sie.STIE 永遠不會被開啓，從而導致時鐘中斷無法中斷虛擬機的執行，單個虛擬機可以獨佔 CPU 資源
-                    // 所以下面這行到底要不要開啓，還是個兩難
-                    CSR.sie
-                        .read_and_clear_bits(traps::interrupt::SUPERVISOR_TIMER);
-
                     let time = get_time();
-                    for vm in &mut self.vm_list {
-                        if time > vm.get_timer() {
-                            // TODO: 僅注入到該 vm
-                            // debug!("注入 hvip.STIP");
-                            CSR.hvip
-                                .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
-                        }
-                    }
-                    // 現在時間已經超出時間片，切換虛擬機
-                    if time > self.switch_vm_timer {
-                        debug!("現在時間 {}，時間片到期時間 {}", time, self.switch_vm_timer);
-                        debug!("切換虛擬機");
-
-                        // 讀取 console 所有可讀字元
-                        loop {
-                            let c = sbi_rt::legacy::console_getchar();
-                            if c == usize::MAX {
-                                break;
-                            } else if c == 96 {
-                                selected_vm_id_for_input =
-                                    (selected_vm_id_for_input + 1) % vm_number;
-                                info!("由虛擬機 {} 控制獲取輸入", selected_vm_id_for_input);
-                            } else {
-                                info!("注入虛擬機 {} 輸入 {}", selected_vm_id_for_input, c);
-                                self.vm_list[selected_vm_id_for_input].add_char_to_input_buffer(c);
-                            }
-                        }
-
-                        // 設定下個時間片
-                        self.set_switch_vm_timer();
-
-                        // 執行下一個虛擬機
-                        id = (id + 1) % vm_number;
+                    // 現在時間已經超出時間片，切換 vCPU
+                    if self.handle_expired_timers(time) {
+                        debug!("切換 vCPU");
+                        self.poll_console_input();
+                        self.wake_interrupted();
+                        self.scheduler.on_exit(current, time - slice_start);
+                        (current, slice_start) = self.start_next_slice();
                     }
+                    self.program_timer();
                 }
             }
         }
@@ -6,4 +6,16 @@ pub enum VmmTrap {
     /// An timer interrupt for the running vCPU that can't be delegated and must be injected. The
     /// interrupt is injected the vCPU is run.
     TimerInterruptEmulation,
+    /// Interrupts arrived in the IMSIC guest interrupt files in the contained `hgeip` bitmap,
+    /// whose vCPUs are not running.
+    GuestExternalInterrupt(usize),
+    /// The guest finished a line of console output, or its output buffer is full.
+    ConsoleOutput,
+    /// The vCPU executed a WFI without an interrupt to take. It waits until an interrupt reaches
+    /// it, at the latest when its Sstc timer fires at the contained host time. Without Sstc it
+    /// is `u64::MAX`, the timer was handed to the VMM with `SetTimer`.
+    Wfi(u64),
+    /// The vCPU was not entered: its IMSIC guest interrupt file belongs to the contained hart,
+    /// and the vCPU can only run there.
+    WrongHart(usize),
 }
@@ -0,0 +1,92 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::dirty_log::{DirtyBitmap, DirtyLog};
+use crate::{GuestPageTableTrait, GuestPhysAddr, HyperError, HyperResult};
+
+/// Resolves EPT violations of guest writes to pages write-protected for dirty logging.
+pub trait DirtyFaultHandler {
+    /// Logs a guest write to `gpa` that faulted on a page write-protected for dirty logging and
+    /// makes the page writable again. Returns false if the fault has another cause.
+    fn handle_dirty_fault(&self, gpa: GuestPhysAddr) -> bool;
+
+    /// Changes whenever pages are write-protected for dirty logging. Before entering the guest, a
+    /// vCPU that last saw another value invalidates the writable EPT translations it cached.
+    fn protect_generation(&self) -> u64;
+}
+
+/// The dirty log of one VM, shared by its vCPUs through `VCpu::set_dirty_fault_handler`.
+///
+/// The embedder owns the VM's EPT and shares it with the log, which write-protects the logged
+/// pages in it. The vCPUs drop the stale writable translations this leaves behind on their next
+/// entry into the guest.
+pub struct EptDirtyLog<G: GuestPageTableTrait> {
+    ept: Arc<Mutex<G>>,
+    log: Mutex<Option<DirtyLog>>,
+    generation: AtomicU64,
+}
+
+impl<G: GuestPageTableTrait> EptDirtyLog<G> {
+    /// Creates the dirty log of the VM with nested page table `ept`, with logging off.
+    pub fn new(ept: Arc<Mutex<G>>) -> Self {
+        Self {
+            ept,
+            log: Mutex::new(None),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Starts logging the guest's writes to `regions` of guest RAM, given by start and size. All
+    /// their pages are write-protected in the EPT.
+    pub fn enable_dirty_log(&self, regions: &[(GuestPhysAddr, usize)]) -> HyperResult<()> {
+        let mut log = self.log.lock();
+        if log.is_some() {
+            return Err(HyperError::BadState);
+        }
+        *log = Some(DirtyLog::enable(&mut *self.ept.lock(), regions)?);
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Stops logging writes and makes the logged regions writable again.
+    pub fn disable_dirty_log(&self) -> HyperResult<()> {
+        let log = self.log.lock().take().ok_or(HyperError::BadState)?;
+        log.disable(&mut *self.ept.lock())
+    }
+
+    /// Returns the pages written since dirty logging was enabled or since the previous call, one
+    /// bitmap per logged region, and write-protects them again.
+    pub fn get_and_clear_dirty_log(&self) -> HyperResult<Vec<DirtyBitmap>> {
+        let mut log = self.log.lock();
+        let log = log.as_mut().ok_or(HyperError::BadState)?;
+        let dirty = log.get_and_clear(&mut *self.ept.lock())?;
+        if dirty.iter().any(|bitmap| bitmap.dirty_count() != 0) {
+            self.generation.fetch_add(1, Ordering::Release);
+        }
+        Ok(dirty)
+    }
+}
+
+impl<G: GuestPageTableTrait> DirtyFaultHandler for EptDirtyLog<G> {
+    fn handle_dirty_fault(&self, gpa: GuestPhysAddr) -> bool {
+        let mut log = self.log.lock();
+        let log = match log.as_mut() {
+            Some(log) => log,
+            None => return false,
+        };
+        match log.handle_write_fault(&mut *self.ept.lock(), gpa) {
+            Ok(handled) => handled,
+            Err(err) => {
+                warn!("dirty logging fault at {:#x} failed: {:?}", gpa, err);
+                false
+            }
+        }
+    }
+
+    fn protect_generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+}
@@ -1,5 +1,8 @@
-use page_table_entry::x86_64::EPTEntry;
-use page_table::{PagingMetaData, PageTable64};
+use memory_addr::VirtAddr;
+use page_table_entry::{x86_64::EPTEntry, MappingFlags};
+use page_table::{PagingIf, PagingMetaData, PageTable64};
+
+use crate::{dirty_log::DirtyLogPageTable, GuestPhysAddr, HyperError, HyperResult};
 
 pub struct ExtendedPageTableMetadata;
 
@@ -11,3 +14,18 @@ impl PagingMetaData for ExtendedPageTableMetadata {
 
 /// The VMX extended page table. (SDM Vol. 3C, Section 28.3)
 pub type ExtendedPageTable<I> = PageTable64<ExtendedPageTableMetadata, EPTEntry, I>;
+
+impl<I: PagingIf> DirtyLogPageTable for ExtendedPageTable<I> {
+    fn set_writable(&mut self, gpa: GuestPhysAddr, writable: bool) -> HyperResult<usize> {
+        let vaddr = VirtAddr::from(gpa);
+        let (_, flags, page_size) = self.query(vaddr).map_err(|_| HyperError::NotFound)?;
+        let flags = if writable {
+            flags | MappingFlags::WRITE
+        } else {
+            flags - MappingFlags::WRITE
+        };
+        self.update(vaddr, None, Some(flags))
+            .map_err(|_| HyperError::Internal)?;
+        Ok(page_size as usize)
+    }
+}
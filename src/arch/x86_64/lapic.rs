@@ -1,11 +1,19 @@
 use bit_field::BitField;
 use core::marker::PhantomData;
 
+use crate::devices::MsiMessage;
+use crate::snapshot::{Decoder, Encoder};
 use crate::{HyperCraftHal, HyperResult, HyperError};
 
 const APIC_FREQ_MHZ: u64 = 1000; // 1000 MHz
 const APIC_CYCLE_NANOS: u64 = 1000 / APIC_FREQ_MHZ;
 
+/// MSIs are writes to the range the local APICs decode. (SDM Vol. 3A, Section 11.11)
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+const MSI_ADDRESS_MASK: u64 = 0xfff0_0000;
+const MSI_DELIVERY_FIXED: u32 = 0b000;
+const MSI_DELIVERY_LOWEST_PRIORITY: u32 = 0b001;
+
 /// Local APIC timer modes.
 #[derive(Debug, Copy, Clone)]
 #[repr(u8)]
@@ -130,6 +138,38 @@ impl<H: HyperCraftHal> ApicTimer<H> {
         Ok(())
     }
 
+    /// Appends the timer's registers and the progress of its count to a snapshot.
+    pub fn save_state(&self, out: &mut Encoder) {
+        let now = H::current_time_nanos();
+        out.put_u32(self.lvt_timer_bits);
+        out.put_u8(self.divide_shift);
+        out.put_u32(self.initial_count);
+        out.put_u64(now.saturating_sub(self.last_start_ns));
+        out.put_u64(match self.deadline_ns {
+            0 => u64::MAX,
+            deadline => deadline.saturating_sub(now),
+        });
+    }
+
+    /// Loads the state written by `save_state`, resuming the count where it stopped.
+    pub fn restore_state(&mut self, input: &mut Decoder) -> HyperResult {
+        let lvt_timer_bits = input.get_u32()?;
+        let divide_shift = input.get_u8()?;
+        let initial_count = input.get_u32()?;
+        let elapsed_ns = input.get_u64()?;
+        let remaining_ns = input.get_u64()?;
+        let now = H::current_time_nanos();
+        self.lvt_timer_bits = lvt_timer_bits;
+        self.divide_shift = divide_shift & 0b111;
+        self.initial_count = initial_count;
+        self.last_start_ns = now.saturating_sub(elapsed_ns);
+        self.deadline_ns = match remaining_ns {
+            u64::MAX => 0,
+            remaining => now + remaining,
+        };
+        Ok(())
+    }
+
     const fn interval_ns(&self) -> u64 {
         (self.initial_count as u64 * APIC_CYCLE_NANOS) << self.divide_shift
     }
@@ -143,3 +183,27 @@ impl<H: HyperCraftHal> ApicTimer<H> {
         }
     }
 }
+
+/// Returns the APIC ID of the local APIC an MSI sent by an emulated device targets, for the VMM
+/// to pick the vCPU to call [`VCpu::inject_msi`](crate::VCpu::inject_msi) on.
+pub fn msi_destination(msi: &MsiMessage) -> HyperResult<u8> {
+    decode_msi(msi).map(|(dest, _)| dest)
+}
+
+/// Decodes an MSI into its destination APIC ID and its vector. Only fixed and lowest priority
+/// delivery are supported.
+pub(crate) fn decode_msi(msi: &MsiMessage) -> HyperResult<(u8, u8)> {
+    if msi.address & MSI_ADDRESS_MASK != MSI_ADDRESS_BASE {
+        return Err(HyperError::InvalidParam);
+    }
+    match msi.data.get_bits(8..11) {
+        MSI_DELIVERY_FIXED | MSI_DELIVERY_LOWEST_PRIORITY => {}
+        _ => return Err(HyperError::NotSupported),
+    }
+    // Vectors 0 to 15 are reserved.
+    let vector = msi.data.get_bits(0..8) as u8;
+    if vector < 16 {
+        return Err(HyperError::InvalidParam);
+    }
+    Ok((msi.address.get_bits(12..20) as u8, vector))
+}
@@ -3,6 +3,7 @@ mod regs;
 
 // Codes in this module come mainly from https://github.com/rcore-os/RVM-Tutorial
 
+mod dirty_log;
 mod ept;
 mod lapic;
 mod memory;
@@ -27,6 +28,8 @@ pub use ept::ExtendedPageTable as NestedPageTable;
 pub use vmx::VmxVcpu as VCpu;
 pub use percpu::PerCpu;
 pub use vmx::{VmxExitReason, VmxExitInfo};
+pub use lapic::msi_destination;
+pub use dirty_log::{DirtyFaultHandler, EptDirtyLog};
 
 ////// Following are things to be implemented
 
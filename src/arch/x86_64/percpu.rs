@@ -60,7 +60,7 @@ impl<H: HyperCraftHal> PerCpu<H> {
         if !self.is_enabled() {
             Err(HyperError::BadState)
         } else {
-            VCpu::new(&self.arch, entry, npt_root)
+            VCpu::new(&self.arch, self.cpu_id, entry, npt_root)
         }
     }
 }
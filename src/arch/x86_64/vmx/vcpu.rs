@@ -1,8 +1,10 @@
 use alloc::collections::VecDeque;
+use alloc::sync::Arc;
 use core::fmt::{Debug, Formatter, Result};
 use core::{arch::asm, mem::size_of};
 
 use bit_field::BitField;
+use page_table_entry::MappingFlags;
 use x86::bits64::vmx;
 use x86::dtables::{self, DescriptorTablePointer};
 use x86::segmentation::SegmentSelector;
@@ -10,14 +12,109 @@ use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
 
 use super::region::{MsrBitmap, VmxRegion};
 use super::vmcs::{
-    self, VmcsControl32, VmcsControl64, VmcsControlNW, VmcsGuest16, VmcsGuest32, VmcsGuest64,
+    self, InvVpidType, VmcsControl16, VmcsControl32, VmcsControl64, VmcsControlNW, VmcsGuest16, VmcsGuest32, VmcsGuest64,
     VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64, VmcsHostNW,
 };
 use super::VmxPerCpuState;
 use super::definitions::VmxExitReason;
 use crate::arch::{msr::Msr, memory::NestedPageFaultInfo, regs::GeneralRegisters};
-use crate::arch::lapic::ApicTimer;
-use crate::{GuestPhysAddr, HostPhysAddr, HyperCraftHal, HyperResult};
+use crate::arch::dirty_log::DirtyFaultHandler;
+use crate::arch::lapic::{decode_msi, ApicTimer};
+use crate::devices::{MsiMessage, PortIoBus};
+use crate::snapshot::{Decoder, Encoder};
+use crate::vmid::{VmidAllocator, VmidSlot};
+use crate::{GuestPhysAddr, HostPhysAddr, HyperCraftHal, HyperError, HyperResult};
+
+/// VPIDs of all vCPUs. VPID 0 is the host's.
+static VPID_ALLOCATOR: VmidAllocator = VmidAllocator::new(0xffff);
+
+/// Checks if the processor supports VPIDs and the all-context INVVPID a VPID rollover needs.
+/// (SDM Vol. 3D, Appendix A.3.3 and A.10)
+fn has_vpid_support() -> bool {
+    use super::vmcs::controls::SecondaryControls;
+    let allowed1 = Msr::IA32_VMX_PROCBASED_CTLS2.read() >> 32;
+    let ept_vpid_cap = Msr::IA32_VMX_EPT_VPID_CAP.read();
+    allowed1 & SecondaryControls::ENABLE_VPID.bits() as u64 != 0
+        && ept_vpid_cap.get_bit(32) // INVVPID
+        && ept_vpid_cap.get_bit(42) // all-context INVVPID
+}
+
+/// 16-bit guest-state fields saved in snapshots.
+const SNAPSHOT_GUEST16: [VmcsGuest16; 8] = {
+    use VmcsGuest16::*;
+    [
+        ES_SELECTOR,
+        CS_SELECTOR,
+        SS_SELECTOR,
+        DS_SELECTOR,
+        FS_SELECTOR,
+        GS_SELECTOR,
+        LDTR_SELECTOR,
+        TR_SELECTOR,
+    ]
+};
+
+/// 32-bit guest-state fields saved in snapshots.
+const SNAPSHOT_GUEST32: [VmcsGuest32; 21] = {
+    use VmcsGuest32::*;
+    [
+        ES_LIMIT,
+        CS_LIMIT,
+        SS_LIMIT,
+        DS_LIMIT,
+        FS_LIMIT,
+        GS_LIMIT,
+        LDTR_LIMIT,
+        TR_LIMIT,
+        GDTR_LIMIT,
+        IDTR_LIMIT,
+        ES_ACCESS_RIGHTS,
+        CS_ACCESS_RIGHTS,
+        SS_ACCESS_RIGHTS,
+        DS_ACCESS_RIGHTS,
+        FS_ACCESS_RIGHTS,
+        GS_ACCESS_RIGHTS,
+        LDTR_ACCESS_RIGHTS,
+        TR_ACCESS_RIGHTS,
+        INTERRUPTIBILITY_STATE,
+        ACTIVITY_STATE,
+        IA32_SYSENTER_CS,
+    ]
+};
+
+/// 64-bit guest-state fields saved in snapshots.
+const SNAPSHOT_GUEST64: [VmcsGuest64; 3] = [
+    VmcsGuest64::IA32_DEBUGCTL,
+    VmcsGuest64::IA32_PAT,
+    VmcsGuest64::IA32_EFER,
+];
+
+/// Natural-width guest-state fields saved in snapshots.
+const SNAPSHOT_GUEST_NW: [VmcsGuestNW; 20] = {
+    use VmcsGuestNW::*;
+    [
+        CR0,
+        CR3,
+        CR4,
+        ES_BASE,
+        CS_BASE,
+        SS_BASE,
+        DS_BASE,
+        FS_BASE,
+        GS_BASE,
+        LDTR_BASE,
+        TR_BASE,
+        GDTR_BASE,
+        IDTR_BASE,
+        DR7,
+        RSP,
+        RIP,
+        RFLAGS,
+        PENDING_DBG_EXCEPTIONS,
+        IA32_SYSENTER_ESP,
+        IA32_SYSENTER_EIP,
+    ]
+};
 
 /// A virtual CPU within a guest.
 #[repr(C)]
@@ -28,11 +125,19 @@ pub struct VmxVcpu<H: HyperCraftHal> {
     msr_bitmap: MsrBitmap<H>,
     apic_timer: ApicTimer<H>,
     pending_events: VecDeque<(u8, Option<u32>)>,
+    cpu_id: usize,
+    /// `None` if the processor has no VPIDs, every VM entry and exit flushes the TLB then.
+    vpid: Option<VmidSlot>,
+    port_io_bus: Option<Arc<PortIoBus>>,
+    dirty_fault_handler: Option<Arc<dyn DirtyFaultHandler>>,
+    /// The dirty log's protect generation when the vCPU last invalidated its EPT translations.
+    protect_generation: u64,
 }
 
 impl<H: HyperCraftHal> VmxVcpu<H> {
     pub(crate) fn new(
         percpu: &VmxPerCpuState<H>,
+        cpu_id: usize,
         entry: GuestPhysAddr,
         ept_root: HostPhysAddr,
     ) -> HyperResult<Self> {
@@ -43,6 +148,11 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
             msr_bitmap: MsrBitmap::passthrough_all()?,
             apic_timer: ApicTimer::new(),
             pending_events: VecDeque::with_capacity(8),
+            cpu_id,
+            vpid: has_vpid_support().then(VmidSlot::new),
+            port_io_bus: None,
+            dirty_fault_handler: None,
+            protect_generation: 0,
         };
         vcpu.setup_msr_bitmap()?;
         vcpu.setup_vmcs(entry, ept_root)?;
@@ -55,6 +165,7 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         VmcsHostNW::RSP
             .write(&self.host_stack_top as *const _ as usize)
             .unwrap();
+        self.sync_write_protection().unwrap();
         unsafe { self.vmx_launch() }
     }
 
@@ -73,11 +184,54 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         vmcs::io_exit_info()
     }
 
+    /// Emulates the `in`/`out` instruction that caused the current VM exit on the devices of
+    /// `bus`, and advances `RIP` past it. String and REP-prefixed port I/O is not supported.
+    pub fn handle_port_io(&mut self, bus: &PortIoBus) -> HyperResult {
+        let io_info = self.io_exit_info()?;
+        if io_info.is_string || io_info.is_repeat {
+            return Err(HyperError::NotSupported);
+        }
+        let width = io_info.access_size as usize;
+        let mask = u64::MAX >> (64 - width * 8);
+        if io_info.is_in {
+            let val = bus.read(io_info.port, width)? as u64;
+            let rax = &mut self.guest_regs.rax;
+            // A 32-bit `in` zero-extends into RAX, narrower ones leave the upper bits alone.
+            *rax = if width == 4 { val } else { (*rax & !mask) | (val & mask) };
+        } else {
+            bus.write(io_info.port, width, (self.guest_regs.rax & mask) as u32)?;
+        }
+        let instr_len = self.exit_info()?.exit_instruction_length;
+        self.advance_rip(instr_len as u8)
+    }
+
+    /// Sets the bus `in`/`out` exits are emulated on before they reach
+    /// [`HyperCraftHal::vmexit_handler`]. String and REP-prefixed port I/O, and all port I/O
+    /// without a bus, is still left to the HAL.
+    pub fn set_port_io_bus(&mut self, bus: Option<Arc<PortIoBus>>) {
+        self.port_io_bus = bus;
+    }
+
+    /// Sets the dirty log EPT violations of guest writes go to before they reach
+    /// [`HyperCraftHal::vmexit_handler`], usually the VM's [`EptDirtyLog`](crate::EptDirtyLog).
+    /// The vCPU invalidates its EPT translations before it enters the guest again whenever the
+    /// log write-protected pages since.
+    pub fn set_dirty_fault_handler(&mut self, handler: Option<Arc<dyn DirtyFaultHandler>>) {
+        self.dirty_fault_handler = handler;
+        self.protect_generation = 0;
+    }
+
     /// Information for VM exits due to nested page table faults (EPT violation).
     pub fn nested_page_fault_info(&self) -> HyperResult<NestedPageFaultInfo> {
         vmcs::ept_violation_info()
     }
 
+    /// Invalidates the EPT translations this CPU cached for the vCPU. Pages write-protected by
+    /// the vCPU's dirty fault handler are taken care of already.
+    pub fn invalidate_ept(&self) -> HyperResult {
+        vmcs::invalidate_ept()
+    }
+
     /// Guest general-purpose registers.
     pub fn regs(&self) -> &GeneralRegisters {
         &self.guest_regs
@@ -109,6 +263,14 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         self.pending_events.push_back((vector, err_code));
     }
 
+    /// Queues the interrupt of an MSI an emulated device sent to this vCPU's local APIC, e.g.
+    /// taken from a [`PciHostBridge`](crate::devices::PciHostBridge).
+    pub fn inject_msi(&mut self, msi: &MsiMessage) -> HyperResult {
+        let (_, vector) = decode_msi(msi)?;
+        self.inject_event(vector, None);
+        Ok(())
+    }
+
     /// If enable, a VM exit occurs at the beginning of any instruction if
     /// `RFLAGS.IF` = 1 and there are no other blocking of interrupts.
     /// (see SDM, Vol. 3C, Section 24.4.2)
@@ -128,6 +290,74 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
     pub fn apic_timer_mut(&mut self) -> &mut ApicTimer<H> {
         &mut self.apic_timer
     }
+
+    /// Appends the guest's registers, the guest-state area of its VMCS, its pending events and
+    /// its APIC timer to a snapshot. The vCPU's VMCS must be the current one, as it is in the
+    /// VM-exit handler.
+    pub fn save_state(&self, out: &mut Encoder) -> HyperResult {
+        let regs = &self.guest_regs;
+        out.put_u64s(&[
+            regs.rax, regs.rcx, regs.rdx, regs.rbx, regs.rbp, regs.rsi, regs.rdi, regs.r8,
+            regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+        ]);
+        for field in SNAPSHOT_GUEST16 {
+            out.put_u16(field.read()?);
+        }
+        for field in SNAPSHOT_GUEST32 {
+            out.put_u32(field.read()?);
+        }
+        for field in SNAPSHOT_GUEST64 {
+            out.put_u64(field.read()?);
+        }
+        for field in SNAPSHOT_GUEST_NW {
+            out.put_u64(field.read()? as u64);
+        }
+        out.put_u64(VmcsControlNW::CR0_READ_SHADOW.read()? as u64);
+        out.put_u64(VmcsControlNW::CR4_READ_SHADOW.read()? as u64);
+        out.put_u32(self.pending_events.len() as u32);
+        for (vector, err_code) in self.pending_events.iter() {
+            out.put_u8(*vector);
+            out.put_bool(err_code.is_some());
+            out.put_u32(err_code.unwrap_or(0));
+        }
+        self.apic_timer.save_state(out);
+        Ok(())
+    }
+
+    /// Loads the state written by `save_state` into the vCPU and its VMCS, which must be the
+    /// current one. The host-state and control areas are left as set up by `new`.
+    pub fn restore_state(&mut self, input: &mut Decoder) -> HyperResult {
+        let mut gprs = [0; 15];
+        input.get_u64s(&mut gprs)?;
+        let regs = &mut self.guest_regs;
+        [
+            regs.rax, regs.rcx, regs.rdx, regs.rbx, regs.rbp, regs.rsi, regs.rdi, regs.r8,
+            regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+        ] = gprs;
+        for field in SNAPSHOT_GUEST16 {
+            field.write(input.get_u16()?)?;
+        }
+        for field in SNAPSHOT_GUEST32 {
+            field.write(input.get_u32()?)?;
+        }
+        for field in SNAPSHOT_GUEST64 {
+            field.write(input.get_u64()?)?;
+        }
+        for field in SNAPSHOT_GUEST_NW {
+            field.write(input.get_u64()? as usize)?;
+        }
+        VmcsControlNW::CR0_READ_SHADOW.write(input.get_u64()? as usize)?;
+        VmcsControlNW::CR4_READ_SHADOW.write(input.get_u64()? as usize)?;
+        self.pending_events.clear();
+        for _ in 0..input.get_u32()? {
+            let vector = input.get_u8()?;
+            let has_err_code = input.get_bool()?;
+            let err_code = input.get_u32()?;
+            self.pending_events
+                .push_back((vector, has_err_code.then_some(err_code)));
+        }
+        self.apic_timer.restore_state(input)
+    }
 }
 
 // Implementation of private methods
@@ -280,17 +510,20 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
             (CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING).bits(),
         )?;
 
-        // Enable EPT, RDTSCP, INVPCID, and unrestricted guest.
+        // Enable EPT, RDTSCP, INVPCID, unrestricted guest, and VPID if supported.
         use SecondaryControls as CpuCtrl2;
+        let mut ctrl2 = CpuCtrl2::ENABLE_EPT
+            | CpuCtrl2::ENABLE_RDTSCP
+            | CpuCtrl2::ENABLE_INVPCID
+            | CpuCtrl2::UNRESTRICTED_GUEST;
+        if self.vpid.is_some() {
+            ctrl2 |= CpuCtrl2::ENABLE_VPID;
+        }
         vmcs::set_control(
             VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_PROCBASED_CTLS2,
             0,
-            (CpuCtrl2::ENABLE_EPT
-                | CpuCtrl2::ENABLE_RDTSCP
-                | CpuCtrl2::ENABLE_INVPCID
-                | CpuCtrl2::UNRESTRICTED_GUEST)
-                .bits(),
+            ctrl2.bits(),
             0,
         )?;
 
@@ -321,6 +554,7 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         )?;
 
         vmcs::set_ept_pointer(ept_root)?;
+        self.refresh_vpid()?;
 
         // No MSR switches if hypervisor doesn't use and there is only one vCPU.
         VmcsControl32::VMEXIT_MSR_STORE_COUNT.write(0)?;
@@ -335,6 +569,50 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         Ok(())
     }
 
+    /// Loads the vCPU's VPID into the VMCS, allocating a new one if VPIDs rolled over since.
+    /// Does nothing without VPID support.
+    fn refresh_vpid(&mut self) -> HyperResult {
+        let slot = match self.vpid.as_mut() {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+        let (vpid, flush) = VPID_ALLOCATOR.get(slot, self.cpu_id);
+        if flush {
+            // The address and VPID are ignored for an all-context invalidation.
+            unsafe { vmcs::invvpid(InvVpidType::AllContext, 0, 0)? };
+        }
+        VmcsControl16::VPID.write(vpid as u16)?;
+        Ok(())
+    }
+
+    /// Invalidates the EPT translations this CPU cached if the dirty log write-protected pages
+    /// since the vCPU last did, so that the next guest write to them faults and is logged.
+    fn sync_write_protection(&mut self) -> HyperResult {
+        let generation = match self.dirty_fault_handler.as_ref() {
+            Some(handler) => handler.protect_generation(),
+            None => return Ok(()),
+        };
+        if generation != self.protect_generation {
+            self.invalidate_ept()?;
+            self.protect_generation = generation;
+        }
+        Ok(())
+    }
+
+    /// Passes the EPT violation of a guest write to the dirty log. Returns true if it was a write
+    /// to a page write-protected for dirty logging, the write is retried then. The EPT violation
+    /// already dropped this CPU's cached translations of the page. (SDM Vol. 3C, Section 29.4.3.1)
+    fn handle_dirty_fault(&self) -> HyperResult<bool> {
+        let info = self.nested_page_fault_info()?;
+        if !info.access_flags.contains(MappingFlags::WRITE) {
+            return Ok(false);
+        }
+        Ok(self
+            .dirty_fault_handler
+            .as_ref()
+            .map_or(false, |handler| handler.handle_dirty_fault(info.fault_guest_paddr)))
+    }
+
     #[naked]
     unsafe extern "C" fn vmx_launch(&mut self) -> ! {
         asm!(
@@ -410,8 +688,26 @@ impl<H: HyperCraftHal> VmxVcpu<H> {
         // them handle all vmexits, but it's not very pragmatic now.
         let result: HyperResult = match exit_info.exit_reason {
             VmxExitReason::INTERRUPT_WINDOW => self.set_interrupt_window(false),
+            VmxExitReason::IO_INSTRUCTION if self.port_io_bus.is_some() => {
+                let bus = self.port_io_bus.clone().unwrap();
+                match self.handle_port_io(&bus) {
+                    Err(HyperError::NotSupported) => H::vmexit_handler(self),
+                    result => result,
+                }
+            }
+            VmxExitReason::EPT_VIOLATION if self.dirty_fault_handler.is_some() => {
+                match self.handle_dirty_fault() {
+                    Ok(true) => Ok(()),
+                    Ok(false) => H::vmexit_handler(self),
+                    Err(err) => Err(err),
+                }
+            }
             _ => H::vmexit_handler(self),
         };
+        // VPIDs may have rolled over and pages been write-protected since the last entry.
+        let result = result
+            .and_then(|()| self.refresh_vpid())
+            .and_then(|()| self.sync_write_protection());
 
         if result.is_err() {
             panic!(
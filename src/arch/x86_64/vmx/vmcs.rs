@@ -618,6 +618,13 @@ pub fn set_ept_pointer(pml4_paddr: HostPhysAddr) -> HyperResult {
     Ok(())
 }
 
+/// Invalidates this CPU's cached EPT translations of the current VMCS.
+pub fn invalidate_ept() -> HyperResult {
+    let eptp = VmcsControl64::EPTP.read()?;
+    unsafe { invept(InvEptType::SingleContext, eptp)? };
+    Ok(())
+}
+
 pub fn instruction_error() -> VmxInstructionError {
     VmcsReadOnly32::VM_INSTRUCTION_ERROR.read().unwrap().into()
 }
@@ -733,6 +740,33 @@ pub enum InvEptType {
     Global = 2,
 }
 
+/// INVVPID type. (SDM Vol. 3C, Section 30.3)
+#[repr(u64)]
+#[derive(Debug)]
+pub enum InvVpidType {
+    /// The logical processor invalidates mappings for the linear address and
+    /// VPID specified in the INVVPID descriptor.
+    IndividualAddress = 0,
+    /// The logical processor invalidates all mappings tagged with the VPID
+    /// specified in the INVVPID descriptor.
+    SingleContext = 1,
+    /// The logical processor invalidates all mappings tagged with all VPIDs
+    /// except VPID 0000H.
+    AllContext = 2,
+}
+
+/// Invalidate Translations Based on VPID. (SDM Vol. 3C, Section 30.3)
+///
+/// Invalidates mappings in the translation lookaside buffers (TLBs) and
+/// paging-structure caches based on virtual-processor identifier (VPID).
+/// Invalidation is based on the INVVPID type specified in the register operand
+/// and the INVVPID descriptor specified in the memory operand.
+pub unsafe fn invvpid(inv_type: InvVpidType, vpid: u16, addr: u64) -> Result<()> {
+    let invvpid_desc = [vpid as u64, addr];
+    asm!("invvpid {0}, [{1}]", in(reg) inv_type as u64, in(reg) &invvpid_desc);
+    vmx_capture_status()
+}
+
 /// Invalidate Translations Derived from EPT. (SDM Vol. 3C, Section 30.3)
 ///
 /// Invalidates mappings in the translation lookaside buffers (TLBs) and
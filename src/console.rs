@@ -0,0 +1,411 @@
+//! Multiplexing of the VMs' consoles onto the host console.
+//!
+//! Every VM's output is copied into a ring buffer and echoed to the host, optionally prefixed
+//! with the VM id at the start of each line. Host input goes to the VM that has the focus. An
+//! escape sequence of one or more keys followed by a command character controls the multiplexer
+//! itself:
+//!
+//! | keys              | action                                    |
+//! |-------------------|-------------------------------------------|
+//! | `escape` `n`      | focus the next VM                         |
+//! | `escape` `0-9`    | focus the VM with that id                 |
+//! | `escape` `l`      | list the VMs                              |
+//! | `escape` `d`      | dump the output history of the focused VM |
+//! | `escape` `h`      | print this help                           |
+//! | `escape` last key | send the escape sequence itself to the VM |
+//!
+//! Keys that only start the escape sequence are passed to the VM once they stop matching it.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::{HyperError, HyperResult};
+
+/// The host side of the console, provided by the embedder (e.g. SBI legacy console calls or a
+/// host UART driver).
+pub trait HostConsole: Send {
+    /// Returns the next input byte, if any.
+    fn getchar(&mut self) -> Option<u8>;
+
+    /// Writes one byte of output.
+    fn putchar(&mut self, c: u8);
+}
+
+/// Settings of a [`ConsoleMux`].
+#[derive(Clone, Copy, Debug)]
+pub struct ConsoleMuxConfig {
+    /// The keys introducing a multiplexer command. Ctrl-A by default, empty to pass all input
+    /// to the VMs.
+    pub escape_sequence: &'static [u8],
+    /// Whether to start every line of VM output with `[vm<id>] `.
+    pub prefix_lines: bool,
+    /// Bytes of output history kept per VM.
+    pub history_size: usize,
+}
+
+impl Default for ConsoleMuxConfig {
+    fn default() -> Self {
+        Self {
+            escape_sequence: &[0x01],
+            prefix_lines: true,
+            history_size: 16 * 1024,
+        }
+    }
+}
+
+struct VmConsole {
+    vm_id: usize,
+    history: VecDeque<u8>,
+    at_line_start: bool,
+}
+
+/// Routes host console input to the focused VM and the output of all VMs to the host console.
+pub struct ConsoleMux {
+    config: ConsoleMuxConfig,
+    vms: Vec<VmConsole>,
+    // Index into `vms` of the VM receiving input.
+    focus: usize,
+    // Keys of the escape sequence matched so far.
+    escape_matched: usize,
+    // The whole escape sequence was typed, the next key is a command.
+    command_pending: bool,
+    // The VM whose output line is unfinished on the host console.
+    line_owner: Option<usize>,
+}
+
+impl ConsoleMux {
+    /// Creates a multiplexer without VMs.
+    pub fn new(config: ConsoleMuxConfig) -> Self {
+        Self {
+            config,
+            vms: Vec::new(),
+            focus: 0,
+            escape_matched: 0,
+            command_pending: false,
+            line_owner: None,
+        }
+    }
+
+    /// Adds the console of VM `vm_id`. The first VM added gets the focus.
+    pub fn add_vm(&mut self, vm_id: usize) -> HyperResult<()> {
+        if self.index_of(vm_id).is_some() {
+            return Err(HyperError::BadState);
+        }
+        self.vms.push(VmConsole {
+            vm_id,
+            history: VecDeque::new(),
+            at_line_start: true,
+        });
+        Ok(())
+    }
+
+    /// Removes the console of VM `vm_id`, dropping its history.
+    pub fn remove_vm(&mut self, vm_id: usize) -> HyperResult<()> {
+        let index = self.index_of(vm_id).ok_or(HyperError::NotFound)?;
+        self.vms.remove(index);
+        if self.focus >= self.vms.len() || self.focus > index {
+            self.focus = self.focus.saturating_sub(1);
+        }
+        if self.line_owner == Some(vm_id) {
+            self.line_owner = None;
+        }
+        Ok(())
+    }
+
+    /// The id of the VM receiving input, if there is any VM.
+    pub fn focus(&self) -> Option<usize> {
+        self.vms.get(self.focus).map(|vm| vm.vm_id)
+    }
+
+    /// Gives the input focus to VM `vm_id`.
+    pub fn set_focus(&mut self, vm_id: usize) -> HyperResult<()> {
+        self.focus = self.index_of(vm_id).ok_or(HyperError::NotFound)?;
+        Ok(())
+    }
+
+    /// The output history of VM `vm_id`, oldest bytes first, as two slices.
+    pub fn history(&self, vm_id: usize) -> Option<(&[u8], &[u8])> {
+        let index = self.index_of(vm_id)?;
+        Some(self.vms[index].history.as_slices())
+    }
+
+    /// Records `data` written by VM `vm_id` and echoes it to `host`.
+    pub fn write_output(&mut self, host: &mut dyn HostConsole, vm_id: usize, data: &[u8]) {
+        let index = match self.index_of(vm_id) {
+            Some(index) => index,
+            None => return,
+        };
+        let history_size = self.config.history_size;
+        let vm = &mut self.vms[index];
+        for &c in data {
+            if vm.history.len() == history_size {
+                vm.history.pop_front();
+            }
+            if history_size > 0 {
+                vm.history.push_back(c);
+            }
+        }
+
+        for &c in data {
+            // Another VM's unfinished line is terminated first, so the lines don't mix. Its rest
+            // gets a prefix of its own.
+            if let Some(owner) = self.line_owner.filter(|&owner| owner != vm_id) {
+                host.putchar(b'\r');
+                host.putchar(b'\n');
+                if let Some(owner) = self.index_of(owner) {
+                    self.vms[owner].at_line_start = true;
+                }
+                self.line_owner = None;
+            }
+            let vm = &mut self.vms[index];
+            if vm.at_line_start && self.config.prefix_lines {
+                let _ = write!(HostWriter(host), "[vm{}] ", vm_id);
+            }
+            host.putchar(c);
+            vm.at_line_start = c == b'\n';
+            self.line_owner = if vm.at_line_start { None } else { Some(vm_id) };
+        }
+    }
+
+    /// Reads all pending input from `host`. Multiplexer commands are executed, any other byte is
+    /// passed to `deliver` together with the id of the focused VM.
+    pub fn poll_input(&mut self, host: &mut dyn HostConsole, mut deliver: impl FnMut(usize, u8)) {
+        while let Some(c) = host.getchar() {
+            let focus = self.focus();
+            self.handle_input(host, c, &mut |c| {
+                if let Some(vm_id) = focus {
+                    deliver(vm_id, c);
+                }
+            });
+        }
+    }
+}
+
+// Private methods implementation
+impl ConsoleMux {
+    fn index_of(&self, vm_id: usize) -> Option<usize> {
+        self.vms.iter().position(|vm| vm.vm_id == vm_id)
+    }
+
+    /// Feeds one input byte to the escape state machine. The bytes meant for the focused VM are
+    /// passed to `deliver`.
+    fn handle_input(&mut self, host: &mut dyn HostConsole, c: u8, deliver: &mut dyn FnMut(u8)) {
+        let sequence = self.config.escape_sequence;
+        if !self.command_pending {
+            if sequence.is_empty() {
+                deliver(c);
+            } else if c == sequence[self.escape_matched] {
+                self.escape_matched += 1;
+                if self.escape_matched == sequence.len() {
+                    self.escape_matched = 0;
+                    self.command_pending = true;
+                }
+            } else if self.escape_matched > 0 {
+                // The first held back key is input. The others and `c` may start the sequence
+                // again, they are fed back without completing it.
+                let matched = core::mem::take(&mut self.escape_matched);
+                deliver(sequence[0]);
+                for &key in sequence[1..matched].iter().chain(Some(&c)) {
+                    self.handle_input(host, key, deliver);
+                }
+            } else {
+                deliver(c);
+            }
+            return;
+        }
+        self.command_pending = false;
+        if c == sequence[sequence.len() - 1] {
+            sequence.iter().for_each(|&key| deliver(key));
+            return;
+        }
+        let mut out = HostWriter(host);
+        match c {
+            b'n' if !self.vms.is_empty() => {
+                self.focus = (self.focus + 1) % self.vms.len();
+                let _ = write!(out, "\r\n[mux] input goes to vm{}\r\n", self.vms[self.focus].vm_id);
+            }
+            b'0'..=b'9' => match self.set_focus((c - b'0') as usize) {
+                Ok(()) => {
+                    let _ = write!(out, "\r\n[mux] input goes to vm{}\r\n", c - b'0');
+                }
+                Err(_) => {
+                    let _ = write!(out, "\r\n[mux] no vm{}\r\n", c - b'0');
+                }
+            },
+            b'l' => {
+                let _ = write!(out, "\r\n[mux] vms:");
+                for (index, vm) in self.vms.iter().enumerate() {
+                    let mark = if index == self.focus { "*" } else { "" };
+                    let _ = write!(
+                        out,
+                        " vm{}{} ({} bytes)",
+                        vm.vm_id,
+                        mark,
+                        vm.history.len()
+                    );
+                }
+                let _ = write!(out, "\r\n");
+            }
+            b'd' => {
+                if let Some(vm) = self.vms.get(self.focus) {
+                    let _ = write!(out, "\r\n[mux] --- history of vm{} ---\r\n", vm.vm_id);
+                    for &b in vm.history.iter() {
+                        out.0.putchar(b);
+                    }
+                    let _ = write!(out, "\r\n[mux] --- end ---\r\n");
+                }
+            }
+            b'h' | b'?' => {
+                let _ = write!(
+                    out,
+                    "\r\n[mux] n: next vm, 0-9: focus vm, l: list vms, d: dump history\r\n"
+                );
+            }
+            _ => {}
+        }
+        // Whatever was on the host line before the message is finished.
+        self.line_owner = None;
+        for vm in self.vms.iter_mut() {
+            vm.at_line_start = true;
+        }
+    }
+}
+
+struct HostWriter<'a>(&'a mut dyn HostConsole);
+
+impl Write for HostWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &c in s.as_bytes() {
+            self.0.putchar(c);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct TestConsole {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl TestConsole {
+        fn take_output(&mut self) -> Vec<u8> {
+            core::mem::take(&mut self.output)
+        }
+    }
+
+    impl HostConsole for TestConsole {
+        fn getchar(&mut self) -> Option<u8> {
+            self.input.pop_front()
+        }
+
+        fn putchar(&mut self, c: u8) {
+            self.output.push(c);
+        }
+    }
+
+    fn mux_with_vms(config: ConsoleMuxConfig, vm_ids: &[usize]) -> ConsoleMux {
+        let mut mux = ConsoleMux::new(config);
+        for &vm_id in vm_ids {
+            mux.add_vm(vm_id).unwrap();
+        }
+        mux
+    }
+
+    fn type_keys(mux: &mut ConsoleMux, host: &mut TestConsole, keys: &[u8]) -> Vec<(usize, u8)> {
+        host.input.extend(keys);
+        let mut delivered = Vec::new();
+        mux.poll_input(host, |vm_id, c| delivered.push((vm_id, c)));
+        delivered
+    }
+
+    #[test]
+    fn output_lines_are_prefixed_and_kept_apart() {
+        let mut mux = mux_with_vms(ConsoleMuxConfig::default(), &[0, 1]);
+        let mut host = TestConsole::default();
+        mux.write_output(&mut host, 0, b"boot");
+        mux.write_output(&mut host, 1, b"hi\n");
+        mux.write_output(&mut host, 0, b"ed\n");
+        assert_eq!(host.take_output(), b"[vm0] boot\r\n[vm1] hi\n[vm0] ed\n");
+        // Output of unknown VMs is dropped.
+        mux.write_output(&mut host, 7, b"x");
+        assert!(host.output.is_empty());
+        assert_eq!(mux.add_vm(1), Err(HyperError::BadState));
+    }
+
+    #[test]
+    fn history_is_a_bounded_ring() {
+        let config = ConsoleMuxConfig {
+            prefix_lines: false,
+            history_size: 4,
+            ..Default::default()
+        };
+        let mut mux = mux_with_vms(config, &[3]);
+        let mut host = TestConsole::default();
+        mux.write_output(&mut host, 3, b"abc");
+        mux.write_output(&mut host, 3, b"def");
+        let (first, second) = mux.history(3).unwrap();
+        assert_eq!([first, second].concat(), b"cdef");
+        assert_eq!(host.take_output(), b"abcdef");
+        assert!(mux.history(0).is_none());
+
+        // Dumping the focused VM's history replays it on the host.
+        type_keys(&mut mux, &mut host, b"\x01d");
+        let dump = host.take_output();
+        assert!(dump.windows(4).any(|w| w == b"cdef"));
+        mux.remove_vm(3).unwrap();
+        assert!(mux.history(3).is_none());
+    }
+
+    #[test]
+    fn input_follows_the_focus() {
+        let mut mux = mux_with_vms(ConsoleMuxConfig::default(), &[0, 1, 2]);
+        let mut host = TestConsole::default();
+        assert_eq!(type_keys(&mut mux, &mut host, b"a"), [(0, b'a')]);
+        assert_eq!(type_keys(&mut mux, &mut host, b"\x01nb"), [(1, b'b')]);
+        assert_eq!(type_keys(&mut mux, &mut host, b"\x012c"), [(2, b'c')]);
+        assert_eq!(type_keys(&mut mux, &mut host, b"\x019d"), [(2, b'd')]);
+        assert_eq!(mux.focus(), Some(2));
+        // The escape key typed twice reaches the VM.
+        assert_eq!(type_keys(&mut mux, &mut host, b"\x01\x01"), [(2, 0x01)]);
+        mux.remove_vm(2).unwrap();
+        assert_eq!(mux.focus(), Some(1));
+    }
+
+    #[test]
+    fn multi_key_escape_sequences() {
+        let config = ConsoleMuxConfig {
+            escape_sequence: b"\r~",
+            ..Default::default()
+        };
+        let mut mux = mux_with_vms(config, &[0, 1]);
+        let mut host = TestConsole::default();
+        assert_eq!(type_keys(&mut mux, &mut host, b"\r~n"), []);
+        assert_eq!(mux.focus(), Some(1));
+        // A key breaking the sequence releases the held back ones.
+        let keys = type_keys(&mut mux, &mut host, b"\rx");
+        assert_eq!(keys, [(1, b'\r'), (1, b'x')]);
+        // The breaking key may start the sequence again.
+        let keys = type_keys(&mut mux, &mut host, b"\r\r~0");
+        assert_eq!(keys, [(1, b'\r')]);
+        assert_eq!(mux.focus(), Some(0));
+        let keys = type_keys(&mut mux, &mut host, b"\r~~");
+        assert_eq!(keys, [(0, b'\r'), (0, b'~')]);
+        // Half a sequence waits for the next poll.
+        assert_eq!(type_keys(&mut mux, &mut host, b"\r"), []);
+        assert_eq!(type_keys(&mut mux, &mut host, b"~1"), []);
+        assert_eq!(mux.focus(), Some(1));
+
+        let config = ConsoleMuxConfig {
+            escape_sequence: &[],
+            ..Default::default()
+        };
+        let mut mux = mux_with_vms(config, &[0]);
+        assert_eq!(type_keys(&mut mux, &mut host, b"\x01n"), [(0, 0x01), (0, b'n')]);
+    }
+}
@@ -0,0 +1,426 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use spin::Mutex;
+
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestMemoryAccess, GuestPhysAddr, HyperError, HyperResult};
+
+/// A message signaled interrupt: the store of `data` to guest physical address `address` that
+/// the interrupt controller turns into an interrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MsiMessage {
+    /// The address written to.
+    pub address: u64,
+    /// The 32-bit value written.
+    pub data: u32,
+}
+
+/// A device emulated behind a range of guest physical addresses.
+pub trait MmioDevice: Send {
+    /// The guest physical address range claimed by the device.
+    fn mmio_range(&self) -> Range<GuestPhysAddr>;
+
+    /// Handles a guest load of `width` bytes at `offset` from the start of the range.
+    fn mmio_read(&mut self, offset: usize, width: usize) -> HyperResult<u64>;
+
+    /// Handles a guest store of the low `width` bytes of `val` at `offset`.
+    fn mmio_write(&mut self, offset: usize, width: usize, val: u64) -> HyperResult<()>;
+
+    /// The interrupt line of the device and its current level, if it has one.
+    fn irq_level(&self) -> Option<(usize, bool)> {
+        None
+    }
+
+    /// Calls `f` with every interrupt line of the device and its current level. Only devices
+    /// driving several lines, such as a PCI host bridge, need to override it.
+    fn for_each_irq(&self, f: &mut dyn FnMut(usize, bool)) {
+        if let Some((irq, level)) = self.irq_level() {
+            f(irq, level);
+        }
+    }
+
+    /// Calls `f` with the MSIs the device sent since the last call, oldest first.
+    fn take_msis(&mut self, _f: &mut dyn FnMut(MsiMessage)) {}
+
+    /// Whether the device has work that needs guest memory, e.g. buffers to fill. Devices doing
+    /// DMA can only reach guest memory from [`poll`](Self::poll).
+    fn has_pending_work(&self) -> bool {
+        false
+    }
+
+    /// Does the device's pending work. Called after each guest store to the device, and before
+    /// the VM runs again while [`has_pending_work`](Self::has_pending_work) is true.
+    fn poll(&mut self, _mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        Ok(())
+    }
+
+    /// Appends the device's state to a snapshot. Stateless devices write nothing.
+    fn save_state(&self, _out: &mut Encoder) {}
+
+    /// Loads the state written by `save_state`.
+    fn restore_state(&mut self, _input: &mut Decoder) -> HyperResult<()> {
+        Ok(())
+    }
+}
+
+/// A device emulated behind a range of x86 I/O ports.
+pub trait PortIoDevice: Send {
+    /// The ports claimed by the device.
+    fn port_range(&self) -> Range<u16>;
+
+    /// Handles an `in` of `width` bytes from `port`.
+    fn port_read(&mut self, port: u16, width: usize) -> HyperResult<u32>;
+
+    /// Handles an `out` of the low `width` bytes of `val` to `port`.
+    fn port_write(&mut self, port: u16, width: usize, val: u32) -> HyperResult<()>;
+
+    /// The interrupt line of the device and its current level, if it has one.
+    fn irq_level(&self) -> Option<(usize, bool)> {
+        None
+    }
+
+    /// Appends the device's state to a snapshot. Stateless devices write nothing.
+    fn save_state(&self, _out: &mut Encoder) {}
+
+    /// Loads the state written by `save_state`.
+    fn restore_state(&mut self, _input: &mut Decoder) -> HyperResult<()> {
+        Ok(())
+    }
+}
+
+/// Routes guest MMIO accesses to the devices registered on it.
+///
+/// Devices are shared with the VMM, which keeps its own handle to e.g. feed input to a UART.
+#[derive(Default)]
+pub struct MmioBus {
+    devices: Vec<Arc<Mutex<dyn MmioDevice>>>,
+}
+
+impl MmioBus {
+    /// Creates an empty bus.
+    pub fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+        }
+    }
+
+    /// Adds `device` to the bus. Fails if its range overlaps the one of a registered device.
+    pub fn register(&mut self, device: Arc<Mutex<dyn MmioDevice>>) -> HyperResult<()> {
+        let range = device.lock().mmio_range();
+        if range.is_empty() {
+            return Err(HyperError::InvalidParam);
+        }
+        if self.devices.iter().any(|d| overlaps(&d.lock().mmio_range(), &range)) {
+            return Err(HyperError::BadState);
+        }
+        self.devices.push(device);
+        Ok(())
+    }
+
+    /// Removes the device claiming `addr`. The other devices keep their registration order.
+    pub fn unregister(&mut self, addr: GuestPhysAddr) -> Option<Arc<Mutex<dyn MmioDevice>>> {
+        let index = self.position(addr)?;
+        Some(self.devices.remove(index))
+    }
+
+    /// Returns true if a device claims `addr`.
+    pub fn contains(&self, addr: GuestPhysAddr) -> bool {
+        self.position(addr).is_some()
+    }
+
+    /// Forwards a load of `width` bytes at `addr` to the device claiming it.
+    pub fn read(&self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64> {
+        let index = self.position(addr).ok_or(HyperError::NotFound)?;
+        let mut device = self.devices[index].lock();
+        let offset = addr - device.mmio_range().start;
+        device.mmio_read(offset, width)
+    }
+
+    /// Forwards a store of `width` bytes of `val` at `addr` to the device claiming it.
+    pub fn write(&self, addr: GuestPhysAddr, width: usize, val: u64) -> HyperResult<()> {
+        let index = self.position(addr).ok_or(HyperError::NotFound)?;
+        let mut device = self.devices[index].lock();
+        let offset = addr - device.mmio_range().start;
+        device.mmio_write(offset, width, val)
+    }
+
+    /// Returns true if a device has pending work for [`poll`](Self::poll).
+    pub fn has_pending_work(&self) -> bool {
+        self.devices.iter().any(|d| d.lock().has_pending_work())
+    }
+
+    /// Lets every device with pending work do it, reaching guest memory through `mem`.
+    pub fn poll(&self, mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        for device in &self.devices {
+            let mut device = device.lock();
+            if device.has_pending_work() {
+                device.poll(mem)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with the interrupt lines and levels of every device that has some.
+    pub fn for_each_irq(&self, mut f: impl FnMut(usize, bool)) {
+        for device in &self.devices {
+            device.lock().for_each_irq(&mut f);
+        }
+    }
+
+    /// Calls `f` with the MSIs the devices sent since the last call, to be delivered by the
+    /// interrupt controller they target.
+    pub fn take_msis(&self, mut f: impl FnMut(MsiMessage)) {
+        for device in &self.devices {
+            device.lock().take_msis(&mut f);
+        }
+    }
+
+    /// Calls `f` with the index and the saved state of every device, in registration order.
+    pub fn save_states(
+        &self,
+        mut f: impl FnMut(usize, &Encoder) -> HyperResult<()>,
+    ) -> HyperResult<()> {
+        for (index, device) in self.devices.iter().enumerate() {
+            let mut out = Encoder::new();
+            device.lock().save_state(&mut out);
+            f(index, &out)?;
+        }
+        Ok(())
+    }
+
+    /// Loads the state saved by `save_states` for the device at `index`. The devices must have
+    /// been registered in the same order as on the bus the state was saved from.
+    pub fn restore_state(&self, index: usize, input: &mut Decoder) -> HyperResult<()> {
+        let device = self.devices.get(index).ok_or(HyperError::NotFound)?;
+        device.lock().restore_state(input)
+    }
+
+    fn position(&self, addr: GuestPhysAddr) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|d| d.lock().mmio_range().contains(&addr))
+    }
+}
+
+/// Routes guest port I/O to the devices registered on it.
+#[derive(Default)]
+pub struct PortIoBus {
+    devices: Vec<Arc<Mutex<dyn PortIoDevice>>>,
+}
+
+impl PortIoBus {
+    /// Creates an empty bus.
+    pub fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+        }
+    }
+
+    /// Adds `device` to the bus. Fails if its ports overlap the ones of a registered device.
+    pub fn register(&mut self, device: Arc<Mutex<dyn PortIoDevice>>) -> HyperResult<()> {
+        let range = device.lock().port_range();
+        if range.is_empty() {
+            return Err(HyperError::InvalidParam);
+        }
+        if self.devices.iter().any(|d| overlaps(&d.lock().port_range(), &range)) {
+            return Err(HyperError::BadState);
+        }
+        self.devices.push(device);
+        Ok(())
+    }
+
+    /// Removes the device claiming `port`. The other devices keep their registration order.
+    pub fn unregister(&mut self, port: u16) -> Option<Arc<Mutex<dyn PortIoDevice>>> {
+        let index = self.position(port)?;
+        Some(self.devices.remove(index))
+    }
+
+    /// Returns true if a device claims `port`.
+    pub fn contains(&self, port: u16) -> bool {
+        self.position(port).is_some()
+    }
+
+    /// Forwards an `in` of `width` bytes from `port`. Unclaimed ports read as all ones, like on
+    /// real hardware.
+    pub fn read(&self, port: u16, width: usize) -> HyperResult<u32> {
+        match self.position(port) {
+            Some(index) => self.devices[index].lock().port_read(port, width),
+            None => Ok(u32::MAX >> (32 - width * 8)),
+        }
+    }
+
+    /// Forwards an `out` of `width` bytes of `val` to `port`. Writes to unclaimed ports are
+    /// dropped.
+    pub fn write(&self, port: u16, width: usize, val: u32) -> HyperResult<()> {
+        match self.position(port) {
+            Some(index) => self.devices[index].lock().port_write(port, width, val),
+            None => Ok(()),
+        }
+    }
+
+    /// Calls `f` with the interrupt line and level of every device that has one.
+    pub fn for_each_irq(&self, mut f: impl FnMut(usize, bool)) {
+        for device in &self.devices {
+            if let Some((irq, level)) = device.lock().irq_level() {
+                f(irq, level);
+            }
+        }
+    }
+
+    /// Calls `f` with the index and the saved state of every device, in registration order.
+    pub fn save_states(
+        &self,
+        mut f: impl FnMut(usize, &Encoder) -> HyperResult<()>,
+    ) -> HyperResult<()> {
+        for (index, device) in self.devices.iter().enumerate() {
+            let mut out = Encoder::new();
+            device.lock().save_state(&mut out);
+            f(index, &out)?;
+        }
+        Ok(())
+    }
+
+    /// Loads the state saved by `save_states` for the device at `index`. The devices must have
+    /// been registered in the same order as on the bus the state was saved from.
+    pub fn restore_state(&self, index: usize, input: &mut Decoder) -> HyperResult<()> {
+        let device = self.devices.get(index).ok_or(HyperError::NotFound)?;
+        device.lock().restore_state(input)
+    }
+
+    fn position(&self, port: u16) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|d| d.lock().port_range().contains(&port))
+    }
+}
+
+fn overlaps<T: PartialOrd>(a: &Range<T>, b: &Range<T>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestDevice {
+        range: Range<usize>,
+        reg: u64,
+        irq: usize,
+    }
+
+    impl TestDevice {
+        fn shared(start: usize, end: usize, irq: usize) -> Arc<Mutex<Self>> {
+            Arc::new(Mutex::new(Self {
+                range: start..end,
+                reg: 0,
+                irq,
+            }))
+        }
+    }
+
+    impl MmioDevice for TestDevice {
+        fn mmio_range(&self) -> Range<GuestPhysAddr> {
+            self.range.clone()
+        }
+
+        fn mmio_read(&mut self, offset: usize, _width: usize) -> HyperResult<u64> {
+            Ok(self.reg + offset as u64)
+        }
+
+        fn mmio_write(&mut self, _offset: usize, _width: usize, val: u64) -> HyperResult<()> {
+            self.reg = val;
+            Ok(())
+        }
+
+        fn irq_level(&self) -> Option<(usize, bool)> {
+            Some((self.irq, self.reg != 0))
+        }
+
+        fn save_state(&self, out: &mut Encoder) {
+            out.put_u64(self.reg);
+        }
+
+        fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+            self.reg = input.get_u64()?;
+            Ok(())
+        }
+    }
+
+    impl PortIoDevice for TestDevice {
+        fn port_range(&self) -> Range<u16> {
+            self.range.start as u16..self.range.end as u16
+        }
+
+        fn port_read(&mut self, port: u16, _width: usize) -> HyperResult<u32> {
+            Ok(self.reg as u32 + port as u32)
+        }
+
+        fn port_write(&mut self, _port: u16, _width: usize, val: u32) -> HyperResult<()> {
+            self.reg = val as u64;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mmio_accesses_reach_the_claiming_device() {
+        let mut bus = MmioBus::new();
+        let (a, b) = (TestDevice::shared(0x1000, 0x2000, 1), TestDevice::shared(0x2000, 0x3000, 2));
+        bus.register(a.clone()).unwrap();
+        bus.register(b.clone()).unwrap();
+        assert_eq!(bus.register(TestDevice::shared(0x1800, 0x2800, 3)), Err(HyperError::BadState));
+        let empty = TestDevice::shared(0x4000, 0x4000, 3);
+        assert_eq!(bus.register(empty), Err(HyperError::InvalidParam));
+
+        bus.write(0x2010, 4, 7).unwrap();
+        assert_eq!((a.lock().reg, b.lock().reg), (0, 7));
+        assert_eq!(bus.read(0x2010, 4), Ok(0x17));
+        assert_eq!(bus.read(0x3000, 4), Err(HyperError::NotFound));
+        let mut irqs = Vec::new();
+        bus.for_each_irq(|irq, level| irqs.push((irq, level)));
+        assert_eq!(irqs, [(1, false), (2, true)]);
+    }
+
+    #[test]
+    fn unregister_keeps_the_order_of_saved_states() {
+        let mut bus = MmioBus::new();
+        for (index, start) in [0x1000, 0x2000, 0x3000].into_iter().enumerate() {
+            let device = TestDevice::shared(start, start + 0x1000, 0);
+            device.lock().reg = index as u64;
+            bus.register(device).unwrap();
+        }
+        assert!(bus.unregister(0x1000).is_some());
+        assert!(!bus.contains(0x1000));
+        let mut saved = Vec::new();
+        bus.save_states(|index, state| {
+            saved.push((index, Decoder::new(state.as_bytes()).get_u64()?));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(saved, [(0, 1), (1, 2)]);
+
+        let mut out = Encoder::new();
+        out.put_u64(9);
+        bus.restore_state(1, &mut Decoder::new(out.as_bytes())).unwrap();
+        assert_eq!(bus.read(0x3000, 8), Ok(9));
+        let mut input = Decoder::new(out.as_bytes());
+        assert_eq!(bus.restore_state(2, &mut input), Err(HyperError::NotFound));
+    }
+
+    #[test]
+    fn unclaimed_ports_read_as_all_ones() {
+        let mut bus = PortIoBus::new();
+        let device = TestDevice::shared(0x3f8, 0x400, 4);
+        bus.register(device.clone()).unwrap();
+        assert_eq!(bus.register(TestDevice::shared(0x3ff, 0x401, 4)), Err(HyperError::BadState));
+        bus.write(0x3f8, 1, 0x10).unwrap();
+        assert_eq!(bus.read(0x3f9, 1), Ok(0x10 + 0x3f9));
+        assert_eq!(bus.read(0x80, 1), Ok(0xff));
+        assert_eq!(bus.read(0x80, 2), Ok(0xffff));
+        assert_eq!(bus.read(0x80, 4), Ok(u32::MAX));
+        bus.write(0x80, 1, 0).unwrap();
+        assert!(bus.unregister(0x3f8).is_some());
+        assert!(!bus.contains(0x3ff));
+    }
+}
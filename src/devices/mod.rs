@@ -0,0 +1,12 @@
+//! Architecture independent device models, and the buses that route trapped guest accesses to
+//! them.
+
+mod bus;
+pub mod pci;
+pub mod uart16550;
+pub mod virtio;
+pub mod vswitch;
+
+pub use bus::{MmioBus, MmioDevice, MsiMessage, PortIoBus, PortIoDevice};
+pub use pci::{PciAddress, PciDevice, PciHostBridge};
+pub use uart16550::Uart16550;
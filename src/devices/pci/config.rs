@@ -0,0 +1,330 @@
+//! The configuration space of a PCI function.
+
+use alloc::vec::Vec;
+
+use super::{
+    PCI_BAR0, PCI_CAPABILITY_LIST, PCI_CAP_END, PCI_CAP_START, PCI_COMMAND, PCI_COMMAND_BUS_MASTER,
+    PCI_COMMAND_INTX_DISABLE, PCI_COMMAND_IO, PCI_COMMAND_MEMORY, PCI_CONFIG_SPACE_SIZE,
+    PCI_DEVICE_ID, PCI_INTERRUPT_LINE, PCI_INTERRUPT_PIN, PCI_NUM_BARS, PCI_REVISION_ID,
+    PCI_STATUS, PCI_STATUS_CAP_LIST, PCI_STATUS_INTERRUPT, PCI_SUBSYSTEM_ID,
+    PCI_SUBSYSTEM_VENDOR_ID, PCI_VENDOR_ID,
+};
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+// Low bits of a memory BAR.
+const PCI_BAR_MEM_64: u32 = 0b10 << 1;
+const PCI_BAR_MEM_PREFETCH: u32 = 1 << 3;
+const PCI_BAR_FLAGS_MASK: u32 = 0xf;
+
+const COMMAND_WRITABLE: u16 =
+    PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_BUS_MASTER | PCI_COMMAND_INTX_DISABLE;
+
+/// A memory BAR of a function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciBar {
+    /// Size in bytes, a power of two of at least 16.
+    pub size: u64,
+    /// The BAR takes two slots and can be placed above 4 GiB.
+    pub is_64bit: bool,
+    /// Reads have no side effects, so the guest may map the BAR cacheable.
+    pub prefetchable: bool,
+}
+
+/// The 4 KiB configuration space of a PCI Express function with a type 0 header.
+///
+/// Every bit is read-only to the guest unless it was made writable, either by the setters below
+/// or by [`set_writable`](Self::set_writable). Writing all ones to a BAR thus reads back its size
+/// mask, as the guest expects when sizing it.
+pub struct PciConfig {
+    data: Vec<u8>,
+    writable: Vec<u8>,
+    bars: [Option<PciBar>; PCI_NUM_BARS],
+    next_cap: usize,
+    last_cap: Option<usize>,
+}
+
+impl PciConfig {
+    /// Creates the configuration space of a function with the given IDs, 24-bit class code and
+    /// revision. It has no BARs, capabilities or interrupt pin.
+    pub fn new(vendor_id: u16, device_id: u16, class_code: u32, revision: u8) -> Self {
+        let mut config = Self {
+            data: vec![0; PCI_CONFIG_SPACE_SIZE],
+            writable: vec![0; PCI_CONFIG_SPACE_SIZE],
+            bars: [None; PCI_NUM_BARS],
+            next_cap: PCI_CAP_START,
+            last_cap: None,
+        };
+        config.set(PCI_VENDOR_ID, 2, vendor_id as u32);
+        config.set(PCI_DEVICE_ID, 2, device_id as u32);
+        config.set(PCI_REVISION_ID, 4, (class_code << 8) | revision as u32);
+        config.writable_mut(PCI_COMMAND, 2, COMMAND_WRITABLE as u32);
+        config.writable_mut(PCI_INTERRUPT_LINE, 1, 0xff);
+        config
+    }
+
+    /// Sets the subsystem vendor and subsystem IDs.
+    pub fn set_subsystem(&mut self, vendor_id: u16, id: u16) {
+        self.set(PCI_SUBSYSTEM_VENDOR_ID, 2, vendor_id as u32);
+        self.set(PCI_SUBSYSTEM_ID, 2, id as u32);
+    }
+
+    /// Sets the INTx pin the function uses, 1 to 4 for INTA to INTD, or 0 for none.
+    pub fn set_interrupt_pin(&mut self, pin: u8) -> HyperResult<()> {
+        if pin > 4 {
+            return Err(HyperError::InvalidParam);
+        }
+        self.set(PCI_INTERRUPT_PIN, 1, pin as u32);
+        Ok(())
+    }
+
+    /// The INTx pin of the function, 0 if it has none.
+    pub fn interrupt_pin(&self) -> u8 {
+        self.data[PCI_INTERRUPT_PIN]
+    }
+
+    /// Declares memory BAR `index`. A 64-bit BAR also takes slot `index + 1`.
+    pub fn add_bar(&mut self, index: usize, bar: PciBar) -> HyperResult<()> {
+        let slots = if bar.is_64bit { 2 } else { 1 };
+        if index + slots > PCI_NUM_BARS
+            || !bar.size.is_power_of_two()
+            || bar.size < 16
+            || (!bar.is_64bit && bar.size > 1 << 31)
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        if (index..index + slots).any(|i| self.bar_slot_used(i)) {
+            return Err(HyperError::BadState);
+        }
+        let mut flags = 0;
+        if bar.is_64bit {
+            flags |= PCI_BAR_MEM_64;
+        }
+        if bar.prefetchable {
+            flags |= PCI_BAR_MEM_PREFETCH;
+        }
+        let mask = !(bar.size - 1);
+        let offset = PCI_BAR0 + index * 4;
+        self.set(offset, 4, flags);
+        self.writable_mut(offset, 4, mask as u32 & !PCI_BAR_FLAGS_MASK);
+        if bar.is_64bit {
+            self.writable_mut(offset + 4, 4, (mask >> 32) as u32);
+        }
+        self.bars[index] = Some(bar);
+        Ok(())
+    }
+
+    /// The BAR declared at `index`, if any.
+    pub fn bar(&self, index: usize) -> Option<PciBar> {
+        self.bars.get(index).copied().flatten()
+    }
+
+    /// The address the guest placed BAR `index` at, or `None` if the BAR does not exist or was
+    /// not assigned an address yet.
+    pub fn bar_address(&self, index: usize) -> Option<GuestPhysAddr> {
+        let bar = self.bar(index)?;
+        let offset = PCI_BAR0 + index * 4;
+        let mut addr = (self.get(offset, 4) & !PCI_BAR_FLAGS_MASK) as u64;
+        if bar.is_64bit {
+            addr |= (self.get(offset + 4, 4) as u64) << 32;
+        }
+        match addr {
+            0 => None,
+            addr => GuestPhysAddr::try_from(addr).ok(),
+        }
+    }
+
+    /// Appends a capability with ID `id` to the capability list and returns its offset. `body`
+    /// is its content after the ID and next pointer bytes, and is read-only to the guest until
+    /// made writable with [`set_writable`](Self::set_writable).
+    pub fn add_capability(&mut self, id: u8, body: &[u8]) -> HyperResult<usize> {
+        let offset = self.next_cap;
+        let end = offset + 2 + body.len();
+        if end > PCI_CAP_END {
+            return Err(HyperError::NoMemory);
+        }
+        self.data[offset] = id;
+        self.data[offset + 1] = 0;
+        self.data[offset + 2..end].copy_from_slice(body);
+        match self.last_cap {
+            Some(last) => self.data[last + 1] = offset as u8,
+            None => self.data[PCI_CAPABILITY_LIST] = offset as u8,
+        }
+        let status = self.get(PCI_STATUS, 2) as u16 | PCI_STATUS_CAP_LIST;
+        self.set(PCI_STATUS, 2, status as u32);
+        self.last_cap = Some(offset);
+        self.next_cap = (end + 3) & !3;
+        Ok(offset)
+    }
+
+    /// Returns the offset of the first capability with ID `id`.
+    pub fn find_capability(&self, id: u8) -> Option<usize> {
+        let mut offset = self.data[PCI_CAPABILITY_LIST] as usize;
+        while offset != 0 {
+            if self.data[offset] == id {
+                return Some(offset);
+            }
+            offset = self.data[offset + 1] as usize;
+        }
+        None
+    }
+
+    /// Lets the guest change the bits set in `mask`, which applies to the bytes from `offset`.
+    pub fn set_writable(&mut self, offset: usize, mask: &[u8]) -> HyperResult<()> {
+        let end = offset
+            .checked_add(mask.len())
+            .filter(|&end| end <= PCI_CONFIG_SPACE_SIZE)
+            .ok_or(HyperError::OutOfRange)?;
+        self.writable[offset..end].copy_from_slice(mask);
+        Ok(())
+    }
+
+    /// Handles a guest load of `width` bytes at `offset`. Accesses that are misaligned or out
+    /// of range read as all ones.
+    pub fn read(&self, offset: usize, width: usize) -> u32 {
+        if !valid_access(offset, width) {
+            return u32::MAX;
+        }
+        self.get(offset, width)
+    }
+
+    /// Handles a guest store of the low `width` bytes of `val` at `offset`, changing only the
+    /// writable bits.
+    pub fn write(&mut self, offset: usize, width: usize, val: u32) {
+        if !valid_access(offset, width) {
+            return;
+        }
+        for (i, byte) in val.to_le_bytes()[..width].iter().enumerate() {
+            let mask = self.writable[offset + i];
+            let old = &mut self.data[offset + i];
+            *old = (*old & !mask) | (byte & mask);
+        }
+    }
+
+    /// Reads `width` bytes at `offset`.
+    pub fn get(&self, offset: usize, width: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes[..width].copy_from_slice(&self.data[offset..offset + width]);
+        u32::from_le_bytes(bytes)
+    }
+
+    /// Sets `width` bytes at `offset`, regardless of which bits the guest may write.
+    pub fn set(&mut self, offset: usize, width: usize, val: u32) {
+        self.data[offset..offset + width].copy_from_slice(&val.to_le_bytes()[..width]);
+    }
+
+    /// The command register.
+    pub fn command(&self) -> u16 {
+        self.get(PCI_COMMAND, 2) as u16
+    }
+
+    /// Whether the guest enabled decoding of the memory BARs.
+    pub fn memory_enabled(&self) -> bool {
+        self.command() & PCI_COMMAND_MEMORY != 0
+    }
+
+    /// Whether the guest lets the function do DMA and send MSIs.
+    pub fn bus_master_enabled(&self) -> bool {
+        self.command() & PCI_COMMAND_BUS_MASTER != 0
+    }
+
+    /// Whether the guest disabled the INTx pin.
+    pub fn intx_disabled(&self) -> bool {
+        self.command() & PCI_COMMAND_INTX_DISABLE != 0
+    }
+
+    /// Reflects the level of the INTx pin in the status register.
+    pub fn set_interrupt_status(&mut self, level: bool) {
+        let mut status = self.get(PCI_STATUS, 2) as u16 & !PCI_STATUS_INTERRUPT;
+        if level {
+            status |= PCI_STATUS_INTERRUPT;
+        }
+        self.set(PCI_STATUS, 2, status as u32);
+    }
+
+    /// Appends the content of the configuration space to a snapshot.
+    pub fn save_state(&self, out: &mut Encoder) {
+        out.put_bytes(&self.data);
+    }
+
+    /// Loads the content written by `save_state`. The layout of BARs and capabilities comes from
+    /// the function and must be the same as when the state was saved.
+    pub fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        let data = input.get_bytes()?;
+        if data.len() != PCI_CONFIG_SPACE_SIZE {
+            return Err(HyperError::DecodeError);
+        }
+        self.data.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+// Private methods implementation
+impl PciConfig {
+    fn writable_mut(&mut self, offset: usize, width: usize, mask: u32) {
+        self.writable[offset..offset + width].copy_from_slice(&mask.to_le_bytes()[..width]);
+    }
+
+    fn bar_slot_used(&self, index: usize) -> bool {
+        self.bars[index].is_some()
+            || (index > 0 && self.bar(index - 1).map_or(false, |b| b.is_64bit))
+    }
+}
+
+fn valid_access(offset: usize, width: usize) -> bool {
+    matches!(width, 1 | 2 | 4) && offset % width == 0 && offset + width <= PCI_CONFIG_SPACE_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{PCI_CAP_ID_MSIX, PCI_CAP_ID_VNDR};
+    use super::*;
+
+    #[test]
+    fn bars_are_sized_by_writing_all_ones() {
+        let mut config = PciConfig::new(0x1af4, 0x1041, 0x02_0000, 1);
+        let bar = PciBar {
+            size: 0x4000,
+            is_64bit: true,
+            prefetchable: true,
+        };
+        config.add_bar(0, bar).unwrap();
+        assert_eq!(config.add_bar(1, bar), Err(HyperError::BadState));
+
+        config.write(PCI_BAR0, 4, u32::MAX);
+        config.write(PCI_BAR0 + 4, 4, u32::MAX);
+        assert_eq!(config.read(PCI_BAR0, 4), 0xffff_c00c);
+        assert_eq!(config.read(PCI_BAR0 + 4, 4), u32::MAX);
+
+        config.write(PCI_BAR0, 4, 0x1234_8000);
+        config.write(PCI_BAR0 + 4, 4, 0x1);
+        assert_eq!(config.bar_address(0), Some(0x1_1234_8000));
+        // Read-only registers keep their value.
+        config.write(PCI_VENDOR_ID, 4, 0);
+        assert_eq!(config.read(PCI_VENDOR_ID, 4), 0x1041_1af4);
+        assert_eq!(config.read(PCI_REVISION_ID, 4), 0x0200_0001);
+    }
+
+    #[test]
+    fn capabilities_are_chained() {
+        let mut config = PciConfig::new(0x1af4, 0x1041, 0x02_0000, 1);
+        assert_eq!(config.read(PCI_STATUS, 2) as u16 & PCI_STATUS_CAP_LIST, 0);
+        let first = config.add_capability(PCI_CAP_ID_VNDR, &[3, 0, 0]).unwrap();
+        let second = config.add_capability(PCI_CAP_ID_MSIX, &[0; 10]).unwrap();
+        assert_eq!((first, second), (0x40, 0x48));
+        assert_ne!(config.read(PCI_STATUS, 2) as u16 & PCI_STATUS_CAP_LIST, 0);
+
+        let mut walked = Vec::new();
+        let mut offset = config.read(PCI_CAPABILITY_LIST, 1) as usize;
+        while offset != 0 {
+            walked.push((offset, config.read(offset, 1) as u8));
+            offset = config.read(offset + 1, 1) as usize;
+        }
+        assert_eq!(walked, [(0x40, PCI_CAP_ID_VNDR), (0x48, PCI_CAP_ID_MSIX)]);
+        assert_eq!(config.find_capability(PCI_CAP_ID_MSIX), Some(0x48));
+
+        config.set_writable(second + 3, &[0xc0]).unwrap();
+        config.write(second + 2, 2, 0xffff);
+        assert_eq!(config.read(second + 2, 2), 0xc000);
+    }
+}
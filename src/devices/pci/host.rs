@@ -0,0 +1,703 @@
+//! An ECAM PCI Express host bridge.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use spin::Mutex;
+
+use super::{
+    PciAddress, PciConfig, PciDevice, PCI_CAP_START, PCI_CLASS_BRIDGE_HOST, PCI_ECAM_BUS_SIZE,
+    PCI_HEADER_TYPE, PCI_HEADER_TYPE_MULTI_FUNCTION, PCI_NUM_BARS,
+};
+use crate::devices::{MmioDevice, MsiMessage, PortIoDevice};
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestMemoryAccess, GuestPhysAddr, HyperError, HyperResult};
+
+// IDs of the host bridge function, the ones QEMU uses for its generic host bridge so that guests
+// treat it the same way.
+const PCI_VENDOR_ID_REDHAT: u16 = 0x1b36;
+const PCI_DEVICE_ID_REDHAT_PCIE_HOST: u16 = 0x0008;
+
+const PCI_CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+const PCI_CONFIG_DATA_PORT: u16 = 0xcfc;
+const PCI_CONFIG_ENABLE: u32 = 1 << 31;
+
+/// A PCI Express host bridge whose configuration space is reached through ECAM.
+///
+/// The bridge is a handle: the VMM keeps one to plug functions in, and registers the devices it
+/// hands out on the VM's buses. [`ecam`](Self::ecam) serves the configuration space and drives
+/// the INTx lines, [`mmio_window`](Self::mmio_window) claims the range the guest places memory
+/// BARs in and routes each access to the function whose BAR covers it, and, for x86 guests,
+/// [`config_ports`](Self::config_ports) serves the configuration space through 0xcf8/0xcfc.
+///
+/// A host bridge function sits at 00:00.0. INTx pins are swizzled onto four interrupt lines by
+/// device number, as in the `interrupt-map` of the generic host bridge binding: pin `p` (1 for
+/// INTA) of device `d` drives line `(d + p - 1) % 4`.
+#[derive(Clone)]
+pub struct PciHostBridge {
+    bus: Arc<Mutex<PciBus>>,
+}
+
+impl PciHostBridge {
+    /// Creates a bridge with `bus_count` buses whose ECAM window starts at `ecam_base`, placing
+    /// memory BARs in `mmio_window`, and with its INTx lines wired to `intx_irqs`.
+    pub fn new(
+        ecam_base: GuestPhysAddr,
+        bus_count: usize,
+        mmio_window: Range<GuestPhysAddr>,
+        intx_irqs: [usize; 4],
+    ) -> HyperResult<Self> {
+        if bus_count == 0 || bus_count > 256 || ecam_base % PCI_ECAM_BUS_SIZE != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let ecam_end = ecam_base
+            .checked_add(bus_count * PCI_ECAM_BUS_SIZE)
+            .ok_or(HyperError::InvalidParam)?;
+        if mmio_window.is_empty() || (ecam_base < mmio_window.end && mmio_window.start < ecam_end) {
+            return Err(HyperError::InvalidParam);
+        }
+        let mut bus = PciBus {
+            ecam: ecam_base..ecam_end,
+            window: mmio_window,
+            intx_irqs,
+            slots: Vec::new(),
+            mappings: Vec::new(),
+        };
+        let host = Arc::new(Mutex::new(HostBridgeFunction::new()));
+        bus.add_device(PciAddress::new(0, 0, 0)?, host)?;
+        Ok(Self {
+            bus: Arc::new(Mutex::new(bus)),
+        })
+    }
+
+    /// Plugs `device` into slot `addr`. Functions other than 0 are only found by the guest if
+    /// function 0 of the same device is present.
+    pub fn add_device(
+        &self,
+        addr: PciAddress,
+        device: Arc<Mutex<dyn PciDevice>>,
+    ) -> HyperResult<()> {
+        self.bus.lock().add_device(addr, device)
+    }
+
+    /// Unplugs the function at `addr`.
+    pub fn remove_device(&self, addr: PciAddress) -> Option<Arc<Mutex<dyn PciDevice>>> {
+        let mut bus = self.bus.lock();
+        let index = bus.find(addr)?;
+        let slot = bus.slots.remove(index);
+        bus.remap();
+        Some(slot.device)
+    }
+
+    /// The ECAM window, for the guest's device tree or MCFG table.
+    pub fn ecam_range(&self) -> Range<GuestPhysAddr> {
+        self.bus.lock().ecam.clone()
+    }
+
+    /// The range memory BARs are placed in.
+    pub fn mmio_window_range(&self) -> Range<GuestPhysAddr> {
+        self.bus.lock().window.clone()
+    }
+
+    /// The device serving the configuration space, to register on the MMIO bus.
+    pub fn ecam(&self) -> PciEcam {
+        PciEcam {
+            bus: self.bus.clone(),
+        }
+    }
+
+    /// The device serving the memory BARs, to register on the MMIO bus.
+    pub fn mmio_window(&self) -> PciMmioWindow {
+        PciMmioWindow {
+            bus: self.bus.clone(),
+        }
+    }
+
+    /// The device serving the configuration space through the x86 configuration ports, to
+    /// register on the port I/O bus.
+    pub fn config_ports(&self) -> PciConfigPorts {
+        PciConfigPorts {
+            bus: self.bus.clone(),
+            address: 0,
+        }
+    }
+}
+
+/// The ECAM window of a [`PciHostBridge`]. It also carries the state of the bridge's functions
+/// in snapshots.
+pub struct PciEcam {
+    bus: Arc<Mutex<PciBus>>,
+}
+
+impl MmioDevice for PciEcam {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        self.bus.lock().ecam.clone()
+    }
+
+    fn mmio_read(&mut self, offset: usize, width: usize) -> HyperResult<u64> {
+        let (addr, reg) = decode_ecam_offset(offset);
+        let bus = self.bus.lock();
+        match width {
+            8 => {
+                let low = bus.config_read(addr, reg, 4) as u64;
+                Ok(low | (bus.config_read(addr, reg + 4, 4) as u64) << 32)
+            }
+            1 | 2 | 4 => Ok(bus.config_read(addr, reg, width) as u64),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, width: usize, val: u64) -> HyperResult<()> {
+        let (addr, reg) = decode_ecam_offset(offset);
+        let mut bus = self.bus.lock();
+        match width {
+            8 => {
+                bus.config_write(addr, reg, 4, val as u32);
+                bus.config_write(addr, reg + 4, 4, (val >> 32) as u32);
+            }
+            1 | 2 | 4 => bus.config_write(addr, reg, width, val as u32),
+            _ => return Err(HyperError::InvalidParam),
+        }
+        Ok(())
+    }
+
+    fn for_each_irq(&self, f: &mut dyn FnMut(usize, bool)) {
+        let bus = self.bus.lock();
+        for (irq, level) in bus.intx_irqs.iter().zip(bus.intx_levels()) {
+            f(*irq, level);
+        }
+    }
+
+    fn take_msis(&mut self, f: &mut dyn FnMut(MsiMessage)) {
+        let bus = self.bus.lock();
+        for slot in &bus.slots {
+            let mut device = slot.device.lock();
+            // A function can only write MSIs to memory while it may master the bus.
+            let enabled = device.config().bus_master_enabled();
+            device.take_msis(&mut |msi| {
+                if enabled {
+                    f(msi);
+                }
+            });
+        }
+    }
+
+    fn has_pending_work(&self) -> bool {
+        self.bus
+            .lock()
+            .slots
+            .iter()
+            .any(|s| s.device.lock().has_pending_work())
+    }
+
+    fn poll(&mut self, mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        let bus = self.bus.lock();
+        for slot in &bus.slots {
+            let mut device = slot.device.lock();
+            if device.has_pending_work() {
+                device.poll(mem)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, out: &mut Encoder) {
+        let bus = self.bus.lock();
+        out.put_u32(bus.slots.len() as u32);
+        for slot in &bus.slots {
+            let device = slot.device.lock();
+            out.put_u8(slot.addr.bus);
+            out.put_u8(slot.addr.device);
+            out.put_u8(slot.addr.function);
+            device.config().save_state(out);
+            let mut state = Encoder::new();
+            device.save_state(&mut state);
+            out.put_bytes(state.as_bytes());
+        }
+    }
+
+    fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        let mut bus = self.bus.lock();
+        if input.get_u32()? as usize != bus.slots.len() {
+            return Err(HyperError::DecodeError);
+        }
+        for slot in &bus.slots {
+            let addr = PciAddress::new(input.get_u8()?, input.get_u8()?, input.get_u8()?)?;
+            if addr != slot.addr {
+                return Err(HyperError::DecodeError);
+            }
+            let mut device = slot.device.lock();
+            device.config_mut().restore_state(input)?;
+            device.restore_state(&mut Decoder::new(input.get_bytes()?))?;
+        }
+        bus.remap();
+        Ok(())
+    }
+}
+
+/// The range memory BARs of a [`PciHostBridge`] are placed in. Accesses outside an enabled BAR
+/// read as all ones and writes to them are dropped.
+pub struct PciMmioWindow {
+    bus: Arc<Mutex<PciBus>>,
+}
+
+impl MmioDevice for PciMmioWindow {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        self.bus.lock().window.clone()
+    }
+
+    fn mmio_read(&mut self, offset: usize, width: usize) -> HyperResult<u64> {
+        if !matches!(width, 1 | 2 | 4 | 8) {
+            return Err(HyperError::InvalidParam);
+        }
+        let bus = self.bus.lock();
+        match bus.bar_at(bus.window.start + offset) {
+            Some((slot, bar, offset)) => slot.device.lock().bar_read(bar, offset, width),
+            None => Ok(u64::MAX >> (64 - width * 8)),
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, width: usize, val: u64) -> HyperResult<()> {
+        if !matches!(width, 1 | 2 | 4 | 8) {
+            return Err(HyperError::InvalidParam);
+        }
+        let bus = self.bus.lock();
+        match bus.bar_at(bus.window.start + offset) {
+            Some((slot, bar, offset)) => slot.device.lock().bar_write(bar, offset, width, val),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The legacy x86 configuration mechanism of a [`PciHostBridge`]: the address of a register is
+/// written to port 0xcf8 and the register is accessed through ports 0xcfc to 0xcff.
+pub struct PciConfigPorts {
+    bus: Arc<Mutex<PciBus>>,
+    address: u32,
+}
+
+impl PortIoDevice for PciConfigPorts {
+    fn port_range(&self) -> Range<u16> {
+        PCI_CONFIG_ADDRESS_PORT..PCI_CONFIG_DATA_PORT + 4
+    }
+
+    fn port_read(&mut self, port: u16, width: usize) -> HyperResult<u32> {
+        match self.data_register(port) {
+            Some((addr, reg)) => Ok(self.bus.lock().config_read(addr, reg, width)),
+            None if port == PCI_CONFIG_ADDRESS_PORT && width == 4 => Ok(self.address),
+            None => Ok(u32::MAX >> (32 - width * 8)),
+        }
+    }
+
+    fn port_write(&mut self, port: u16, width: usize, val: u32) -> HyperResult<()> {
+        match self.data_register(port) {
+            Some((addr, reg)) => self.bus.lock().config_write(addr, reg, width, val),
+            None if port == PCI_CONFIG_ADDRESS_PORT && width == 4 => self.address = val,
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, out: &mut Encoder) {
+        out.put_u32(self.address);
+    }
+
+    fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        self.address = input.get_u32()?;
+        Ok(())
+    }
+}
+
+// Private methods implementation
+impl PciConfigPorts {
+    /// The function and register accessed through data port `port`, if the guest enabled the
+    /// access in the address register.
+    fn data_register(&self, port: u16) -> Option<(PciAddress, usize)> {
+        if port < PCI_CONFIG_DATA_PORT || self.address & PCI_CONFIG_ENABLE == 0 {
+            return None;
+        }
+        let addr = PciAddress {
+            bus: (self.address >> 16) as u8,
+            device: ((self.address >> 11) & 0x1f) as u8,
+            function: ((self.address >> 8) & 0x7) as u8,
+        };
+        let reg = (self.address & 0xfc) as usize + (port - PCI_CONFIG_DATA_PORT) as usize;
+        Some((addr, reg))
+    }
+}
+
+struct Slot {
+    addr: PciAddress,
+    device: Arc<Mutex<dyn PciDevice>>,
+}
+
+// An enabled memory BAR.
+struct BarMapping {
+    range: Range<GuestPhysAddr>,
+    slot: usize,
+    bar: usize,
+}
+
+struct PciBus {
+    ecam: Range<GuestPhysAddr>,
+    window: Range<GuestPhysAddr>,
+    intx_irqs: [usize; 4],
+    // Sorted by address.
+    slots: Vec<Slot>,
+    mappings: Vec<BarMapping>,
+}
+
+impl PciBus {
+    fn add_device(
+        &mut self,
+        addr: PciAddress,
+        device: Arc<Mutex<dyn PciDevice>>,
+    ) -> HyperResult<()> {
+        if addr.bus as usize >= self.ecam.len() / PCI_ECAM_BUS_SIZE {
+            return Err(HyperError::InvalidParam);
+        }
+        match self.slots.binary_search_by_key(&addr, |s| s.addr) {
+            Ok(_) => Err(HyperError::BadState),
+            Err(index) => {
+                self.slots.insert(index, Slot { addr, device });
+                self.remap();
+                Ok(())
+            }
+        }
+    }
+
+    fn find(&self, addr: PciAddress) -> Option<usize> {
+        self.slots.binary_search_by_key(&addr, |s| s.addr).ok()
+    }
+
+    fn config_read(&self, addr: PciAddress, reg: usize, width: usize) -> u32 {
+        let all_ones = u32::MAX >> (32 - width * 8);
+        let index = match self.find(addr) {
+            Some(index) => index,
+            None => return all_ones,
+        };
+        let mut device = self.slots[index].device.lock();
+        let level = device.intx_level();
+        device.config_mut().set_interrupt_status(level);
+        let mut val = device.config().read(reg, width);
+        // Tell the guest to scan the other functions of the device.
+        if addr.function == 0
+            && (reg..reg + width).contains(&PCI_HEADER_TYPE)
+            && self.multi_function(addr)
+        {
+            val |= (PCI_HEADER_TYPE_MULTI_FUNCTION as u32) << ((PCI_HEADER_TYPE - reg) * 8);
+        }
+        val
+    }
+
+    fn config_write(&mut self, addr: PciAddress, reg: usize, width: usize, val: u32) {
+        let index = match self.find(addr) {
+            Some(index) => index,
+            None => return,
+        };
+        {
+            let mut device = self.slots[index].device.lock();
+            device.config_mut().write(reg, width, val);
+            device.config_written(reg, width);
+        }
+        // The command register or a BAR may have changed.
+        if reg < PCI_CAP_START {
+            self.remap();
+        }
+    }
+
+    fn multi_function(&self, addr: PciAddress) -> bool {
+        self.slots
+            .iter()
+            .any(|s| s.addr.bus == addr.bus && s.addr.device == addr.device && s.addr.function != 0)
+    }
+
+    /// Rebuilds the routing of the memory window from the BARs the guest enabled. BARs outside
+    /// the window or overlapping another are not decoded.
+    fn remap(&mut self) {
+        let mut mappings: Vec<BarMapping> = Vec::new();
+        for (index, slot) in self.slots.iter().enumerate() {
+            let device = slot.device.lock();
+            let config = device.config();
+            if !config.memory_enabled() {
+                continue;
+            }
+            for bar in 0..PCI_NUM_BARS {
+                let (start, size) = match (config.bar_address(bar), config.bar(bar)) {
+                    (Some(start), Some(desc)) => (start, desc.size as usize),
+                    _ => continue,
+                };
+                let range = start..start.saturating_add(size);
+                if range.start < self.window.start || range.end > self.window.end {
+                    warn!(
+                        "pci {}: BAR {} at {:#x} is outside the window",
+                        slot.addr, bar, start
+                    );
+                    continue;
+                }
+                if mappings
+                    .iter()
+                    .any(|m| m.range.start < range.end && range.start < m.range.end)
+                {
+                    warn!(
+                        "pci {}: BAR {} at {:#x} overlaps another BAR",
+                        slot.addr, bar, start
+                    );
+                    continue;
+                }
+                mappings.push(BarMapping {
+                    range,
+                    slot: index,
+                    bar,
+                });
+            }
+        }
+        self.mappings = mappings;
+    }
+
+    /// The function, BAR and offset within it that `gpa` falls in.
+    fn bar_at(&self, gpa: GuestPhysAddr) -> Option<(&Slot, usize, usize)> {
+        let mapping = self.mappings.iter().find(|m| m.range.contains(&gpa))?;
+        let slot = &self.slots[mapping.slot];
+        Some((slot, mapping.bar, gpa - mapping.range.start))
+    }
+
+    fn intx_levels(&self) -> [bool; 4] {
+        let mut levels = [false; 4];
+        for slot in &self.slots {
+            let device = slot.device.lock();
+            let pin = device.config().interrupt_pin() as usize;
+            if pin != 0 && !device.config().intx_disabled() && device.intx_level() {
+                levels[(slot.addr.device as usize + pin - 1) % 4] = true;
+            }
+        }
+        levels
+    }
+}
+
+fn decode_ecam_offset(offset: usize) -> (PciAddress, usize) {
+    let addr = PciAddress {
+        bus: (offset >> 20) as u8,
+        device: ((offset >> 15) & 0x1f) as u8,
+        function: ((offset >> 12) & 0x7) as u8,
+    };
+    (addr, offset & 0xfff)
+}
+
+/// The host bridge function at 00:00.0.
+struct HostBridgeFunction {
+    config: PciConfig,
+}
+
+impl HostBridgeFunction {
+    fn new() -> Self {
+        Self {
+            config: PciConfig::new(
+                PCI_VENDOR_ID_REDHAT,
+                PCI_DEVICE_ID_REDHAT_PCIE_HOST,
+                PCI_CLASS_BRIDGE_HOST,
+                0,
+            ),
+        }
+    }
+}
+
+impl PciDevice for HostBridgeFunction {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn bar_read(&mut self, _bar: usize, _offset: usize, _width: usize) -> HyperResult<u64> {
+        Err(HyperError::NotFound)
+    }
+
+    fn bar_write(
+        &mut self,
+        _bar: usize,
+        _offset: usize,
+        _width: usize,
+        _val: u64,
+    ) -> HyperResult<()> {
+        Err(HyperError::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+
+    const ECAM_BASE: GuestPhysAddr = 0x3000_0000;
+    const WINDOW: Range<GuestPhysAddr> = 0x4000_0000..0x5000_0000;
+
+    struct TestFunction {
+        config: PciConfig,
+        regs: [u32; 4],
+        irq: bool,
+    }
+
+    impl TestFunction {
+        fn new() -> Self {
+            let mut config = PciConfig::new(0x1234, 0x5678, 0xff_0000, 0);
+            config
+                .add_bar(
+                    0,
+                    PciBar {
+                        size: 0x1000,
+                        is_64bit: true,
+                        prefetchable: false,
+                    },
+                )
+                .unwrap();
+            config.set_interrupt_pin(1).unwrap();
+            Self {
+                config,
+                regs: [0; 4],
+                irq: false,
+            }
+        }
+    }
+
+    impl PciDevice for TestFunction {
+        fn config(&self) -> &PciConfig {
+            &self.config
+        }
+
+        fn config_mut(&mut self) -> &mut PciConfig {
+            &mut self.config
+        }
+
+        fn bar_read(&mut self, _bar: usize, offset: usize, _width: usize) -> HyperResult<u64> {
+            Ok(self.regs.get(offset / 4).copied().unwrap_or(0) as u64)
+        }
+
+        fn bar_write(
+            &mut self,
+            _bar: usize,
+            offset: usize,
+            _width: usize,
+            val: u64,
+        ) -> HyperResult<()> {
+            if let Some(reg) = self.regs.get_mut(offset / 4) {
+                *reg = val as u32;
+            }
+            Ok(())
+        }
+
+        fn intx_level(&self) -> bool {
+            self.irq
+        }
+    }
+
+    fn ecam_offset(device: usize, function: usize, reg: usize) -> usize {
+        (device << 15) | (function << 12) | reg
+    }
+
+    fn setup() -> (
+        PciHostBridge,
+        Arc<Mutex<TestFunction>>,
+        PciEcam,
+        PciMmioWindow,
+    ) {
+        let bridge = PciHostBridge::new(ECAM_BASE, 1, WINDOW, [32, 33, 34, 35]).unwrap();
+        let function = Arc::new(Mutex::new(TestFunction::new()));
+        bridge
+            .add_device(PciAddress::new(0, 2, 0).unwrap(), function.clone())
+            .unwrap();
+        let (ecam, window) = (bridge.ecam(), bridge.mmio_window());
+        (bridge, function, ecam, window)
+    }
+
+    #[test]
+    fn functions_are_enumerated_through_ecam() {
+        let (bridge, _, mut ecam, _) = setup();
+        assert_eq!(ecam.mmio_range(), ECAM_BASE..ECAM_BASE + PCI_ECAM_BUS_SIZE);
+        assert_eq!(
+            ecam.mmio_read(ecam_offset(0, 0, PCI_VENDOR_ID), 4),
+            Ok(0x0008_1b36)
+        );
+        assert_eq!(
+            ecam.mmio_read(ecam_offset(0, 0, PCI_REVISION_ID), 4),
+            Ok(0x0600_0000)
+        );
+        assert_eq!(
+            ecam.mmio_read(ecam_offset(2, 0, PCI_VENDOR_ID), 4),
+            Ok(0x5678_1234)
+        );
+        // Empty slots read as all ones.
+        assert_eq!(
+            ecam.mmio_read(ecam_offset(1, 0, PCI_VENDOR_ID), 2),
+            Ok(0xffff)
+        );
+        assert_eq!(
+            ecam.mmio_read(ecam_offset(2, 1, PCI_VENDOR_ID), 4),
+            Ok(0xffff_ffff)
+        );
+        assert_eq!(ecam.mmio_read(ecam_offset(2, 0, PCI_HEADER_TYPE), 1), Ok(0));
+
+        let second = Arc::new(Mutex::new(TestFunction::new()));
+        bridge
+            .add_device(PciAddress::new(0, 2, 1).unwrap(), second)
+            .unwrap();
+        assert_eq!(
+            ecam.mmio_read(ecam_offset(2, 0, PCI_HEADER_TYPE), 1),
+            Ok(PCI_HEADER_TYPE_MULTI_FUNCTION as u64)
+        );
+
+        let mut ports = bridge.config_ports();
+        ports.port_write(0xcf8, 4, 0x8000_1000).unwrap();
+        assert_eq!(ports.port_read(0xcf8, 4), Ok(0x8000_1000));
+        assert_eq!(ports.port_read(0xcfe, 2), Ok(0x5678));
+    }
+
+    #[test]
+    fn bars_are_routed_where_the_guest_places_them() {
+        let (_, function, mut ecam, mut window) = setup();
+        let bar0 = ecam_offset(2, 0, PCI_BAR0);
+        ecam.mmio_write(bar0, 4, 0xffff_ffff).unwrap();
+        ecam.mmio_write(bar0 + 4, 4, 0xffff_ffff).unwrap();
+        assert_eq!(ecam.mmio_read(bar0, 8), Ok(0xffff_ffff_ffff_f004));
+
+        ecam.mmio_write(bar0, 8, 0x4000_1000).unwrap();
+        function.lock().regs[1] = 0xabcd;
+        // Nothing is decoded until memory decoding is enabled.
+        assert_eq!(window.mmio_read(0x1004, 4), Ok(0xffff_ffff));
+        ecam.mmio_write(ecam_offset(2, 0, PCI_COMMAND), 2, PCI_COMMAND_MEMORY as u64)
+            .unwrap();
+        assert_eq!(window.mmio_read(0x1004, 4), Ok(0xabcd));
+
+        // Relocating the BAR moves its handler.
+        ecam.mmio_write(bar0, 4, 0x4800_0000).unwrap();
+        assert_eq!(window.mmio_read(0x1004, 4), Ok(0xffff_ffff));
+        window.mmio_write(0x800_0008, 4, 0x55).unwrap();
+        assert_eq!(function.lock().regs[2], 0x55);
+    }
+
+    #[test]
+    fn intx_pins_are_swizzled_and_maskable() {
+        let (_, function, mut ecam, _) = setup();
+        let levels = |ecam: &PciEcam| {
+            let mut levels = Vec::new();
+            ecam.for_each_irq(&mut |irq, level| levels.push((irq, level)));
+            levels
+        };
+        function.lock().irq = true;
+        // INTA of device 2 is the third line.
+        assert_eq!(
+            levels(&ecam),
+            [(32, false), (33, false), (34, true), (35, false)]
+        );
+        let status = ecam.mmio_read(ecam_offset(2, 0, PCI_STATUS), 2).unwrap() as u16;
+        assert_ne!(status & PCI_STATUS_INTERRUPT, 0);
+
+        let command = PCI_COMMAND_INTX_DISABLE as u64;
+        ecam.mmio_write(ecam_offset(2, 0, PCI_COMMAND), 2, command)
+            .unwrap();
+        assert!(levels(&ecam).iter().all(|(_, level)| !level));
+    }
+}
@@ -0,0 +1,185 @@
+//! PCI Express emulation.
+//!
+//! A [`PciHostBridge`] exposes the configuration space of the functions plugged into it through
+//! an ECAM window (and, for x86 guests, the legacy 0xcf8/0xcfc ports), and routes accesses to the
+//! memory BARs the guest programmed to the function owning them. Guests find the bridge the
+//! standard way: a `pci-host-ecam-generic` device tree node on riscv and arm64, or an MCFG table
+//! on x86.
+//!
+//! A function implements [`PciDevice`]. Its configuration header, BARs and capability list are
+//! kept by a [`PciConfig`], which applies the guest's writes to the bits the function lets it
+//! change, so that BAR sizing and the command register work without help from the function.
+//! Functions signaling MSI-X interrupts keep their vectors in an [`MsixTable`].
+
+mod config;
+mod host;
+mod msix;
+
+pub use config::{PciBar, PciConfig};
+pub use host::{PciConfigPorts, PciEcam, PciHostBridge, PciMmioWindow};
+pub use msix::{MsixTable, MSIX_ENTRY_SIZE};
+
+use core::fmt;
+
+use super::MsiMessage;
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestMemoryAccess, HyperError, HyperResult};
+
+/// Size of the configuration space of a PCI Express function.
+pub const PCI_CONFIG_SPACE_SIZE: usize = 4096;
+/// Size of the ECAM window of one bus.
+pub const PCI_ECAM_BUS_SIZE: usize = 1 << 20;
+/// Number of BARs in a type 0 header.
+pub const PCI_NUM_BARS: usize = 6;
+
+/// Offset of the vendor ID register.
+pub const PCI_VENDOR_ID: usize = 0x00;
+/// Offset of the device ID register.
+pub const PCI_DEVICE_ID: usize = 0x02;
+/// Offset of the command register.
+pub const PCI_COMMAND: usize = 0x04;
+/// Offset of the status register.
+pub const PCI_STATUS: usize = 0x06;
+/// Offset of the revision ID register, followed by the 24-bit class code.
+pub const PCI_REVISION_ID: usize = 0x08;
+/// Offset of the header type register.
+pub const PCI_HEADER_TYPE: usize = 0x0e;
+/// Offset of the first BAR.
+pub const PCI_BAR0: usize = 0x10;
+/// Offset of the subsystem vendor ID register.
+pub const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+/// Offset of the subsystem ID register.
+pub const PCI_SUBSYSTEM_ID: usize = 0x2e;
+/// Offset of the pointer to the first capability.
+pub const PCI_CAPABILITY_LIST: usize = 0x34;
+/// Offset of the interrupt line register.
+pub const PCI_INTERRUPT_LINE: usize = 0x3c;
+/// Offset of the interrupt pin register.
+pub const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+/// The function decodes accesses to its I/O BARs.
+pub const PCI_COMMAND_IO: u16 = 1 << 0;
+/// The function decodes accesses to its memory BARs.
+pub const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+/// The function may issue DMA and MSIs.
+pub const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// The function must not assert its INTx pin.
+pub const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// The function asserts its INTx pin, whether or not it is disabled.
+pub const PCI_STATUS_INTERRUPT: u16 = 1 << 3;
+/// The function has a capability list.
+pub const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+
+/// The function is one of several of its device.
+pub const PCI_HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+
+/// Capability ID of MSI.
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+/// Capability ID of a vendor specific capability.
+pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+/// Capability ID of the PCI Express capability.
+pub const PCI_CAP_ID_EXP: u8 = 0x10;
+/// Capability ID of MSI-X.
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+/// Class code of a host bridge.
+pub const PCI_CLASS_BRIDGE_HOST: u32 = 0x06_0000;
+
+// Standard capabilities live between the type 0 header and the extended configuration space.
+const PCI_CAP_START: usize = 0x40;
+const PCI_CAP_END: usize = 0x100;
+
+/// The location of a function: its bus, device and function numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+impl PciAddress {
+    /// Creates the address of `function` of `device` on `bus`.
+    pub fn new(bus: u8, device: u8, function: u8) -> HyperResult<Self> {
+        if device >= 32 || function >= 8 {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(Self {
+            bus,
+            device,
+            function,
+        })
+    }
+
+    /// The bus number.
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    /// The device number, below 32.
+    pub fn device(&self) -> u8 {
+        self.device
+    }
+
+    /// The function number, below 8.
+    pub fn function(&self) -> u8 {
+        self.function
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A PCI function emulated behind a [`PciHostBridge`].
+///
+/// The bridge serves configuration space accesses from [`config`](Self::config) and routes
+/// accesses to the memory BARs the guest enabled to [`bar_read`](Self::bar_read) and
+/// [`bar_write`](Self::bar_write).
+pub trait PciDevice: Send {
+    /// The configuration space of the function.
+    fn config(&self) -> &PciConfig;
+
+    /// The configuration space of the function, for the bridge to apply the guest's writes.
+    fn config_mut(&mut self) -> &mut PciConfig;
+
+    /// Called after the guest wrote `width` bytes at `offset` of the configuration space, e.g. to
+    /// act on a capability being enabled.
+    fn config_written(&mut self, _offset: usize, _width: usize) {}
+
+    /// Handles a guest load of `width` bytes at `offset` within BAR `bar`.
+    fn bar_read(&mut self, bar: usize, offset: usize, width: usize) -> HyperResult<u64>;
+
+    /// Handles a guest store of the low `width` bytes of `val` at `offset` within BAR `bar`.
+    fn bar_write(&mut self, bar: usize, offset: usize, width: usize, val: u64) -> HyperResult<()>;
+
+    /// Whether the function asserts its INTx pin. The bridge masks it while the guest set
+    /// [`PCI_COMMAND_INTX_DISABLE`].
+    fn intx_level(&self) -> bool {
+        false
+    }
+
+    /// Calls `f` with the MSIs the function sent since the last call, oldest first.
+    fn take_msis(&mut self, _f: &mut dyn FnMut(MsiMessage)) {}
+
+    /// Whether the function has work that needs guest memory, see
+    /// [`MmioDevice::has_pending_work`](super::MmioDevice::has_pending_work).
+    fn has_pending_work(&self) -> bool {
+        false
+    }
+
+    /// Does the function's pending work.
+    fn poll(&mut self, _mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        Ok(())
+    }
+
+    /// Appends the function's state, other than its configuration space, to a snapshot.
+    fn save_state(&self, _out: &mut Encoder) {}
+
+    /// Loads the state written by `save_state`. The configuration space is restored first.
+    fn restore_state(&mut self, _input: &mut Decoder) -> HyperResult<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! MSI-X (PCI Express base specification, section 6.1.4).
+
+use alloc::vec::Vec;
+
+use super::{PciConfig, PCI_CAP_ID_MSIX, PCI_NUM_BARS};
+use crate::devices::MsiMessage;
+use crate::snapshot::{Decoder, Encoder};
+use crate::{HyperError, HyperResult};
+
+/// Size of an entry of the vector table.
+pub const MSIX_ENTRY_SIZE: usize = 16;
+
+const MSIX_MAX_VECTORS: usize = 2048;
+
+// Bits of the message control register, in its high byte.
+const MSIX_CONTROL_ENABLE: u32 = 1 << 15;
+const MSIX_CONTROL_FUNCTION_MASK: u32 = 1 << 14;
+
+// Dwords of a table entry.
+const ENTRY_ADDR_LOW: usize = 0;
+const ENTRY_ADDR_HIGH: usize = 1;
+const ENTRY_DATA: usize = 2;
+const ENTRY_VECTOR_CONTROL: usize = 3;
+const VECTOR_CONTROL_MASKED: u32 = 1;
+
+/// The MSI-X vectors of a function: its capability, its vector table and its pending bit array.
+///
+/// The table and the pending bit array live in a BAR of the function, which forwards the
+/// accesses falling in them to [`read_table`](Self::read_table),
+/// [`write_table`](Self::write_table) and [`read_pba`](Self::read_pba). An interrupt signaled
+/// while its vector is masked stays pending until the guest unmasks it.
+pub struct MsixTable {
+    cap: usize,
+    entries: Vec<[u32; 4]>,
+    pending: Vec<u64>,
+}
+
+impl MsixTable {
+    /// Adds an MSI-X capability with `vectors` vectors to `config`. The table is at
+    /// `table_offset` of BAR `bar` and the pending bit array at `pba_offset` of the same BAR. All
+    /// vectors start masked.
+    pub fn new(
+        config: &mut PciConfig,
+        vectors: usize,
+        bar: usize,
+        table_offset: u32,
+        pba_offset: u32,
+    ) -> HyperResult<Self> {
+        if vectors == 0
+            || vectors > MSIX_MAX_VECTORS
+            || bar >= PCI_NUM_BARS
+            || table_offset % 8 != 0
+            || pba_offset % 8 != 0
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        let mut body = [0; 10];
+        body[..2].copy_from_slice(&(vectors as u16 - 1).to_le_bytes());
+        body[2..6].copy_from_slice(&(table_offset | bar as u32).to_le_bytes());
+        body[6..].copy_from_slice(&(pba_offset | bar as u32).to_le_bytes());
+        let cap = config.add_capability(PCI_CAP_ID_MSIX, &body)?;
+        // Only the enable and function mask bits are writable.
+        config.set_writable(cap + 3, &[0xc0])?;
+        Ok(Self {
+            cap,
+            entries: vec![[0, 0, 0, VECTOR_CONTROL_MASKED]; vectors],
+            pending: vec![0; vectors.div_ceil(64)],
+        })
+    }
+
+    /// The number of vectors.
+    pub fn vectors(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Size of the vector table in bytes.
+    pub fn table_size(&self) -> usize {
+        self.entries.len() * MSIX_ENTRY_SIZE
+    }
+
+    /// Size of the pending bit array in bytes.
+    pub fn pba_size(&self) -> usize {
+        self.pending.len() * 8
+    }
+
+    /// Whether the guest enabled MSI-X, in which case the function must not use its INTx pin.
+    pub fn enabled(&self, config: &PciConfig) -> bool {
+        self.control(config) & MSIX_CONTROL_ENABLE != 0
+    }
+
+    /// Handles a guest load of `width` bytes at `offset` of the vector table.
+    pub fn read_table(&self, offset: usize, width: usize) -> u64 {
+        match (width, self.entry_dword(offset, width)) {
+            (4, Some((entry, dword))) => self.entries[entry][dword] as u64,
+            (8, Some((entry, dword))) => {
+                let entry = &self.entries[entry];
+                entry[dword] as u64 | (entry[dword + 1] as u64) << 32
+            }
+            _ => 0,
+        }
+    }
+
+    /// Handles a guest store of the low `width` bytes of `val` at `offset` of the vector table.
+    pub fn write_table(&mut self, offset: usize, width: usize, val: u64) {
+        match (width, self.entry_dword(offset, width)) {
+            (4, Some((entry, dword))) => self.entries[entry][dword] = val as u32,
+            (8, Some((entry, dword))) => {
+                self.entries[entry][dword] = val as u32;
+                self.entries[entry][dword + 1] = (val >> 32) as u32;
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles a guest load of `width` bytes at `offset` of the pending bit array.
+    pub fn read_pba(&self, offset: usize, width: usize) -> u64 {
+        let word = match self.pending.get(offset / 8) {
+            Some(word) if matches!(width, 4 | 8) && offset % width == 0 => *word,
+            _ => return 0,
+        };
+        match width {
+            8 => word,
+            _ => (word >> ((offset % 8) * 8)) as u32 as u64,
+        }
+    }
+
+    /// Signals `vector` and returns the message to send, or `None` if the vector is masked, in
+    /// which case it is left pending. MSI-X must be enabled.
+    pub fn signal(&mut self, config: &PciConfig, vector: usize) -> Option<MsiMessage> {
+        if vector >= self.entries.len() || !self.enabled(config) {
+            return None;
+        }
+        if self.masked(config, vector) {
+            self.pending[vector / 64] |= 1 << (vector % 64);
+            return None;
+        }
+        Some(self.message(vector))
+    }
+
+    /// Calls `f` with the message of every pending vector the guest unmasked since it was
+    /// signaled, and clears their pending bits. To be called after the guest wrote the vector
+    /// table or the message control register.
+    pub fn take_unmasked(&mut self, config: &PciConfig, mut f: impl FnMut(MsiMessage)) {
+        if !self.enabled(config) {
+            return;
+        }
+        for vector in 0..self.entries.len() {
+            let bit = 1 << (vector % 64);
+            if self.pending[vector / 64] & bit != 0 && !self.masked(config, vector) {
+                self.pending[vector / 64] &= !bit;
+                f(self.message(vector));
+            }
+        }
+    }
+
+    /// Appends the vector table and the pending bits to a snapshot. The capability is saved
+    /// with the configuration space.
+    pub fn save_state(&self, out: &mut Encoder) {
+        out.put_u32(self.entries.len() as u32);
+        for entry in &self.entries {
+            out.put_u32s(entry);
+        }
+        out.put_u64s(&self.pending);
+    }
+
+    /// Loads the state written by `save_state`.
+    pub fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        if input.get_u32()? as usize != self.entries.len() {
+            return Err(HyperError::DecodeError);
+        }
+        for entry in self.entries.iter_mut() {
+            input.get_u32s(entry)?;
+        }
+        input.get_u64s(&mut self.pending)
+    }
+}
+
+// Private methods implementation
+impl MsixTable {
+    fn control(&self, config: &PciConfig) -> u32 {
+        config.get(self.cap + 2, 2)
+    }
+
+    fn masked(&self, config: &PciConfig, vector: usize) -> bool {
+        self.control(config) & MSIX_CONTROL_FUNCTION_MASK != 0
+            || self.entries[vector][ENTRY_VECTOR_CONTROL] & VECTOR_CONTROL_MASKED != 0
+    }
+
+    fn message(&self, vector: usize) -> MsiMessage {
+        let entry = &self.entries[vector];
+        MsiMessage {
+            address: entry[ENTRY_ADDR_LOW] as u64 | (entry[ENTRY_ADDR_HIGH] as u64) << 32,
+            data: entry[ENTRY_DATA],
+        }
+    }
+
+    /// The entry and the first dword of an aligned access of 4 or 8 bytes to the table.
+    fn entry_dword(&self, offset: usize, width: usize) -> Option<(usize, usize)> {
+        if !matches!(width, 4 | 8) || offset % width != 0 || offset >= self.table_size() {
+            return None;
+        }
+        Some((offset / MSIX_ENTRY_SIZE, (offset % MSIX_ENTRY_SIZE) / 4))
+    }
+}
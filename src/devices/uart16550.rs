@@ -0,0 +1,503 @@
+//! An emulated 16550A UART.
+//!
+//! The same model is used behind MMIO (riscv and aarch64 guests, at the address and `reg-shift`
+//! given in the guest's device tree) and behind x86 I/O ports (COM1 at 0x3f8). Bytes sent by the
+//! guest collect in a TX buffer and bytes for the guest are queued in an RX buffer, both drained
+//! and filled by the VMM.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use super::{MmioDevice, PortIoDevice};
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+/// I/O port base of the first PC serial port.
+pub const COM1_PORT: u16 = 0x3f8;
+/// Interrupt line of the first PC serial port.
+pub const COM1_IRQ: usize = 4;
+
+/// Bytes kept for the VMM before the oldest output is dropped.
+pub const TX_BUFFER_SIZE: usize = 4096;
+/// Bytes of input that can be queued for the guest.
+pub const RX_BUFFER_SIZE: usize = 4096;
+
+const UART_NUM_REGS: usize = 8;
+
+// Register indices.
+const RBR_THR_DLL: usize = 0;
+const IER_DLM: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const IER_ERBFI: u8 = 1 << 0;
+const IER_ETBEI: u8 = 1 << 1;
+const IER_ELSI: u8 = 1 << 2;
+const IER_EDSSI: u8 = 1 << 3;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_MSI: u8 = 0x00;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_RLSI: u8 = 0x06;
+const IIR_CTI: u8 = 0x0c;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_LOOP: u8 = 1 << 4;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_OE: u8 = 1 << 1;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+// DCD, DSR and CTS asserted: a terminal is always connected.
+const MSR_CONNECTED: u8 = 0xb0;
+const MSR_DELTAS: u8 = 0x0f;
+
+/// Where the registers of a [`Uart16550`] are visible to the guest.
+#[derive(Clone, Copy, Debug)]
+enum UartAttachment {
+    /// Register `i` is at `base + (i << reg_shift)`.
+    Mmio { base: GuestPhysAddr, reg_shift: u32 },
+    /// Register `i` is at port `base + i`.
+    Port { base: u16 },
+}
+
+/// An emulated 16550A UART with a 16-byte FIFO.
+pub struct Uart16550 {
+    attachment: UartAttachment,
+    irq: Option<usize>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    msr: u8,
+    scr: u8,
+    divisor: u16,
+    // Set when the THR became empty, cleared when the guest reads the THRE interrupt in IIR.
+    thr_ipending: bool,
+    tx: VecDeque<u8>,
+    rx: VecDeque<u8>,
+}
+
+impl Uart16550 {
+    /// Creates a UART whose registers are mapped at guest physical address `base`, spaced by
+    /// `1 << reg_shift` bytes. `irq` is the interrupt line it is wired to, if any.
+    pub fn new_mmio(base: GuestPhysAddr, reg_shift: u32, irq: Option<usize>) -> Self {
+        Self::new(UartAttachment::Mmio { base, reg_shift }, irq)
+    }
+
+    /// Creates a UART whose registers are at I/O ports `base..base + 8`. `irq` is the interrupt
+    /// line it is wired to, if any.
+    pub fn new_port(base: u16, irq: Option<usize>) -> Self {
+        Self::new(UartAttachment::Port { base }, irq)
+    }
+
+    /// Queues `data` as input for the guest. Returns how many bytes were accepted, the rest is
+    /// dropped and reported to the guest as an overrun.
+    pub fn push_rx(&mut self, data: &[u8]) -> usize {
+        let accepted = data.len().min(RX_BUFFER_SIZE - self.rx.len());
+        self.rx.extend(&data[..accepted]);
+        if accepted < data.len() {
+            self.lsr |= LSR_OE;
+        }
+        accepted
+    }
+
+    /// Number of input bytes not yet read by the guest.
+    pub fn rx_len(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// Moves the guest's output into `buf`, returning the number of bytes copied.
+    pub fn take_tx(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.tx.len());
+        for (dst, src) in buf.iter_mut().zip(self.tx.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+
+    /// Returns true if the guest wrote bytes the VMM has not taken yet.
+    pub fn has_tx(&self) -> bool {
+        !self.tx.is_empty()
+    }
+
+    /// Returns true if the UART asserts its interrupt line.
+    pub fn irq_pending(&self) -> bool {
+        self.interrupt_id() != IIR_NO_INT
+    }
+
+    /// Handles a guest read of register `reg`.
+    pub fn read_reg(&mut self, reg: usize) -> u8 {
+        match reg {
+            RBR_THR_DLL if self.lcr & LCR_DLAB != 0 => self.divisor as u8,
+            RBR_THR_DLL => {
+                let val = self.rx.pop_front().unwrap_or(0);
+                self.update_lsr();
+                val
+            }
+            IER_DLM if self.lcr & LCR_DLAB != 0 => (self.divisor >> 8) as u8,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                if id == IIR_THRI {
+                    self.thr_ipending = false;
+                }
+                let fifo = if self.fcr & FCR_ENABLE != 0 {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                id | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.update_lsr();
+                let val = self.lsr;
+                self.lsr &= !LSR_OE;
+                val
+            }
+            MSR => {
+                let val = self.msr;
+                self.msr &= !MSR_DELTAS;
+                val
+            }
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    /// Handles a guest write of `val` to register `reg`.
+    pub fn write_reg(&mut self, reg: usize, val: u8) {
+        match reg {
+            RBR_THR_DLL if self.lcr & LCR_DLAB != 0 => {
+                self.divisor = (self.divisor & 0xff00) | val as u16;
+            }
+            RBR_THR_DLL => {
+                if self.mcr & MCR_LOOP != 0 {
+                    self.push_rx(&[val]);
+                } else {
+                    if self.tx.len() == TX_BUFFER_SIZE {
+                        self.tx.pop_front();
+                    }
+                    self.tx.push_back(val);
+                }
+                // Transmission is instantaneous, so the THR is empty again right away.
+                self.thr_ipending = true;
+            }
+            IER_DLM if self.lcr & LCR_DLAB != 0 => {
+                self.divisor = (self.divisor & 0x00ff) | (val as u16) << 8;
+            }
+            IER_DLM => {
+                let old = self.ier;
+                self.ier = val & 0x0f;
+                if old & IER_ETBEI == 0 && self.ier & IER_ETBEI != 0 {
+                    self.thr_ipending = true;
+                }
+            }
+            IIR_FCR => {
+                if val & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+                if val & FCR_CLEAR_TX != 0 {
+                    self.thr_ipending = true;
+                }
+                self.fcr = val & !(FCR_CLEAR_RX | FCR_CLEAR_TX);
+                self.update_lsr();
+            }
+            LCR => self.lcr = val,
+            MCR => {
+                self.mcr = val & 0x1f;
+                self.update_msr();
+            }
+            SCR => self.scr = val,
+            // LSR and MSR are read-only.
+            _ => {}
+        }
+    }
+
+    /// Appends the registers and buffered bytes to a snapshot. Where the UART is attached and
+    /// its interrupt line are configuration, they are not saved.
+    pub fn save_state(&self, out: &mut Encoder) {
+        for reg in [self.ier, self.fcr, self.lcr, self.mcr, self.lsr, self.msr, self.scr] {
+            out.put_u8(reg);
+        }
+        out.put_u16(self.divisor);
+        out.put_bool(self.thr_ipending);
+        for buf in [&self.tx, &self.rx] {
+            out.put_bytes(&buf.iter().copied().collect::<Vec<u8>>());
+        }
+    }
+
+    /// Loads the state written by `save_state`.
+    pub fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        for reg in [
+            &mut self.ier,
+            &mut self.fcr,
+            &mut self.lcr,
+            &mut self.mcr,
+            &mut self.lsr,
+            &mut self.msr,
+            &mut self.scr,
+        ] {
+            *reg = input.get_u8()?;
+        }
+        self.divisor = input.get_u16()?;
+        self.thr_ipending = input.get_bool()?;
+        let tx = input.get_bytes()?;
+        let rx = input.get_bytes()?;
+        if tx.len() > TX_BUFFER_SIZE || rx.len() > RX_BUFFER_SIZE {
+            return Err(HyperError::DecodeError);
+        }
+        self.tx = tx.iter().copied().collect();
+        self.rx = rx.iter().copied().collect();
+        Ok(())
+    }
+}
+
+// Private methods implementation
+impl Uart16550 {
+    fn new(attachment: UartAttachment, irq: Option<usize>) -> Self {
+        Self {
+            attachment,
+            irq,
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr: LSR_THRE | LSR_TEMT,
+            msr: MSR_CONNECTED,
+            scr: 0,
+            divisor: 0,
+            thr_ipending: false,
+            tx: VecDeque::new(),
+            rx: VecDeque::new(),
+        }
+    }
+
+    fn mmio_reg(&self, offset: usize) -> Option<usize> {
+        let reg_shift = match self.attachment {
+            UartAttachment::Mmio { reg_shift, .. } => reg_shift,
+            UartAttachment::Port { .. } => 0,
+        };
+        if offset & ((1 << reg_shift) - 1) != 0 {
+            return None;
+        }
+        Some(offset >> reg_shift)
+    }
+
+    fn update_lsr(&mut self) {
+        if self.rx.is_empty() {
+            self.lsr &= !LSR_DR;
+        } else {
+            self.lsr |= LSR_DR;
+        }
+    }
+
+    fn update_msr(&mut self) {
+        let old = self.msr;
+        self.msr = if self.mcr & MCR_LOOP != 0 {
+            // DTR -> DSR, RTS -> CTS, OUT1 -> RI, OUT2 -> DCD.
+            let mcr = self.mcr;
+            (mcr & 0x01) << 5 | (mcr & 0x02) << 3 | (mcr & 0x0c) << 4
+        } else {
+            MSR_CONNECTED
+        };
+        if (old ^ self.msr) & 0xf0 != 0 {
+            self.msr |= ((old ^ self.msr) >> 4) & MSR_DELTAS;
+        }
+    }
+
+    // The FIFO trigger level in bytes, as selected by FCR[7:6].
+    fn rx_trigger_level(&self) -> usize {
+        if self.fcr & FCR_ENABLE == 0 {
+            return 1;
+        }
+        [1, 4, 8, 14][(self.fcr >> 6) as usize]
+    }
+
+    /// The highest priority pending interrupt, as reported in IIR[3:0].
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_ELSI != 0 && self.lsr & LSR_OE != 0 {
+            IIR_RLSI
+        } else if self.ier & IER_ERBFI != 0 && !self.rx.is_empty() {
+            // Input below the trigger level is reported right away as a character timeout.
+            if self.rx.len() >= self.rx_trigger_level() {
+                IIR_RDI
+            } else {
+                IIR_CTI
+            }
+        } else if self.ier & IER_ETBEI != 0 && self.thr_ipending {
+            IIR_THRI
+        } else if self.ier & IER_EDSSI != 0 && self.msr & MSR_DELTAS != 0 {
+            IIR_MSI
+        } else {
+            IIR_NO_INT
+        }
+    }
+}
+
+impl MmioDevice for Uart16550 {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        match self.attachment {
+            UartAttachment::Mmio { base, reg_shift } => base..base + (UART_NUM_REGS << reg_shift),
+            UartAttachment::Port { .. } => 0..0,
+        }
+    }
+
+    fn mmio_read(&mut self, offset: usize, _width: usize) -> HyperResult<u64> {
+        // Accesses to the unused bytes of a widely spaced register read as zero.
+        match self.mmio_reg(offset) {
+            Some(reg) => Ok(self.read_reg(reg) as u64),
+            None => Ok(0),
+        }
+    }
+
+    fn mmio_write(&mut self, offset: usize, _width: usize, val: u64) -> HyperResult<()> {
+        if let Some(reg) = self.mmio_reg(offset) {
+            self.write_reg(reg, val as u8);
+        }
+        Ok(())
+    }
+
+    fn irq_level(&self) -> Option<(usize, bool)> {
+        self.irq.map(|irq| (irq, self.irq_pending()))
+    }
+
+    fn save_state(&self, out: &mut Encoder) {
+        Uart16550::save_state(self, out)
+    }
+
+    fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        Uart16550::restore_state(self, input)
+    }
+}
+
+impl PortIoDevice for Uart16550 {
+    fn port_range(&self) -> Range<u16> {
+        match self.attachment {
+            UartAttachment::Port { base } => base..base + UART_NUM_REGS as u16,
+            UartAttachment::Mmio { .. } => 0..0,
+        }
+    }
+
+    fn port_read(&mut self, port: u16, _width: usize) -> HyperResult<u32> {
+        let base = self.port_range().start;
+        Ok(self.read_reg((port - base) as usize) as u32)
+    }
+
+    fn port_write(&mut self, port: u16, _width: usize, val: u32) -> HyperResult<()> {
+        let base = self.port_range().start;
+        self.write_reg((port - base) as usize, val as u8);
+        Ok(())
+    }
+
+    fn irq_level(&self) -> Option<(usize, bool)> {
+        self.irq.map(|irq| (irq, self.irq_pending()))
+    }
+
+    fn save_state(&self, out: &mut Encoder) {
+        Uart16550::save_state(self, out)
+    }
+
+    fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        Uart16550::restore_state(self, input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_status_tracks_input_and_overruns() {
+        let mut uart = Uart16550::new_port(COM1_PORT, Some(COM1_IRQ));
+        assert_eq!(uart.read_reg(LSR), LSR_THRE | LSR_TEMT);
+        assert_eq!(uart.push_rx(b"hi"), 2);
+        assert_eq!(uart.read_reg(LSR) & LSR_DR, LSR_DR);
+        assert_eq!(uart.read_reg(RBR_THR_DLL), b'h');
+        assert_eq!(uart.read_reg(RBR_THR_DLL), b'i');
+        assert_eq!(uart.read_reg(LSR) & LSR_DR, 0);
+
+        let input = [0; RX_BUFFER_SIZE + 1];
+        assert_eq!(uart.push_rx(&input), RX_BUFFER_SIZE);
+        assert_eq!(uart.read_reg(LSR) & LSR_OE, LSR_OE);
+        // Reading LSR clears the overrun.
+        assert_eq!(uart.read_reg(LSR) & LSR_OE, 0);
+    }
+
+    #[test]
+    fn interrupts_are_reported_by_priority() {
+        let mut uart = Uart16550::new_port(COM1_PORT, Some(COM1_IRQ));
+        assert_eq!(uart.read_reg(IIR_FCR), IIR_NO_INT);
+        uart.write_reg(IER_DLM, IER_ERBFI | IER_ETBEI | IER_ELSI);
+        assert_eq!(PortIoDevice::irq_level(&uart), Some((COM1_IRQ, true)));
+        uart.push_rx(b"x");
+        assert_eq!(uart.read_reg(IIR_FCR), IIR_RDI);
+
+        uart.push_rx(&[0; RX_BUFFER_SIZE]);
+        assert_eq!(uart.read_reg(IIR_FCR), IIR_RLSI);
+        uart.read_reg(LSR);
+        assert_eq!(uart.read_reg(IIR_FCR), IIR_RDI);
+        uart.write_reg(IIR_FCR, FCR_CLEAR_RX);
+
+        // The THR empty interrupt is cleared by reading it in IIR.
+        assert_eq!(uart.read_reg(IIR_FCR), IIR_THRI);
+        assert_eq!(uart.read_reg(IIR_FCR), IIR_NO_INT);
+        assert!(!uart.irq_pending());
+        uart.write_reg(RBR_THR_DLL, b'a');
+        assert_eq!(uart.read_reg(IIR_FCR), IIR_THRI);
+        let mut buf = [0; 4];
+        assert_eq!(uart.take_tx(&mut buf), 1);
+        assert_eq!(buf[0], b'a');
+    }
+
+    #[test]
+    fn fifo_trigger_level_selects_data_or_timeout() {
+        let mut uart = Uart16550::new_mmio(0x1000_0000, 2, Some(10));
+        // Enable the FIFO with a trigger level of 4 bytes.
+        uart.mmio_write(IIR_FCR << 2, 1, (FCR_ENABLE | 1 << 6) as u64).unwrap();
+        uart.mmio_write(IER_DLM << 2, 1, IER_ERBFI as u64).unwrap();
+        uart.push_rx(b"abc");
+        assert_eq!(uart.mmio_read(IIR_FCR << 2, 1).unwrap() as u8, IIR_CTI | IIR_FIFO_ENABLED);
+        uart.push_rx(b"d");
+        assert_eq!(uart.mmio_read(IIR_FCR << 2, 1).unwrap() as u8, IIR_RDI | IIR_FIFO_ENABLED);
+        assert_eq!(uart.mmio_read(RBR_THR_DLL << 2, 1).unwrap() as u8, b'a');
+        assert_eq!(uart.mmio_read(IIR_FCR << 2, 1).unwrap() as u8, IIR_CTI | IIR_FIFO_ENABLED);
+        // Unaligned accesses to a widely spaced register are ignored.
+        assert_eq!(uart.mmio_read((RBR_THR_DLL << 2) + 1, 1).unwrap(), 0);
+        assert_eq!(uart.rx_len(), 3);
+    }
+
+    #[test]
+    fn divisor_latch_and_loopback() {
+        let mut uart = Uart16550::new_port(COM1_PORT, None);
+        uart.write_reg(LCR, LCR_DLAB);
+        uart.write_reg(RBR_THR_DLL, 0x0c);
+        uart.write_reg(IER_DLM, 0x01);
+        assert_eq!(uart.read_reg(RBR_THR_DLL), 0x0c);
+        assert_eq!(uart.read_reg(IER_DLM), 0x01);
+        uart.write_reg(LCR, 0x03);
+        assert_eq!(uart.read_reg(IER_DLM), 0);
+
+        uart.write_reg(MCR, MCR_LOOP);
+        uart.write_reg(RBR_THR_DLL, b'z');
+        assert!(!uart.has_tx());
+        assert_eq!(uart.read_reg(RBR_THR_DLL), b'z');
+        assert_eq!(PortIoDevice::irq_level(&uart), None);
+    }
+}
@@ -0,0 +1,352 @@
+//! A virtio memory balloon (virtio 1.2, section 5.5) for taking RAM back from guests.
+//!
+//! The VMM sets the number of pages it wants the guest to give up with
+//! [`VirtioBalloon::set_target`]. The driver inflates the balloon by allocating pages and
+//! reporting them, which the device discards through [`GuestMemoryAccess::discard_page`], and
+//! deflates it again by reporting pages it wants back, which get fresh frames. With free page
+//! reporting, the driver also reports ranges of RAM it has no use for at the moment; those are
+//! discarded as well, but the guest reuses them without asking.
+
+use alloc::collections::BTreeSet;
+
+use super::{read_config_bytes, VirtQueue, VirtioDevice, VIRTIO_ID_BALLOON};
+use crate::memory::PAGE_SIZE_4K;
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestMemoryAccess, GuestPhysAddr, HyperError, HyperResult};
+
+const QUEUE_SIZE: u16 = 128;
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+// Follows the deflate queue, as neither the statistics nor the free page hinting queue is
+// offered.
+const REPORTING_QUEUE: usize = 2;
+
+/// The driver reports pages before it reuses them after deflating.
+const VIRTIO_BALLOON_F_MUST_TELL_HOST: u64 = 1 << 0;
+/// The driver may deflate on its own when the guest runs out of memory.
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 1 << 2;
+/// The driver reports free pages on the reporting queue.
+const VIRTIO_BALLOON_F_REPORTING: u64 = 1 << 5;
+
+/// Page frame numbers on the inflate and deflate queues are in 4K units, whatever the guest's
+/// page size.
+const VIRTIO_BALLOON_PFN_SHIFT: usize = 12;
+
+const CONFIG_SIZE: usize = 8;
+
+/// A virtio memory balloon.
+pub struct VirtioBalloon {
+    // Pages the VMM asks the guest to give up.
+    target: u32,
+    // Pages the driver says the balloon holds.
+    actual: u32,
+    // Pages in the balloon, by guest page frame number.
+    inflated: BTreeSet<u32>,
+    config_changed: bool,
+    // The pages in the balloon have frames again, e.g. after a restore, and must be discarded.
+    rediscard: bool,
+    queue_sizes: [u16; 3],
+}
+
+impl Default for VirtioBalloon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtioBalloon {
+    /// Creates an empty balloon.
+    pub fn new() -> Self {
+        Self {
+            target: 0,
+            actual: 0,
+            inflated: BTreeSet::new(),
+            config_changed: false,
+            rediscard: false,
+            queue_sizes: [QUEUE_SIZE; 3],
+        }
+    }
+
+    /// Asks the guest to give up `pages` 4K pages of its RAM in total. The driver is told with a
+    /// configuration change interrupt and inflates or deflates the balloon at its own pace.
+    pub fn set_target(&mut self, pages: u32) {
+        if pages != self.target {
+            self.target = pages;
+            self.config_changed = true;
+        }
+    }
+
+    /// The number of pages the guest is asked to give up.
+    pub fn target(&self) -> u32 {
+        self.target
+    }
+
+    /// The number of pages the driver says the balloon holds.
+    pub fn actual(&self) -> u32 {
+        self.actual
+    }
+
+    /// The number of pages the device took away from the guest through the balloon. Free pages
+    /// reported by the driver are not counted.
+    pub fn inflated_pages(&self) -> usize {
+        self.inflated.len()
+    }
+}
+
+// Private methods implementation
+impl VirtioBalloon {
+    /// Reads the page frame numbers of every buffer on `queue` and hands them to `f`.
+    fn for_each_pfn(
+        queue: &mut VirtQueue,
+        mem: &dyn GuestMemoryAccess,
+        mut f: impl FnMut(u32),
+    ) -> HyperResult<()> {
+        while let Some(chain) = queue.pop(mem)? {
+            let pfns = chain.read_all(mem)?;
+            for pfn in pfns.chunks_exact(4) {
+                f(u32::from_le_bytes(pfn.try_into().unwrap()));
+            }
+            queue.add_used(mem, chain.head(), 0)?;
+        }
+        Ok(())
+    }
+
+    fn inflate(&mut self, queue: &mut VirtQueue, mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        let inflated = &mut self.inflated;
+        Self::for_each_pfn(queue, mem, |pfn| {
+            match mem.discard_page(pfn_to_gpa(pfn)) {
+                Ok(()) => {
+                    inflated.insert(pfn);
+                }
+                // The page stays with the guest, which does not use it while it is ballooned.
+                Err(err) => debug!("virtio-balloon: page {:#x} not discarded: {:?}", pfn, err),
+            }
+        })
+    }
+
+    fn deflate(&mut self, queue: &mut VirtQueue, mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        let inflated = &mut self.inflated;
+        Self::for_each_pfn(queue, mem, |pfn| {
+            if inflated.remove(&pfn) {
+                // Should this fail, the page gets a frame once the guest touches it.
+                if let Err(err) = mem.populate_page(pfn_to_gpa(pfn)) {
+                    warn!("virtio-balloon: page {:#x} not populated: {:?}", pfn, err);
+                }
+            }
+        })
+    }
+
+    /// Discards the free ranges of RAM the driver reports. Each buffer is one range.
+    fn report_free(
+        &mut self,
+        queue: &mut VirtQueue,
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()> {
+        while let Some(chain) = queue.pop(mem)? {
+            for desc in chain.descs() {
+                let start = desc.addr.next_multiple_of(PAGE_SIZE_4K);
+                let end = (desc.addr + desc.len as usize) & !(PAGE_SIZE_4K - 1);
+                for gpa in (start..end).step_by(PAGE_SIZE_4K) {
+                    if let Err(err) = mem.discard_page(gpa) {
+                        debug!(
+                            "virtio-balloon: free page {:#x} not discarded: {:?}",
+                            gpa, err
+                        );
+                        break;
+                    }
+                }
+            }
+            queue.add_used(mem, chain.head(), 0)?;
+        }
+        Ok(())
+    }
+}
+
+fn pfn_to_gpa(pfn: u32) -> GuestPhysAddr {
+    (pfn as GuestPhysAddr) << VIRTIO_BALLOON_PFN_SHIFT
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BALLOON
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_BALLOON_F_MUST_TELL_HOST
+            | VIRTIO_BALLOON_F_DEFLATE_ON_OOM
+            | VIRTIO_BALLOON_F_REPORTING
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let mut config = [0; CONFIG_SIZE];
+        config[0..4].copy_from_slice(&self.target.to_le_bytes());
+        config[4..8].copy_from_slice(&self.actual.to_le_bytes());
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        // Only `actual` is writable.
+        let mut actual = self.actual.to_le_bytes();
+        for (i, &byte) in data.iter().enumerate() {
+            if let Some(at) = (offset + i).checked_sub(4) {
+                if let Some(slot) = actual.get_mut(at) {
+                    *slot = byte;
+                }
+            }
+        }
+        self.actual = u32::from_le_bytes(actual);
+    }
+
+    fn reset(&mut self) {
+        // The new driver starts with an empty balloon. The pages still discarded get frames
+        // again as the guest touches them.
+        self.inflated.clear();
+        self.actual = 0;
+        self.rediscard = false;
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [VirtQueue],
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()> {
+        match index {
+            INFLATE_QUEUE => self.inflate(&mut queues[INFLATE_QUEUE], mem),
+            DEFLATE_QUEUE => self.deflate(&mut queues[DEFLATE_QUEUE], mem),
+            REPORTING_QUEUE => self.report_free(&mut queues[REPORTING_QUEUE], mem),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    fn has_pending_work(&self) -> bool {
+        self.config_changed || self.rediscard
+    }
+
+    fn poll(&mut self, _queues: &mut [VirtQueue], mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        if self.rediscard {
+            self.rediscard = false;
+            for &pfn in self.inflated.iter() {
+                if let Err(err) = mem.discard_page(pfn_to_gpa(pfn)) {
+                    debug!("virtio-balloon: page {:#x} not discarded: {:?}", pfn, err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn take_config_changed(&mut self) -> bool {
+        core::mem::take(&mut self.config_changed)
+    }
+
+    fn save_state(&self, out: &mut Encoder) {
+        out.put_u32(self.target);
+        out.put_u32(self.actual);
+        out.put_u32(self.inflated.len() as u32);
+        self.inflated.iter().for_each(|&pfn| out.put_u32(pfn));
+    }
+
+    /// The RAM restored with the VM covers the pages in the balloon, they are discarded again
+    /// before the guest next runs.
+    fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        self.target = input.get_u32()?;
+        self.actual = input.get_u32()?;
+        let len = input.get_u32()?;
+        self.inflated.clear();
+        for _ in 0..len {
+            self.inflated.insert(input.get_u32()?);
+        }
+        self.rediscard = !self.inflated.is_empty();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{TestDriverQueue, TestMemory};
+    use super::*;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    /// Records the pages discarded and populated.
+    struct BalloonMemory {
+        mem: TestMemory,
+        discarded: RefCell<BTreeSet<GuestPhysAddr>>,
+    }
+
+    impl GuestMemoryAccess for BalloonMemory {
+        fn read_guest(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()> {
+            self.mem.read_guest(gpa, buf)
+        }
+
+        fn write_guest(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HyperResult<()> {
+            self.mem.write_guest(gpa, buf)
+        }
+
+        fn discard_page(&self, gpa: GuestPhysAddr) -> HyperResult<()> {
+            self.discarded.borrow_mut().insert(gpa);
+            Ok(())
+        }
+
+        fn populate_page(&self, gpa: GuestPhysAddr) -> HyperResult<()> {
+            self.discarded.borrow_mut().remove(&gpa);
+            Ok(())
+        }
+    }
+
+    fn pfns(pfns: &[u32]) -> Vec<u8> {
+        pfns.iter().flat_map(|pfn| pfn.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn balloon_discards_and_restores_pages() {
+        let mut balloon = VirtioBalloon::new();
+        let mem = BalloonMemory {
+            mem: TestMemory::new(0x30000),
+            discarded: RefCell::new(BTreeSet::new()),
+        };
+        let mut drivers: Vec<_> = (0..3)
+            .map(|i| TestDriverQueue::new(0x10000 * i, 16))
+            .collect();
+        let mut queues: Vec<_> = drivers.iter().map(TestDriverQueue::queue).collect();
+
+        balloon.set_target(3);
+        assert!(balloon.has_pending_work());
+        assert!(balloon.take_config_changed());
+        let mut config = [0; 4];
+        balloon.read_config(0, &mut config);
+        assert_eq!(u32::from_le_bytes(config), 3);
+
+        drivers[INFLATE_QUEUE].add(&mem.mem, &pfns(&[0x80000, 0x80001, 0x80002]), 0);
+        balloon
+            .queue_notify(INFLATE_QUEUE, &mut queues, &mem)
+            .unwrap();
+        balloon.write_config(4, &3u32.to_le_bytes());
+        assert_eq!((balloon.inflated_pages(), balloon.actual()), (3, 3));
+        assert!(mem.discarded.borrow().contains(&0x8000_1000));
+
+        drivers[DEFLATE_QUEUE].add(&mem.mem, &pfns(&[0x80001]), 0);
+        balloon
+            .queue_notify(DEFLATE_QUEUE, &mut queues, &mem)
+            .unwrap();
+        assert_eq!(balloon.inflated_pages(), 2);
+        assert!(!mem.discarded.borrow().contains(&0x8000_1000));
+
+        // A free range reported by the driver, with a partial page at either end.
+        let (_, range) = drivers[REPORTING_QUEUE].add(&mem.mem, &[], 0x2800);
+        balloon
+            .queue_notify(REPORTING_QUEUE, &mut queues, &mem)
+            .unwrap();
+        let first = range.next_multiple_of(PAGE_SIZE_4K);
+        assert!(mem.discarded.borrow().contains(&first));
+        assert_eq!(
+            mem.discarded.borrow().len(),
+            2 + (range + 0x2800 - first) / PAGE_SIZE_4K
+        );
+        assert_eq!(balloon.inflated_pages(), 2);
+    }
+}
@@ -0,0 +1,468 @@
+//! A virtio block device (virtio 1.2, section 5.2) on top of a [`BlockBackend`].
+//!
+//! The backend is the storage itself: a [`RamDisk`], a [`BlockPartition`] of a disk shared by
+//! several VMs, or whatever the embedder implements on top of its own drivers. Requests are
+//! carried out synchronously when the guest notifies the queue.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{read_config_bytes, DescChain, VirtQueue, VirtioDevice, VIRTIO_ID_BLOCK};
+use crate::{GuestMemoryAccess, HyperError, HyperResult};
+
+/// Size of a sector, the unit of all block device addresses and lengths.
+pub const SECTOR_SIZE: usize = 512;
+
+const QUEUE_SIZE: u16 = 128;
+
+/// Most sectors one discard segment may cover.
+const MAX_DISCARD_SECTORS: u32 = 0x40_0000;
+/// Most segments in one discard request.
+const MAX_DISCARD_SEG: u32 = 16;
+
+/// The device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// `blk_size` in the configuration space is valid.
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+/// The device supports cache flushes.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+/// The device supports discard requests.
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+
+// Request types.
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+
+// Request status.
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const REQUEST_HEADER_SIZE: usize = 16;
+const DISCARD_SEGMENT_SIZE: usize = 16;
+const ID_SIZE: usize = 20;
+const CONFIG_SIZE: usize = 48;
+
+/// Storage behind a virtio block device, addressed in [`SECTOR_SIZE`] sectors.
+pub trait BlockBackend: Send {
+    /// Size of the storage in sectors.
+    fn sector_count(&self) -> u64;
+
+    /// Reads `buf.len() / SECTOR_SIZE` sectors starting at `sector`.
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> HyperResult<()>;
+
+    /// Writes `data.len() / SECTOR_SIZE` sectors starting at `sector`.
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) -> HyperResult<()>;
+
+    /// Makes the writes done so far durable.
+    fn flush(&mut self) -> HyperResult<()> {
+        Ok(())
+    }
+
+    /// Whether the storage can drop the contents of sectors with [`discard`](Self::discard).
+    fn supports_discard(&self) -> bool {
+        false
+    }
+
+    /// Drops the contents of `count` sectors starting at `sector`. They read back as zeroes or
+    /// as their old contents.
+    fn discard(&mut self, _sector: u64, _count: u64) -> HyperResult<()> {
+        Err(HyperError::NotSupported)
+    }
+}
+
+/// A disk held in host memory. Discarded sectors read back as zeroes.
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    /// Creates a zeroed disk of `sectors` sectors.
+    pub fn new(sectors: usize) -> Self {
+        Self {
+            data: vec![0; sectors * SECTOR_SIZE],
+        }
+    }
+
+    /// Creates a disk holding `data`, e.g. an image loaded by the embedder. It is padded with
+    /// zeroes to a whole number of sectors.
+    pub fn from_vec(mut data: Vec<u8>) -> Self {
+        let len = data.len().div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+        data.resize(len, 0);
+        Self { data }
+    }
+
+    /// The contents of the disk.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn range(&self, sector: u64, len: usize) -> HyperResult<core::ops::Range<usize>> {
+        let start = (sector as usize)
+            .checked_mul(SECTOR_SIZE)
+            .ok_or(HyperError::OutOfRange)?;
+        let end = start.checked_add(len).ok_or(HyperError::OutOfRange)?;
+        if end > self.data.len() {
+            return Err(HyperError::OutOfRange);
+        }
+        Ok(start..end)
+    }
+}
+
+impl BlockBackend for RamDisk {
+    fn sector_count(&self) -> u64 {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> HyperResult<()> {
+        let range = self.range(sector, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) -> HyperResult<()> {
+        let range = self.range(sector, data.len())?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn supports_discard(&self) -> bool {
+        true
+    }
+
+    fn discard(&mut self, sector: u64, count: u64) -> HyperResult<()> {
+        let len = (count as usize)
+            .checked_mul(SECTOR_SIZE)
+            .ok_or(HyperError::OutOfRange)?;
+        let range = self.range(sector, len)?;
+        self.data[range].fill(0);
+        Ok(())
+    }
+}
+
+/// A range of sectors of a disk shared with other users, e.g. one partition per VM.
+pub struct BlockPartition<B: BlockBackend> {
+    disk: Arc<Mutex<B>>,
+    start: u64,
+    count: u64,
+}
+
+impl<B: BlockBackend> BlockPartition<B> {
+    /// The `count` sectors of `disk` starting at `start`.
+    pub fn new(disk: Arc<Mutex<B>>, start: u64, count: u64) -> HyperResult<Self> {
+        let end = start.checked_add(count).ok_or(HyperError::InvalidParam)?;
+        if end > disk.lock().sector_count() {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(Self { disk, start, count })
+    }
+
+    /// Translates `len` bytes at `sector` of the partition to a sector of the disk.
+    fn translate(&self, sector: u64, len: u64) -> HyperResult<u64> {
+        let sectors = len.div_ceil(SECTOR_SIZE as u64);
+        match sector.checked_add(sectors) {
+            Some(end) if end <= self.count => Ok(self.start + sector),
+            _ => Err(HyperError::OutOfRange),
+        }
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for BlockPartition<B> {
+    fn sector_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> HyperResult<()> {
+        let sector = self.translate(sector, buf.len() as u64)?;
+        self.disk.lock().read_sectors(sector, buf)
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) -> HyperResult<()> {
+        let sector = self.translate(sector, data.len() as u64)?;
+        self.disk.lock().write_sectors(sector, data)
+    }
+
+    fn flush(&mut self) -> HyperResult<()> {
+        self.disk.lock().flush()
+    }
+
+    fn supports_discard(&self) -> bool {
+        self.disk.lock().supports_discard()
+    }
+
+    fn discard(&mut self, sector: u64, count: u64) -> HyperResult<()> {
+        let bytes = count
+            .checked_mul(SECTOR_SIZE as u64)
+            .ok_or(HyperError::OutOfRange)?;
+        let sector = self.translate(sector, bytes)?;
+        self.disk.lock().discard(sector, count)
+    }
+}
+
+/// A virtio block device with a single request queue.
+pub struct VirtioBlk<B: BlockBackend> {
+    backend: B,
+    read_only: bool,
+    serial: String,
+    queue_sizes: [u16; 1],
+}
+
+impl<B: BlockBackend> VirtioBlk<B> {
+    /// Creates a device on top of `backend`. A `read_only` device fails all writes.
+    pub fn new(backend: B, read_only: bool) -> Self {
+        Self {
+            backend,
+            read_only,
+            serial: String::new(),
+            queue_sizes: [QUEUE_SIZE],
+        }
+    }
+
+    /// Sets the serial number reported to the guest, at most 20 bytes of it are used.
+    pub fn set_serial(&mut self, serial: &str) {
+        self.serial = String::from(serial);
+    }
+
+    /// The storage behind the device.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// The storage behind the device.
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+}
+
+// Private methods implementation
+impl<B: BlockBackend> VirtioBlk<B> {
+    /// Carries out the request in `chain`. Returns the status and the number of bytes written
+    /// into the chain before the status byte.
+    fn handle_request(
+        &mut self,
+        chain: &DescChain,
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<(u8, usize)> {
+        let mut header = [0; REQUEST_HEADER_SIZE];
+        if chain.read_at(mem, 0, &mut header)? < REQUEST_HEADER_SIZE || chain.writable_len() == 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let kind = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..].try_into().unwrap());
+        // The last writable byte holds the status.
+        let in_len = chain.writable_len() - 1;
+        let out_len = chain.readable_len() - REQUEST_HEADER_SIZE;
+        let ret = match kind {
+            VIRTIO_BLK_T_IN => {
+                if in_len % SECTOR_SIZE != 0 {
+                    return Ok((VIRTIO_BLK_S_IOERR, 0));
+                }
+                let mut buf = vec![0; in_len];
+                self.backend.read_sectors(sector, &mut buf).and_then(|_| {
+                    chain.write_at(mem, 0, &buf)?;
+                    Ok(in_len)
+                })
+            }
+            VIRTIO_BLK_T_OUT => {
+                if self.read_only || out_len % SECTOR_SIZE != 0 {
+                    return Ok((VIRTIO_BLK_S_IOERR, 0));
+                }
+                let mut buf = vec![0; out_len];
+                chain.read_at(mem, REQUEST_HEADER_SIZE, &mut buf)?;
+                self.backend.write_sectors(sector, &buf).map(|_| 0)
+            }
+            VIRTIO_BLK_T_FLUSH => self.backend.flush().map(|_| 0),
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = [0; ID_SIZE];
+                let len = self.serial.len().min(ID_SIZE);
+                id[..len].copy_from_slice(&self.serial.as_bytes()[..len]);
+                let len = in_len.min(ID_SIZE);
+                chain.write_at(mem, 0, &id[..len])?;
+                Ok(len)
+            }
+            VIRTIO_BLK_T_DISCARD if self.backend.supports_discard() => {
+                if self.read_only {
+                    return Ok((VIRTIO_BLK_S_IOERR, 0));
+                }
+                let mut segments = vec![0; out_len];
+                chain.read_at(mem, REQUEST_HEADER_SIZE, &mut segments)?;
+                self.discard(&segments).map(|_| 0)
+            }
+            _ => return Ok((VIRTIO_BLK_S_UNSUPP, 0)),
+        };
+        match ret {
+            Ok(len) => Ok((VIRTIO_BLK_S_OK, len)),
+            Err(err) => {
+                debug!(
+                    "virtio-blk: request {} at sector {} failed: {:?}",
+                    kind, sector, err
+                );
+                Ok((VIRTIO_BLK_S_IOERR, 0))
+            }
+        }
+    }
+
+    fn discard(&mut self, segments: &[u8]) -> HyperResult<()> {
+        if segments.len() % DISCARD_SEGMENT_SIZE != 0
+            || segments.len() / DISCARD_SEGMENT_SIZE > MAX_DISCARD_SEG as usize
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        for segment in segments.chunks_exact(DISCARD_SEGMENT_SIZE) {
+            let sector = u64::from_le_bytes(segment[..8].try_into().unwrap());
+            let count = u32::from_le_bytes(segment[8..12].try_into().unwrap());
+            if count > MAX_DISCARD_SECTORS {
+                return Err(HyperError::InvalidParam);
+            }
+            self.backend.discard(sector, count as u64)?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: BlockBackend> VirtioDevice for VirtioBlk<B> {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn device_features(&self) -> u64 {
+        let mut features = VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+        if self.read_only {
+            features |= VIRTIO_BLK_F_RO;
+        }
+        if self.backend.supports_discard() {
+            features |= VIRTIO_BLK_F_DISCARD;
+        }
+        features
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let mut config = [0; CONFIG_SIZE];
+        config[0..8].copy_from_slice(&self.backend.sector_count().to_le_bytes());
+        config[20..24].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        config[36..40].copy_from_slice(&MAX_DISCARD_SECTORS.to_le_bytes());
+        config[40..44].copy_from_slice(&MAX_DISCARD_SEG.to_le_bytes());
+        config[44..48].copy_from_slice(&1u32.to_le_bytes());
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn reset(&mut self) {}
+
+    fn queue_notify(
+        &mut self,
+        _index: usize,
+        queues: &mut [VirtQueue],
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()> {
+        let queue = &mut queues[0];
+        while let Some(chain) = queue.pop(mem)? {
+            let (status, len) = self.handle_request(&chain, mem)?;
+            chain.write_at(mem, chain.writable_len() - 1, &[status])?;
+            queue.add_used(mem, chain.head(), len as u32 + 1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{TestDriverQueue, TestMemory};
+    use super::*;
+
+    fn request(kind: u32, sector: u64, data: &[u8]) -> Vec<u8> {
+        let mut req = Vec::new();
+        req.extend_from_slice(&kind.to_le_bytes());
+        req.extend_from_slice(&0u32.to_le_bytes());
+        req.extend_from_slice(&sector.to_le_bytes());
+        req.extend_from_slice(data);
+        req
+    }
+
+    fn submit<B: BlockBackend>(
+        blk: &mut VirtioBlk<B>,
+        mem: &TestMemory,
+        driver: &mut TestDriverQueue,
+        queues: &mut [VirtQueue],
+        req: &[u8],
+        in_len: usize,
+    ) -> (u8, Vec<u8>) {
+        let (_, buf) = driver.add(mem, req, in_len as u32 + 1);
+        blk.queue_notify(0, queues, mem).unwrap();
+        let (_, len) = driver.used(mem)[0];
+        let mut data = vec![0; in_len + 1];
+        mem.read_guest(buf, &mut data).unwrap();
+        let status = data.pop().unwrap();
+        data.truncate(len as usize - 1);
+        (status, data)
+    }
+
+    #[test]
+    fn requests_reach_the_backend() {
+        let mem = TestMemory::new(0x20000);
+        let mut driver = TestDriverQueue::new(0, 32);
+        let mut queues = [driver.queue()];
+        let mut blk = VirtioBlk::new(RamDisk::new(16), false);
+        blk.set_serial("disk0");
+
+        let sector = [0xab; SECTOR_SIZE];
+        let req = request(VIRTIO_BLK_T_OUT, 3, &sector);
+        let (status, _) = submit(&mut blk, &mem, &mut driver, &mut queues, &req, 0);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        let req = request(VIRTIO_BLK_T_IN, 3, &[]);
+        let (status, data) = submit(&mut blk, &mem, &mut driver, &mut queues, &req, SECTOR_SIZE);
+        assert_eq!((status, &data[..]), (VIRTIO_BLK_S_OK, &sector[..]));
+
+        // Past the end of the disk.
+        let req = request(VIRTIO_BLK_T_IN, 16, &[]);
+        let (status, _) = submit(&mut blk, &mem, &mut driver, &mut queues, &req, SECTOR_SIZE);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+
+        let mut segment = Vec::new();
+        segment.extend_from_slice(&3u64.to_le_bytes());
+        segment.extend_from_slice(&1u32.to_le_bytes());
+        segment.extend_from_slice(&0u32.to_le_bytes());
+        let req = request(VIRTIO_BLK_T_DISCARD, 0, &segment);
+        let (status, _) = submit(&mut blk, &mem, &mut driver, &mut queues, &req, 0);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert!(blk.backend().as_bytes().iter().all(|&b| b == 0));
+
+        let req = request(VIRTIO_BLK_T_GET_ID, 0, &[]);
+        let (status, id) = submit(&mut blk, &mem, &mut driver, &mut queues, &req, ID_SIZE);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(&id[..6], b"disk0\0");
+    }
+
+    #[test]
+    fn partitions_of_a_read_only_disk() {
+        let disk = Arc::new(Mutex::new(RamDisk::new(16)));
+        disk.lock().write_sectors(8, &[7; SECTOR_SIZE]).unwrap();
+        assert!(BlockPartition::new(disk.clone(), 8, 9).is_err());
+        let part = BlockPartition::new(disk.clone(), 8, 8).unwrap();
+        let mut blk = VirtioBlk::new(part, true);
+        assert_ne!(blk.device_features() & VIRTIO_BLK_F_RO, 0);
+
+        let mem = TestMemory::new(0x20000);
+        let mut driver = TestDriverQueue::new(0, 32);
+        let mut queues = [driver.queue()];
+        let req = request(VIRTIO_BLK_T_IN, 0, &[]);
+        let (status, data) = submit(&mut blk, &mem, &mut driver, &mut queues, &req, SECTOR_SIZE);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert!(data.iter().all(|&b| b == 7));
+        let req = request(VIRTIO_BLK_T_OUT, 0, &[0; SECTOR_SIZE]);
+        let (status, _) = submit(&mut blk, &mem, &mut driver, &mut queues, &req, 0);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+        let req = request(VIRTIO_BLK_T_IN, 8, &[]);
+        let (status, _) = submit(&mut blk, &mem, &mut driver, &mut queues, &req, SECTOR_SIZE);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+    }
+}
@@ -0,0 +1,563 @@
+//! A virtio console with multiport support (virtio 1.2, section 5.3).
+//!
+//! Port 0 is the console, `hvc0` in a Linux guest. Further ports show up as
+//! `/dev/vportNpM` and, when named, as `/dev/virtio-ports/<name>`. Like with the
+//! [`Uart16550`](crate::devices::Uart16550), the guest's output collects in a TX buffer per port
+//! and input for the guest is queued in an RX buffer per port, both drained and filled by the
+//! VMM. Unlike the UART, the guest hands over whole buffers at a time.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{read_config_bytes, DescChain, VirtQueue, VirtioDevice, VIRTIO_ID_CONSOLE};
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestMemoryAccess, HyperError, HyperResult};
+
+/// Bytes of output kept per port before the oldest output is dropped.
+pub const TX_BUFFER_SIZE: usize = 64 * 1024;
+/// Bytes of input that can be queued per port.
+pub const RX_BUFFER_SIZE: usize = 16 * 1024;
+/// Most ports a console can have, so that all queues fit a transport.
+pub const MAX_PORTS: usize = 31;
+
+const QUEUE_SIZE: u16 = 128;
+
+/// The console size is in the configuration space.
+const VIRTIO_CONSOLE_F_SIZE: u64 = 1 << 0;
+/// The device has several ports and a control queue pair.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+/// The driver can write single characters to `emerg_wr` in the configuration space.
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+// Control events.
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+
+const CONTROL_SIZE: usize = 8;
+
+// Queue indices of the control queue pair, present with multiport.
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+// Configuration space layout.
+const CONFIG_EMERG_WR: usize = 8;
+const CONFIG_SIZE: usize = 12;
+
+#[derive(Default)]
+struct ConsolePort {
+    name: Option<String>,
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    // The driver reported the port as set up.
+    ready: bool,
+    // A guest program has the port open.
+    guest_open: bool,
+    // RX data is waiting for the driver to add receive buffers.
+    rx_starved: bool,
+}
+
+/// A virtio console with up to [`MAX_PORTS`] ports.
+pub struct VirtioConsole {
+    ports: Vec<ConsolePort>,
+    queue_sizes: Vec<u16>,
+    cols: u16,
+    rows: u16,
+    multiport: bool,
+    // Control messages for the driver, not yet placed in its buffers.
+    control_rx: VecDeque<Vec<u8>>,
+    control_starved: bool,
+    config_changed: bool,
+}
+
+impl VirtioConsole {
+    /// Creates a console with `nr_ports` ports. With more than one port, the driver must support
+    /// multiport to reach ports other than 0.
+    pub fn new(nr_ports: usize) -> HyperResult<Self> {
+        if nr_ports == 0 || nr_ports > MAX_PORTS {
+            return Err(HyperError::InvalidParam);
+        }
+        // Port 0 pair, control pair, then one pair for each further port.
+        let nr_queues = if nr_ports > 1 { 2 * nr_ports + 2 } else { 2 };
+        Ok(Self {
+            ports: (0..nr_ports).map(|_| ConsolePort::default()).collect(),
+            queue_sizes: vec![QUEUE_SIZE; nr_queues],
+            cols: 0,
+            rows: 0,
+            multiport: false,
+            control_rx: VecDeque::new(),
+            control_starved: false,
+            config_changed: false,
+        })
+    }
+
+    /// Number of ports.
+    pub fn nr_ports(&self) -> usize {
+        self.ports.len()
+    }
+
+    /// Names port `port`, so that guest programs can find it by name. Must be done before the
+    /// driver sets the port up.
+    pub fn set_port_name(&mut self, port: usize, name: &str) -> HyperResult<()> {
+        let port = self.ports.get_mut(port).ok_or(HyperError::NotFound)?;
+        port.name = Some(String::from(name));
+        Ok(())
+    }
+
+    /// Sets the size of the console in characters, as reported to the driver.
+    pub fn set_size(&mut self, cols: u16, rows: u16) {
+        self.cols = cols;
+        self.rows = rows;
+        self.config_changed = true;
+    }
+
+    /// Queues `data` as input for the guest on `port`. Returns how many bytes were accepted, the
+    /// rest is dropped.
+    pub fn push_rx(&mut self, port: usize, data: &[u8]) -> usize {
+        let port = match self.ports.get_mut(port) {
+            Some(port) => port,
+            None => return 0,
+        };
+        let accepted = data.len().min(RX_BUFFER_SIZE - port.rx.len());
+        port.rx.extend(&data[..accepted]);
+        accepted
+    }
+
+    /// Moves the guest's output on `port` into `buf`, returning the number of bytes copied.
+    pub fn take_tx(&mut self, port: usize, buf: &mut [u8]) -> usize {
+        let port = match self.ports.get_mut(port) {
+            Some(port) => port,
+            None => return 0,
+        };
+        let len = buf.len().min(port.tx.len());
+        for (dst, src) in buf.iter_mut().zip(port.tx.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+
+    /// Returns true if the guest wrote bytes on `port` the VMM has not taken yet.
+    pub fn has_tx(&self, port: usize) -> bool {
+        self.ports
+            .get(port)
+            .map_or(false, |port| !port.tx.is_empty())
+    }
+
+    /// Returns true if a guest program has `port` open. The console port counts as always open.
+    pub fn is_guest_open(&self, port: usize) -> bool {
+        match self.ports.get(port) {
+            Some(_) if port == 0 || !self.multiport => true,
+            Some(port) => port.guest_open,
+            None => false,
+        }
+    }
+}
+
+// Private methods implementation
+impl VirtioConsole {
+    /// Index of the receive queue of `port`. Its transmit queue follows.
+    fn rx_queue(port: usize) -> usize {
+        if port == 0 {
+            0
+        } else {
+            2 * port + 2
+        }
+    }
+
+    /// The port whose receive or transmit queue is `queue`, if it is not a control queue.
+    fn port_of_queue(queue: usize) -> Option<usize> {
+        match queue {
+            0 | 1 => Some(0),
+            CONTROL_RX | CONTROL_TX => None,
+            _ => Some(queue / 2 - 1),
+        }
+    }
+
+    fn push_control(&mut self, id: usize, event: u16, value: u16, extra: &[u8]) {
+        let mut msg = Vec::with_capacity(CONTROL_SIZE + extra.len());
+        msg.extend_from_slice(&(id as u32).to_le_bytes());
+        msg.extend_from_slice(&event.to_le_bytes());
+        msg.extend_from_slice(&value.to_le_bytes());
+        msg.extend_from_slice(extra);
+        self.control_rx.push_back(msg);
+        self.control_starved = false;
+    }
+
+    fn handle_control(&mut self, msg: &[u8]) {
+        if msg.len() < CONTROL_SIZE {
+            warn!("virtio-console: short control message");
+            return;
+        }
+        let id = u32::from_le_bytes(msg[..4].try_into().unwrap()) as usize;
+        let event = u16::from_le_bytes([msg[4], msg[5]]);
+        let value = u16::from_le_bytes([msg[6], msg[7]]);
+        match event {
+            DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() {
+                    self.push_control(id, DEVICE_ADD, 0, &[]);
+                }
+            }
+            PORT_READY if value == 1 && id < self.ports.len() => {
+                self.ports[id].ready = true;
+                if id == 0 {
+                    self.push_control(id, CONSOLE_PORT, 1, &[]);
+                }
+                if let Some(name) = self.ports[id].name.clone() {
+                    self.push_control(id, PORT_NAME, 1, name.as_bytes());
+                }
+                // The host end of every port is always connected.
+                self.push_control(id, PORT_OPEN, 1, &[]);
+            }
+            PORT_OPEN if id < self.ports.len() => {
+                self.ports[id].guest_open = value == 1;
+                self.ports[id].rx_starved = false;
+            }
+            _ => debug!(
+                "virtio-console: ignored control event {} for port {}",
+                event, id
+            ),
+        }
+    }
+
+    /// Whether input for `port` can be placed in the driver's buffers.
+    fn can_receive(&self, port: usize) -> bool {
+        let p = &self.ports[port];
+        if !self.multiport {
+            return port == 0;
+        }
+        // Linux drops data for closed ports, the console port is always open.
+        p.ready && (port == 0 || p.guest_open)
+    }
+
+    /// Moves as much RX data of `port` as the driver has buffers for.
+    fn fill_rx(
+        &mut self,
+        port: usize,
+        queues: &mut [VirtQueue],
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()> {
+        let queue = &mut queues[Self::rx_queue(port)];
+        let p = &mut self.ports[port];
+        while !p.rx.is_empty() {
+            let chain = match queue.pop(mem)? {
+                Some(chain) => chain,
+                None => {
+                    p.rx_starved = true;
+                    return Ok(());
+                }
+            };
+            let len = write_bytes(&chain, mem, &mut p.rx)?;
+            queue.add_used(mem, chain.head(), len as u32)?;
+        }
+        Ok(())
+    }
+
+    fn fill_control(
+        &mut self,
+        queues: &mut [VirtQueue],
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()> {
+        let queue = &mut queues[CONTROL_RX];
+        while let Some(msg) = self.control_rx.front() {
+            let chain = match queue.pop(mem)? {
+                Some(chain) => chain,
+                None => {
+                    self.control_starved = true;
+                    return Ok(());
+                }
+            };
+            let len = chain.write_at(mem, 0, msg)?;
+            queue.add_used(mem, chain.head(), len as u32)?;
+            self.control_rx.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Takes the buffers the driver sent on `port`.
+    fn drain_tx(
+        &mut self,
+        port: usize,
+        queue: &mut VirtQueue,
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()> {
+        while let Some(chain) = queue.pop(mem)? {
+            let data = chain.read_all(mem)?;
+            self.write_tx(port, &data);
+            queue.add_used(mem, chain.head(), 0)?;
+        }
+        Ok(())
+    }
+
+    fn write_tx(&mut self, port: usize, data: &[u8]) {
+        let tx = &mut self.ports[port].tx;
+        let data = &data[data.len().saturating_sub(TX_BUFFER_SIZE)..];
+        let overflow = (tx.len() + data.len()).saturating_sub(TX_BUFFER_SIZE);
+        tx.drain(..overflow);
+        tx.extend(data);
+    }
+}
+
+/// Moves the front of `data` into the writable buffers of `chain`, returning the bytes moved.
+fn write_bytes(
+    chain: &DescChain,
+    mem: &dyn GuestMemoryAccess,
+    data: &mut VecDeque<u8>,
+) -> HyperResult<usize> {
+    let len = chain.writable_len().min(data.len());
+    let (front, back) = data.as_slices();
+    let first = len.min(front.len());
+    chain.write_at(mem, 0, &front[..first])?;
+    chain.write_at(mem, first, &back[..len - first])?;
+    data.drain(..len);
+    Ok(len)
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn device_features(&self) -> u64 {
+        let mut features = VIRTIO_CONSOLE_F_SIZE | VIRTIO_CONSOLE_F_EMERG_WRITE;
+        if self.ports.len() > 1 {
+            features |= VIRTIO_CONSOLE_F_MULTIPORT;
+        }
+        features
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let mut config = [0; CONFIG_SIZE];
+        config[0..2].copy_from_slice(&self.cols.to_le_bytes());
+        config[2..4].copy_from_slice(&self.rows.to_le_bytes());
+        config[4..8].copy_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        if offset == CONFIG_EMERG_WR && !data.is_empty() {
+            self.write_tx(0, &data[..1]);
+        }
+    }
+
+    fn activate(&mut self, features: u64) -> HyperResult<()> {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        for port in self.ports.iter_mut() {
+            port.ready = false;
+            port.guest_open = false;
+            port.rx_starved = false;
+        }
+        self.multiport = false;
+        self.control_rx.clear();
+        self.control_starved = false;
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [VirtQueue],
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()> {
+        match (index, Self::port_of_queue(index)) {
+            (CONTROL_TX, None) if self.multiport => {
+                while let Some(chain) = queues[CONTROL_TX].pop(mem)? {
+                    let msg = chain.read_all(mem)?;
+                    self.handle_control(&msg);
+                    queues[CONTROL_TX].add_used(mem, chain.head(), 0)?;
+                }
+                self.fill_control(queues, mem)
+            }
+            (CONTROL_RX, None) if self.multiport => {
+                self.control_starved = false;
+                self.fill_control(queues, mem)
+            }
+            (index, Some(port)) if index == Self::rx_queue(port) => {
+                self.ports[port].rx_starved = false;
+                if self.can_receive(port) {
+                    self.fill_rx(port, queues, mem)?;
+                }
+                Ok(())
+            }
+            (index, Some(port)) => self.drain_tx(port, &mut queues[index], mem),
+            _ => Ok(()),
+        }
+    }
+
+    fn has_pending_work(&self) -> bool {
+        (!self.control_rx.is_empty() && !self.control_starved)
+            || (0..self.ports.len()).any(|port| {
+                let p = &self.ports[port];
+                !p.rx.is_empty() && !p.rx_starved && self.can_receive(port)
+            })
+    }
+
+    fn poll(&mut self, queues: &mut [VirtQueue], mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        if self.multiport && !self.control_starved {
+            self.fill_control(queues, mem)?;
+        }
+        for port in 0..self.ports.len() {
+            if !self.ports[port].rx_starved && self.can_receive(port) {
+                self.fill_rx(port, queues, mem)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn take_config_changed(&mut self) -> bool {
+        core::mem::take(&mut self.config_changed)
+    }
+
+    fn save_state(&self, out: &mut Encoder) {
+        out.put_u16(self.cols);
+        out.put_u16(self.rows);
+        out.put_bool(self.multiport);
+        out.put_u32(self.ports.len() as u32);
+        for port in self.ports.iter() {
+            out.put_bool(port.ready);
+            out.put_bool(port.guest_open);
+            for buf in [&port.tx, &port.rx] {
+                out.put_bytes(&buf.iter().copied().collect::<Vec<u8>>());
+            }
+        }
+        out.put_u32(self.control_rx.len() as u32);
+        for msg in self.control_rx.iter() {
+            out.put_bytes(msg);
+        }
+    }
+
+    fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        self.cols = input.get_u16()?;
+        self.rows = input.get_u16()?;
+        self.multiport = input.get_bool()?;
+        if input.get_u32()? as usize != self.ports.len() {
+            return Err(HyperError::DecodeError);
+        }
+        for port in self.ports.iter_mut() {
+            port.ready = input.get_bool()?;
+            port.guest_open = input.get_bool()?;
+            let tx = input.get_bytes()?;
+            let rx = input.get_bytes()?;
+            if tx.len() > TX_BUFFER_SIZE || rx.len() > RX_BUFFER_SIZE {
+                return Err(HyperError::DecodeError);
+            }
+            port.tx = tx.iter().copied().collect();
+            port.rx = rx.iter().copied().collect();
+            port.rx_starved = false;
+        }
+        self.control_rx.clear();
+        for _ in 0..input.get_u32()? {
+            self.control_rx.push_back(input.get_bytes()?.to_vec());
+        }
+        self.control_starved = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{TestDriverQueue, TestMemory};
+    use super::*;
+    use crate::GuestPhysAddr;
+
+    fn control(id: u32, event: u16, value: u16) -> [u8; CONTROL_SIZE] {
+        let mut msg = [0; CONTROL_SIZE];
+        msg[..4].copy_from_slice(&id.to_le_bytes());
+        msg[4..6].copy_from_slice(&event.to_le_bytes());
+        msg[6..].copy_from_slice(&value.to_le_bytes());
+        msg
+    }
+
+    fn read_events(
+        mem: &TestMemory,
+        driver: &mut TestDriverQueue,
+        bufs: &[GuestPhysAddr],
+    ) -> Vec<(u32, u16)> {
+        driver
+            .used(mem)
+            .iter()
+            .map(|&(head, _)| {
+                let mut msg = [0; CONTROL_SIZE];
+                mem.read_guest(bufs[head as usize], &mut msg).unwrap();
+                (
+                    u32::from_le_bytes(msg[..4].try_into().unwrap()),
+                    msg[4] as u16,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn multiport_handshake_and_data() {
+        let mem = TestMemory::new(0x80000);
+        let mut console = VirtioConsole::new(2).unwrap();
+        console.set_port_name(1, "org.test.0").unwrap();
+        let mut drivers: Vec<_> = (0..6)
+            .map(|i| TestDriverQueue::new(0x10000 * i, 16))
+            .collect();
+        let mut queues: Vec<_> = drivers.iter().map(TestDriverQueue::queue).collect();
+        console
+            .activate(console.device_features() | VIRTIO_CONSOLE_F_MULTIPORT)
+            .unwrap();
+
+        // Buffers for control messages, indexed by head.
+        let ctrl_bufs: Vec<_> = (0..8)
+            .map(|_| drivers[CONTROL_RX].add(&mem, &[], 64).1)
+            .collect();
+        drivers[CONTROL_TX].add(&mem, &control(0, DEVICE_READY, 1), 0);
+        console.queue_notify(CONTROL_TX, &mut queues, &mem).unwrap();
+        let events = read_events(&mem, &mut drivers[CONTROL_RX], &ctrl_bufs);
+        assert_eq!(events, [(0, DEVICE_ADD), (1, DEVICE_ADD)]);
+
+        drivers[CONTROL_TX].add(&mem, &control(0, PORT_READY, 1), 0);
+        drivers[CONTROL_TX].add(&mem, &control(1, PORT_READY, 1), 0);
+        console.queue_notify(CONTROL_TX, &mut queues, &mem).unwrap();
+        let events = read_events(&mem, &mut drivers[CONTROL_RX], &ctrl_bufs);
+        assert_eq!(
+            events,
+            [
+                (0, CONSOLE_PORT),
+                (0, PORT_OPEN),
+                (1, PORT_NAME),
+                (1, PORT_OPEN)
+            ]
+        );
+
+        // Input waits for receive buffers, without polling in the meantime.
+        assert_eq!(console.push_rx(0, b"hello"), 5);
+        assert!(console.has_pending_work());
+        console.poll(&mut queues, &mem).unwrap();
+        assert!(!console.has_pending_work());
+        let (_, buf) = drivers[0].add(&mem, &[], 64);
+        console.queue_notify(0, &mut queues, &mem).unwrap();
+        assert_eq!(drivers[0].used(&mem), [(0, 5)]);
+        let mut data = [0; 5];
+        mem.read_guest(buf, &mut data).unwrap();
+        assert_eq!(&data, b"hello");
+
+        // Port 1 only gets input once a guest program opened it.
+        console.push_rx(1, b"x");
+        assert!(!console.has_pending_work());
+        drivers[CONTROL_TX].add(&mem, &control(1, PORT_OPEN, 1), 0);
+        console.queue_notify(CONTROL_TX, &mut queues, &mem).unwrap();
+        assert!(console.is_guest_open(1));
+        assert!(console.has_pending_work());
+
+        drivers[5].add(&mem, b"output", 0);
+        console.queue_notify(5, &mut queues, &mem).unwrap();
+        let mut out = [0; 16];
+        assert_eq!(console.take_tx(1, &mut out), 6);
+        assert_eq!(&out[..6], b"output");
+        assert!(!console.has_tx(0));
+    }
+}
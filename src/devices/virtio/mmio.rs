@@ -0,0 +1,380 @@
+//! The virtio-mmio transport, version 2 (virtio 1.2, section 4.2).
+
+use core::ops::Range;
+
+use super::transport::{QueueAddr, VirtioTransport};
+use super::VirtioDevice;
+use crate::devices::MmioDevice;
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestMemoryAccess, GuestPhysAddr, HyperError, HyperResult};
+
+/// Size of the register window of one device, including its configuration space.
+pub const VIRTIO_MMIO_SIZE: usize = 0x200;
+
+const MAGIC: u32 = 0x7472_6976; // "virt"
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x554d_4551; // "QEMU", what Linux and most firmware expect
+
+// Register offsets.
+const MAGIC_VALUE: usize = 0x000;
+const VERSION_REG: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID_REG: usize = 0x00c;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+/// A used buffer was returned on one of the queues.
+const INT_VRING: u32 = 1 << 0;
+/// The configuration space changed, or the device needs a reset.
+const INT_CONFIG: u32 = 1 << 1;
+
+/// A virtio device behind a virtio-mmio register window.
+///
+/// Buffers are processed in [`MmioDevice::poll`], called right after the guest's notification
+/// store and whenever the device has pending work. All queues handled in one poll raise at most
+/// one interrupt.
+pub struct VirtioMmio<D: VirtioDevice> {
+    base: GuestPhysAddr,
+    irq: usize,
+    transport: VirtioTransport<D>,
+    interrupt_status: u32,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    /// Creates the transport for `device` at guest physical address `base`, raising interrupt
+    /// line `irq`.
+    pub fn new(base: GuestPhysAddr, irq: usize, device: D) -> HyperResult<Self> {
+        Ok(Self {
+            base,
+            irq,
+            transport: VirtioTransport::new(device)?,
+            interrupt_status: 0,
+        })
+    }
+
+    /// The device model.
+    pub fn device(&self) -> &D {
+        &self.transport.device
+    }
+
+    /// The device model, e.g. for the VMM to feed it input. Work queued this way is picked up
+    /// through [`VirtioDevice::has_pending_work`].
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.transport.device
+    }
+
+    /// Whether the driver set `DRIVER_OK` and the device did not fail since.
+    pub fn is_active(&self) -> bool {
+        self.transport.is_active()
+    }
+}
+
+// Private methods implementation
+impl<D: VirtioDevice> VirtioMmio<D> {
+    fn write_queue_addr(&mut self, offset: usize, val: u32) {
+        let which = match offset & !0x7 {
+            QUEUE_DESC_LOW => QueueAddr::Desc,
+            QUEUE_DRIVER_LOW => QueueAddr::Driver,
+            _ => QueueAddr::Device,
+        };
+        self.transport
+            .write_queue_addr(which, offset & 0x4 != 0, val);
+    }
+
+    fn write_reg(&mut self, offset: usize, val: u32) {
+        let transport = &mut self.transport;
+        match offset {
+            DEVICE_FEATURES_SEL => transport.device_features_sel = val,
+            DRIVER_FEATURES => transport.write_driver_features(val),
+            DRIVER_FEATURES_SEL => transport.driver_features_sel = val,
+            QUEUE_SEL => transport.queue_sel = val,
+            QUEUE_NUM => {
+                if let Some(queue) = transport.selected_queue_mut() {
+                    if queue.set_size(val as u16).is_err() {
+                        warn!("virtio-mmio: bad queue size {}", val);
+                    }
+                }
+            }
+            QUEUE_READY => transport.set_queue_ready(val & 1 != 0),
+            QUEUE_NOTIFY => transport.notify((val & 0xffff) as usize),
+            INTERRUPT_ACK => self.interrupt_status &= !val,
+            STATUS => {
+                transport.write_status(val);
+                if val == 0 {
+                    self.interrupt_status = 0;
+                }
+            }
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH
+            | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => self.write_queue_addr(offset, val),
+            _ => debug!("virtio-mmio: ignored write of {:#x} at {:#x}", val, offset),
+        }
+    }
+
+    fn read_reg(&mut self, offset: usize) -> u32 {
+        let transport = &self.transport;
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION_REG => VERSION,
+            DEVICE_ID => transport.device.device_id(),
+            VENDOR_ID_REG => VENDOR_ID,
+            DEVICE_FEATURES => transport.device_features_word(),
+            QUEUE_NUM_MAX => transport
+                .selected_queue()
+                .map_or(0, |q| q.max_size() as u32),
+            QUEUE_READY => transport
+                .selected_queue()
+                .map_or(0, |q| q.is_ready() as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => transport.status,
+            CONFIG_GENERATION => transport.config_generation,
+            _ => 0,
+        }
+    }
+}
+
+impl<D: VirtioDevice> MmioDevice for VirtioMmio<D> {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        self.base..self.base + VIRTIO_MMIO_SIZE
+    }
+
+    fn mmio_read(&mut self, offset: usize, width: usize) -> HyperResult<u64> {
+        if offset >= CONFIG {
+            let mut bytes = [0; 8];
+            let data = bytes.get_mut(..width).ok_or(HyperError::InvalidParam)?;
+            self.transport.device.read_config(offset - CONFIG, data);
+            return Ok(u64::from_le_bytes(bytes));
+        }
+        if width != 4 || offset % 4 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(self.read_reg(offset) as u64)
+    }
+
+    fn mmio_write(&mut self, offset: usize, width: usize, val: u64) -> HyperResult<()> {
+        if offset >= CONFIG {
+            let bytes = val.to_le_bytes();
+            let data = bytes.get(..width).ok_or(HyperError::InvalidParam)?;
+            self.transport.device.write_config(offset - CONFIG, data);
+            return Ok(());
+        }
+        if width != 4 || offset % 4 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        self.write_reg(offset, val as u32);
+        Ok(())
+    }
+
+    fn irq_level(&self) -> Option<(usize, bool)> {
+        Some((self.irq, self.interrupt_status != 0))
+    }
+
+    fn has_pending_work(&self) -> bool {
+        self.transport.has_pending_work()
+    }
+
+    fn poll(&mut self, mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        let interrupts = self.transport.poll(mem);
+        if interrupts.queues != 0 {
+            self.interrupt_status |= INT_VRING;
+        }
+        if interrupts.config {
+            self.interrupt_status |= INT_CONFIG;
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, out: &mut Encoder) {
+        let transport = &self.transport;
+        out.put_u32s(&[
+            transport.status,
+            transport.device_features_sel,
+            transport.driver_features_sel,
+            transport.queue_sel,
+            self.interrupt_status,
+            transport.config_generation,
+        ]);
+        out.put_u64(transport.driver_features);
+        out.put_u64(transport.pending_notify);
+        transport.save_queues(out);
+    }
+
+    fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        let mut regs = [0; 6];
+        input.get_u32s(&mut regs)?;
+        let driver_features = input.get_u64()?;
+        let pending_notify = input.get_u64()?;
+        let transport = &mut self.transport;
+        transport.restore_queues(input)?;
+        transport.status = regs[0];
+        transport.device_features_sel = regs[1];
+        transport.driver_features_sel = regs[2];
+        transport.queue_sel = regs[3];
+        self.interrupt_status = regs[4];
+        transport.config_generation = regs[5];
+        transport.driver_features = driver_features;
+        transport.pending_notify = pending_notify;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{TestDriverQueue, TestMemory};
+    use super::super::{
+        read_config_bytes, VirtQueue, VIRTIO_F_VERSION_1, VIRTIO_ID_RNG,
+        VIRTIO_STATUS_ACKNOWLEDGE, VIRTIO_STATUS_DRIVER, VIRTIO_STATUS_DRIVER_OK,
+        VIRTIO_STATUS_FEATURES_OK, VIRTIO_TRANSPORT_FEATURES,
+    };
+    use super::*;
+
+    const IRQ: usize = 5;
+
+    struct TestDevice {
+        queue_sizes: [u16; 1],
+    }
+
+    impl VirtioDevice for TestDevice {
+        fn device_id(&self) -> u32 {
+            VIRTIO_ID_RNG
+        }
+
+        fn device_features(&self) -> u64 {
+            0
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &self.queue_sizes
+        }
+
+        fn read_config(&self, offset: usize, data: &mut [u8]) {
+            read_config_bytes(&[1, 2, 3, 4], offset, data);
+        }
+
+        fn reset(&mut self) {}
+
+        fn queue_notify(
+            &mut self,
+            index: usize,
+            queues: &mut [VirtQueue],
+            mem: &dyn GuestMemoryAccess,
+        ) -> HyperResult<()> {
+            while let Some(chain) = queues[index].pop(mem)? {
+                queues[index].add_used(mem, chain.head(), 0)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn read(mmio: &mut VirtioMmio<TestDevice>, offset: usize) -> u32 {
+        mmio.mmio_read(offset, 4).unwrap() as u32
+    }
+
+    fn write(mmio: &mut VirtioMmio<TestDevice>, offset: usize, val: u32) {
+        mmio.mmio_write(offset, 4, val as u64).unwrap();
+    }
+
+    #[test]
+    fn registers_identify_the_device() {
+        let mut mmio = VirtioMmio::new(0x1000_0000, IRQ, TestDevice { queue_sizes: [16] }).unwrap();
+        assert_eq!(mmio.mmio_range(), 0x1000_0000..0x1000_0000 + VIRTIO_MMIO_SIZE);
+        assert_eq!(read(&mut mmio, MAGIC_VALUE), MAGIC);
+        assert_eq!(read(&mut mmio, VERSION_REG), 2);
+        assert_eq!(read(&mut mmio, DEVICE_ID), VIRTIO_ID_RNG);
+        assert_eq!(read(&mut mmio, VENDOR_ID_REG), VENDOR_ID);
+        // Registers only take aligned 32-bit accesses, the configuration space any width.
+        assert_eq!(mmio.mmio_read(DEVICE_ID, 2), Err(HyperError::InvalidParam));
+        assert_eq!(mmio.mmio_read(DEVICE_ID + 2, 4), Err(HyperError::InvalidParam));
+        assert_eq!(mmio.mmio_read(CONFIG + 2, 2), Ok(0x0403));
+
+        assert_eq!(
+            read(&mut mmio, DEVICE_FEATURES) as u64,
+            VIRTIO_TRANSPORT_FEATURES & 0xffff_ffff
+        );
+        write(&mut mmio, DEVICE_FEATURES_SEL, 1);
+        assert_eq!(
+            read(&mut mmio, DEVICE_FEATURES) as u64,
+            VIRTIO_TRANSPORT_FEATURES >> 32
+        );
+        write(&mut mmio, QUEUE_SEL, 0);
+        assert_eq!(read(&mut mmio, QUEUE_NUM_MAX), 16);
+        write(&mut mmio, QUEUE_SEL, 1);
+        assert_eq!(read(&mut mmio, QUEUE_NUM_MAX), 0);
+    }
+
+    #[test]
+    fn handshake_queue_setup_and_interrupts() {
+        let mut mmio = VirtioMmio::new(0x1000_0000, IRQ, TestDevice { queue_sizes: [16] }).unwrap();
+        let mem = TestMemory::new(0x10000);
+        let mut driver = TestDriverQueue::new(0, 16);
+
+        let driver_status = VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER;
+        write(&mut mmio, STATUS, driver_status);
+        // Without VIRTIO_F_VERSION_1 the device refuses the features.
+        write(&mut mmio, STATUS, driver_status | VIRTIO_STATUS_FEATURES_OK);
+        assert_eq!(read(&mut mmio, STATUS), driver_status);
+        write(&mut mmio, DRIVER_FEATURES_SEL, 1);
+        write(&mut mmio, DRIVER_FEATURES, (VIRTIO_F_VERSION_1 >> 32) as u32);
+        write(&mut mmio, STATUS, driver_status | VIRTIO_STATUS_FEATURES_OK);
+        assert_eq!(read(&mut mmio, STATUS), driver_status | VIRTIO_STATUS_FEATURES_OK);
+
+        write(&mut mmio, QUEUE_SEL, 0);
+        write(&mut mmio, QUEUE_NUM, 16);
+        write(&mut mmio, QUEUE_DESC_LOW, 0);
+        write(&mut mmio, QUEUE_DESC_HIGH, 0);
+        write(&mut mmio, QUEUE_DRIVER_LOW, 0x1000);
+        write(&mut mmio, QUEUE_DRIVER_HIGH, 0);
+        write(&mut mmio, QUEUE_DEVICE_LOW, 0x2000);
+        write(&mut mmio, QUEUE_DEVICE_HIGH, 0);
+        assert_eq!(read(&mut mmio, QUEUE_READY), 0);
+        write(&mut mmio, QUEUE_READY, 1);
+        assert_eq!(read(&mut mmio, QUEUE_READY), 1);
+        let status = driver_status | VIRTIO_STATUS_FEATURES_OK | VIRTIO_STATUS_DRIVER_OK;
+        write(&mut mmio, STATUS, status);
+        assert!(mmio.is_active());
+
+        driver.add(&mem, b"a", 0);
+        assert!(!mmio.has_pending_work());
+        write(&mut mmio, QUEUE_NOTIFY, 0);
+        assert!(mmio.has_pending_work());
+        mmio.poll(&mem).unwrap();
+        assert_eq!(driver.used(&mem), [(0, 0)]);
+        assert_eq!(read(&mut mmio, INTERRUPT_STATUS), INT_VRING);
+        assert_eq!(mmio.irq_level(), Some((IRQ, true)));
+
+        // The line stays raised until the driver acknowledges every reported cause.
+        write(&mut mmio, INTERRUPT_ACK, INT_CONFIG);
+        assert_eq!(mmio.irq_level(), Some((IRQ, true)));
+        write(&mut mmio, INTERRUPT_ACK, INT_VRING);
+        assert_eq!(read(&mut mmio, INTERRUPT_STATUS), 0);
+        assert_eq!(mmio.irq_level(), Some((IRQ, false)));
+
+        // A reset disables the queue and drops pending interrupts.
+        driver.add(&mem, b"b", 0);
+        write(&mut mmio, QUEUE_NOTIFY, 0);
+        mmio.poll(&mem).unwrap();
+        assert_eq!(read(&mut mmio, INTERRUPT_STATUS), INT_VRING);
+        write(&mut mmio, STATUS, 0);
+        assert_eq!(read(&mut mmio, STATUS), 0);
+        assert_eq!(read(&mut mmio, INTERRUPT_STATUS), 0);
+        assert_eq!(read(&mut mmio, QUEUE_READY), 0);
+        assert!(!mmio.is_active());
+    }
+}
@@ -0,0 +1,274 @@
+//! Virtio devices (virtio 1.2).
+//!
+//! A device model implements [`VirtioDevice`]: it describes its features, queues and
+//! configuration space, and processes the buffers the driver places on its queues. A transport,
+//! [`VirtioMmio`] or [`VirtioPci`], exposes the model to the guest, negotiates features, keeps the
+//! [`VirtQueue`]s and raises the interrupts.
+//!
+//! Queues are processed on the hart that took the guest's notification, right after the trapped
+//! store, and before a vCPU of the VM enters the guest when a device has work that did not come
+//! from the guest, e.g. input fed by the VMM.
+
+pub mod balloon;
+pub mod blk;
+pub mod console;
+mod mmio;
+pub mod net;
+mod pci;
+mod queue;
+pub mod rng;
+mod transport;
+pub mod vsock;
+
+pub use balloon::VirtioBalloon;
+pub use blk::{BlockBackend, BlockPartition, RamDisk, VirtioBlk};
+pub use console::VirtioConsole;
+pub use mmio::{VirtioMmio, VIRTIO_MMIO_SIZE};
+pub use net::VirtioNet;
+pub use pci::{VirtioPci, VIRTIO_PCI_BAR_SIZE};
+pub use queue::{DescChain, Descriptor, VirtQueue};
+pub use rng::{RngRateLimit, VirtioRng};
+pub use vsock::{VirtioVsock, VsockHost, VsockState, VsockStream};
+
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestMemoryAccess, HyperResult};
+
+/// Device ID of a network card.
+pub const VIRTIO_ID_NET: u32 = 1;
+/// Device ID of a block device.
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+/// Device ID of a console.
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+/// Device ID of an entropy source.
+pub const VIRTIO_ID_RNG: u32 = 4;
+/// Device ID of a memory balloon.
+pub const VIRTIO_ID_BALLOON: u32 = 5;
+/// Device ID of a socket device.
+pub const VIRTIO_ID_VSOCK: u32 = 19;
+
+/// The guest OS noticed the device.
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
+/// The guest OS knows how to drive the device.
+pub const VIRTIO_STATUS_DRIVER: u32 = 2;
+/// The driver is set up and ready to drive the device.
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+/// The driver acknowledged the features it understands, and negotiation is complete.
+pub const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+/// The device hit an error it cannot recover from without a reset.
+pub const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+/// The driver gave up on the device.
+pub const VIRTIO_STATUS_FAILED: u32 = 128;
+
+/// The driver can use indirect descriptor tables.
+pub const VIRTIO_F_RING_INDIRECT_DESC: u64 = 1 << 28;
+/// Interrupts and notifications are suppressed with the used and available event indices.
+pub const VIRTIO_F_RING_EVENT_IDX: u64 = 1 << 29;
+/// The device complies with virtio 1.0 and later.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Features every device gets from the transport and the queue implementation.
+pub const VIRTIO_TRANSPORT_FEATURES: u64 =
+    VIRTIO_F_RING_INDIRECT_DESC | VIRTIO_F_RING_EVENT_IDX | VIRTIO_F_VERSION_1;
+
+/// A virtio device model, independent of the transport it sits behind.
+pub trait VirtioDevice: Send {
+    /// The virtio device ID.
+    fn device_id(&self) -> u32;
+
+    /// The device specific feature bits offered to the driver. The transport adds
+    /// [`VIRTIO_TRANSPORT_FEATURES`].
+    fn device_features(&self) -> u64;
+
+    /// The maximum size of each queue of the device. Its length is the number of queues.
+    fn queue_max_sizes(&self) -> &[u16];
+
+    /// Reads `data.len()` bytes at `offset` of the device configuration space. Bytes past its
+    /// end read as zero.
+    fn read_config(&self, offset: usize, data: &mut [u8]);
+
+    /// Writes `data` at `offset` of the device configuration space. Read-only fields ignore it.
+    fn write_config(&mut self, _offset: usize, _data: &[u8]) {}
+
+    /// Called once the driver set `DRIVER_OK`, with the features it accepted.
+    fn activate(&mut self, _features: u64) -> HyperResult<()> {
+        Ok(())
+    }
+
+    /// Returns the device to its state before the driver found it. The transport resets the
+    /// queues.
+    fn reset(&mut self);
+
+    /// Processes the buffers the driver placed on queue `index` and notified the device of.
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [VirtQueue],
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()>;
+
+    /// Whether the device has work the driver did not ask for, e.g. received data to place in
+    /// the driver's buffers. [`poll`](Self::poll) is called before the guest runs again if so.
+    fn has_pending_work(&self) -> bool {
+        false
+    }
+
+    /// Does the work announced by [`has_pending_work`](Self::has_pending_work).
+    fn poll(&mut self, _queues: &mut [VirtQueue], _mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        Ok(())
+    }
+
+    /// Whether the configuration space changed since the previous call, so that the driver is
+    /// told with a configuration change interrupt.
+    fn take_config_changed(&mut self) -> bool {
+        false
+    }
+
+    /// Appends the device's state to a snapshot. The transport saves the queues.
+    fn save_state(&self, _out: &mut Encoder) {}
+
+    /// Loads the state written by `save_state`.
+    fn restore_state(&mut self, _input: &mut Decoder) -> HyperResult<()> {
+        Ok(())
+    }
+}
+
+/// Copies the bytes of `config` at `offset` into `data`, for
+/// [`VirtioDevice::read_config`] implementations that keep their configuration space as bytes.
+pub fn read_config_bytes(config: &[u8], offset: usize, data: &mut [u8]) {
+    data.fill(0);
+    if offset < config.len() {
+        let len = data.len().min(config.len() - offset);
+        data[..len].copy_from_slice(&config[offset..offset + len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    use super::VirtQueue;
+    use crate::{GuestMemoryAccess, GuestPhysAddr, HyperError, HyperResult};
+
+    /// Guest memory starting at guest physical address 0.
+    pub struct TestMemory(RefCell<Vec<u8>>);
+
+    impl TestMemory {
+        pub fn new(size: usize) -> Self {
+            Self(RefCell::new(vec![0; size]))
+        }
+    }
+
+    impl GuestMemoryAccess for TestMemory {
+        fn read_guest(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()> {
+            let mem = self.0.borrow();
+            let src = mem.get(gpa..gpa + buf.len()).ok_or(HyperError::PageFault)?;
+            buf.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn write_guest(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HyperResult<()> {
+            let mut mem = self.0.borrow_mut();
+            let dst = mem
+                .get_mut(gpa..gpa + buf.len())
+                .ok_or(HyperError::PageFault)?;
+            dst.copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    /// The driver side of a queue whose rings and buffers are in a `TestMemory`, starting at
+    /// `base`: the descriptor table, then the available ring and the used ring 4K apart, then
+    /// the buffers.
+    pub struct TestDriverQueue {
+        base: GuestPhysAddr,
+        size: u16,
+        next_desc: u16,
+        avail_idx: u16,
+        used_seen: u16,
+        next_buf: GuestPhysAddr,
+    }
+
+    impl TestDriverQueue {
+        pub fn new(base: GuestPhysAddr, size: u16) -> Self {
+            Self {
+                base,
+                size,
+                next_desc: 0,
+                avail_idx: 0,
+                used_seen: 0,
+                next_buf: base + 0x3000,
+            }
+        }
+
+        /// The device side of the queue, set up and enabled.
+        pub fn queue(&self) -> VirtQueue {
+            let mut queue = VirtQueue::new(self.size);
+            queue.set_addrs(self.base, self.base + 0x1000, self.base + 0x2000);
+            queue.set_ready(true);
+            queue
+        }
+
+        /// Makes a chain of a buffer holding `readable` and a writable buffer of `writable_len`
+        /// bytes available, leaving out empty ones. Returns the head and the address of the
+        /// writable buffer.
+        pub fn add(
+            &mut self,
+            mem: &TestMemory,
+            readable: &[u8],
+            writable_len: u32,
+        ) -> (u16, GuestPhysAddr) {
+            let mut bufs = Vec::new();
+            if !readable.is_empty() {
+                let addr = self.alloc(readable.len());
+                mem.write_guest(addr, readable).unwrap();
+                bufs.push((addr, readable.len() as u32, 0u16));
+            }
+            let writable = self.alloc(writable_len as usize);
+            if writable_len > 0 {
+                bufs.push((writable, writable_len, 2));
+            }
+            let head = self.next_desc;
+            for (i, &(addr, len, flags)) in bufs.iter().enumerate() {
+                let index = self.next_desc;
+                self.next_desc = (self.next_desc + 1) % self.size;
+                let last = i == bufs.len() - 1;
+                let flags = if last { flags } else { flags | 1 };
+                let desc = self.base + index as usize * 16;
+                mem.write_guest_u64(desc, addr as u64).unwrap();
+                mem.write_guest_u32(desc + 8, len).unwrap();
+                mem.write_guest(desc + 12, &flags.to_le_bytes()).unwrap();
+                mem.write_guest(desc + 14, &self.next_desc.to_le_bytes())
+                    .unwrap();
+            }
+            let slot = self.base + 0x1000 + 4 + (self.avail_idx % self.size) as usize * 2;
+            mem.write_guest(slot, &head.to_le_bytes()).unwrap();
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            mem.write_guest(self.base + 0x1002, &self.avail_idx.to_le_bytes())
+                .unwrap();
+            (head, writable)
+        }
+
+        /// The chains the device returned since the previous call, with the lengths written.
+        pub fn used(&mut self, mem: &TestMemory) -> Vec<(u16, u32)> {
+            let used = self.base + 0x2000;
+            let mut idx = [0; 2];
+            mem.read_guest(used + 2, &mut idx).unwrap();
+            let idx = u16::from_le_bytes(idx);
+            let mut entries = Vec::new();
+            while self.used_seen != idx {
+                let elem = used + 4 + (self.used_seen % self.size) as usize * 8;
+                let head = mem.read_guest_u32(elem).unwrap() as u16;
+                entries.push((head, mem.read_guest_u32(elem + 4).unwrap()));
+                self.used_seen = self.used_seen.wrapping_add(1);
+            }
+            entries
+        }
+
+        fn alloc(&mut self, len: usize) -> GuestPhysAddr {
+            let addr = self.next_buf;
+            self.next_buf += (len + 15) & !15;
+            addr
+        }
+    }
+}
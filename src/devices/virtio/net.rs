@@ -0,0 +1,200 @@
+//! A virtio network device (virtio 1.2, section 5.1) attached to a [`VirtualSwitch`] port.
+//!
+//! The device offers no offloads: every frame is a plain Ethernet frame of at most
+//! [`MAX_FRAME_SIZE`] bytes behind a zeroed `virtio_net_hdr`.
+//!
+//! [`VirtualSwitch`]: crate::devices::vswitch::VirtualSwitch
+//! [`MAX_FRAME_SIZE`]: crate::devices::vswitch::MAX_FRAME_SIZE
+
+use super::{read_config_bytes, VirtQueue, VirtioDevice, VIRTIO_ID_NET};
+use crate::devices::vswitch::{MacAddr, SwitchPort, ETH_HEADER_SIZE};
+use crate::{GuestMemoryAccess, HyperResult};
+
+const QUEUE_SIZE: u16 = 256;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// The MAC address is in the configuration space.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// The link status is in the configuration space.
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// Size of `virtio_net_hdr` with `num_buffers`, as used by virtio 1.0 and later.
+const NET_HEADER_SIZE: usize = 12;
+const CONFIG_SIZE: usize = 8;
+
+/// A virtio network card whose frames go through a switch port.
+pub struct VirtioNet {
+    port: SwitchPort,
+    queue_sizes: [u16; 2],
+    // Frames are waiting for the driver to add receive buffers.
+    rx_starved: bool,
+}
+
+impl VirtioNet {
+    /// Creates a network card on `port`. The guest sees the MAC address the port was created
+    /// with.
+    pub fn new(port: SwitchPort) -> Self {
+        Self {
+            port,
+            queue_sizes: [QUEUE_SIZE; 2],
+            rx_starved: false,
+        }
+    }
+
+    /// The MAC address of the card.
+    pub fn mac(&self) -> MacAddr {
+        self.port.mac()
+    }
+}
+
+// Private methods implementation
+impl VirtioNet {
+    /// Hands the frames the driver sent to the switch.
+    fn transmit(&mut self, queue: &mut VirtQueue, mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        while let Some(chain) = queue.pop(mem)? {
+            let packet = chain.read_all(mem)?;
+            match packet.get(NET_HEADER_SIZE..) {
+                Some(frame) if frame.len() >= ETH_HEADER_SIZE => self.port.send(frame),
+                _ => debug!("virtio-net: dropped runt frame of {} bytes", packet.len()),
+            }
+            queue.add_used(mem, chain.head(), 0)?;
+        }
+        Ok(())
+    }
+
+    /// Places the frames waiting at the port in the driver's receive buffers.
+    fn receive(&mut self, queue: &mut VirtQueue, mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        while self.port.has_frames() {
+            let chain = match queue.pop(mem)? {
+                Some(chain) => chain,
+                None => {
+                    self.rx_starved = true;
+                    return Ok(());
+                }
+            };
+            let frame = match self.port.recv() {
+                Some(frame) => frame,
+                None => break,
+            };
+            if chain.writable_len() < NET_HEADER_SIZE + frame.len() {
+                debug!(
+                    "virtio-net: receive buffer too small for {} bytes",
+                    frame.len()
+                );
+                queue.add_used(mem, chain.head(), 0)?;
+                continue;
+            }
+            let mut header = [0; NET_HEADER_SIZE];
+            // num_buffers
+            header[10] = 1;
+            chain.write_at(mem, 0, &header)?;
+            chain.write_at(mem, NET_HEADER_SIZE, &frame)?;
+            queue.add_used(mem, chain.head(), (NET_HEADER_SIZE + frame.len()) as u32)?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let mut config = [0; CONFIG_SIZE];
+        config[..6].copy_from_slice(&self.port.mac());
+        config[6..8].copy_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn reset(&mut self) {
+        // Frames that arrived for the old driver are stale.
+        while self.port.recv().is_some() {}
+        self.rx_starved = false;
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [VirtQueue],
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()> {
+        match index {
+            RX_QUEUE => {
+                self.rx_starved = false;
+                self.receive(&mut queues[RX_QUEUE], mem)
+            }
+            TX_QUEUE => self.transmit(&mut queues[TX_QUEUE], mem),
+            _ => Ok(()),
+        }
+    }
+
+    fn has_pending_work(&self) -> bool {
+        !self.rx_starved && self.port.has_frames()
+    }
+
+    fn poll(&mut self, queues: &mut [VirtQueue], mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        self.receive(&mut queues[RX_QUEUE], mem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{TestDriverQueue, TestMemory};
+    use super::*;
+    use crate::devices::vswitch::VirtualSwitch;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn frames_travel_between_two_vms() {
+        let switch = VirtualSwitch::new();
+        let mac_a = [2, 0, 0, 0, 0, 1];
+        let mac_b = [2, 0, 0, 0, 0, 2];
+        let mut nets = [
+            VirtioNet::new(switch.add_port(mac_a).unwrap()),
+            VirtioNet::new(switch.add_port(mac_b).unwrap()),
+        ];
+        let mem = TestMemory::new(0x40000);
+        let mut drivers: Vec<_> = (0..4)
+            .map(|i| TestDriverQueue::new(0x10000 * i, 16))
+            .collect();
+        let mut queues: Vec<_> = drivers.iter().map(TestDriverQueue::queue).collect();
+        let (queues_a, queues_b) = queues.split_at_mut(2);
+
+        let mut config = [0; 6];
+        nets[1].read_config(0, &mut config);
+        assert_eq!(config, mac_b);
+
+        let mut packet = vec![0; NET_HEADER_SIZE];
+        packet.extend_from_slice(&mac_b);
+        packet.extend_from_slice(&mac_a);
+        packet.extend_from_slice(&[0x08, 0x00, 0x45]);
+        drivers[1].add(&mem, &packet, 0);
+        nets[0].queue_notify(TX_QUEUE, queues_a, &mem).unwrap();
+        assert_eq!(drivers[1].used(&mem).len(), 1);
+
+        // B has no receive buffers yet.
+        assert!(nets[1].has_pending_work());
+        nets[1].poll(queues_b, &mem).unwrap();
+        assert!(!nets[1].has_pending_work());
+        let (_, buf) = drivers[2].add(&mem, &[], 2048);
+        nets[1].queue_notify(RX_QUEUE, queues_b, &mem).unwrap();
+        let used = drivers[2].used(&mem);
+        assert_eq!(used, [(0, packet.len() as u32)]);
+        let mut received = vec![0; packet.len()];
+        mem.read_guest(buf, &mut received).unwrap();
+        assert_eq!(&received[NET_HEADER_SIZE..], &packet[NET_HEADER_SIZE..]);
+    }
+}
@@ -0,0 +1,663 @@
+//! The virtio PCI transport, modern interface only (virtio 1.2, section 4.1).
+//!
+//! Everything the driver touches is in BAR 0: the common configuration structure, the ISR
+//! status, the device configuration space, the notification area and the MSI-X table, each
+//! described by a vendor specific capability. Interrupts are MSI-X messages once the driver
+//! enabled MSI-X, and INTA otherwise.
+
+use alloc::vec::Vec;
+
+use super::transport::{Interrupts, QueueAddr, VirtioTransport};
+use super::{VirtioDevice, VIRTIO_ID_BLOCK, VIRTIO_ID_CONSOLE, VIRTIO_ID_NET};
+use crate::devices::pci::{MsixTable, PciBar, PciConfig, PciDevice, PCI_CAP_ID_VNDR};
+use crate::devices::MsiMessage;
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestMemoryAccess, HyperError, HyperResult};
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+// Modern devices have the virtio device ID added to this.
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+const VIRTIO_PCI_SUBSYSTEM_ID: u16 = 0x1100;
+const VIRTIO_PCI_REVISION: u8 = 1;
+
+/// Size of BAR 0.
+pub const VIRTIO_PCI_BAR_SIZE: u64 = 0x8000;
+
+// Layout of BAR 0.
+const COMMON_CFG: usize = 0x0000;
+const COMMON_CFG_SIZE: usize = 0x38;
+const ISR_CFG: usize = 0x1000;
+const ISR_CFG_SIZE: usize = 4;
+const DEVICE_CFG: usize = 0x2000;
+const DEVICE_CFG_SIZE: usize = 0x1000;
+const NOTIFY_CFG: usize = 0x3000;
+const NOTIFY_CFG_SIZE: usize = 0x1000;
+const MSIX_TABLE: usize = 0x4000;
+const MSIX_TABLE_SIZE: usize = 0x2000;
+const MSIX_PBA: usize = 0x6000;
+const MSIX_PBA_SIZE: usize = 0x2000;
+// Queue `n` is notified by a store at `NOTIFY_CFG + n * NOTIFY_OFF_MULTIPLIER`.
+const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+
+// Types of the vendor specific capabilities.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// Fields of the common configuration structure.
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0c;
+const CONFIG_MSIX_VECTOR: usize = 0x10;
+const NUM_QUEUES: usize = 0x12;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_MSIX_VECTOR: usize = 0x1a;
+const QUEUE_ENABLE: usize = 0x1c;
+const QUEUE_NOTIFY_OFF: usize = 0x1e;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+/// The driver did not assign an MSI-X vector to the event.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+// ISR status bits.
+const ISR_QUEUE: u8 = 1 << 0;
+const ISR_CONFIG: u8 = 1 << 1;
+
+/// A structure of BAR 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Region {
+    Common,
+    Isr,
+    Device,
+    Notify,
+    MsixTable,
+    MsixPba,
+}
+
+impl Region {
+    /// The structure an access of `width` bytes at `offset` of BAR 0 falls in, and the offset
+    /// within it.
+    fn decode(offset: usize, width: usize) -> Option<(Self, usize)> {
+        [
+            (Region::Common, COMMON_CFG, COMMON_CFG_SIZE),
+            (Region::Isr, ISR_CFG, ISR_CFG_SIZE),
+            (Region::Device, DEVICE_CFG, DEVICE_CFG_SIZE),
+            (Region::Notify, NOTIFY_CFG, NOTIFY_CFG_SIZE),
+            (Region::MsixTable, MSIX_TABLE, MSIX_TABLE_SIZE),
+            (Region::MsixPba, MSIX_PBA, MSIX_PBA_SIZE),
+        ]
+        .into_iter()
+        .find(|&(_, start, size)| offset >= start && offset + width <= start + size)
+        .map(|(region, start, _)| (region, offset - start))
+    }
+}
+
+/// A virtio device behind the virtio PCI transport, to plug into a
+/// [`PciHostBridge`](crate::devices::PciHostBridge).
+///
+/// Buffers are processed when the bridge polls the function, after the guest's notification
+/// store and whenever the device has pending work. Queues sharing an MSI-X vector raise it once
+/// per poll.
+pub struct VirtioPci<D: VirtioDevice> {
+    transport: VirtioTransport<D>,
+    config: PciConfig,
+    msix: MsixTable,
+    config_vector: u16,
+    queue_vectors: Vec<u16>,
+    isr: u8,
+    // MSIs sent and not handed to the interrupt controller yet.
+    msis: Vec<MsiMessage>,
+}
+
+impl<D: VirtioDevice> VirtioPci<D> {
+    /// Creates the transport for `device`, with an MSI-X vector for each queue and one for
+    /// configuration changes.
+    pub fn new(device: D) -> HyperResult<Self> {
+        let transport = VirtioTransport::new(device)?;
+        let device_id = transport.device.device_id();
+        let class = match device_id {
+            VIRTIO_ID_NET => 0x02_0000,
+            VIRTIO_ID_BLOCK => 0x01_8000,
+            VIRTIO_ID_CONSOLE => 0x07_8000,
+            _ => 0xff_0000,
+        };
+        let mut config = PciConfig::new(
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + device_id as u16,
+            class,
+            VIRTIO_PCI_REVISION,
+        );
+        config.set_subsystem(VIRTIO_PCI_VENDOR_ID, VIRTIO_PCI_SUBSYSTEM_ID);
+        config.set_interrupt_pin(1)?;
+        config.add_bar(
+            0,
+            PciBar {
+                size: VIRTIO_PCI_BAR_SIZE,
+                is_64bit: true,
+                prefetchable: false,
+            },
+        )?;
+        add_virtio_cap(
+            &mut config,
+            VIRTIO_PCI_CAP_COMMON_CFG,
+            COMMON_CFG,
+            COMMON_CFG_SIZE,
+            &[],
+        )?;
+        add_virtio_cap(
+            &mut config,
+            VIRTIO_PCI_CAP_NOTIFY_CFG,
+            NOTIFY_CFG,
+            NOTIFY_CFG_SIZE,
+            &NOTIFY_OFF_MULTIPLIER.to_le_bytes(),
+        )?;
+        add_virtio_cap(
+            &mut config,
+            VIRTIO_PCI_CAP_ISR_CFG,
+            ISR_CFG,
+            ISR_CFG_SIZE,
+            &[],
+        )?;
+        add_virtio_cap(
+            &mut config,
+            VIRTIO_PCI_CAP_DEVICE_CFG,
+            DEVICE_CFG,
+            DEVICE_CFG_SIZE,
+            &[],
+        )?;
+        let num_queues = transport.queues.len();
+        let msix = MsixTable::new(
+            &mut config,
+            num_queues + 1,
+            0,
+            MSIX_TABLE as u32,
+            MSIX_PBA as u32,
+        )?;
+        Ok(Self {
+            transport,
+            config,
+            msix,
+            config_vector: VIRTIO_MSI_NO_VECTOR,
+            queue_vectors: vec![VIRTIO_MSI_NO_VECTOR; num_queues],
+            isr: 0,
+            msis: Vec::new(),
+        })
+    }
+
+    /// The device model.
+    pub fn device(&self) -> &D {
+        &self.transport.device
+    }
+
+    /// The device model, e.g. for the VMM to feed it input. Work queued this way is picked up
+    /// through [`VirtioDevice::has_pending_work`].
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.transport.device
+    }
+
+    /// Whether the driver set `DRIVER_OK` and the device did not fail since.
+    pub fn is_active(&self) -> bool {
+        self.transport.is_active()
+    }
+}
+
+// Private methods implementation
+impl<D: VirtioDevice> VirtioPci<D> {
+    fn reset(&mut self) {
+        self.transport.reset();
+        self.config_vector = VIRTIO_MSI_NO_VECTOR;
+        self.queue_vectors.fill(VIRTIO_MSI_NO_VECTOR);
+        self.isr = 0;
+    }
+
+    /// `vector` if the function has it, `VIRTIO_MSI_NO_VECTOR` otherwise, telling the driver
+    /// the assignment failed.
+    fn checked_vector(&self, vector: u16) -> u16 {
+        if (vector as usize) < self.msix.vectors() {
+            vector
+        } else {
+            VIRTIO_MSI_NO_VECTOR
+        }
+    }
+
+    fn read_common(&self, offset: usize, width: usize) -> u64 {
+        let transport = &self.transport;
+        let queue = transport.selected_queue();
+        let queue_vector = self
+            .queue_vectors
+            .get(transport.queue_sel as usize)
+            .copied()
+            .unwrap_or(VIRTIO_MSI_NO_VECTOR);
+        let mut regs = [0u8; COMMON_CFG_SIZE];
+        let mut put = |field: usize, bytes: &[u8]| {
+            regs[field..field + bytes.len()].copy_from_slice(bytes);
+        };
+        put(
+            DEVICE_FEATURE_SELECT,
+            &transport.device_features_sel.to_le_bytes(),
+        );
+        put(
+            DEVICE_FEATURE,
+            &transport.device_features_word().to_le_bytes(),
+        );
+        put(
+            DRIVER_FEATURE_SELECT,
+            &transport.driver_features_sel.to_le_bytes(),
+        );
+        let driver_features = match transport.driver_features_sel {
+            0 => transport.driver_features as u32,
+            1 => (transport.driver_features >> 32) as u32,
+            _ => 0,
+        };
+        put(DRIVER_FEATURE, &driver_features.to_le_bytes());
+        put(CONFIG_MSIX_VECTOR, &self.config_vector.to_le_bytes());
+        put(NUM_QUEUES, &(transport.queues.len() as u16).to_le_bytes());
+        put(DEVICE_STATUS, &[transport.status as u8]);
+        put(CONFIG_GENERATION, &[transport.config_generation as u8]);
+        put(QUEUE_SELECT, &(transport.queue_sel as u16).to_le_bytes());
+        if let Some(queue) = queue {
+            put(QUEUE_SIZE, &queue.size().to_le_bytes());
+            put(QUEUE_MSIX_VECTOR, &queue_vector.to_le_bytes());
+            put(QUEUE_ENABLE, &(queue.is_ready() as u16).to_le_bytes());
+            put(
+                QUEUE_NOTIFY_OFF,
+                &(transport.queue_sel as u16).to_le_bytes(),
+            );
+            for (field, which) in [
+                (QUEUE_DESC, QueueAddr::Desc),
+                (QUEUE_DRIVER, QueueAddr::Driver),
+                (QUEUE_DEVICE, QueueAddr::Device),
+            ] {
+                put(field, &transport.queue_addr(which, false).to_le_bytes());
+                put(field + 4, &transport.queue_addr(which, true).to_le_bytes());
+            }
+        }
+        let mut bytes = [0; 8];
+        bytes[..width].copy_from_slice(&regs[offset..offset + width]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn write_common(&mut self, offset: usize, width: usize, val: u64) {
+        let transport = &mut self.transport;
+        match (offset, width) {
+            (DEVICE_FEATURE_SELECT, 4) => transport.device_features_sel = val as u32,
+            (DRIVER_FEATURE_SELECT, 4) => transport.driver_features_sel = val as u32,
+            (DRIVER_FEATURE, 4) => transport.write_driver_features(val as u32),
+            (CONFIG_MSIX_VECTOR, 2) => self.config_vector = self.checked_vector(val as u16),
+            (DEVICE_STATUS, 1) => {
+                if val == 0 {
+                    self.reset();
+                } else {
+                    transport.write_status(val as u32);
+                }
+            }
+            (QUEUE_SELECT, 2) => transport.queue_sel = val as u32,
+            (QUEUE_SIZE, 2) => {
+                if let Some(queue) = transport.selected_queue_mut() {
+                    if queue.set_size(val as u16).is_err() {
+                        warn!("virtio-pci: bad queue size {}", val);
+                    }
+                }
+            }
+            (QUEUE_MSIX_VECTOR, 2) => {
+                let vector = self.checked_vector(val as u16);
+                if let Some(slot) = self
+                    .queue_vectors
+                    .get_mut(self.transport.queue_sel as usize)
+                {
+                    *slot = vector;
+                }
+            }
+            // Queues are disabled by a reset, not by writing 0.
+            (QUEUE_ENABLE, 2) if val == 1 => transport.set_queue_ready(true),
+            (QUEUE_DESC.., 4 | 8) if offset % width == 0 => {
+                let which = match offset & !0x7 {
+                    QUEUE_DESC => QueueAddr::Desc,
+                    QUEUE_DRIVER => QueueAddr::Driver,
+                    _ => QueueAddr::Device,
+                };
+                transport.write_queue_addr(which, offset & 0x4 != 0, val as u32);
+                if width == 8 {
+                    transport.write_queue_addr(which, true, (val >> 32) as u32);
+                }
+            }
+            _ => debug!("virtio-pci: ignored write of {:#x} at {:#x}", val, offset),
+        }
+    }
+
+    /// Raises the interrupts the transport asked for: MSI-X messages if the driver enabled
+    /// MSI-X, the ISR status and INTA otherwise.
+    fn raise(&mut self, interrupts: Interrupts) {
+        if !self.msix.enabled(&self.config) {
+            if interrupts.queues != 0 {
+                self.isr |= ISR_QUEUE;
+            }
+            if interrupts.config {
+                self.isr |= ISR_CONFIG;
+            }
+            return;
+        }
+        let mut vectors: Vec<u16> = (0..self.queue_vectors.len())
+            .filter(|&index| interrupts.queues & (1 << index) != 0)
+            .map(|index| self.queue_vectors[index])
+            .collect();
+        if interrupts.config {
+            vectors.push(self.config_vector);
+        }
+        vectors.sort_unstable();
+        vectors.dedup();
+        for vector in vectors {
+            if vector == VIRTIO_MSI_NO_VECTOR {
+                continue;
+            }
+            if let Some(msi) = self.msix.signal(&self.config, vector as usize) {
+                self.msis.push(msi);
+            }
+        }
+    }
+
+    /// Sends the pending MSI-X vectors the driver unmasked.
+    fn send_unmasked(&mut self) {
+        let msis = &mut self.msis;
+        self.msix.take_unmasked(&self.config, |msi| msis.push(msi));
+    }
+}
+
+impl<D: VirtioDevice> PciDevice for VirtioPci<D> {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfig {
+        &mut self.config
+    }
+
+    fn config_written(&mut self, _offset: usize, _width: usize) {
+        // The driver may have cleared the function mask.
+        self.send_unmasked();
+    }
+
+    fn bar_read(&mut self, bar: usize, offset: usize, width: usize) -> HyperResult<u64> {
+        if bar != 0 {
+            return Err(HyperError::NotFound);
+        }
+        let val = match Region::decode(offset, width) {
+            Some((Region::Common, offset)) => self.read_common(offset, width),
+            Some((Region::Isr, 0)) => {
+                // Reading the ISR status acknowledges the interrupt.
+                let isr = self.isr;
+                self.isr = 0;
+                isr as u64
+            }
+            Some((Region::Device, offset)) => {
+                let mut bytes = [0; 8];
+                let data = bytes.get_mut(..width).ok_or(HyperError::InvalidParam)?;
+                self.transport.device.read_config(offset, data);
+                u64::from_le_bytes(bytes)
+            }
+            Some((Region::MsixTable, offset)) => self.msix.read_table(offset, width),
+            Some((Region::MsixPba, offset)) => self.msix.read_pba(offset, width),
+            _ => 0,
+        };
+        Ok(val)
+    }
+
+    fn bar_write(&mut self, bar: usize, offset: usize, width: usize, val: u64) -> HyperResult<()> {
+        if bar != 0 {
+            return Err(HyperError::NotFound);
+        }
+        match Region::decode(offset, width) {
+            Some((Region::Common, offset)) => self.write_common(offset, width, val),
+            Some((Region::Device, offset)) => {
+                let bytes = val.to_le_bytes();
+                let data = bytes.get(..width).ok_or(HyperError::InvalidParam)?;
+                self.transport.device.write_config(offset, data);
+            }
+            Some((Region::Notify, offset)) => {
+                self.transport
+                    .notify(offset / NOTIFY_OFF_MULTIPLIER as usize);
+            }
+            Some((Region::MsixTable, offset)) => {
+                self.msix.write_table(offset, width, val);
+                self.send_unmasked();
+            }
+            _ => debug!("virtio-pci: ignored write of {:#x} at {:#x}", val, offset),
+        }
+        Ok(())
+    }
+
+    fn intx_level(&self) -> bool {
+        self.isr != 0 && !self.msix.enabled(&self.config)
+    }
+
+    fn take_msis(&mut self, f: &mut dyn FnMut(MsiMessage)) {
+        self.msis.drain(..).for_each(f);
+    }
+
+    fn has_pending_work(&self) -> bool {
+        self.transport.has_pending_work()
+    }
+
+    fn poll(&mut self, mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        let interrupts = self.transport.poll(mem);
+        self.raise(interrupts);
+        Ok(())
+    }
+
+    fn save_state(&self, out: &mut Encoder) {
+        let transport = &self.transport;
+        out.put_u32s(&[
+            transport.status,
+            transport.device_features_sel,
+            transport.driver_features_sel,
+            transport.queue_sel,
+            transport.config_generation,
+            self.isr as u32,
+            self.config_vector as u32,
+        ]);
+        out.put_u64(transport.driver_features);
+        out.put_u64(transport.pending_notify);
+        for vector in self.queue_vectors.iter() {
+            out.put_u16(*vector);
+        }
+        self.msix.save_state(out);
+        out.put_u32(self.msis.len() as u32);
+        for msi in self.msis.iter() {
+            out.put_u64(msi.address);
+            out.put_u32(msi.data);
+        }
+        transport.save_queues(out);
+    }
+
+    fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        let mut regs = [0; 7];
+        input.get_u32s(&mut regs)?;
+        let driver_features = input.get_u64()?;
+        let pending_notify = input.get_u64()?;
+        for vector in self.queue_vectors.iter_mut() {
+            *vector = input.get_u16()?;
+        }
+        self.msix.restore_state(input)?;
+        self.msis.clear();
+        for _ in 0..input.get_u32()? {
+            let address = input.get_u64()?;
+            let data = input.get_u32()?;
+            self.msis.push(MsiMessage { address, data });
+        }
+        let transport = &mut self.transport;
+        transport.restore_queues(input)?;
+        transport.status = regs[0];
+        transport.device_features_sel = regs[1];
+        transport.driver_features_sel = regs[2];
+        transport.queue_sel = regs[3];
+        transport.config_generation = regs[4];
+        self.isr = regs[5] as u8;
+        self.config_vector = regs[6] as u16;
+        transport.driver_features = driver_features;
+        transport.pending_notify = pending_notify;
+        Ok(())
+    }
+}
+
+/// Appends a `virtio_pci_cap` pointing at `length` bytes at `offset` of BAR 0, followed by
+/// `extra`.
+fn add_virtio_cap(
+    config: &mut PciConfig,
+    cfg_type: u8,
+    offset: usize,
+    length: usize,
+    extra: &[u8],
+) -> HyperResult<usize> {
+    let mut body = Vec::with_capacity(14 + extra.len());
+    // cap_len counts the ID and next pointer bytes too.
+    body.extend_from_slice(&[(16 + extra.len()) as u8, cfg_type, 0, 0, 0, 0]);
+    body.extend_from_slice(&(offset as u32).to_le_bytes());
+    body.extend_from_slice(&(length as u32).to_le_bytes());
+    body.extend_from_slice(extra);
+    config.add_capability(PCI_CAP_ID_VNDR, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{TestDriverQueue, TestMemory};
+    use super::super::{read_config_bytes, VirtQueue, VIRTIO_ID_RNG};
+    use super::*;
+    use crate::devices::pci::{PCI_CAPABILITY_LIST, PCI_CAP_ID_MSIX};
+
+    struct TestDevice {
+        queue_sizes: [u16; 1],
+    }
+
+    impl VirtioDevice for TestDevice {
+        fn device_id(&self) -> u32 {
+            VIRTIO_ID_RNG
+        }
+
+        fn device_features(&self) -> u64 {
+            0
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &self.queue_sizes
+        }
+
+        fn read_config(&self, offset: usize, data: &mut [u8]) {
+            read_config_bytes(&[1, 2, 3, 4], offset, data);
+        }
+
+        fn reset(&mut self) {}
+
+        fn queue_notify(
+            &mut self,
+            index: usize,
+            queues: &mut [VirtQueue],
+            mem: &dyn GuestMemoryAccess,
+        ) -> HyperResult<()> {
+            while let Some(chain) = queues[index].pop(mem)? {
+                queues[index].add_used(mem, chain.head(), 0)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn msis(pci: &mut VirtioPci<TestDevice>) -> Vec<MsiMessage> {
+        let mut msis = Vec::new();
+        pci.take_msis(&mut |msi| msis.push(msi));
+        msis
+    }
+
+    fn notify(pci: &mut VirtioPci<TestDevice>, mem: &TestMemory) {
+        pci.bar_write(0, NOTIFY_CFG, 2, 0).unwrap();
+        assert!(pci.has_pending_work());
+        pci.poll(mem).unwrap();
+    }
+
+    #[test]
+    fn queues_interrupt_through_msix_or_intx() {
+        let mut pci = VirtioPci::new(TestDevice { queue_sizes: [16] }).unwrap();
+        let mem = TestMemory::new(0x10000);
+        let mut driver = TestDriverQueue::new(0, 16);
+
+        // The driver finds the structures through the vendor specific capabilities.
+        let mut found = Vec::new();
+        let mut cap = pci.config().read(PCI_CAPABILITY_LIST, 1) as usize;
+        while cap != 0 {
+            if pci.config().read(cap, 1) as u8 == PCI_CAP_ID_VNDR {
+                let cfg_type = pci.config().read(cap + 3, 1);
+                found.push((cfg_type as u8, pci.config().read(cap + 8, 4) as usize));
+            }
+            cap = pci.config().read(cap + 1, 1) as usize;
+        }
+        assert_eq!(
+            found,
+            [
+                (VIRTIO_PCI_CAP_COMMON_CFG, COMMON_CFG),
+                (VIRTIO_PCI_CAP_NOTIFY_CFG, NOTIFY_CFG),
+                (VIRTIO_PCI_CAP_ISR_CFG, ISR_CFG),
+                (VIRTIO_PCI_CAP_DEVICE_CFG, DEVICE_CFG),
+            ]
+        );
+        assert_eq!(pci.bar_read(0, DEVICE_CFG + 2, 2), Ok(0x0403));
+
+        pci.bar_write(0, DEVICE_STATUS, 1, 3).unwrap();
+        pci.bar_write(0, DRIVER_FEATURE_SELECT, 4, 1).unwrap();
+        pci.bar_write(0, DRIVER_FEATURE, 4, 1).unwrap();
+        pci.bar_write(0, DEVICE_STATUS, 1, 11).unwrap();
+        assert_eq!(pci.bar_read(0, DEVICE_STATUS, 1), Ok(11));
+        pci.bar_write(0, QUEUE_SELECT, 2, 0).unwrap();
+        pci.bar_write(0, QUEUE_DESC, 8, 0).unwrap();
+        pci.bar_write(0, QUEUE_DRIVER, 4, 0x1000).unwrap();
+        pci.bar_write(0, QUEUE_DEVICE, 4, 0x2000).unwrap();
+        pci.bar_write(0, QUEUE_MSIX_VECTOR, 2, 0).unwrap();
+        assert_eq!(pci.bar_read(0, QUEUE_MSIX_VECTOR, 2), Ok(0));
+        // The device has two vectors.
+        pci.bar_write(0, CONFIG_MSIX_VECTOR, 2, 2).unwrap();
+        assert_eq!(pci.bar_read(0, CONFIG_MSIX_VECTOR, 2), Ok(0xffff));
+        pci.bar_write(0, QUEUE_ENABLE, 2, 1).unwrap();
+        pci.bar_write(0, DEVICE_STATUS, 1, 15).unwrap();
+        assert!(pci.is_active());
+
+        let msix = pci.config().find_capability(PCI_CAP_ID_MSIX).unwrap();
+        pci.bar_write(0, MSIX_TABLE, 8, 0xfee0_0000).unwrap();
+        pci.bar_write(0, MSIX_TABLE + 8, 4, 0x41).unwrap();
+        pci.bar_write(0, MSIX_TABLE + 12, 4, 0).unwrap();
+        pci.config_mut().write(msix + 2, 2, 0x8000);
+        pci.config_written(msix + 2, 2);
+
+        driver.add(&mem, b"a", 0);
+        notify(&mut pci, &mem);
+        assert_eq!(driver.used(&mem), [(0, 0)]);
+        let msi = MsiMessage {
+            address: 0xfee0_0000,
+            data: 0x41,
+        };
+        assert_eq!(msis(&mut pci), [msi]);
+        assert!(!pci.intx_level());
+
+        // A masked vector stays pending until the driver unmasks it.
+        pci.bar_write(0, MSIX_TABLE + 12, 4, 1).unwrap();
+        driver.add(&mem, b"b", 0);
+        notify(&mut pci, &mem);
+        assert!(msis(&mut pci).is_empty());
+        assert_eq!(pci.bar_read(0, MSIX_PBA, 8), Ok(1));
+        pci.bar_write(0, MSIX_TABLE + 12, 4, 0).unwrap();
+        assert_eq!(msis(&mut pci), [msi]);
+        assert_eq!(pci.bar_read(0, MSIX_PBA, 8), Ok(0));
+
+        // Without MSI-X, the ISR status and INTA tell the driver.
+        pci.config_mut().write(msix + 2, 2, 0);
+        driver.add(&mem, b"c", 0);
+        notify(&mut pci, &mem);
+        assert!(msis(&mut pci).is_empty());
+        assert!(pci.intx_level());
+        assert_eq!(pci.bar_read(0, ISR_CFG, 1), Ok(ISR_QUEUE as u64));
+        assert!(!pci.intx_level());
+    }
+}
@@ -0,0 +1,538 @@
+//! Split virtqueues (virtio 1.2, section 2.7), as seen from the device.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestMemoryAccess, GuestPhysAddr, HyperError, HyperResult};
+
+/// The buffer continues in the descriptor in `next`.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The buffer is write-only for the device.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// The buffer holds a table of indirect descriptors.
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/// The driver does not want an interrupt when buffers are used. Ignored with event index.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const DESC_SIZE: usize = 16;
+const USED_ELEM_SIZE: usize = 8;
+
+/// One guest buffer of a descriptor chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Descriptor {
+    /// Guest physical address of the buffer.
+    pub addr: GuestPhysAddr,
+    /// Length of the buffer in bytes.
+    pub len: u32,
+    /// Whether the device writes the buffer, as opposed to reading it.
+    pub writable: bool,
+}
+
+/// A request made available by the driver: the device-readable buffers, followed by the
+/// device-writable ones.
+#[derive(Clone, Debug)]
+pub struct DescChain {
+    head: u16,
+    descs: Vec<Descriptor>,
+}
+
+impl DescChain {
+    /// Index of the first descriptor, which identifies the chain when it is returned.
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    /// The buffers of the chain, in order.
+    pub fn descs(&self) -> &[Descriptor] {
+        &self.descs
+    }
+
+    /// Total length of the buffers the device reads.
+    pub fn readable_len(&self) -> usize {
+        self.descs
+            .iter()
+            .filter(|desc| !desc.writable)
+            .map(|desc| desc.len as usize)
+            .sum()
+    }
+
+    /// Total length of the buffers the device writes.
+    pub fn writable_len(&self) -> usize {
+        self.descs
+            .iter()
+            .filter(|desc| desc.writable)
+            .map(|desc| desc.len as usize)
+            .sum()
+    }
+
+    /// Copies the device-readable bytes starting at `offset` into `buf`, as if the readable
+    /// buffers were contiguous. Returns the number of bytes copied.
+    pub fn read_at(
+        &self,
+        mem: &dyn GuestMemoryAccess,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> HyperResult<usize> {
+        let mut done = 0;
+        for (addr, len) in self.segments(false, offset, buf.len()) {
+            mem.read_guest(addr, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(done)
+    }
+
+    /// Copies `data` into the device-writable bytes starting at `offset`, as if the writable
+    /// buffers were contiguous. Returns the number of bytes copied.
+    pub fn write_at(
+        &self,
+        mem: &dyn GuestMemoryAccess,
+        offset: usize,
+        data: &[u8],
+    ) -> HyperResult<usize> {
+        let mut done = 0;
+        for (addr, len) in self.segments(true, offset, data.len()) {
+            mem.write_guest(addr, &data[done..done + len])?;
+            done += len;
+        }
+        Ok(done)
+    }
+
+    /// Reads all device-readable bytes.
+    pub fn read_all(&self, mem: &dyn GuestMemoryAccess) -> HyperResult<Vec<u8>> {
+        let mut buf = vec![0; self.readable_len()];
+        self.read_at(mem, 0, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// The guest ranges covering `len` bytes at `offset` of the readable or writable buffers.
+    fn segments(
+        &self,
+        writable: bool,
+        mut offset: usize,
+        mut len: usize,
+    ) -> impl Iterator<Item = (GuestPhysAddr, usize)> + '_ {
+        self.descs
+            .iter()
+            .filter(move |desc| desc.writable == writable)
+            .filter_map(move |desc| {
+                let desc_len = desc.len as usize;
+                if offset >= desc_len {
+                    offset -= desc_len;
+                    return None;
+                }
+                let chunk = (desc_len - offset).min(len);
+                let addr = desc.addr + offset;
+                offset = 0;
+                len -= chunk;
+                (chunk > 0).then_some((addr, chunk))
+            })
+    }
+}
+
+/// The device side of a split virtqueue.
+///
+/// The driver places the rings anywhere in guest memory; the queue only keeps their addresses
+/// and the indices it is at, and reaches the rings through [`GuestMemoryAccess`].
+#[derive(Clone, Debug)]
+pub struct VirtQueue {
+    max_size: u16,
+    size: u16,
+    ready: bool,
+    desc_addr: GuestPhysAddr,
+    avail_addr: GuestPhysAddr,
+    used_addr: GuestPhysAddr,
+    event_idx: bool,
+    // The next available ring entry to process.
+    last_avail_idx: u16,
+    // The next used ring entry to fill.
+    used_idx: u16,
+    // `used_idx` when interrupts were last considered.
+    signalled_used: u16,
+}
+
+impl VirtQueue {
+    /// Creates an unconfigured queue of at most `max_size` entries.
+    pub fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_addr: 0,
+            avail_addr: 0,
+            used_addr: 0,
+            event_idx: false,
+            last_avail_idx: 0,
+            used_idx: 0,
+            signalled_used: 0,
+        }
+    }
+
+    /// Returns the queue to its state before the driver configured it.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.max_size);
+    }
+
+    /// The largest size the driver may choose.
+    pub fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    /// Number of entries of the rings.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Whether the driver enabled the queue.
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Sets the number of entries. Split queue sizes are powers of two.
+    pub fn set_size(&mut self, size: u16) -> HyperResult<()> {
+        if self.ready || size == 0 || size > self.max_size || !size.is_power_of_two() {
+            return Err(HyperError::InvalidParam);
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    /// Sets the guest physical addresses of the descriptor table and of the available and used
+    /// rings.
+    pub fn set_addrs(&mut self, desc: GuestPhysAddr, avail: GuestPhysAddr, used: GuestPhysAddr) {
+        self.desc_addr = desc;
+        self.avail_addr = avail;
+        self.used_addr = used;
+    }
+
+    /// Guest physical addresses of the descriptor table and of the available and used rings.
+    pub fn addrs(&self) -> (GuestPhysAddr, GuestPhysAddr, GuestPhysAddr) {
+        (self.desc_addr, self.avail_addr, self.used_addr)
+    }
+
+    /// Enables or disables the queue.
+    pub fn set_ready(&mut self, ready: bool) {
+        self.ready = ready;
+    }
+
+    /// Whether the driver negotiated `VIRTIO_F_EVENT_IDX`.
+    pub fn set_event_idx(&mut self, enabled: bool) {
+        self.event_idx = enabled;
+    }
+
+    /// Whether the driver made buffers available that were not popped yet.
+    pub fn has_available(&self, mem: &dyn GuestMemoryAccess) -> HyperResult<bool> {
+        if !self.ready {
+            return Ok(false);
+        }
+        Ok(self.avail_idx(mem)? != self.last_avail_idx)
+    }
+
+    /// Returns the next chain the driver made available, if any, leaving it on the ring.
+    pub fn peek(&self, mem: &dyn GuestMemoryAccess) -> HyperResult<Option<DescChain>> {
+        if !self.has_available(mem)? {
+            return Ok(None);
+        }
+        // Read the ring entry and the descriptors only after the index that published them.
+        fence(Ordering::Acquire);
+        let slot = (self.last_avail_idx % self.size) as usize;
+        let head = self.read_u16(mem, self.avail_addr + 4 + slot * 2)?;
+        self.read_chain(mem, head).map(Some)
+    }
+
+    /// Takes the next chain the driver made available, if any.
+    pub fn pop(&mut self, mem: &dyn GuestMemoryAccess) -> HyperResult<Option<DescChain>> {
+        let chain = match self.peek(mem)? {
+            Some(chain) => chain,
+            None => return Ok(None),
+        };
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+        if self.event_idx {
+            // Ask for a notification once the driver adds past what was just taken.
+            let avail_event = self.used_addr + 4 + self.size as usize * USED_ELEM_SIZE;
+            mem.write_guest(avail_event, &self.last_avail_idx.to_le_bytes())?;
+        }
+        Ok(Some(chain))
+    }
+
+    /// Returns the chain starting at `head` to the driver, with `len` bytes written into its
+    /// device-writable buffers.
+    pub fn add_used(
+        &mut self,
+        mem: &dyn GuestMemoryAccess,
+        head: u16,
+        len: u32,
+    ) -> HyperResult<()> {
+        let slot = (self.used_idx % self.size) as usize;
+        let elem = self.used_addr + 4 + slot * USED_ELEM_SIZE;
+        let mut bytes = [0; USED_ELEM_SIZE];
+        bytes[..4].copy_from_slice(&(head as u32).to_le_bytes());
+        bytes[4..].copy_from_slice(&len.to_le_bytes());
+        mem.write_guest(elem, &bytes)?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        // Publish the entry before the index.
+        fence(Ordering::Release);
+        mem.write_guest(self.used_addr + 2, &self.used_idx.to_le_bytes())
+    }
+
+    /// Whether the driver wants an interrupt for the buffers used since the last one. Checked
+    /// once after a batch of `add_used`, so a batch raises at most one interrupt.
+    pub fn needs_interrupt(&mut self, mem: &dyn GuestMemoryAccess) -> HyperResult<bool> {
+        let old = self.signalled_used;
+        if old == self.used_idx {
+            return Ok(false);
+        }
+        // Read the driver's suppression state only after the used index is visible.
+        fence(Ordering::SeqCst);
+        let notify = if self.event_idx {
+            let used_event = self.read_u16(mem, self.avail_addr + 4 + self.size as usize * 2)?;
+            // vring_need_event()
+            self.used_idx.wrapping_sub(used_event).wrapping_sub(1) < self.used_idx.wrapping_sub(old)
+        } else {
+            self.read_u16(mem, self.avail_addr)? & VIRTQ_AVAIL_F_NO_INTERRUPT == 0
+        };
+        self.signalled_used = self.used_idx;
+        Ok(notify)
+    }
+
+    /// Appends the configuration and ring positions of the queue to a snapshot.
+    pub fn save_state(&self, out: &mut Encoder) {
+        out.put_u16(self.size);
+        out.put_bool(self.ready);
+        out.put_u64s(&[
+            self.desc_addr as u64,
+            self.avail_addr as u64,
+            self.used_addr as u64,
+        ]);
+        out.put_bool(self.event_idx);
+        out.put_u16(self.last_avail_idx);
+        out.put_u16(self.used_idx);
+    }
+
+    /// Loads the state written by `save_state`.
+    pub fn restore_state(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        let size = input.get_u16()?;
+        if size == 0 || size > self.max_size || !size.is_power_of_two() {
+            return Err(HyperError::DecodeError);
+        }
+        self.size = size;
+        self.ready = input.get_bool()?;
+        let mut addrs = [0; 3];
+        input.get_u64s(&mut addrs)?;
+        self.set_addrs(addrs[0] as usize, addrs[1] as usize, addrs[2] as usize);
+        self.event_idx = input.get_bool()?;
+        self.last_avail_idx = input.get_u16()?;
+        self.used_idx = input.get_u16()?;
+        self.signalled_used = self.used_idx;
+        Ok(())
+    }
+}
+
+// Private methods implementation
+impl VirtQueue {
+    fn avail_idx(&self, mem: &dyn GuestMemoryAccess) -> HyperResult<u16> {
+        self.read_u16(mem, self.avail_addr + 2)
+    }
+
+    fn read_u16(&self, mem: &dyn GuestMemoryAccess, gpa: GuestPhysAddr) -> HyperResult<u16> {
+        let mut bytes = [0; 2];
+        mem.read_guest(gpa, &mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn read_desc(
+        &self,
+        mem: &dyn GuestMemoryAccess,
+        table: GuestPhysAddr,
+        index: u16,
+    ) -> HyperResult<(Descriptor, u16, u16)> {
+        let mut bytes = [0; DESC_SIZE];
+        mem.read_guest(table + index as usize * DESC_SIZE, &mut bytes)?;
+        let flags = u16::from_le_bytes([bytes[12], bytes[13]]);
+        let desc = Descriptor {
+            addr: u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize,
+            len: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            writable: flags & VIRTQ_DESC_F_WRITE != 0,
+        };
+        let next = u16::from_le_bytes([bytes[14], bytes[15]]);
+        Ok((desc, flags, next))
+    }
+
+    /// Walks the chain starting at `head`, following an indirect table if the head points to
+    /// one. Malformed chains, e.g. with loops, more descriptors than the queue size or readable
+    /// buffers after writable ones, are an error.
+    fn read_chain(&self, mem: &dyn GuestMemoryAccess, head: u16) -> HyperResult<DescChain> {
+        if head >= self.size {
+            return Err(HyperError::InvalidParam);
+        }
+        let (mut table, mut table_len) = (self.desc_addr, self.size as usize);
+        let mut index = head;
+        let mut descs = Vec::new();
+        loop {
+            let (desc, flags, next) = self.read_desc(mem, table, index)?;
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                // Only the head of a chain may point to an indirect table.
+                if !descs.is_empty()
+                    || table != self.desc_addr
+                    || desc.len as usize % DESC_SIZE != 0
+                {
+                    return Err(HyperError::InvalidParam);
+                }
+                table = desc.addr;
+                table_len = desc.len as usize / DESC_SIZE;
+                index = 0;
+                // A chain has at most as many descriptors as the queue. (virtio 1.1, 2.6.5.3.1)
+                if table_len == 0 || table_len > self.size as usize {
+                    return Err(HyperError::InvalidParam);
+                }
+                continue;
+            }
+            if !desc.writable && descs.last().map_or(false, |d: &Descriptor| d.writable) {
+                return Err(HyperError::InvalidParam);
+            }
+            descs.push(desc);
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            // `table_len` is at most the queue size, which bounds the chain even if it loops.
+            if next as usize >= table_len || descs.len() >= table_len {
+                return Err(HyperError::InvalidParam);
+            }
+            index = next;
+        }
+        Ok(DescChain { head, descs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::TestMemory;
+    use super::*;
+
+    const DESC: GuestPhysAddr = 0x1000;
+    const AVAIL: GuestPhysAddr = 0x2000;
+    const USED: GuestPhysAddr = 0x3000;
+
+    fn write_desc(
+        mem: &TestMemory,
+        table: GuestPhysAddr,
+        index: usize,
+        desc: (u64, u32, u16, u16),
+    ) {
+        let (addr, len, flags, next) = desc;
+        let gpa = table + index * DESC_SIZE;
+        mem.write_guest_u64(gpa, addr).unwrap();
+        mem.write_guest_u32(gpa + 8, len).unwrap();
+        mem.write_guest(gpa + 12, &flags.to_le_bytes()).unwrap();
+        mem.write_guest(gpa + 14, &next.to_le_bytes()).unwrap();
+    }
+
+    fn make_available(mem: &TestMemory, slot: usize, head: u16, idx: u16) {
+        mem.write_guest(AVAIL + 4 + slot * 2, &head.to_le_bytes())
+            .unwrap();
+        mem.write_guest(AVAIL + 2, &idx.to_le_bytes()).unwrap();
+    }
+
+    fn ready_queue() -> VirtQueue {
+        let mut queue = VirtQueue::new(8);
+        queue.set_addrs(DESC, AVAIL, USED);
+        queue.set_ready(true);
+        queue
+    }
+
+    #[test]
+    fn chains_are_popped_and_returned() {
+        let mem = TestMemory::new(0x10000);
+        let mut queue = ready_queue();
+        mem.write_guest(0x4000, b"request").unwrap();
+        write_desc(&mem, DESC, 0, (0x4000, 7, VIRTQ_DESC_F_NEXT, 3));
+        write_desc(&mem, DESC, 3, (0x5000, 16, VIRTQ_DESC_F_WRITE, 0));
+        make_available(&mem, 0, 0, 1);
+
+        let chain = queue.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.head(), 0);
+        assert_eq!((chain.readable_len(), chain.writable_len()), (7, 16));
+        assert_eq!(chain.read_all(&mem).unwrap(), b"request");
+        assert_eq!(chain.write_at(&mem, 2, b"response").unwrap(), 8);
+        assert!(queue.pop(&mem).unwrap().is_none());
+
+        queue.add_used(&mem, chain.head(), 10).unwrap();
+        assert_eq!(mem.read_guest_u32(USED + 4).unwrap(), 0);
+        assert_eq!(mem.read_guest_u32(USED + 8).unwrap(), 10);
+        let mut idx = [0; 2];
+        mem.read_guest(USED + 2, &mut idx).unwrap();
+        assert_eq!(u16::from_le_bytes(idx), 1);
+        let mut out = [0; 8];
+        mem.read_guest(0x5002, &mut out).unwrap();
+        assert_eq!(&out, b"response");
+    }
+
+    #[test]
+    fn indirect_tables_and_loops() {
+        let mem = TestMemory::new(0x10000);
+        let mut queue = ready_queue();
+        write_desc(
+            &mem,
+            DESC,
+            1,
+            (0x6000, 2 * DESC_SIZE as u32, VIRTQ_DESC_F_INDIRECT, 0),
+        );
+        write_desc(&mem, 0x6000, 0, (0x4000, 4, VIRTQ_DESC_F_NEXT, 1));
+        write_desc(&mem, 0x6000, 1, (0x5000, 4, VIRTQ_DESC_F_WRITE, 0));
+        make_available(&mem, 0, 1, 1);
+        let chain = queue.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.head(), 1);
+        assert_eq!(chain.descs().len(), 2);
+
+        // A chain pointing back at itself.
+        write_desc(&mem, DESC, 2, (0x4000, 4, VIRTQ_DESC_F_NEXT, 2));
+        make_available(&mem, 1, 2, 2);
+        assert_eq!(queue.pop(&mem).unwrap_err(), HyperError::InvalidParam);
+    }
+
+    #[test]
+    fn oversized_indirect_tables() {
+        let mem = TestMemory::new(0x10000);
+        let mut queue = ready_queue();
+        // A cyclic table claiming far more entries than the queue holds.
+        let len = u32::MAX - u32::MAX % DESC_SIZE as u32;
+        write_desc(&mem, DESC, 0, (0x6000, len, VIRTQ_DESC_F_INDIRECT, 0));
+        write_desc(&mem, 0x6000, 0, (0x4000, 4, VIRTQ_DESC_F_NEXT, 1));
+        write_desc(&mem, 0x6000, 1, (0x4000, 4, VIRTQ_DESC_F_NEXT, 0));
+        make_available(&mem, 0, 0, 1);
+        assert_eq!(queue.pop(&mem).unwrap_err(), HyperError::InvalidParam);
+
+        // The same cycle in a table of the queue size ends at the chain length limit.
+        let mut queue = ready_queue();
+        write_desc(
+            &mem,
+            DESC,
+            1,
+            (0x6000, 8 * DESC_SIZE as u32, VIRTQ_DESC_F_INDIRECT, 0),
+        );
+        make_available(&mem, 0, 1, 1);
+        assert_eq!(queue.pop(&mem).unwrap_err(), HyperError::InvalidParam);
+    }
+
+    #[test]
+    fn event_index_suppresses_interrupts() {
+        let mem = TestMemory::new(0x10000);
+        let mut queue = ready_queue();
+        queue.set_event_idx(true);
+        for head in 0..3 {
+            queue.add_used(&mem, head, 0).unwrap();
+        }
+        // The driver starts out asking for the first used entry.
+        assert!(queue.needs_interrupt(&mem).unwrap());
+        assert!(!queue.needs_interrupt(&mem).unwrap());
+        // The driver wants to hear once entry 5 is used.
+        mem.write_guest(AVAIL + 4 + 8 * 2, &5u16.to_le_bytes())
+            .unwrap();
+        queue.add_used(&mem, 3, 0).unwrap();
+        assert!(!queue.needs_interrupt(&mem).unwrap());
+        queue.add_used(&mem, 4, 0).unwrap();
+        queue.add_used(&mem, 5, 0).unwrap();
+        assert!(queue.needs_interrupt(&mem).unwrap());
+    }
+}
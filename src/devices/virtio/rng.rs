@@ -0,0 +1,277 @@
+//! A virtio entropy device (virtio 1.2, section 5.4) fed by [`HyperCraftHal::fill_entropy`].
+//!
+//! A token bucket limits the rate at which each VM draws from the embedder's entropy source, so
+//! that one guest cannot drain a slow hardware source for the others. Requests the bucket
+//! cannot serve wait on the queue until it has refilled, as do requests while the source has
+//! nothing to give.
+
+use super::{read_config_bytes, VirtQueue, VirtioDevice, VIRTIO_ID_RNG};
+use crate::{GuestMemoryAccess, HyperCraftHal, HyperError, HyperResult};
+
+const QUEUE_SIZE: u16 = 64;
+const REQUEST_QUEUE: usize = 0;
+
+/// Largest number of bytes handed out per request.
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// How fast a VM may draw entropy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RngRateLimit {
+    /// Bytes per second on average.
+    pub bytes_per_sec: u64,
+    /// Bytes the VM may draw at once after being idle. Must not be zero.
+    pub burst: u64,
+}
+
+impl Default for RngRateLimit {
+    /// Plenty for seeding a kernel and its processes, while keeping a guest reading
+    /// `/dev/hwrng` in a loop from monopolizing the source.
+    fn default() -> Self {
+        Self {
+            bytes_per_sec: 4096,
+            burst: 16 * 1024,
+        }
+    }
+}
+
+/// A virtio entropy device.
+pub struct VirtioRng {
+    fill: fn(&mut [u8]) -> usize,
+    now_nanos: fn() -> u64,
+    limit: RngRateLimit,
+    tokens: u64,
+    last_refill_ns: u64,
+    // Requests wait for the bucket to refill.
+    throttled: bool,
+    queue_sizes: [u16; 1],
+}
+
+impl VirtioRng {
+    /// Creates a device drawing from `H::fill_entropy` at most as fast as `limit` allows, with
+    /// time in nanoseconds from `now_nanos`.
+    pub fn new<H: HyperCraftHal>(now_nanos: fn() -> u64, limit: RngRateLimit) -> HyperResult<Self> {
+        Self::with_source(H::fill_entropy, now_nanos, limit)
+    }
+
+    /// Changes the rate limit. The bucket starts out full.
+    pub fn set_rate_limit(&mut self, limit: RngRateLimit) -> HyperResult<()> {
+        if limit.burst == 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        self.limit = limit;
+        self.tokens = limit.burst;
+        self.last_refill_ns = (self.now_nanos)();
+        Ok(())
+    }
+
+    /// The current rate limit.
+    pub fn rate_limit(&self) -> RngRateLimit {
+        self.limit
+    }
+}
+
+// Private methods implementation
+impl VirtioRng {
+    fn with_source(
+        fill: fn(&mut [u8]) -> usize,
+        now_nanos: fn() -> u64,
+        limit: RngRateLimit,
+    ) -> HyperResult<Self> {
+        let mut rng = Self {
+            fill,
+            now_nanos,
+            limit,
+            tokens: 0,
+            last_refill_ns: 0,
+            throttled: false,
+            queue_sizes: [QUEUE_SIZE],
+        };
+        rng.set_rate_limit(limit)?;
+        Ok(rng)
+    }
+
+    /// Tokens the bucket holds at `now`.
+    fn available(&self, now: u64) -> (u64, bool) {
+        let elapsed = now.saturating_sub(self.last_refill_ns) as u128;
+        let added = elapsed * self.limit.bytes_per_sec as u128 / 1_000_000_000;
+        let tokens = (self.tokens as u128 + added).min(self.limit.burst as u128) as u64;
+        (tokens, added > 0)
+    }
+
+    fn refill(&mut self) {
+        let now = (self.now_nanos)();
+        let (tokens, refilled) = self.available(now);
+        // Keep the fraction of a token earned so far until a whole one is.
+        if refilled {
+            self.tokens = tokens;
+            self.last_refill_ns = now;
+        }
+    }
+
+    fn serve(&mut self, queue: &mut VirtQueue, mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        self.refill();
+        while queue.has_available(mem)? {
+            if self.tokens == 0 {
+                self.throttled = true;
+                return Ok(());
+            }
+            let chain = match queue.peek(mem)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let len = chain
+                .writable_len()
+                .min(MAX_REQUEST_SIZE)
+                .min(self.tokens as usize);
+            let mut buf = vec![0; len];
+            let filled = (self.fill)(&mut buf).min(len);
+            if filled == 0 && len > 0 {
+                // An empty completion would be resubmitted at once. Leave the request on the
+                // queue and ask the source again once the bucket earned a token.
+                debug!("virtio-rng: the entropy source returned nothing");
+                self.tokens = 0;
+                self.last_refill_ns = (self.now_nanos)();
+                self.throttled = true;
+                return Ok(());
+            }
+            queue.pop(mem)?;
+            let written = chain.write_at(mem, 0, &buf[..filled])?;
+            self.tokens -= filled as u64;
+            queue.add_used(mem, chain.head(), written as u32)?;
+        }
+        self.throttled = false;
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_RNG
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        // The device has no configuration space.
+        read_config_bytes(&[], offset, data);
+    }
+
+    fn reset(&mut self) {
+        self.throttled = false;
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [VirtQueue],
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()> {
+        match index {
+            REQUEST_QUEUE => self.serve(&mut queues[REQUEST_QUEUE], mem),
+            _ => Ok(()),
+        }
+    }
+
+    fn has_pending_work(&self) -> bool {
+        self.throttled && self.available((self.now_nanos)()).0 > 0
+    }
+
+    fn poll(&mut self, queues: &mut [VirtQueue], mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        self.serve(&mut queues[REQUEST_QUEUE], mem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{TestDriverQueue, TestMemory};
+    use super::*;
+    use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    static NOW: AtomicU64 = AtomicU64::new(0);
+    // Separate from `NOW`, the tests run in parallel.
+    static STARVED_NOW: AtomicU64 = AtomicU64::new(0);
+    static SOURCE_EMPTY: AtomicBool = AtomicBool::new(true);
+
+    fn fill(buf: &mut [u8]) -> usize {
+        buf.fill(0xa5);
+        buf.len()
+    }
+
+    fn now() -> u64 {
+        NOW.load(Ordering::Relaxed)
+    }
+
+    fn fill_when_ready(buf: &mut [u8]) -> usize {
+        if SOURCE_EMPTY.load(Ordering::Relaxed) {
+            return 0;
+        }
+        fill(buf)
+    }
+
+    fn starved_now() -> u64 {
+        STARVED_NOW.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn requests_are_rate_limited() {
+        let limit = RngRateLimit {
+            bytes_per_sec: 100,
+            burst: 48,
+        };
+        let mut rng = VirtioRng::with_source(fill, now, limit).unwrap();
+        let mem = TestMemory::new(0x10000);
+        let mut driver = TestDriverQueue::new(0, 16);
+        let mut queues = [driver.queue()];
+
+        let (_, buf) = driver.add(&mem, &[], 32);
+        driver.add(&mem, &[], 32);
+        rng.queue_notify(REQUEST_QUEUE, &mut queues, &mem).unwrap();
+        // The burst covers the first request and part of the second.
+        assert_eq!(driver.used(&mem), [(0, 32), (1, 16)]);
+        let mut data = [0; 32];
+        mem.read_guest(buf, &mut data).unwrap();
+        assert_eq!(data, [0xa5; 32]);
+
+        driver.add(&mem, &[], 32);
+        rng.queue_notify(REQUEST_QUEUE, &mut queues, &mem).unwrap();
+        assert!(driver.used(&mem).is_empty());
+        assert!(!rng.has_pending_work());
+        // 100 ms later, 10 bytes were earned.
+        NOW.store(100_000_000, Ordering::Relaxed);
+        assert!(rng.has_pending_work());
+        rng.poll(&mut queues, &mem).unwrap();
+        assert_eq!(driver.used(&mem), [(2, 10)]);
+        assert!(!rng.has_pending_work());
+    }
+
+    #[test]
+    fn requests_wait_for_an_empty_source() {
+        let limit = RngRateLimit {
+            bytes_per_sec: 100,
+            burst: 48,
+        };
+        let mut rng = VirtioRng::with_source(fill_when_ready, starved_now, limit).unwrap();
+        let mem = TestMemory::new(0x10000);
+        let mut driver = TestDriverQueue::new(0, 16);
+        let mut queues = [driver.queue()];
+
+        driver.add(&mem, &[], 32);
+        rng.queue_notify(REQUEST_QUEUE, &mut queues, &mem).unwrap();
+        // The request stays available instead of completing empty.
+        assert!(driver.used(&mem).is_empty());
+        assert!(queues[REQUEST_QUEUE].has_available(&mem).unwrap());
+        assert!(!rng.has_pending_work());
+
+        SOURCE_EMPTY.store(false, Ordering::Relaxed);
+        STARVED_NOW.store(100_000_000, Ordering::Relaxed);
+        assert!(rng.has_pending_work());
+        rng.poll(&mut queues, &mem).unwrap();
+        assert_eq!(driver.used(&mem), [(0, 10)]);
+    }
+}
@@ -0,0 +1,285 @@
+//! The parts of a virtio transport that do not depend on how the device is exposed to the guest:
+//! feature negotiation, the device status and the queues (virtio 1.2, sections 2 and 3).
+
+use alloc::vec::Vec;
+
+use super::{
+    VirtQueue, VirtioDevice, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
+    VIRTIO_STATUS_DEVICE_NEEDS_RESET, VIRTIO_STATUS_DRIVER_OK, VIRTIO_STATUS_FEATURES_OK,
+    VIRTIO_TRANSPORT_FEATURES,
+};
+use crate::snapshot::{Decoder, Encoder};
+use crate::{GuestMemoryAccess, HyperError, HyperResult};
+
+/// A queue address register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum QueueAddr {
+    Desc,
+    Driver,
+    Device,
+}
+
+/// The interrupts a transport raises after processing the device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) struct Interrupts {
+    /// The queues with used buffers the driver wants to hear about, one bit per queue.
+    pub queues: u64,
+    /// The configuration space changed, or the device needs a reset.
+    pub config: bool,
+}
+
+/// A virtio device model with the registers every transport has.
+pub(super) struct VirtioTransport<D: VirtioDevice> {
+    pub device: D,
+    pub queues: Vec<VirtQueue>,
+    pub status: u32,
+    pub device_features_sel: u32,
+    pub driver_features: u64,
+    pub driver_features_sel: u32,
+    pub queue_sel: u32,
+    pub config_generation: u32,
+    // Queues notified by the driver and not processed yet, one bit per queue.
+    pub pending_notify: u64,
+}
+
+impl<D: VirtioDevice> VirtioTransport<D> {
+    pub fn new(device: D) -> HyperResult<Self> {
+        let queues: Vec<VirtQueue> = device
+            .queue_max_sizes()
+            .iter()
+            .map(|&size| VirtQueue::new(size))
+            .collect();
+        if queues.len() > u64::BITS as usize
+            || queues
+                .iter()
+                .any(|q| q.max_size() == 0 || !q.max_size().is_power_of_two())
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(Self {
+            device,
+            queues,
+            status: 0,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            config_generation: 0,
+            pending_notify: 0,
+        })
+    }
+
+    /// Whether the driver set `DRIVER_OK` and the device did not fail since.
+    pub fn is_active(&self) -> bool {
+        self.status & (VIRTIO_STATUS_DRIVER_OK | VIRTIO_STATUS_DEVICE_NEEDS_RESET)
+            == VIRTIO_STATUS_DRIVER_OK
+    }
+
+    pub fn offered_features(&self) -> u64 {
+        self.device.device_features() | VIRTIO_TRANSPORT_FEATURES
+    }
+
+    /// The 32 feature bits selected by `device_features_sel`.
+    pub fn device_features_word(&self) -> u32 {
+        match self.device_features_sel {
+            0 => self.offered_features() as u32,
+            1 => (self.offered_features() >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    /// Sets the 32 driver feature bits selected by `driver_features_sel`. Ignored once the
+    /// features were negotiated.
+    pub fn write_driver_features(&mut self, val: u32) {
+        if self.status & VIRTIO_STATUS_FEATURES_OK != 0 {
+            return;
+        }
+        match self.driver_features_sel {
+            0 => self.driver_features = (self.driver_features & !0xffff_ffff) | val as u64,
+            1 => self.driver_features = (self.driver_features & 0xffff_ffff) | ((val as u64) << 32),
+            _ => {}
+        }
+    }
+
+    pub fn selected_queue(&self) -> Option<&VirtQueue> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    pub fn selected_queue_mut(&mut self) -> Option<&mut VirtQueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Enables or disables the selected queue.
+    pub fn set_queue_ready(&mut self, ready: bool) {
+        let event_idx = self.driver_features & VIRTIO_F_RING_EVENT_IDX != 0;
+        if let Some(queue) = self.selected_queue_mut() {
+            queue.set_event_idx(event_idx);
+            queue.set_ready(ready);
+        }
+    }
+
+    /// Half of an address of the selected queue, the high one if `high`.
+    pub fn queue_addr(&self, which: QueueAddr, high: bool) -> u32 {
+        let queue = match self.selected_queue() {
+            Some(queue) => queue,
+            None => return 0,
+        };
+        let (desc, avail, used) = queue.addrs();
+        let addr = match which {
+            QueueAddr::Desc => desc,
+            QueueAddr::Driver => avail,
+            QueueAddr::Device => used,
+        } as u64;
+        if high {
+            (addr >> 32) as u32
+        } else {
+            addr as u32
+        }
+    }
+
+    /// Sets half of an address of the selected queue, the high one if `high`. Ignored while the
+    /// queue is enabled.
+    pub fn write_queue_addr(&mut self, which: QueueAddr, high: bool, val: u32) {
+        let queue = match self.selected_queue_mut() {
+            Some(queue) if !queue.is_ready() => queue,
+            _ => return,
+        };
+        let (mut desc, mut avail, mut used) = queue.addrs();
+        let addr = match which {
+            QueueAddr::Desc => &mut desc,
+            QueueAddr::Driver => &mut avail,
+            QueueAddr::Device => &mut used,
+        };
+        *addr = if high {
+            ((*addr as u64 & 0xffff_ffff) | ((val as u64) << 32)) as usize
+        } else {
+            ((*addr as u64 & !0xffff_ffff) | val as u64) as usize
+        };
+        queue.set_addrs(desc, avail, used);
+    }
+
+    /// Records a notification of queue `index`, processed by the next `poll`.
+    pub fn notify(&mut self, index: usize) {
+        if index < self.queues.len() {
+            self.pending_notify |= 1 << index;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.device.reset();
+        self.queues.iter_mut().for_each(VirtQueue::reset);
+        self.status = 0;
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.pending_notify = 0;
+    }
+
+    /// Handles a write of the device status. Writing 0 resets the device.
+    pub fn write_status(&mut self, val: u32) {
+        if val == 0 {
+            self.reset();
+            return;
+        }
+        let mut val = val | (self.status & VIRTIO_STATUS_DEVICE_NEEDS_RESET);
+        let newly_set = val & !self.status;
+        if newly_set & VIRTIO_STATUS_FEATURES_OK != 0 {
+            // Refuse features we did not offer, and drivers of legacy devices.
+            let features = self.driver_features;
+            if features & !self.offered_features() != 0 || features & VIRTIO_F_VERSION_1 == 0 {
+                val &= !VIRTIO_STATUS_FEATURES_OK;
+            }
+        }
+        if newly_set & VIRTIO_STATUS_DRIVER_OK != 0 {
+            if let Err(err) = self.device.activate(self.driver_features) {
+                warn!(
+                    "virtio device {} failed to activate: {:?}",
+                    self.device.device_id(),
+                    err
+                );
+                val |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+            }
+        }
+        self.status = val;
+    }
+
+    pub fn has_pending_work(&self) -> bool {
+        self.is_active() && (self.pending_notify != 0 || self.device.has_pending_work())
+    }
+
+    /// Processes the notified queues and the device's own work, and returns the interrupts to
+    /// raise. A device the driver handed something malformed stops until the driver resets it.
+    pub fn poll(&mut self, mem: &dyn GuestMemoryAccess) -> Interrupts {
+        if !self.is_active() {
+            self.pending_notify = 0;
+            return Interrupts::default();
+        }
+        match self.process(mem) {
+            Ok(interrupts) => interrupts,
+            Err(err) => {
+                warn!(
+                    "virtio device {} failed: {:?}",
+                    self.device.device_id(),
+                    err
+                );
+                self.status |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+                self.pending_notify = 0;
+                Interrupts {
+                    queues: 0,
+                    config: true,
+                }
+            }
+        }
+    }
+
+    /// Appends the queues and the state of the device model to a snapshot.
+    pub fn save_queues(&self, out: &mut Encoder) {
+        out.put_u32(self.queues.len() as u32);
+        for queue in self.queues.iter() {
+            queue.save_state(out);
+        }
+        let mut device = Encoder::new();
+        self.device.save_state(&mut device);
+        out.put_bytes(device.as_bytes());
+    }
+
+    /// Loads the state written by `save_queues`.
+    pub fn restore_queues(&mut self, input: &mut Decoder) -> HyperResult<()> {
+        if input.get_u32()? as usize != self.queues.len() {
+            return Err(HyperError::DecodeError);
+        }
+        for queue in self.queues.iter_mut() {
+            queue.restore_state(input)?;
+        }
+        self.device
+            .restore_state(&mut Decoder::new(input.get_bytes()?))
+    }
+}
+
+// Private methods implementation
+impl<D: VirtioDevice> VirtioTransport<D> {
+    fn process(&mut self, mem: &dyn GuestMemoryAccess) -> HyperResult<Interrupts> {
+        while self.pending_notify != 0 {
+            let index = self.pending_notify.trailing_zeros() as usize;
+            self.pending_notify &= !(1 << index);
+            if self.queues[index].is_ready() {
+                self.device.queue_notify(index, &mut self.queues, mem)?;
+            }
+        }
+        if self.device.has_pending_work() {
+            self.device.poll(&mut self.queues, mem)?;
+        }
+        let mut interrupts = Interrupts::default();
+        for (index, queue) in self.queues.iter_mut().enumerate() {
+            if queue.is_ready() && queue.needs_interrupt(mem)? {
+                interrupts.queues |= 1 << index;
+            }
+        }
+        if self.device.take_config_changed() {
+            self.config_generation = self.config_generation.wrapping_add(1);
+            interrupts.config = true;
+        }
+        Ok(interrupts)
+    }
+}
@@ -0,0 +1,836 @@
+//! A virtio socket device (virtio 1.2, section 5.10) and the hypervisor end of its
+//! connections.
+//!
+//! A [`VsockHost`] is the hypervisor's endpoint, CID 2 for every guest. Each VM gets a
+//! [`VirtioVsock`] attached to it, which assigns the VM its own CID. Services in the hypervisor
+//! listen on ports of the host and accept the connections guests make, or connect to ports
+//! guests listen on, and exchange data over the resulting [`VsockStream`]s. Only stream sockets
+//! are supported.
+//!
+//! Data goes through a buffer of [`STREAM_BUFFER_SIZE`] bytes in each direction. The
+//! credit-based flow control of vsock keeps either side from sending more than the other side
+//! has room for.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{read_config_bytes, VirtQueue, VirtioDevice, VIRTIO_ID_VSOCK};
+use crate::snapshot::Decoder;
+use crate::{GuestMemoryAccess, HyperError, HyperResult};
+
+/// The CID of the hypervisor, as seen by every guest.
+pub const VMADDR_CID_HOST: u64 = 2;
+/// Bytes buffered per connection and direction.
+pub const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+/// Connections waiting to be accepted per listening port.
+pub const MAX_BACKLOG: usize = 16;
+
+const FIRST_GUEST_CID: u64 = 3;
+const FIRST_EPHEMERAL_PORT: u32 = 0xc000;
+
+const QUEUE_SIZE: u16 = 128;
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const EVENT_QUEUE: usize = 2;
+
+const HEADER_SIZE: usize = 44;
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+// Operations.
+const OP_REQUEST: u16 = 1;
+const OP_RESPONSE: u16 = 2;
+const OP_RST: u16 = 3;
+const OP_SHUTDOWN: u16 = 4;
+const OP_RW: u16 = 5;
+const OP_CREDIT_UPDATE: u16 = 6;
+const OP_CREDIT_REQUEST: u16 = 7;
+
+const SHUTDOWN_RCV: u32 = 1;
+const SHUTDOWN_SEND: u32 = 2;
+const SHUTDOWN_BOTH: u32 = SHUTDOWN_RCV | SHUTDOWN_SEND;
+
+/// The guest must drop all its connections, e.g. after the VM was restored.
+const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+
+// Packets a connection owes the guest.
+const PENDING_REQUEST: u8 = 1 << 0;
+const PENDING_RESPONSE: u8 = 1 << 1;
+const PENDING_CREDIT: u8 = 1 << 2;
+const PENDING_SHUTDOWN: u8 = 1 << 3;
+
+/// The state of a connection, as seen by the hypervisor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VsockState {
+    /// Waiting for the guest to accept the connection.
+    Connecting,
+    /// Data flows both ways.
+    Connected,
+    /// The guest will send no more data. What it sent before can still be received.
+    PeerClosed,
+    /// The connection is gone, or was refused.
+    Closed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ConnKey {
+    cid: u64,
+    guest_port: u32,
+    host_port: u32,
+}
+
+struct Connection {
+    state: VsockState,
+    // Guest to host.
+    rx: VecDeque<u8>,
+    // Host to guest.
+    tx: VecDeque<u8>,
+    pending: u8,
+    // The guest's receive buffer, and how much of it it freed.
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    // Bytes sent to the guest.
+    tx_cnt: u32,
+    // Bytes of `rx` taken by the service, and the count last told to the guest.
+    fwd_cnt: u32,
+    fwd_cnt_sent: u32,
+    // No stream refers to the connection anymore.
+    host_closed: bool,
+}
+
+impl Connection {
+    fn new(state: VsockState, pending: u8) -> Self {
+        Self {
+            state,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            pending,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            fwd_cnt_sent: 0,
+            host_closed: false,
+        }
+    }
+
+    /// Bytes the guest has room for.
+    fn peer_credit(&self) -> usize {
+        self.peer_buf_alloc
+            .wrapping_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt)) as usize
+    }
+
+    fn can_send_data(&self) -> bool {
+        matches!(self.state, VsockState::Connected | VsockState::PeerClosed)
+            && !self.tx.is_empty()
+            && self.peer_credit() > 0
+    }
+
+    fn has_output(&self) -> bool {
+        self.can_send_data()
+            || self.pending & !PENDING_SHUTDOWN != 0
+            || (self.pending & PENDING_SHUTDOWN != 0 && self.tx.is_empty())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PacketHeader {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    kind: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl PacketHeader {
+    fn decode(bytes: &[u8]) -> Self {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u16_at = |at: usize| u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap());
+        Self {
+            src_cid: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            dst_cid: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            kind: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        }
+    }
+
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.len.to_le_bytes());
+        bytes[28..30].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[30..32].copy_from_slice(&self.op.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        bytes[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        bytes
+    }
+
+    /// A packet from the host to the guest on connection `key`.
+    fn to_guest(key: ConnKey, op: u16) -> Self {
+        Self {
+            src_cid: VMADDR_CID_HOST,
+            dst_cid: key.cid,
+            src_port: key.host_port,
+            dst_port: key.guest_port,
+            len: 0,
+            kind: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags: 0,
+            buf_alloc: STREAM_BUFFER_SIZE as u32,
+            fwd_cnt: 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct HostInner {
+    // Attached guests, with the resets they are owed for packets without connection.
+    guests: BTreeMap<u64, VecDeque<ConnKey>>,
+    listeners: BTreeMap<u32, VecDeque<ConnKey>>,
+    conns: BTreeMap<ConnKey, Connection>,
+    next_port: u32,
+}
+
+/// The hypervisor's end of the vsock connections of all VMs. Cloning gives another handle to
+/// the same endpoint.
+#[derive(Clone, Default)]
+pub struct VsockHost {
+    inner: Arc<Mutex<HostInner>>,
+}
+
+impl VsockHost {
+    /// Creates an endpoint without guests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts connections from guests to `port`.
+    pub fn listen(&self, port: u32) -> HyperResult<()> {
+        let mut inner = self.inner.lock();
+        if inner.listeners.contains_key(&port) {
+            return Err(HyperError::BadState);
+        }
+        inner.listeners.insert(port, VecDeque::new());
+        Ok(())
+    }
+
+    /// Stops accepting connections to `port`. Connections not accepted yet are reset.
+    pub fn unlisten(&self, port: u32) -> HyperResult<()> {
+        let mut inner = self.inner.lock();
+        let backlog = inner.listeners.remove(&port).ok_or(HyperError::NotFound)?;
+        for key in backlog {
+            inner.reset(key);
+        }
+        Ok(())
+    }
+
+    /// Takes the next connection a guest made to `port`, if any.
+    pub fn accept(&self, port: u32) -> Option<VsockStream> {
+        let key = self.inner.lock().listeners.get_mut(&port)?.pop_front()?;
+        Some(VsockStream {
+            inner: self.inner.clone(),
+            key,
+        })
+    }
+
+    /// Connects to `port` of the guest with CID `cid`. The stream is in
+    /// [`VsockState::Connecting`] until the guest accepts, or refuses, the connection.
+    pub fn connect(&self, cid: u64, port: u32) -> HyperResult<VsockStream> {
+        let mut inner = self.inner.lock();
+        if !inner.guests.contains_key(&cid) {
+            return Err(HyperError::NotFound);
+        }
+        let host_port = inner.alloc_port(cid, port)?;
+        let key = ConnKey {
+            cid,
+            guest_port: port,
+            host_port,
+        };
+        let conn = Connection::new(VsockState::Connecting, PENDING_REQUEST);
+        inner.conns.insert(key, conn);
+        Ok(VsockStream {
+            inner: self.inner.clone(),
+            key,
+        })
+    }
+}
+
+/// A connection between a hypervisor service and a guest process. Dropping it closes the
+/// connection once the data sent on it reached the guest.
+pub struct VsockStream {
+    inner: Arc<Mutex<HostInner>>,
+    key: ConnKey,
+}
+
+impl VsockStream {
+    /// The guest's CID and port.
+    pub fn peer(&self) -> (u64, u32) {
+        (self.key.cid, self.key.guest_port)
+    }
+
+    /// The hypervisor's port.
+    pub fn local_port(&self) -> u32 {
+        self.key.host_port
+    }
+
+    /// The state of the connection.
+    pub fn state(&self) -> VsockState {
+        self.inner
+            .lock()
+            .conns
+            .get(&self.key)
+            .map_or(VsockState::Closed, |conn| conn.state)
+    }
+
+    /// Queues `data` for the guest. Returns how many bytes fit in the send buffer.
+    pub fn send(&self, data: &[u8]) -> HyperResult<usize> {
+        let mut inner = self.inner.lock();
+        let conn = inner.conns.get_mut(&self.key).ok_or(HyperError::BadState)?;
+        if !matches!(
+            conn.state,
+            VsockState::Connecting | VsockState::Connected | VsockState::PeerClosed
+        ) {
+            return Err(HyperError::BadState);
+        }
+        let len = data.len().min(STREAM_BUFFER_SIZE - conn.tx.len());
+        conn.tx.extend(&data[..len]);
+        Ok(len)
+    }
+
+    /// Moves the data the guest sent into `buf`, returning the number of bytes copied. Zero
+    /// bytes with a state other than [`VsockState::Connected`] means the guest sends no more.
+    pub fn recv(&self, buf: &mut [u8]) -> usize {
+        let mut inner = self.inner.lock();
+        let conn = match inner.conns.get_mut(&self.key) {
+            Some(conn) => conn,
+            None => return 0,
+        };
+        let len = buf.len().min(conn.rx.len());
+        for (dst, src) in buf.iter_mut().zip(conn.rx.drain(..len)) {
+            *dst = src;
+        }
+        conn.fwd_cnt = conn.fwd_cnt.wrapping_add(len as u32);
+        // Tell the guest about the room made once it is worth a packet.
+        if conn.fwd_cnt.wrapping_sub(conn.fwd_cnt_sent) as usize >= STREAM_BUFFER_SIZE / 4 {
+            conn.pending |= PENDING_CREDIT;
+        }
+        len
+    }
+}
+
+impl Drop for VsockStream {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        let conn = match inner.conns.get_mut(&self.key) {
+            Some(conn) => conn,
+            None => return,
+        };
+        conn.host_closed = true;
+        match conn.state {
+            VsockState::Connected | VsockState::PeerClosed => conn.pending |= PENDING_SHUTDOWN,
+            VsockState::Connecting => inner.reset(self.key),
+            VsockState::Closed => {
+                inner.conns.remove(&self.key);
+            }
+        }
+    }
+}
+
+// Private methods implementation
+impl HostInner {
+    fn alloc_port(&mut self, cid: u64, guest_port: u32) -> HyperResult<u32> {
+        for _ in 0..=(u32::MAX - FIRST_EPHEMERAL_PORT) {
+            let port = self.next_port.max(FIRST_EPHEMERAL_PORT);
+            self.next_port = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+            let key = ConnKey {
+                cid,
+                guest_port,
+                host_port: port,
+            };
+            if !self.listeners.contains_key(&port) && !self.conns.contains_key(&key) {
+                return Ok(port);
+            }
+        }
+        Err(HyperError::NoMemory)
+    }
+
+    /// Drops the connection and tells the guest.
+    fn reset(&mut self, key: ConnKey) {
+        self.close(key);
+        if let Some(resets) = self.guests.get_mut(&key.cid) {
+            resets.push_back(key);
+        }
+    }
+
+    /// Marks the connection closed, dropping it once no stream refers to it.
+    fn close(&mut self, key: ConnKey) {
+        let in_backlog = self
+            .listeners
+            .get(&key.host_port)
+            .map_or(false, |backlog| backlog.contains(&key));
+        if let Some(conn) = self.conns.get_mut(&key) {
+            conn.state = VsockState::Closed;
+            conn.pending = 0;
+            if conn.host_closed && !in_backlog {
+                self.conns.remove(&key);
+            }
+        }
+    }
+
+    /// Handles a packet the guest with CID `cid` sent to the host.
+    fn handle_packet(&mut self, cid: u64, hdr: &PacketHeader, payload: &[u8]) {
+        if hdr.src_cid != cid {
+            debug!(
+                "vsock: dropped packet from cid {} claiming {}",
+                cid, hdr.src_cid
+            );
+            return;
+        }
+        let key = ConnKey {
+            cid,
+            guest_port: hdr.src_port,
+            host_port: hdr.dst_port,
+        };
+        if hdr.dst_cid != VMADDR_CID_HOST || hdr.kind != VIRTIO_VSOCK_TYPE_STREAM {
+            if hdr.op != OP_RST {
+                self.reset(key);
+            }
+            return;
+        }
+        let conn = match self.conns.get_mut(&key) {
+            Some(conn) => conn,
+            None => {
+                match self.listeners.get_mut(&hdr.dst_port) {
+                    Some(backlog) if hdr.op == OP_REQUEST && backlog.len() < MAX_BACKLOG => {
+                        backlog.push_back(key);
+                        let mut conn = Connection::new(VsockState::Connected, PENDING_RESPONSE);
+                        conn.peer_buf_alloc = hdr.buf_alloc;
+                        conn.peer_fwd_cnt = hdr.fwd_cnt;
+                        self.conns.insert(key, conn);
+                    }
+                    _ if hdr.op != OP_RST => self.reset(key),
+                    _ => {}
+                }
+                return;
+            }
+        };
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+        match (hdr.op, conn.state) {
+            (OP_RESPONSE, VsockState::Connecting) => conn.state = VsockState::Connected,
+            (OP_RW, VsockState::Connected) => {
+                if conn.rx.len() + payload.len() > STREAM_BUFFER_SIZE {
+                    // The guest ignored our credit.
+                    self.reset(key);
+                    return;
+                }
+                conn.rx.extend(payload);
+            }
+            (OP_CREDIT_UPDATE, _) => {}
+            (OP_CREDIT_REQUEST, _) => conn.pending |= PENDING_CREDIT,
+            (OP_SHUTDOWN, VsockState::Connected | VsockState::PeerClosed) => {
+                if hdr.flags & SHUTDOWN_SEND != 0 {
+                    conn.state = VsockState::PeerClosed;
+                }
+                if hdr.flags & SHUTDOWN_BOTH == SHUTDOWN_BOTH {
+                    // The guest expects a reset once both directions are shut down.
+                    self.reset(key);
+                }
+            }
+            (OP_RST, _) => self.close(key),
+            _ => self.reset(key),
+        }
+    }
+
+    fn has_output(&self, cid: u64) -> bool {
+        self.guests
+            .get(&cid)
+            .map_or(false, |resets| !resets.is_empty())
+            || self.guest_conns(cid).any(|(_, conn)| conn.has_output())
+    }
+
+    fn guest_conns(&self, cid: u64) -> impl Iterator<Item = (&ConnKey, &Connection)> {
+        let first = ConnKey {
+            cid,
+            guest_port: 0,
+            host_port: 0,
+        };
+        let last = ConnKey {
+            cid,
+            guest_port: u32::MAX,
+            host_port: u32::MAX,
+        };
+        self.conns.range(first..=last)
+    }
+
+    /// Builds the next packet for the guest with CID `cid`, with at most `max_payload` bytes of
+    /// data.
+    fn next_packet(&mut self, cid: u64, max_payload: usize) -> Option<(PacketHeader, Vec<u8>)> {
+        if let Some(key) = self.guests.get_mut(&cid)?.pop_front() {
+            return Some((PacketHeader::to_guest(key, OP_RST), Vec::new()));
+        }
+        let key = *self.guest_conns(cid).find(|(_, conn)| conn.has_output())?.0;
+        let conn = self.conns.get_mut(&key).unwrap();
+        let mut hdr = PacketHeader::to_guest(key, OP_CREDIT_UPDATE);
+        let mut payload = Vec::new();
+        if conn.pending & PENDING_REQUEST != 0 {
+            conn.pending &= !PENDING_REQUEST;
+            hdr.op = OP_REQUEST;
+        } else if conn.pending & PENDING_RESPONSE != 0 {
+            conn.pending &= !PENDING_RESPONSE;
+            hdr.op = OP_RESPONSE;
+        } else if conn.can_send_data() && max_payload > 0 {
+            let len = conn.tx.len().min(conn.peer_credit()).min(max_payload);
+            payload.extend(conn.tx.drain(..len));
+            conn.tx_cnt = conn.tx_cnt.wrapping_add(len as u32);
+            hdr.op = OP_RW;
+            hdr.len = len as u32;
+        } else if conn.pending & PENDING_CREDIT != 0 {
+            conn.pending &= !PENDING_CREDIT;
+        } else if conn.pending & PENDING_SHUTDOWN != 0 && conn.tx.is_empty() {
+            conn.pending &= !PENDING_SHUTDOWN;
+            hdr.op = OP_SHUTDOWN;
+            hdr.flags = SHUTDOWN_BOTH;
+        } else {
+            return None;
+        }
+        hdr.fwd_cnt = conn.fwd_cnt;
+        conn.fwd_cnt_sent = conn.fwd_cnt;
+        Some((hdr, payload))
+    }
+
+    /// Drops all connections of the guest with CID `cid`, e.g. after its driver reset.
+    fn reset_guest(&mut self, cid: u64) {
+        let keys: Vec<ConnKey> = self.guest_conns(cid).map(|(key, _)| *key).collect();
+        for key in keys {
+            self.close(key);
+        }
+        if let Some(resets) = self.guests.get_mut(&cid) {
+            resets.clear();
+        }
+    }
+}
+
+/// A virtio socket device giving one VM its CID and its connections to a [`VsockHost`].
+pub struct VirtioVsock {
+    host: Arc<Mutex<HostInner>>,
+    cid: u64,
+    queue_sizes: [u16; 3],
+    // Packets are waiting for the driver to add receive buffers.
+    rx_starved: bool,
+    // The driver must be told that its connections are gone.
+    reset_event: bool,
+}
+
+impl VirtioVsock {
+    /// Attaches a VM to `host`, with CID `cid` or the lowest free one.
+    pub fn new(host: &VsockHost, cid: Option<u64>) -> HyperResult<Self> {
+        let mut inner = host.inner.lock();
+        let cid = match cid {
+            Some(cid) if cid < FIRST_GUEST_CID || cid > u32::MAX as u64 => {
+                return Err(HyperError::InvalidParam)
+            }
+            Some(cid) if inner.guests.contains_key(&cid) => return Err(HyperError::BadState),
+            Some(cid) => cid,
+            None => (FIRST_GUEST_CID..)
+                .find(|cid| !inner.guests.contains_key(cid))
+                .unwrap(),
+        };
+        inner.guests.insert(cid, VecDeque::new());
+        Ok(Self {
+            host: host.inner.clone(),
+            cid,
+            queue_sizes: [QUEUE_SIZE; 3],
+            rx_starved: false,
+            reset_event: false,
+        })
+    }
+
+    /// The CID of the VM.
+    pub fn cid(&self) -> u64 {
+        self.cid
+    }
+}
+
+// Private methods implementation
+impl VirtioVsock {
+    fn transmit(&mut self, queue: &mut VirtQueue, mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        while let Some(chain) = queue.pop(mem)? {
+            let packet = chain.read_all(mem)?;
+            if packet.len() >= HEADER_SIZE {
+                let hdr = PacketHeader::decode(&packet);
+                let payload = &packet[HEADER_SIZE..];
+                let payload = &payload[..payload.len().min(hdr.len as usize)];
+                self.host.lock().handle_packet(self.cid, &hdr, payload);
+            }
+            queue.add_used(mem, chain.head(), 0)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, queue: &mut VirtQueue, mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        loop {
+            if !self.host.lock().has_output(self.cid) {
+                return Ok(());
+            }
+            let chain = match queue.pop(mem)? {
+                Some(chain) => chain,
+                None => {
+                    self.rx_starved = true;
+                    return Ok(());
+                }
+            };
+            let max_payload = chain.writable_len().saturating_sub(HEADER_SIZE);
+            let packet = self.host.lock().next_packet(self.cid, max_payload);
+            let len = match packet {
+                Some((hdr, payload)) if chain.writable_len() >= HEADER_SIZE => {
+                    chain.write_at(mem, 0, &hdr.encode())?;
+                    chain.write_at(mem, HEADER_SIZE, &payload)?;
+                    HEADER_SIZE + payload.len()
+                }
+                _ => 0,
+            };
+            queue.add_used(mem, chain.head(), len as u32)?;
+        }
+    }
+
+    fn send_reset_event(
+        &mut self,
+        queue: &mut VirtQueue,
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()> {
+        if let Some(chain) = queue.pop(mem)? {
+            let len = chain.write_at(mem, 0, &VIRTIO_VSOCK_EVENT_TRANSPORT_RESET.to_le_bytes())?;
+            queue.add_used(mem, chain.head(), len as u32)?;
+            self.reset_event = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VirtioVsock {
+    fn drop(&mut self) {
+        let mut host = self.host.lock();
+        host.reset_guest(self.cid);
+        host.guests.remove(&self.cid);
+    }
+}
+
+impl VirtioDevice for VirtioVsock {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_VSOCK
+    }
+
+    fn device_features(&self) -> u64 {
+        0
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        read_config_bytes(&self.cid.to_le_bytes(), offset, data);
+    }
+
+    fn reset(&mut self) {
+        self.host.lock().reset_guest(self.cid);
+        self.rx_starved = false;
+        self.reset_event = false;
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [VirtQueue],
+        mem: &dyn GuestMemoryAccess,
+    ) -> HyperResult<()> {
+        match index {
+            TX_QUEUE => {
+                self.transmit(&mut queues[TX_QUEUE], mem)?;
+                // Answer right away, e.g. accept the connection just requested.
+                if !self.rx_starved {
+                    self.receive(&mut queues[RX_QUEUE], mem)?;
+                }
+                Ok(())
+            }
+            RX_QUEUE => {
+                self.rx_starved = false;
+                self.receive(&mut queues[RX_QUEUE], mem)
+            }
+            EVENT_QUEUE if self.reset_event => self.send_reset_event(&mut queues[EVENT_QUEUE], mem),
+            _ => Ok(()),
+        }
+    }
+
+    fn has_pending_work(&self) -> bool {
+        self.reset_event || (!self.rx_starved && self.host.lock().has_output(self.cid))
+    }
+
+    fn poll(&mut self, queues: &mut [VirtQueue], mem: &dyn GuestMemoryAccess) -> HyperResult<()> {
+        if self.reset_event {
+            self.send_reset_event(&mut queues[EVENT_QUEUE], mem)?;
+        }
+        self.receive(&mut queues[RX_QUEUE], mem)
+    }
+
+    /// Connections do not survive a snapshot: the restored guest is told to drop its own.
+    fn restore_state(&mut self, _input: &mut Decoder) -> HyperResult<()> {
+        self.host.lock().reset_guest(self.cid);
+        self.reset_event = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{TestDriverQueue, TestMemory};
+    use super::*;
+    use crate::GuestPhysAddr;
+
+    struct Guest {
+        mem: TestMemory,
+        drivers: Vec<TestDriverQueue>,
+        queues: Vec<VirtQueue>,
+        dev: VirtioVsock,
+        // Receive buffers the device has not used yet.
+        rx_bufs: Vec<(u16, GuestPhysAddr)>,
+    }
+
+    impl Guest {
+        fn new(host: &VsockHost) -> Self {
+            let drivers: Vec<_> = (0..3)
+                .map(|i| TestDriverQueue::new(0x20000 * i, 64))
+                .collect();
+            let queues = drivers.iter().map(TestDriverQueue::queue).collect();
+            Self {
+                mem: TestMemory::new(0x60000),
+                drivers,
+                queues,
+                dev: VirtioVsock::new(host, None).unwrap(),
+                rx_bufs: Vec::new(),
+            }
+        }
+
+        fn send(&mut self, op: u16, src_port: u32, dst_port: u32, flags: u32, data: &[u8]) {
+            let hdr = PacketHeader {
+                src_cid: self.dev.cid(),
+                dst_cid: VMADDR_CID_HOST,
+                src_port,
+                dst_port,
+                len: data.len() as u32,
+                kind: VIRTIO_VSOCK_TYPE_STREAM,
+                op,
+                flags,
+                buf_alloc: 1024,
+                fwd_cnt: 0,
+            };
+            let mut packet = hdr.encode().to_vec();
+            packet.extend_from_slice(data);
+            self.drivers[TX_QUEUE].add(&self.mem, &packet, 0);
+            self.dev
+                .queue_notify(TX_QUEUE, &mut self.queues, &self.mem)
+                .unwrap();
+        }
+
+        /// Tops the device up to four receive buffers and returns what it placed in them.
+        fn receive(&mut self) -> Vec<(PacketHeader, Vec<u8>)> {
+            while self.rx_bufs.len() < 4 {
+                let buf = self.drivers[RX_QUEUE].add(&self.mem, &[], 256);
+                self.rx_bufs.push(buf);
+            }
+            self.dev
+                .queue_notify(RX_QUEUE, &mut self.queues, &self.mem)
+                .unwrap();
+            self.drivers[RX_QUEUE]
+                .used(&self.mem)
+                .iter()
+                .map(|&(head, len)| {
+                    let at = self.rx_bufs.iter().position(|(h, _)| *h == head).unwrap();
+                    let addr = self.rx_bufs.remove(at).1;
+                    let mut packet = vec![0; len as usize];
+                    self.mem.read_guest(addr, &mut packet).unwrap();
+                    (
+                        PacketHeader::decode(&packet),
+                        packet[HEADER_SIZE..].to_vec(),
+                    )
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn guest_connects_to_a_service() {
+        let host = VsockHost::new();
+        let mut guest = Guest::new(&host);
+        assert_eq!(guest.dev.cid(), 3);
+        host.listen(1234).unwrap();
+
+        guest.send(OP_REQUEST, 5000, 1234, 0, &[]);
+        let stream = host.accept(1234).unwrap();
+        assert_eq!(stream.peer(), (3, 5000));
+        assert_eq!(stream.state(), VsockState::Connected);
+        guest.send(OP_RW, 5000, 1234, 0, b"ping");
+        let mut buf = [0; 16];
+        assert_eq!(stream.recv(&mut buf), 4);
+        assert_eq!(&buf[..4], b"ping");
+
+        assert_eq!(stream.send(b"pong").unwrap(), 4);
+        let packets = guest.receive();
+        let ops: Vec<_> = packets.iter().map(|(hdr, _)| hdr.op).collect();
+        assert_eq!(ops, [OP_RESPONSE, OP_RW]);
+        assert_eq!(packets[1].1, b"pong");
+
+        // Nobody listens on port 1.
+        guest.send(OP_REQUEST, 5001, 1, 0, &[]);
+        assert_eq!(guest.receive()[0].0.op, OP_RST);
+
+        // Closing from the hypervisor side.
+        drop(stream);
+        let packets = guest.receive();
+        assert_eq!(
+            (packets[0].0.op, packets[0].0.flags),
+            (OP_SHUTDOWN, SHUTDOWN_BOTH)
+        );
+        guest.send(OP_RST, 5000, 1234, 0, &[]);
+        assert!(host.inner.lock().conns.is_empty());
+    }
+
+    #[test]
+    fn service_connects_to_the_guest() {
+        let host = VsockHost::new();
+        let mut guest = Guest::new(&host);
+        assert!(host.connect(9, 22).is_err());
+        let stream = host.connect(guest.dev.cid(), 22).unwrap();
+        assert_eq!(stream.state(), VsockState::Connecting);
+        let packets = guest.receive();
+        let request = packets[0].0;
+        assert_eq!((request.op, request.dst_port), (OP_REQUEST, 22));
+
+        guest.send(OP_RESPONSE, 22, request.src_port, 0, &[]);
+        assert_eq!(stream.state(), VsockState::Connected);
+        guest.send(OP_SHUTDOWN, 22, request.src_port, SHUTDOWN_SEND, &[]);
+        assert_eq!(stream.state(), VsockState::PeerClosed);
+
+        // The guest's driver goes away.
+        guest.dev.reset();
+        assert_eq!(stream.state(), VsockState::Closed);
+    }
+}
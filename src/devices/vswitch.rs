@@ -0,0 +1,331 @@
+//! A learning Ethernet switch connecting the network devices of the VMs.
+//!
+//! Every VM network device gets a [`SwitchPort`], created with the MAC address the VM is
+//! configured with. The switch learns which port other source addresses are behind, forwards
+//! unicast frames to the port of their destination and floods broadcast, multicast and unknown
+//! unicast frames to all other ports. An optional [`Uplink`] bound to a real NIC by the
+//! embedder takes part in flooding and learning like any other port.
+//!
+//! Frames wait in a bounded queue at the receiving port until its device picks them up; when
+//! the queue is full they are dropped, as on a congested physical link.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::{HyperError, HyperResult};
+
+/// An Ethernet MAC address.
+pub type MacAddr = [u8; 6];
+
+/// Size of the destination, source and EtherType fields.
+pub const ETH_HEADER_SIZE: usize = 14;
+/// Largest frame forwarded: a 1500 byte payload with a VLAN tag, without FCS.
+pub const MAX_FRAME_SIZE: usize = 1518;
+/// Frames queued per port before further ones are dropped.
+pub const PORT_QUEUE_LEN: usize = 256;
+/// Addresses the switch learns at most, frames to further ones are flooded.
+pub const MAX_LEARNED: usize = 1024;
+
+/// The switch's link to a physical network, implemented by the embedder on top of a NIC
+/// driver. Called with the switch locked, it must not call back into the switch.
+pub trait Uplink: Send {
+    /// Sends `frame` out of the NIC.
+    fn transmit(&mut self, frame: &[u8]);
+
+    /// Returns the next frame the NIC received, if any.
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PortId {
+    Vm(usize),
+    Uplink,
+}
+
+#[derive(Clone, Copy)]
+struct FdbEntry {
+    port: PortId,
+    // Configured with the port, never moved by learning.
+    is_static: bool,
+}
+
+struct SwitchInner {
+    ports: Vec<Option<VecDeque<Vec<u8>>>>,
+    fdb: BTreeMap<MacAddr, FdbEntry>,
+    uplink: Option<Box<dyn Uplink>>,
+}
+
+/// A learning L2 switch. Cloning gives another handle to the same switch.
+#[derive(Clone)]
+pub struct VirtualSwitch {
+    inner: Arc<Mutex<SwitchInner>>,
+}
+
+impl Default for VirtualSwitch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualSwitch {
+    /// Creates a switch without ports.
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(SwitchInner {
+                ports: Vec::new(),
+                fdb: BTreeMap::new(),
+                uplink: None,
+            })),
+        }
+    }
+
+    /// Adds a port for a VM using `mac`. Fails if another port uses the address, or if it is a
+    /// multicast address.
+    pub fn add_port(&self, mac: MacAddr) -> HyperResult<SwitchPort> {
+        if is_multicast(&mac) {
+            return Err(HyperError::InvalidParam);
+        }
+        let mut inner = self.inner.lock();
+        if inner.fdb.get(&mac).map_or(false, |entry| entry.is_static) {
+            return Err(HyperError::BadState);
+        }
+        let id = match inner.ports.iter().position(Option::is_none) {
+            Some(id) => id,
+            None => {
+                inner.ports.push(None);
+                inner.ports.len() - 1
+            }
+        };
+        inner.ports[id] = Some(VecDeque::new());
+        inner.fdb.insert(
+            mac,
+            FdbEntry {
+                port: PortId::Vm(id),
+                is_static: true,
+            },
+        );
+        Ok(SwitchPort {
+            inner: self.inner.clone(),
+            id,
+            mac,
+        })
+    }
+
+    /// Connects the switch to a physical network through `uplink`, replacing the previous one.
+    pub fn set_uplink(&self, uplink: Box<dyn Uplink>) -> Option<Box<dyn Uplink>> {
+        let mut inner = self.inner.lock();
+        inner.forget_port(PortId::Uplink);
+        inner.uplink.replace(uplink)
+    }
+
+    /// Disconnects the uplink.
+    pub fn remove_uplink(&self) -> Option<Box<dyn Uplink>> {
+        let mut inner = self.inner.lock();
+        inner.forget_port(PortId::Uplink);
+        inner.uplink.take()
+    }
+
+    /// Forwards the frames the uplink received. Returns how many there were. The embedder calls
+    /// this from its NIC interrupt handler or periodically.
+    pub fn poll_uplink(&self) -> usize {
+        let mut inner = self.inner.lock();
+        let mut count = 0;
+        while let Some(frame) = inner.uplink.as_mut().and_then(|uplink| uplink.receive()) {
+            inner.forward(PortId::Uplink, &frame);
+            count += 1;
+        }
+        count
+    }
+}
+
+/// The connection of one VM network device to a [`VirtualSwitch`]. Dropping it removes the
+/// port from the switch.
+pub struct SwitchPort {
+    inner: Arc<Mutex<SwitchInner>>,
+    id: usize,
+    mac: MacAddr,
+}
+
+impl SwitchPort {
+    /// The MAC address the port was created with.
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    /// Hands a frame sent by the VM to the switch.
+    pub fn send(&self, frame: &[u8]) {
+        self.inner.lock().forward(PortId::Vm(self.id), frame);
+    }
+
+    /// Takes the next frame for the VM, if any.
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        self.inner.lock().ports[self.id].as_mut()?.pop_front()
+    }
+
+    /// Returns true if frames for the VM are waiting.
+    pub fn has_frames(&self) -> bool {
+        self.inner.lock().ports[self.id]
+            .as_ref()
+            .map_or(false, |queue| !queue.is_empty())
+    }
+}
+
+impl Drop for SwitchPort {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        inner.ports[self.id] = None;
+        inner.forget_port(PortId::Vm(self.id));
+    }
+}
+
+// Private methods implementation
+impl SwitchInner {
+    fn forget_port(&mut self, port: PortId) {
+        self.fdb.retain(|_, entry| entry.port != port);
+    }
+
+    fn learn(&mut self, mac: MacAddr, port: PortId) {
+        if let Some(entry) = self.fdb.get_mut(&mac) {
+            if !entry.is_static {
+                entry.port = port;
+            }
+        } else if self.fdb.len() < MAX_LEARNED {
+            let entry = FdbEntry {
+                port,
+                is_static: false,
+            };
+            self.fdb.insert(mac, entry);
+        }
+    }
+
+    fn forward(&mut self, ingress: PortId, frame: &[u8]) {
+        if frame.len() < ETH_HEADER_SIZE || frame.len() > MAX_FRAME_SIZE {
+            return;
+        }
+        let dst: MacAddr = frame[..6].try_into().unwrap();
+        let src: MacAddr = frame[6..12].try_into().unwrap();
+        if !is_multicast(&src) {
+            self.learn(src, ingress);
+        }
+        if !is_multicast(&dst) {
+            if let Some(entry) = self.fdb.get(&dst) {
+                let port = entry.port;
+                if port != ingress {
+                    self.deliver(port, frame);
+                }
+                return;
+            }
+        }
+        for id in 0..self.ports.len() {
+            if ingress != PortId::Vm(id) {
+                self.deliver(PortId::Vm(id), frame);
+            }
+        }
+        if ingress != PortId::Uplink {
+            self.deliver(PortId::Uplink, frame);
+        }
+    }
+
+    fn deliver(&mut self, port: PortId, frame: &[u8]) {
+        match port {
+            PortId::Vm(id) => {
+                if let Some(queue) = self.ports[id].as_mut() {
+                    if queue.len() < PORT_QUEUE_LEN {
+                        queue.push_back(frame.to_vec());
+                    }
+                }
+            }
+            PortId::Uplink => {
+                if let Some(uplink) = self.uplink.as_mut() {
+                    uplink.transmit(frame);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `mac` is a group address, i.e. multicast or broadcast.
+fn is_multicast(mac: &MacAddr) -> bool {
+    mac[0] & 1 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BROADCAST: MacAddr = [0xff; 6];
+
+    fn frame(dst: MacAddr, src: MacAddr, payload: u8) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&src);
+        frame.extend_from_slice(&[0x08, 0x00, payload]);
+        frame
+    }
+
+    struct FakeUplink {
+        sent: Arc<Mutex<Vec<Vec<u8>>>>,
+        incoming: VecDeque<Vec<u8>>,
+    }
+
+    impl Uplink for FakeUplink {
+        fn transmit(&mut self, frame: &[u8]) {
+            self.sent.lock().push(frame.to_vec());
+        }
+
+        fn receive(&mut self) -> Option<Vec<u8>> {
+            self.incoming.pop_front()
+        }
+    }
+
+    #[test]
+    fn frames_are_switched_between_ports() {
+        let switch = VirtualSwitch::new();
+        let a = switch.add_port([2, 0, 0, 0, 0, 1]).unwrap();
+        let b = switch.add_port([2, 0, 0, 0, 0, 2]).unwrap();
+        let c = switch.add_port([2, 0, 0, 0, 0, 3]).unwrap();
+        assert!(switch.add_port(b.mac()).is_err());
+
+        a.send(&frame(b.mac(), a.mac(), 1));
+        assert_eq!(b.recv(), Some(frame(b.mac(), a.mac(), 1)));
+        assert!(!c.has_frames());
+
+        b.send(&frame(BROADCAST, b.mac(), 2));
+        assert!(a.has_frames() && c.has_frames() && !b.has_frames());
+
+        // Frames from a removed port's address are flooded again.
+        let mac = c.mac();
+        drop(c);
+        a.recv();
+        a.send(&frame(mac, a.mac(), 3));
+        assert!(b.recv().is_some());
+    }
+
+    #[test]
+    fn uplink_learns_remote_addresses() {
+        let switch = VirtualSwitch::new();
+        let a = switch.add_port([2, 0, 0, 0, 0, 1]).unwrap();
+        let b = switch.add_port([2, 0, 0, 0, 0, 2]).unwrap();
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let remote = [2, 0, 0, 0, 0, 9];
+        switch.set_uplink(Box::new(FakeUplink {
+            sent: sent.clone(),
+            incoming: VecDeque::from(vec![frame(a.mac(), remote, 1)]),
+        }));
+
+        assert_eq!(switch.poll_uplink(), 1);
+        assert!(a.recv().is_some() && !b.has_frames());
+        // The remote address was learned behind the uplink.
+        a.send(&frame(remote, a.mac(), 2));
+        assert_eq!(sent.lock().len(), 1);
+        assert!(!b.has_frames());
+        // A VM cannot take over another VM's address.
+        b.send(&frame(remote, a.mac(), 3));
+        a.send(&frame(b.mac(), a.mac(), 4));
+        assert_eq!(b.recv(), Some(frame(b.mac(), a.mac(), 4)));
+    }
+}
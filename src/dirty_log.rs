@@ -0,0 +1,332 @@
+//! Logging of the guest pages written while a VM runs, for live migration and incremental
+//! snapshots.
+//!
+//! While logging is enabled, every page of the logged RAM regions is write-protected in the
+//! nested page table. The first write to a page faults, the fault marks the page in the region's
+//! [`DirtyBitmap`] and makes the page writable again, so later writes to it run at full speed.
+//! [`DirtyLog::get_and_clear`] hands out the pages written since the previous call and
+//! write-protects them again.
+//!
+//! The nested page table is reached through [`GuestPageTableTrait::set_writable`]; embedders
+//! wrapping one of the arch's [`NestedPageTable`](crate::NestedPageTable)s forward it to
+//! [`DirtyLogPageTable::set_writable`]. Invalidating the stale TLB entries after pages are
+//! write-protected is left to the caller, it depends on the arch and on where the VM runs.
+
+use alloc::vec::Vec;
+
+use crate::{memory::PAGE_SIZE_4K, GuestPageTableTrait, GuestPhysAddr, HyperError, HyperResult};
+
+/// Changing the write permission of single mappings of a nested page table.
+pub trait DirtyLogPageTable {
+    /// Grants or revokes write access to the mapping that covers `gpa`, keeping its other
+    /// permissions. Returns the size of the mapping, which may be a huge page.
+    fn set_writable(&mut self, gpa: GuestPhysAddr, writable: bool) -> HyperResult<usize>;
+}
+
+/// The pages written in one guest RAM region, one bit per 4K page.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirtyBitmap {
+    base: GuestPhysAddr,
+    pages: usize,
+    bits: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    /// Creates a bitmap of the `size` bytes at `base` with no page dirty.
+    pub fn new(base: GuestPhysAddr, size: usize) -> Self {
+        let pages = size / PAGE_SIZE_4K;
+        Self {
+            base,
+            pages,
+            bits: vec![0; (pages + 63) / 64],
+        }
+    }
+
+    /// Guest physical address of the first page of the region.
+    pub fn base(&self) -> GuestPhysAddr {
+        self.base
+    }
+
+    /// Size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE_4K
+    }
+
+    /// Whether `gpa` lies within the region.
+    pub fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        gpa >= self.base && gpa - self.base < self.size()
+    }
+
+    /// Whether the page containing `gpa` was written.
+    pub fn is_dirty(&self, gpa: GuestPhysAddr) -> bool {
+        if !self.contains(gpa) {
+            return false;
+        }
+        let page = (gpa - self.base) / PAGE_SIZE_4K;
+        self.bits[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    /// Marks the pages overlapping the `size` bytes at `gpa` as written. The part of the range
+    /// outside the region is ignored.
+    pub fn mark(&mut self, gpa: GuestPhysAddr, size: usize) {
+        let start = gpa.max(self.base);
+        let end = gpa.saturating_add(size).min(self.base + self.size());
+        if start >= end {
+            return;
+        }
+        let first = (start - self.base) / PAGE_SIZE_4K;
+        let last = (end - self.base + PAGE_SIZE_4K - 1) / PAGE_SIZE_4K;
+        for page in first..last {
+            self.bits[page / 64] |= 1 << (page % 64);
+        }
+    }
+
+    /// Number of pages written.
+    pub fn dirty_count(&self) -> usize {
+        self.bits.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Guest physical addresses of the pages written, in ascending order.
+    pub fn dirty_pages(&self) -> impl Iterator<Item = GuestPhysAddr> + '_ {
+        self.bits.iter().enumerate().flat_map(move |(index, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| self.base + (index * 64 + bit) * PAGE_SIZE_4K)
+        })
+    }
+
+    /// Marks every page of the region as written.
+    pub fn mark_all(&mut self) {
+        self.mark(self.base, self.size());
+    }
+
+    /// Marks the pages written in `other`, a bitmap of the same region, as written in this one
+    /// too.
+    pub fn merge(&mut self, other: &DirtyBitmap) -> HyperResult<()> {
+        if other.base != self.base || other.pages != self.pages {
+            return Err(HyperError::InvalidParam);
+        }
+        for (word, other) in self.bits.iter_mut().zip(other.bits.iter()) {
+            *word |= other;
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of the bitmap and clears this one.
+    fn take(&mut self) -> Self {
+        let taken = self.clone();
+        self.bits.iter_mut().for_each(|word| *word = 0);
+        taken
+    }
+}
+
+/// Dirty logging of the RAM regions of one VM.
+pub struct DirtyLog {
+    bitmaps: Vec<DirtyBitmap>,
+}
+
+impl DirtyLog {
+    /// Starts logging the writes to `regions`, each given by its start and size, by
+    /// write-protecting all their pages in `gpt`. Regions must be 4K aligned and mapped.
+    pub fn enable<G: GuestPageTableTrait>(
+        gpt: &mut G,
+        regions: &[(GuestPhysAddr, usize)],
+    ) -> HyperResult<Self> {
+        if regions
+            .iter()
+            .any(|&(gpa, size)| size == 0 || gpa % PAGE_SIZE_4K != 0 || size % PAGE_SIZE_4K != 0)
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        for &(gpa, size) in regions {
+            protect_range(gpt, gpa, size, false)?;
+        }
+        Ok(Self {
+            bitmaps: regions
+                .iter()
+                .map(|&(gpa, size)| DirtyBitmap::new(gpa, size))
+                .collect(),
+        })
+    }
+
+    /// Stops logging and makes every logged page writable again.
+    pub fn disable<G: GuestPageTableTrait>(self, gpt: &mut G) -> HyperResult<()> {
+        for bitmap in self.bitmaps.iter() {
+            protect_range(gpt, bitmap.base(), bitmap.size(), true)?;
+        }
+        Ok(())
+    }
+
+    /// Whether writes to `gpa` are logged.
+    pub fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        self.bitmaps.iter().any(|bitmap| bitmap.contains(gpa))
+    }
+
+    /// Handles a write fault of the guest at `gpa`: marks the faulting mapping as written and
+    /// makes it writable again. Returns false if `gpa` is not logged, the fault has another
+    /// cause then. The faulting access must be retried, not skipped.
+    pub fn handle_write_fault<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        gpa: GuestPhysAddr,
+    ) -> HyperResult<bool> {
+        if !self.contains(gpa) {
+            return Ok(false);
+        }
+        let size = gpt.set_writable(gpa, true)?;
+        self.mark(gpa & !(size - 1), size);
+        Ok(true)
+    }
+
+    /// Marks the pages of the `size` bytes at `gpa` as written, for changes to guest RAM that
+    /// did not go through a write fault.
+    pub fn mark(&mut self, gpa: GuestPhysAddr, size: usize) {
+        self.bitmaps
+            .iter_mut()
+            .for_each(|bitmap| bitmap.mark(gpa, size));
+    }
+
+    /// Returns the pages written since logging was enabled or since the previous call, one
+    /// bitmap per logged region, and write-protects them again. The caller must invalidate the
+    /// guest's stale TLB entries before the next write to them can be relied on to fault.
+    pub fn get_and_clear<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+    ) -> HyperResult<Vec<DirtyBitmap>> {
+        let taken: Vec<DirtyBitmap> = self.bitmaps.iter_mut().map(DirtyBitmap::take).collect();
+        for bitmap in taken.iter() {
+            for gpa in bitmap.dirty_pages() {
+                // Unmapped since it was written, e.g. by a balloon: there is nothing to protect.
+                if gpt.translate(gpa).is_ok() {
+                    gpt.set_writable(gpa, false)?;
+                }
+            }
+        }
+        Ok(taken)
+    }
+}
+
+/// Grants or revokes write access to the `size` bytes at `gpa`, one mapping at a time.
+fn protect_range<G: GuestPageTableTrait>(
+    gpt: &mut G,
+    gpa: GuestPhysAddr,
+    size: usize,
+    writable: bool,
+) -> HyperResult<()> {
+    let end = gpa + size;
+    let mut addr = gpa;
+    while addr < end {
+        if gpt.translate(addr).is_err() {
+            // Not backed by a frame, e.g. taken by a balloon. It is mapped writable when it
+            // gets one again.
+            addr += PAGE_SIZE_4K;
+            continue;
+        }
+        let mapping = gpt.set_writable(addr, writable)?;
+        addr = (addr & !(mapping - 1)) + mapping;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use page_table_entry::MappingFlags;
+
+    /// Page table of 4K mappings that only tracks write permission.
+    #[derive(Default)]
+    struct FakePageTable {
+        writable: BTreeMap<GuestPhysAddr, bool>,
+    }
+
+    impl GuestPageTableTrait for FakePageTable {
+        fn new() -> HyperResult<Self> {
+            Ok(Self::default())
+        }
+
+        fn map(&mut self, gpa: GuestPhysAddr, _: usize, flags: MappingFlags) -> HyperResult<()> {
+            self.writable
+                .insert(gpa, flags.contains(MappingFlags::WRITE));
+            Ok(())
+        }
+
+        fn map_region(
+            &mut self,
+            gpa: GuestPhysAddr,
+            hpa: usize,
+            size: usize,
+            flags: MappingFlags,
+        ) -> HyperResult<()> {
+            for offset in (0..size).step_by(PAGE_SIZE_4K) {
+                self.map(gpa + offset, hpa + offset, flags)?;
+            }
+            Ok(())
+        }
+
+        fn unmap(&mut self, gpa: GuestPhysAddr) -> HyperResult<()> {
+            self.writable.remove(&gpa).map(|_| ()).ok_or(HyperError::NotFound)
+        }
+
+        fn translate(&self, gpa: GuestPhysAddr) -> HyperResult<usize> {
+            match self.writable.contains_key(&(gpa & !(PAGE_SIZE_4K - 1))) {
+                true => Ok(gpa),
+                false => Err(HyperError::NotFound),
+            }
+        }
+
+        fn token(&self) -> usize {
+            0
+        }
+
+        fn set_writable(&mut self, gpa: GuestPhysAddr, writable: bool) -> HyperResult<usize> {
+            let entry = self
+                .writable
+                .get_mut(&(gpa & !(PAGE_SIZE_4K - 1)))
+                .ok_or(HyperError::NotFound)?;
+            *entry = writable;
+            Ok(PAGE_SIZE_4K)
+        }
+    }
+
+    #[test]
+    fn bitmap_marks_and_lists_pages() {
+        let mut bitmap = DirtyBitmap::new(0x8000_0000, 0x100 * PAGE_SIZE_4K);
+        bitmap.mark(0x8000_1234, 1);
+        bitmap.mark(0x8004_0000, 2 * PAGE_SIZE_4K);
+        // Only the part inside the region is marked.
+        bitmap.mark(0x7fff_f000, 2 * PAGE_SIZE_4K);
+        assert!(bitmap.is_dirty(0x8000_1fff));
+        assert!(!bitmap.is_dirty(0x8000_2000));
+        assert!(!bitmap.is_dirty(0x7fff_f000));
+        assert_eq!(bitmap.dirty_count(), 4);
+        let pages: Vec<_> = bitmap.dirty_pages().collect();
+        assert_eq!(pages, [0x8000_0000, 0x8000_1000, 0x8004_0000, 0x8004_1000]);
+    }
+
+    #[test]
+    fn write_faults_are_logged_until_cleared() {
+        let mut gpt = FakePageTable::default();
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+        gpt.map_region(0x8000_0000, 0, 4 * PAGE_SIZE_4K, flags).unwrap();
+        let mut log = DirtyLog::enable(&mut gpt, &[(0x8000_0000, 4 * PAGE_SIZE_4K)]).unwrap();
+        assert!(gpt.writable.values().all(|writable| !writable));
+
+        assert_eq!(log.handle_write_fault(&mut gpt, 0x8000_2008), Ok(true));
+        assert_eq!(log.handle_write_fault(&mut gpt, 0x9000_0000), Ok(false));
+        assert!(gpt.writable[&0x8000_2000]);
+
+        let dirty = log.get_and_clear(&mut gpt).unwrap();
+        assert_eq!(dirty[0].dirty_pages().collect::<Vec<_>>(), [0x8000_2000]);
+        assert!(!gpt.writable[&0x8000_2000]);
+        assert_eq!(log.get_and_clear(&mut gpt).unwrap()[0].dirty_count(), 0);
+
+        // A page unmapped after it was written, as by a balloon, is skipped.
+        log.handle_write_fault(&mut gpt, 0x8000_1000).unwrap();
+        gpt.unmap(0x8000_1000).unwrap();
+        assert_eq!(log.get_and_clear(&mut gpt).unwrap()[0].dirty_count(), 1);
+
+        log.disable(&mut gpt).unwrap();
+        assert!(gpt.writable.values().all(|writable| *writable));
+    }
+}
@@ -27,6 +27,11 @@ pub trait HyperCraftHal: Sized {
     fn alloc_pages(num_pages: usize) -> Option<HostVirtAddr>;
     /// Gives back the allocated pages starts from `pa` to the page allocator.
     fn dealloc_pages(va: HostVirtAddr, num_pages: usize);
+    /// Takes back the 4K frame at `hpa` of guest RAM the embedder mapped, once hypercraft
+    /// unmapped it for good, e.g. for a memory balloon. Frames hypercraft allocated itself go
+    /// back through `dealloc_pages` instead. The default leaves the frame with the embedder,
+    /// which frees it along with the rest of the VM's RAM.
+    fn release_guest_frame(_hpa: HostPhysAddr) {}
     // /// VM-Exit handler
     // fn vmexit_handler(vcpu: &mut crate::VCpu<Self>, vm_exit_info: VmExitInfo);
 
@@ -42,4 +47,10 @@ pub trait HyperCraftHal: Sized {
     /// Current time in nanoseconds.
     #[cfg(target_arch = "x86_64")]
     fn current_time_nanos() -> u64;
+    /// Fills `buf` with random bytes for the entropy devices of guests, from a hardware source
+    /// such as RDRAND or the Zkr `seed` CSR, or from a DRBG seeded by one. Returns how many bytes
+    /// were filled; the default has no entropy source.
+    fn fill_entropy(_buf: &mut [u8]) -> usize {
+        0
+    }
 }
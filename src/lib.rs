@@ -38,10 +38,18 @@ mod arch;
 #[path = "arch/x86_64/mod.rs"]
 mod arch;
 
+pub mod console;
+pub mod devices;
+pub mod dirty_log;
 mod hal;
 mod memory;
+pub mod migration;
+pub mod sched;
+pub mod snapshot;
+pub mod timer;
 mod traits;
 mod vcpus;
+pub mod vmid;
 
 /// HyperCraft Result Define.
 pub type HyperResult<T = ()> = Result<T, HyperError>;
@@ -53,19 +61,25 @@ pub use arch::{NestedPageTable, PerCpu, VCpu, VM};
 
 pub use hal::HyperCraftHal;
 pub use memory::{
-    GuestPageNum, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPageNum, HostPhysAddr,
-    HostVirtAddr,
+    GuestMemoryAccess, GuestPageNum, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr,
+    HostPageNum, HostPhysAddr, HostVirtAddr,
 };
 pub use vcpus::VmCpus;
 
 #[cfg(target_arch = "riscv64")]
-pub use arch::VMM;
+pub use arch::{
+    host_capabilities, init_imsic, run_ecall_bench, EcallBenchResult, HostCapabilities,
+    ImsicGeometry, SbiConsole, SbiExtensionHandler, SbiReturn, ECALL_BENCH_EID, ECALL_BENCH_GUEST,
+    VMM,
+};
 
 #[cfg(target_arch = "aarch64")]
-pub use arch::lower_aarch64_synchronous;
+pub use arch::{
+    lower_aarch64_synchronous, set_dirty_fault_handler, GicV2mFrame, GICV2M_FRAME_SIZE,
+};
 
 #[cfg(target_arch = "x86_64")]
-pub use arch::{VmxExitInfo, VmxExitReason};
+pub use arch::{msi_destination, DirtyFaultHandler, EptDirtyLog, VmxExitInfo, VmxExitReason};
 
 /// The error type for hypervisor operation failures.
 #[derive(Debug, PartialEq)]
@@ -1,4 +1,4 @@
-use crate::{HyperCraftHal, HyperResult};
+use crate::{HyperCraftHal, HyperError, HyperResult};
 use page_table_entry::MappingFlags;
 
 /// Guest physical address.
@@ -50,4 +50,70 @@ pub trait GuestPageTableTrait {
 
     /// Get guest page table token.
     fn token(&self) -> usize;
+
+    /// Grants or revokes write access to the mapping that covers `gpa`, returning the size of
+    /// the mapping. Needed for dirty logging, see [`crate::dirty_log`].
+    fn set_writable(&mut self, gpa: GuestPhysAddr, writable: bool) -> HyperResult<usize> {
+        let _ = (gpa, writable);
+        Err(HyperError::NotSupported)
+    }
+
+    /// Size of the mapping that covers `gpa`, which may be a huge page. Needed to discard single
+    /// pages of guest RAM, e.g. for a memory balloon. For the arch's
+    /// [`NestedPageTable`](crate::NestedPageTable) it is the page size `query` returns.
+    fn mapping_size(&self, gpa: GuestPhysAddr) -> HyperResult<usize> {
+        let _ = gpa;
+        Err(HyperError::NotSupported)
+    }
+}
+
+/// Access to the guest physical memory of the VM that is currently loaded on this CPU.
+///
+/// Used by emulated devices and hypercall handlers that need to read or write buffers the guest
+/// hands to them by guest physical address.
+pub trait GuestMemoryAccess {
+    /// Copies `buf.len()` bytes starting at guest physical address `gpa` into `buf`.
+    fn read_guest(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()>;
+
+    /// Copies `buf` into guest physical memory starting at `gpa`.
+    fn write_guest(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HyperResult<()>;
+
+    /// Reads a little-endian `u32` from guest physical address `gpa`.
+    fn read_guest_u32(&self, gpa: GuestPhysAddr) -> HyperResult<u32> {
+        let mut bytes = [0u8; 4];
+        self.read_guest(gpa, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Reads a little-endian `u64` from guest physical address `gpa`.
+    fn read_guest_u64(&self, gpa: GuestPhysAddr) -> HyperResult<u64> {
+        let mut bytes = [0u8; 8];
+        self.read_guest(gpa, &mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Writes a little-endian `u32` to guest physical address `gpa`.
+    fn write_guest_u32(&self, gpa: GuestPhysAddr, val: u32) -> HyperResult<()> {
+        self.write_guest(gpa, &val.to_le_bytes())
+    }
+
+    /// Writes a little-endian `u64` to guest physical address `gpa`.
+    fn write_guest_u64(&self, gpa: GuestPhysAddr, val: u64) -> HyperResult<()> {
+        self.write_guest(gpa, &val.to_le_bytes())
+    }
+
+    /// Takes the RAM page at `gpa` away from the guest and gives its host frame back to the
+    /// hypervisor, e.g. when a memory balloon inflates. If the guest touches the page again, it
+    /// gets a zeroed one.
+    fn discard_page(&self, gpa: GuestPhysAddr) -> HyperResult<()> {
+        let _ = gpa;
+        Err(HyperError::NotSupported)
+    }
+
+    /// Gives the guest a zeroed frame for the RAM page at `gpa` if it was discarded, and does
+    /// nothing otherwise.
+    fn populate_page(&self, gpa: GuestPhysAddr) -> HyperResult<()> {
+        let _ = gpa;
+        Err(HyperError::NotSupported)
+    }
 }
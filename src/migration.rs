@@ -0,0 +1,418 @@
+//! Pre-copy live migration of a VM to another hypervisor.
+//!
+//! The source copies all guest RAM while the VM keeps running, with dirty logging on. Each
+//! following round resends the pages written during the previous one, until few enough pages
+//! are left or the round limit is reached. The VMM then stops the VM's vCPUs, and the pages
+//! written since the last round are sent along with the state of the vCPUs and devices.
+//!
+//! The stream is a snapshot in the format of [`crate::snapshot`] in which a page may appear in
+//! several RAM sections, the last one holding its final contents. The destination restores it
+//! like any snapshot and answers with a 4-byte status, so the source knows whether it may drop
+//! the VM or has to resume it.
+//!
+//! Bytes travel over a [`MigrationTransport`] provided by the embedder, e.g. a network
+//! connection. [`MemoryPipe`] connects two VMs within the same hypervisor.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::{
+    dirty_log::DirtyBitmap,
+    memory::PAGE_SIZE_4K,
+    snapshot::{SnapshotSink, SnapshotSource, SnapshotWriter},
+    GuestPhysAddr, HyperError, HyperResult,
+};
+
+/// Status the destination sends once the VM was restored.
+const STATUS_DONE: [u8; 4] = *b"DONE";
+/// Status the destination sends if restoring the VM failed.
+const STATUS_FAILED: [u8; 4] = *b"FAIL";
+
+/// A reliable byte stream between the source and the destination hypervisor, provided by the
+/// embedder. Reads block until enough bytes arrived.
+pub trait MigrationTransport: SnapshotSink + SnapshotSource {}
+
+/// A VM that can be migrated away.
+pub trait MigrationSource {
+    /// Start and size of each guest RAM region.
+    fn ram_regions(&self) -> Vec<(GuestPhysAddr, usize)>;
+
+    /// Starts logging the guest's writes to its RAM regions.
+    fn enable_dirty_log(&self) -> HyperResult<()>;
+
+    /// Returns the pages written since the previous call, one bitmap per RAM region in the
+    /// order of `ram_regions`.
+    fn get_and_clear_dirty_log(&self) -> HyperResult<Vec<DirtyBitmap>>;
+
+    /// Stops logging writes.
+    fn disable_dirty_log(&self) -> HyperResult<()>;
+
+    /// Copies guest RAM at `gpa` into `buf`.
+    fn read_ram(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()>;
+
+    /// Writes the state of the vCPUs and devices as snapshot sections. The vCPUs are stopped.
+    fn save_state(&self, writer: &mut SnapshotWriter) -> HyperResult<()>;
+}
+
+/// A VM that can receive a migrated one.
+pub trait MigrationTarget {
+    /// Restores the snapshot read from `source`, up to and including its end section.
+    fn restore(&mut self, source: &mut dyn SnapshotSource) -> HyperResult<()>;
+}
+
+/// Settings of a migration.
+#[derive(Clone, Copy, Debug)]
+pub struct MigrationConfig {
+    /// Rounds of copying before the VM is stopped, however many pages are still dirty. The
+    /// first round copies all of RAM.
+    pub max_rounds: usize,
+    /// The VM is stopped once a round leaves at most this many dirty pages.
+    pub stop_copy_pages: usize,
+}
+
+impl Default for MigrationConfig {
+    fn default() -> Self {
+        Self {
+            max_rounds: 30,
+            stop_copy_pages: 256,
+        }
+    }
+}
+
+/// What a migration transferred.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MigrationStats {
+    /// Rounds copied while the VM was running.
+    pub rounds: usize,
+    /// Pages sent in all rounds and in the final phase.
+    pub pages_sent: usize,
+    /// Pages sent after the VM was stopped.
+    pub stop_copy_pages: usize,
+}
+
+/// Migrates `vm` to the hypervisor at the other end of `transport`, which runs
+/// [`migrate_in`]. Once the pre-copy rounds are over `stop` is called, it must keep the VM's
+/// vCPUs from running again and return once none is running.
+///
+/// On success the VM lives on at the destination and must not be resumed here. On failure the
+/// VMM may resume it.
+pub fn migrate_out<V: MigrationSource, T: MigrationTransport>(
+    vm: &V,
+    transport: &mut T,
+    config: &MigrationConfig,
+    stop: impl FnOnce() -> HyperResult<()>,
+) -> HyperResult<MigrationStats> {
+    vm.enable_dirty_log()?;
+    let sent = send_vm(vm, transport, config, stop);
+    let disabled = vm.disable_dirty_log();
+    let stats = sent?;
+    disabled?;
+    let mut status = [0; 4];
+    transport.read(&mut status)?;
+    match status {
+        STATUS_DONE => Ok(stats),
+        _ => Err(HyperError::BadState),
+    }
+}
+
+/// Receives the VM sent by [`migrate_out`] into `vm`, which must be set up like the source VM,
+/// and reports the outcome to the source.
+pub fn migrate_in<V: MigrationTarget, T: MigrationTransport>(
+    vm: &mut V,
+    transport: &mut T,
+) -> HyperResult<()> {
+    let restored = vm.restore(transport);
+    let status = match restored {
+        Ok(()) => STATUS_DONE,
+        Err(_) => STATUS_FAILED,
+    };
+    transport.write(&status)?;
+    restored
+}
+
+fn send_vm<V: MigrationSource, T: MigrationTransport>(
+    vm: &V,
+    transport: &mut T,
+    config: &MigrationConfig,
+    stop: impl FnOnce() -> HyperResult<()>,
+) -> HyperResult<MigrationStats> {
+    let mut stats = MigrationStats::default();
+    let mut page = vec![0; PAGE_SIZE_4K];
+    let mut writer = SnapshotWriter::new(transport)?;
+    for (gpa, size) in vm.ram_regions() {
+        send_range(vm, &mut writer, gpa, size, &mut page)?;
+        stats.pages_sent += size / PAGE_SIZE_4K;
+    }
+    stats.rounds = 1;
+
+    let mut dirty = vm.get_and_clear_dirty_log()?;
+    while stats.rounds < config.max_rounds && dirty_count(&dirty) > config.stop_copy_pages {
+        stats.pages_sent += send_dirty(vm, &mut writer, &dirty, &mut page)?;
+        stats.rounds += 1;
+        dirty = vm.get_and_clear_dirty_log()?;
+    }
+    debug!(
+        "migration: {} rounds, stopping with {} dirty pages",
+        stats.rounds,
+        dirty_count(&dirty)
+    );
+
+    stop()?;
+    // The pages of the last round and the ones written until the vCPUs stopped.
+    for (bitmap, last) in dirty.iter_mut().zip(vm.get_and_clear_dirty_log()?.iter()) {
+        bitmap.merge(last)?;
+    }
+    stats.stop_copy_pages = send_dirty(vm, &mut writer, &dirty, &mut page)?;
+    stats.pages_sent += stats.stop_copy_pages;
+    vm.save_state(&mut writer)?;
+    writer.finish()?;
+    Ok(stats)
+}
+
+fn dirty_count(bitmaps: &[DirtyBitmap]) -> usize {
+    bitmaps.iter().map(DirtyBitmap::dirty_count).sum()
+}
+
+/// Sends the dirty pages in `bitmaps`, one RAM section per run of consecutive pages. Returns
+/// the number of pages sent.
+fn send_dirty<V: MigrationSource>(
+    vm: &V,
+    writer: &mut SnapshotWriter,
+    bitmaps: &[DirtyBitmap],
+    page: &mut [u8],
+) -> HyperResult<usize> {
+    for bitmap in bitmaps {
+        let mut run: Option<(GuestPhysAddr, usize)> = None;
+        for gpa in bitmap.dirty_pages() {
+            run = match run {
+                Some((start, len)) if start + len == gpa => Some((start, len + PAGE_SIZE_4K)),
+                Some((start, len)) => {
+                    send_range(vm, writer, start, len, page)?;
+                    Some((gpa, PAGE_SIZE_4K))
+                }
+                None => Some((gpa, PAGE_SIZE_4K)),
+            };
+        }
+        if let Some((start, len)) = run {
+            send_range(vm, writer, start, len, page)?;
+        }
+    }
+    Ok(dirty_count(bitmaps))
+}
+
+/// Sends the `len` bytes of guest RAM at `gpa` as one RAM section, a page at a time.
+fn send_range<V: MigrationSource>(
+    vm: &V,
+    writer: &mut SnapshotWriter,
+    gpa: GuestPhysAddr,
+    len: usize,
+    page: &mut [u8],
+) -> HyperResult<()> {
+    writer.begin_ram(gpa, len)?;
+    for offset in (0..len).step_by(PAGE_SIZE_4K) {
+        vm.read_ram(gpa + offset, page)?;
+        writer.write_raw(page)?;
+    }
+    Ok(())
+}
+
+/// One end of an in-memory byte stream between two VMs of the same hypervisor, e.g. migrated
+/// from one hart to another. Reads spin until the other end wrote enough bytes, and fail once it
+/// is dropped.
+pub struct MemoryPipe {
+    tx: Arc<Mutex<VecDeque<u8>>>,
+    rx: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl MemoryPipe {
+    /// Creates the two connected ends of a pipe.
+    pub fn new() -> (Self, Self) {
+        let a = Arc::new(Mutex::new(VecDeque::new()));
+        let b = Arc::new(Mutex::new(VecDeque::new()));
+        (
+            Self {
+                tx: a.clone(),
+                rx: b.clone(),
+            },
+            Self { tx: b, rx: a },
+        )
+    }
+}
+
+impl SnapshotSink for MemoryPipe {
+    fn write(&mut self, data: &[u8]) -> HyperResult<()> {
+        self.tx.lock().extend(data.iter());
+        Ok(())
+    }
+}
+
+impl SnapshotSource for MemoryPipe {
+    fn read(&mut self, buf: &mut [u8]) -> HyperResult<()> {
+        loop {
+            {
+                let mut rx = self.rx.lock();
+                if rx.len() >= buf.len() {
+                    buf.iter_mut().zip(rx.drain(..buf.len())).for_each(|(b, c)| *b = c);
+                    return Ok(());
+                }
+                if Arc::strong_count(&self.rx) == 1 {
+                    return Err(HyperError::DecodeError);
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl MigrationTransport for MemoryPipe {}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::snapshot::{Decoder, Encoder, SnapshotReader, RAM_TAG, VCPU_TAG};
+    use core::cell::{Cell, RefCell};
+
+    const RAM_BASE: GuestPhysAddr = 0x8000_0000;
+    const RAM_PAGES: usize = 16;
+
+    /// A VM whose guest writes one page every time its dirty log is read, until it is stopped.
+    #[derive(Default)]
+    struct FakeVm {
+        ram: RefCell<Vec<u8>>,
+        dirty: RefCell<Option<DirtyBitmap>>,
+        reg: u64,
+        stopped: Cell<bool>,
+        writes: Cell<usize>,
+    }
+
+    impl FakeVm {
+        fn new() -> Self {
+            Self {
+                ram: RefCell::new(vec![0; RAM_PAGES * PAGE_SIZE_4K]),
+                ..Default::default()
+            }
+        }
+
+        fn guest_write(&self, gpa: GuestPhysAddr, val: u8) {
+            self.ram.borrow_mut()[gpa - RAM_BASE] = val;
+            if let Some(bitmap) = self.dirty.borrow_mut().as_mut() {
+                bitmap.mark(gpa, 1);
+            }
+        }
+    }
+
+    impl MigrationSource for FakeVm {
+        fn ram_regions(&self) -> Vec<(GuestPhysAddr, usize)> {
+            vec![(RAM_BASE, RAM_PAGES * PAGE_SIZE_4K)]
+        }
+
+        fn enable_dirty_log(&self) -> HyperResult<()> {
+            *self.dirty.borrow_mut() = Some(DirtyBitmap::new(RAM_BASE, RAM_PAGES * PAGE_SIZE_4K));
+            Ok(())
+        }
+
+        fn get_and_clear_dirty_log(&self) -> HyperResult<Vec<DirtyBitmap>> {
+            if !self.stopped.get() {
+                let n = self.writes.get();
+                self.writes.set(n + 1);
+                self.guest_write(RAM_BASE + (n % RAM_PAGES) * PAGE_SIZE_4K, n as u8 + 1);
+            }
+            let mut dirty = self.dirty.borrow_mut();
+            let bitmap = dirty.as_mut().ok_or(HyperError::BadState)?;
+            let taken = bitmap.clone();
+            *bitmap = DirtyBitmap::new(RAM_BASE, RAM_PAGES * PAGE_SIZE_4K);
+            Ok(vec![taken])
+        }
+
+        fn disable_dirty_log(&self) -> HyperResult<()> {
+            self.dirty.borrow_mut().take().map(|_| ()).ok_or(HyperError::BadState)
+        }
+
+        fn read_ram(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult<()> {
+            let offset = gpa - RAM_BASE;
+            buf.copy_from_slice(&self.ram.borrow()[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn save_state(&self, writer: &mut SnapshotWriter) -> HyperResult<()> {
+            let mut out = Encoder::new();
+            out.put_u64(self.reg);
+            writer.write_section(VCPU_TAG, 1, &out)
+        }
+    }
+
+    impl MigrationTarget for FakeVm {
+        fn restore(&mut self, source: &mut dyn SnapshotSource) -> HyperResult<()> {
+            let mut reader = SnapshotReader::new(source)?;
+            while let Some(section) = reader.next_section()? {
+                match section.tag {
+                    VCPU_TAG => {
+                        let payload = reader.read_payload()?;
+                        self.reg = Decoder::new(&payload).get_u64()?;
+                    }
+                    RAM_TAG => {
+                        let (gpa, len) = reader.read_ram_header()?;
+                        let offset = gpa - RAM_BASE;
+                        reader.read_raw(&mut self.ram.get_mut()[offset..offset + len])?;
+                    }
+                    _ => {}
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pre_copy_converges_and_transfers_state() {
+        let mut source = FakeVm::new();
+        source.reg = 0x1234;
+        source.guest_write(RAM_BASE + 5 * PAGE_SIZE_4K + 7, 0xaa);
+        let (mut src_end, mut dst_end) = MemoryPipe::new();
+
+        let destination = std::thread::spawn(move || {
+            let mut vm = FakeVm::new();
+            migrate_in(&mut vm, &mut dst_end).map(|_| vm)
+        });
+        let config = MigrationConfig {
+            max_rounds: 4,
+            stop_copy_pages: 0,
+        };
+        let stats = migrate_out(&source, &mut src_end, &config, || {
+            // Written after the last round, before the vCPUs stop.
+            source.guest_write(RAM_BASE + 9 * PAGE_SIZE_4K, 0x55);
+            source.stopped.set(true);
+            Ok(())
+        })
+        .unwrap();
+        let destination = destination.join().unwrap().unwrap();
+
+        assert_eq!(stats.rounds, 4);
+        assert_eq!(stats.stop_copy_pages, 2);
+        assert_eq!(stats.pages_sent, RAM_PAGES + 3 + 2);
+        assert_eq!(destination.reg, 0x1234);
+        assert!(*destination.ram.borrow() == *source.ram.borrow());
+        assert!(source.dirty.borrow().is_none());
+    }
+
+    #[test]
+    fn failed_restore_is_reported_to_the_source() {
+        let source = FakeVm::new();
+        let (mut src_end, mut dst_end) = MemoryPipe::new();
+        let destination = std::thread::spawn(move || {
+            let mut header = [0; 8];
+            dst_end.read(&mut header).unwrap();
+            dst_end.write(&STATUS_FAILED).unwrap();
+            // Drain the rest of the stream until the source hangs up.
+            let mut byte = [0];
+            while dst_end.read(&mut byte).is_ok() {}
+        });
+        let result = migrate_out(&source, &mut src_end, &MigrationConfig::default(), || Ok(()));
+        assert_eq!(result, Err(HyperError::BadState));
+        drop(src_end);
+        destination.join().unwrap();
+    }
+}
@@ -0,0 +1,407 @@
+//! vCPU scheduling policies for the VMM.
+//!
+//! A [`Scheduler`] only decides which vCPU runs next and for how long, the VMM does the actual
+//! switching. A vCPU is in one of three states: queued, running (returned by
+//! [`Scheduler::pick_next`]) or blocked. The VMM reports every end of a run with either
+//! [`Scheduler::on_exit`] or [`Scheduler::on_block`]. Times are in host timer ticks.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// Identifies a vCPU of a VM managed by the VMM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VcpuHandle {
+    /// The VM's index in the VMM.
+    pub vm_id: usize,
+    /// The vCPU's id within the VM.
+    pub vcpu_id: usize,
+}
+
+impl VcpuHandle {
+    /// Creates a handle for vCPU `vcpu_id` of VM `vm_id`.
+    pub const fn new(vm_id: usize, vcpu_id: usize) -> Self {
+        Self { vm_id, vcpu_id }
+    }
+}
+
+/// Scheduling parameters, set per VM and applied to each of its vCPUs.
+#[derive(Clone, Copy, Debug)]
+pub struct SchedParams {
+    /// Maximum time a vCPU runs before it is preempted.
+    pub time_slice: u64,
+    /// Relative share of CPU time under the credit scheduler.
+    pub weight: u32,
+    /// Priority under the fixed-priority scheduler, higher runs first.
+    pub priority: u8,
+}
+
+impl Default for SchedParams {
+    fn default() -> Self {
+        Self {
+            time_slice: DEFAULT_TIME_SLICE,
+            weight: DEFAULT_WEIGHT,
+            priority: 0,
+        }
+    }
+}
+
+/// Default time slice, 20ms with the 10MHz timer of QEMU's virt machine.
+pub const DEFAULT_TIME_SLICE: u64 = 200_000;
+/// Default credit scheduler weight.
+pub const DEFAULT_WEIGHT: u32 = 256;
+
+/// A vCPU scheduling policy.
+pub trait Scheduler: Send {
+    /// Adds a runnable vCPU.
+    fn add_vcpu(&mut self, vcpu: VcpuHandle, params: SchedParams);
+
+    /// Forgets a vCPU, whatever its state.
+    fn remove_vcpu(&mut self, vcpu: VcpuHandle);
+
+    /// Dequeues the vCPU to run next and returns it with the length of its time slice. Returns
+    /// `None` if no vCPU is runnable.
+    fn pick_next(&mut self) -> Option<(VcpuHandle, u64)>;
+
+    /// The running `vcpu` was descheduled after running for `ran` ticks, but is still runnable.
+    fn on_exit(&mut self, vcpu: VcpuHandle, ran: u64);
+
+    /// The running `vcpu` ran for `ran` ticks and now waits for an event, e.g. after a WFI.
+    fn on_block(&mut self, vcpu: VcpuHandle, ran: u64);
+
+    /// The blocked `vcpu` became runnable again.
+    fn on_wake(&mut self, vcpu: VcpuHandle);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum VcpuState {
+    Queued,
+    Running,
+    Blocked,
+}
+
+struct Entry {
+    vcpu: VcpuHandle,
+    params: SchedParams,
+    state: VcpuState,
+    // Remaining credit in ticks, only used by the credit scheduler.
+    credit: i64,
+}
+
+/// The vCPUs known to a scheduler and their states, with the queued ones in FIFO order.
+#[derive(Default)]
+struct RunQueue {
+    entries: Vec<Entry>,
+    queue: VecDeque<VcpuHandle>,
+}
+
+impl RunQueue {
+    fn add(&mut self, vcpu: VcpuHandle, params: SchedParams) {
+        self.remove(vcpu);
+        self.entries.push(Entry {
+            vcpu,
+            params,
+            state: VcpuState::Queued,
+            credit: 0,
+        });
+        self.queue.push_back(vcpu);
+    }
+
+    fn remove(&mut self, vcpu: VcpuHandle) {
+        self.entries.retain(|e| e.vcpu != vcpu);
+        self.queue.retain(|v| *v != vcpu);
+    }
+
+    fn entry(&mut self, vcpu: VcpuHandle) -> Option<&mut Entry> {
+        self.entries.iter_mut().find(|e| e.vcpu == vcpu)
+    }
+
+    /// Dequeues the first queued vCPU accepted by `filter` and marks it running.
+    fn take_first(&mut self, filter: impl Fn(&Entry) -> bool) -> Option<(VcpuHandle, u64)> {
+        let entries = &self.entries;
+        let pos = self.queue.iter().position(|v| {
+            entries
+                .iter()
+                .find(|e| e.vcpu == *v)
+                .map_or(false, &filter)
+        })?;
+        let vcpu = self.queue.remove(pos)?;
+        let entry = self.entry(vcpu)?;
+        entry.state = VcpuState::Running;
+        Some((vcpu, entry.params.time_slice))
+    }
+
+    /// Moves `vcpu` to `state`, appending it to the queue if it becomes queued.
+    fn set_state(&mut self, vcpu: VcpuHandle, state: VcpuState) {
+        let entry = match self.entry(vcpu) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let old = core::mem::replace(&mut entry.state, state);
+        if old == VcpuState::Queued && state != VcpuState::Queued {
+            self.queue.retain(|v| *v != vcpu);
+        } else if old != VcpuState::Queued && state == VcpuState::Queued {
+            self.queue.push_back(vcpu);
+        }
+    }
+}
+
+/// Runs the runnable vCPUs in turn, each for its VM's time slice.
+#[derive(Default)]
+pub struct RoundRobinScheduler {
+    rq: RunQueue,
+}
+
+impl RoundRobinScheduler {
+    /// Creates a scheduler without vCPUs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn add_vcpu(&mut self, vcpu: VcpuHandle, params: SchedParams) {
+        self.rq.add(vcpu, params);
+    }
+
+    fn remove_vcpu(&mut self, vcpu: VcpuHandle) {
+        self.rq.remove(vcpu);
+    }
+
+    fn pick_next(&mut self) -> Option<(VcpuHandle, u64)> {
+        self.rq.take_first(|_| true)
+    }
+
+    fn on_exit(&mut self, vcpu: VcpuHandle, _ran: u64) {
+        self.rq.set_state(vcpu, VcpuState::Queued);
+    }
+
+    fn on_block(&mut self, vcpu: VcpuHandle, _ran: u64) {
+        self.rq.set_state(vcpu, VcpuState::Blocked);
+    }
+
+    fn on_wake(&mut self, vcpu: VcpuHandle) {
+        if self.rq.entry(vcpu).map_or(false, |e| e.state == VcpuState::Blocked) {
+            self.rq.set_state(vcpu, VcpuState::Queued);
+        }
+    }
+}
+
+/// Always runs a runnable vCPU of the highest priority, round-robin among equal priorities.
+/// Lower priority vCPUs starve as long as a higher priority one is runnable.
+#[derive(Default)]
+pub struct FixedPriorityScheduler {
+    rq: RunQueue,
+}
+
+impl FixedPriorityScheduler {
+    /// Creates a scheduler without vCPUs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Scheduler for FixedPriorityScheduler {
+    fn add_vcpu(&mut self, vcpu: VcpuHandle, params: SchedParams) {
+        self.rq.add(vcpu, params);
+    }
+
+    fn remove_vcpu(&mut self, vcpu: VcpuHandle) {
+        self.rq.remove(vcpu);
+    }
+
+    fn pick_next(&mut self) -> Option<(VcpuHandle, u64)> {
+        let top = self
+            .rq
+            .entries
+            .iter()
+            .filter(|e| e.state == VcpuState::Queued)
+            .map(|e| e.params.priority)
+            .max()?;
+        self.rq.take_first(|e| e.params.priority == top)
+    }
+
+    fn on_exit(&mut self, vcpu: VcpuHandle, _ran: u64) {
+        self.rq.set_state(vcpu, VcpuState::Queued);
+    }
+
+    fn on_block(&mut self, vcpu: VcpuHandle, _ran: u64) {
+        self.rq.set_state(vcpu, VcpuState::Blocked);
+    }
+
+    fn on_wake(&mut self, vcpu: VcpuHandle) {
+        if self.rq.entry(vcpu).map_or(false, |e| e.state == VcpuState::Blocked) {
+            self.rq.set_state(vcpu, VcpuState::Queued);
+        }
+    }
+}
+
+/// Shares the CPU in proportion to the weights of the vCPUs, in the style of Xen's credit
+/// scheduler.
+///
+/// Running consumes credit. vCPUs with credit left run first, in turn. Once every runnable vCPU
+/// has used up its credit, a new accounting period starts and each of them earns the share of
+/// the period given by its weight.
+#[derive(Default)]
+pub struct CreditScheduler {
+    rq: RunQueue,
+}
+
+impl CreditScheduler {
+    /// Creates a scheduler without vCPUs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn replenish(&mut self) {
+        let runnable = || {
+            self.rq
+                .entries
+                .iter()
+                .filter(|e| e.state != VcpuState::Blocked)
+        };
+        let period: u64 = runnable().map(|e| e.params.time_slice).sum();
+        let total_weight: u64 = runnable().map(|e| e.params.weight as u64).sum();
+        if total_weight == 0 {
+            return;
+        }
+        for entry in self.rq.entries.iter_mut() {
+            if entry.state == VcpuState::Blocked {
+                continue;
+            }
+            let share = (period * entry.params.weight as u64 / total_weight) as i64;
+            // Unused credit is not hoarded beyond one period.
+            entry.credit = (entry.credit + share).min(period as i64);
+        }
+    }
+}
+
+impl Scheduler for CreditScheduler {
+    fn add_vcpu(&mut self, vcpu: VcpuHandle, params: SchedParams) {
+        self.rq.add(vcpu, params);
+    }
+
+    fn remove_vcpu(&mut self, vcpu: VcpuHandle) {
+        self.rq.remove(vcpu);
+    }
+
+    fn pick_next(&mut self) -> Option<(VcpuHandle, u64)> {
+        if let Some(next) = self.rq.take_first(|e| e.credit > 0) {
+            return Some(next);
+        }
+        self.replenish();
+        self.rq
+            .take_first(|e| e.credit > 0)
+            .or_else(|| self.rq.take_first(|_| true))
+    }
+
+    fn on_exit(&mut self, vcpu: VcpuHandle, ran: u64) {
+        if let Some(entry) = self.rq.entry(vcpu) {
+            entry.credit -= ran as i64;
+        }
+        self.rq.set_state(vcpu, VcpuState::Queued);
+    }
+
+    fn on_block(&mut self, vcpu: VcpuHandle, ran: u64) {
+        if let Some(entry) = self.rq.entry(vcpu) {
+            entry.credit -= ran as i64;
+        }
+        self.rq.set_state(vcpu, VcpuState::Blocked);
+    }
+
+    fn on_wake(&mut self, vcpu: VcpuHandle) {
+        if self.rq.entry(vcpu).map_or(false, |e| e.state == VcpuState::Blocked) {
+            self.rq.set_state(vcpu, VcpuState::Queued);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(time_slice: u64, weight: u32, priority: u8) -> SchedParams {
+        SchedParams {
+            time_slice,
+            weight,
+            priority,
+        }
+    }
+
+    /// Runs every picked vCPU for its full time slice, `rounds` times, and returns the vCPUs in
+    /// the order they ran.
+    fn run_slices(sched: &mut dyn Scheduler, rounds: usize) -> Vec<VcpuHandle> {
+        let mut order = Vec::new();
+        for _ in 0..rounds {
+            let (vcpu, time_slice) = sched.pick_next().unwrap();
+            sched.on_exit(vcpu, time_slice);
+            order.push(vcpu);
+        }
+        order
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let (a, b, c) = (VcpuHandle::new(0, 0), VcpuHandle::new(0, 1), VcpuHandle::new(1, 0));
+        let mut sched = RoundRobinScheduler::new();
+        assert!(sched.pick_next().is_none());
+        sched.add_vcpu(a, SchedParams::default());
+        sched.add_vcpu(b, params(100, DEFAULT_WEIGHT, 0));
+        sched.add_vcpu(c, SchedParams::default());
+        assert_eq!(run_slices(&mut sched, 4), [a, b, c, a]);
+
+        // A blocked vCPU is skipped until it is woken, and then queued last.
+        assert_eq!(sched.pick_next(), Some((b, 100)));
+        sched.on_block(b, 10);
+        assert_eq!(run_slices(&mut sched, 3), [c, a, c]);
+        sched.on_wake(b);
+        // Waking a runnable vCPU does not queue it twice.
+        sched.on_wake(a);
+        assert_eq!(run_slices(&mut sched, 4), [a, c, b, a]);
+
+        sched.remove_vcpu(b);
+        assert_eq!(run_slices(&mut sched, 3), [c, a, c]);
+    }
+
+    #[test]
+    fn fixed_priority_is_strict() {
+        let low = VcpuHandle::new(0, 0);
+        let (high1, high2) = (VcpuHandle::new(1, 0), VcpuHandle::new(1, 1));
+        let mut sched = FixedPriorityScheduler::new();
+        sched.add_vcpu(low, params(100, DEFAULT_WEIGHT, 1));
+        sched.add_vcpu(high1, params(100, DEFAULT_WEIGHT, 5));
+        sched.add_vcpu(high2, params(100, DEFAULT_WEIGHT, 5));
+        // Equal priorities share the CPU, the lower one starves.
+        assert_eq!(run_slices(&mut sched, 4), [high1, high2, high1, high2]);
+
+        let (vcpu, _) = sched.pick_next().unwrap();
+        sched.on_block(vcpu, 50);
+        let (vcpu, _) = sched.pick_next().unwrap();
+        sched.on_block(vcpu, 50);
+        assert_eq!(run_slices(&mut sched, 2), [low, low]);
+        // A woken higher priority vCPU runs first again.
+        sched.on_wake(high2);
+        assert_eq!(run_slices(&mut sched, 2), [high2, high2]);
+    }
+
+    #[test]
+    fn credit_follows_weights() {
+        let (heavy, light) = (VcpuHandle::new(0, 0), VcpuHandle::new(1, 0));
+        let mut sched = CreditScheduler::new();
+        sched.add_vcpu(heavy, params(100, 512, 0));
+        sched.add_vcpu(light, params(100, 256, 0));
+        let order = run_slices(&mut sched, 300);
+        let heavy_runs = order.iter().filter(|v| **v == heavy).count();
+        let light_runs = order.len() - heavy_runs;
+        // Twice the weight is twice the CPU time, within a few slices.
+        let skew = heavy_runs as i64 - 2 * light_runs as i64;
+        assert!(skew.abs() <= 6, "{}:{}", heavy_runs, light_runs);
+
+        // A blocked vCPU earns no credit, the other one runs alone.
+        let (vcpu, time_slice) = sched.pick_next().unwrap();
+        sched.on_block(vcpu, time_slice);
+        let other = if vcpu == heavy { light } else { heavy };
+        assert!(run_slices(&mut sched, 10).iter().all(|v| *v == other));
+        sched.on_wake(vcpu);
+        assert!(run_slices(&mut sched, 10).contains(&vcpu));
+    }
+}
@@ -0,0 +1,517 @@
+//! Versioned byte format of VM snapshots.
+//!
+//! A snapshot starts with a header: the magic [`SNAPSHOT_MAGIC`], the format version and the
+//! architecture the snapshot was taken on. A sequence of sections follows, each made of a 4-byte
+//! tag, the version of the section's layout, the length of its payload and the payload itself.
+//! The stream ends with an [`END_TAG`] section. All integers are little endian.
+//!
+//! Readers skip sections with unknown tags, so new kinds of state can be added without breaking
+//! older snapshots. A section whose layout changes gets a new version, and readers refuse
+//! versions newer than the ones they know.
+//!
+//! The bytes go to a [`SnapshotSink`] and come from a [`SnapshotSource`], which the embedder
+//! implements on top of a file, a network connection or a buffer in memory.
+
+use alloc::vec::Vec;
+
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+/// Bytes every snapshot starts with.
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"HYPCRAFT";
+/// Version of the snapshot header and section framing.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// Architecture tag of riscv64 snapshots.
+pub const SNAPSHOT_ARCH_RISCV64: u32 = 1;
+/// Architecture tag of aarch64 snapshots.
+pub const SNAPSHOT_ARCH_AARCH64: u32 = 2;
+/// Architecture tag of x86_64 snapshots.
+pub const SNAPSHOT_ARCH_X86_64: u32 = 3;
+
+/// Architecture tag of snapshots taken by this build.
+#[cfg(target_arch = "riscv64")]
+pub const SNAPSHOT_ARCH: u32 = SNAPSHOT_ARCH_RISCV64;
+/// Architecture tag of snapshots taken by this build.
+#[cfg(target_arch = "aarch64")]
+pub const SNAPSHOT_ARCH: u32 = SNAPSHOT_ARCH_AARCH64;
+/// Architecture tag of snapshots taken by this build.
+#[cfg(target_arch = "x86_64")]
+pub const SNAPSHOT_ARCH: u32 = SNAPSHOT_ARCH_X86_64;
+
+/// Tag of the section closing a snapshot.
+pub const END_TAG: [u8; 4] = *b"END ";
+/// Tag of the register state of one vCPU.
+pub const VCPU_TAG: [u8; 4] = *b"VCPU";
+/// Tag of a region of guest RAM.
+pub const RAM_TAG: [u8; 4] = *b"RAM ";
+/// Tag of the state of the riscv PLIC.
+pub const PLIC_TAG: [u8; 4] = *b"PLIC";
+/// Tag of the state of the riscv APLIC.
+pub const APLIC_TAG: [u8; 4] = *b"APLC";
+/// Tag of the state of the riscv ACLINT.
+pub const ACLINT_TAG: [u8; 4] = *b"ACLN";
+/// Tag of the state of a device on the MMIO bus.
+pub const MMIO_DEVICE_TAG: [u8; 4] = *b"MDEV";
+/// Tag of the console buffers of the SBI console.
+pub const CONSOLE_TAG: [u8; 4] = *b"CONS";
+
+// Tag, version and payload length.
+const SECTION_HEADER_SIZE: usize = 4 + 4 + 8;
+// Guest physical address and length in front of the bytes of a RAM section.
+const RAM_HEADER_SIZE: usize = 8 + 8;
+/// Largest payload `SnapshotReader::read_payload` reads into memory. Device and vCPU states stay
+/// well below it, RAM sections are streamed with `read_raw` instead.
+pub const MAX_SECTION_LEN: usize = 1 << 20;
+
+/// Destination of a snapshot.
+pub trait SnapshotSink {
+    /// Appends `data` to the snapshot.
+    fn write(&mut self, data: &[u8]) -> HyperResult<()>;
+}
+
+/// Origin of a snapshot being restored.
+pub trait SnapshotSource {
+    /// Fills `buf` with the next bytes of the snapshot. Fails if the snapshot ends before.
+    fn read(&mut self, buf: &mut [u8]) -> HyperResult<()>;
+}
+
+impl SnapshotSink for Vec<u8> {
+    fn write(&mut self, data: &[u8]) -> HyperResult<()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+impl SnapshotSource for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> HyperResult<()> {
+        if self.len() < buf.len() {
+            return Err(HyperError::DecodeError);
+        }
+        let (head, tail) = self.split_at(buf.len());
+        buf.copy_from_slice(head);
+        *self = tail;
+        Ok(())
+    }
+}
+
+/// Builds the payload of a section.
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    /// Creates an empty payload.
+    pub fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    /// Appends a byte.
+    pub fn put_u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    /// Appends a 16-bit value.
+    pub fn put_u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Appends a 32-bit value.
+    pub fn put_u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Appends a 64-bit value.
+    pub fn put_u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Appends a boolean as one byte.
+    pub fn put_bool(&mut self, val: bool) {
+        self.put_u8(val as u8);
+    }
+
+    /// Appends every value of `vals`, without their count.
+    pub fn put_u32s(&mut self, vals: &[u32]) {
+        vals.iter().for_each(|val| self.put_u32(*val));
+    }
+
+    /// Appends every value of `vals`, without their count.
+    pub fn put_u64s(&mut self, vals: &[u64]) {
+        vals.iter().for_each(|val| self.put_u64(*val));
+    }
+
+    /// Appends the length of `data` followed by `data`.
+    pub fn put_bytes(&mut self, data: &[u8]) {
+        self.put_u64(data.len() as u64);
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The payload built so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+/// Reads back the payload of a section built by an [`Encoder`].
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    /// Reads from the start of `buf`.
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, len: usize) -> HyperResult<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(HyperError::DecodeError);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    /// Reads a byte.
+    pub fn get_u8(&mut self) -> HyperResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a 16-bit value.
+    pub fn get_u16(&mut self) -> HyperResult<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    /// Reads a 32-bit value.
+    pub fn get_u32(&mut self) -> HyperResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Reads a 64-bit value.
+    pub fn get_u64(&mut self) -> HyperResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a boolean written by [`Encoder::put_bool`].
+    pub fn get_bool(&mut self) -> HyperResult<bool> {
+        match self.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(HyperError::DecodeError),
+        }
+    }
+
+    /// Fills `vals` with as many 32-bit values.
+    pub fn get_u32s(&mut self, vals: &mut [u32]) -> HyperResult<()> {
+        for val in vals.iter_mut() {
+            *val = self.get_u32()?;
+        }
+        Ok(())
+    }
+
+    /// Fills `vals` with as many 64-bit values.
+    pub fn get_u64s(&mut self, vals: &mut [u64]) -> HyperResult<()> {
+        for val in vals.iter_mut() {
+            *val = self.get_u64()?;
+        }
+        Ok(())
+    }
+
+    /// Reads bytes written by [`Encoder::put_bytes`].
+    pub fn get_bytes(&mut self) -> HyperResult<&'a [u8]> {
+        let len = self.get_u64()?;
+        let len = usize::try_from(len).map_err(|_| HyperError::DecodeError)?;
+        self.take(len)
+    }
+}
+
+/// Writes the sections of a snapshot to a sink.
+pub struct SnapshotWriter<'a> {
+    sink: &'a mut dyn SnapshotSink,
+    // Bytes still owed to the section being streamed.
+    remaining: u64,
+}
+
+impl<'a> SnapshotWriter<'a> {
+    /// Starts a snapshot of this architecture by writing its header to `sink`.
+    pub fn new(sink: &'a mut dyn SnapshotSink) -> HyperResult<Self> {
+        Self::with_arch(sink, SNAPSHOT_ARCH)
+    }
+
+    /// Starts a snapshot tagged with architecture `arch`.
+    pub fn with_arch(sink: &'a mut dyn SnapshotSink, arch: u32) -> HyperResult<Self> {
+        sink.write(&SNAPSHOT_MAGIC)?;
+        sink.write(&SNAPSHOT_FORMAT_VERSION.to_le_bytes())?;
+        sink.write(&arch.to_le_bytes())?;
+        Ok(Self { sink, remaining: 0 })
+    }
+
+    /// Writes a section with layout `version` and the payload built by `payload`.
+    pub fn write_section(
+        &mut self,
+        tag: [u8; 4],
+        version: u32,
+        payload: &Encoder,
+    ) -> HyperResult<()> {
+        self.begin_section(tag, version, payload.as_bytes().len() as u64)?;
+        self.write_raw(payload.as_bytes())
+    }
+
+    /// Starts a section with a payload of `len` bytes, which must then be written with
+    /// `write_raw`. Lets large payloads such as guest RAM be written without a copy.
+    pub fn begin_section(&mut self, tag: [u8; 4], version: u32, len: u64) -> HyperResult<()> {
+        if self.remaining != 0 {
+            return Err(HyperError::BadState);
+        }
+        self.sink.write(&tag)?;
+        self.sink.write(&version.to_le_bytes())?;
+        self.sink.write(&len.to_le_bytes())?;
+        self.remaining = len;
+        Ok(())
+    }
+
+    /// Writes the next bytes of the payload of the section started by `begin_section`.
+    pub fn write_raw(&mut self, data: &[u8]) -> HyperResult<()> {
+        if data.len() as u64 > self.remaining {
+            return Err(HyperError::OutOfRange);
+        }
+        self.sink.write(data)?;
+        self.remaining -= data.len() as u64;
+        Ok(())
+    }
+
+    /// Starts a section holding `len` bytes of guest RAM at `gpa`. The bytes must follow with
+    /// `write_raw`.
+    pub fn begin_ram(&mut self, gpa: GuestPhysAddr, len: usize) -> HyperResult<()> {
+        self.begin_section(RAM_TAG, 1, (RAM_HEADER_SIZE + len) as u64)?;
+        self.write_raw(&(gpa as u64).to_le_bytes())?;
+        self.write_raw(&(len as u64).to_le_bytes())
+    }
+
+    /// Writes the guest RAM `data` found at `gpa`.
+    pub fn write_ram(&mut self, gpa: GuestPhysAddr, data: &[u8]) -> HyperResult<()> {
+        self.begin_ram(gpa, data.len())?;
+        self.write_raw(data)
+    }
+
+    /// Closes the snapshot.
+    pub fn finish(mut self) -> HyperResult<()> {
+        self.begin_section(END_TAG, 1, 0)
+    }
+}
+
+/// The framing of a section, as returned by [`SnapshotReader::next_section`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SectionHeader {
+    /// What the section holds.
+    pub tag: [u8; 4],
+    /// Version of the payload's layout.
+    pub version: u32,
+    /// Length of the payload in bytes.
+    pub len: u64,
+}
+
+impl SectionHeader {
+    /// Fails unless the payload's layout is at most `max_version`.
+    pub fn check_version(&self, max_version: u32) -> HyperResult<()> {
+        if self.version == 0 || self.version > max_version {
+            return Err(HyperError::NotSupported);
+        }
+        Ok(())
+    }
+}
+
+/// Reads the sections of a snapshot from a source.
+pub struct SnapshotReader<'a> {
+    source: &'a mut dyn SnapshotSource,
+    arch: u32,
+    // Bytes of the current section's payload not read yet.
+    remaining: u64,
+}
+
+impl<'a> SnapshotReader<'a> {
+    /// Checks the header of the snapshot in `source`. Fails if it is not a snapshot, or one of a
+    /// newer format.
+    pub fn new(source: &'a mut dyn SnapshotSource) -> HyperResult<Self> {
+        let mut magic = [0; 8];
+        source.read(&mut magic)?;
+        if magic != SNAPSHOT_MAGIC {
+            return Err(HyperError::DecodeError);
+        }
+        let mut word = [0; 4];
+        source.read(&mut word)?;
+        if u32::from_le_bytes(word) > SNAPSHOT_FORMAT_VERSION {
+            return Err(HyperError::NotSupported);
+        }
+        source.read(&mut word)?;
+        Ok(Self {
+            source,
+            arch: u32::from_le_bytes(word),
+            remaining: 0,
+        })
+    }
+
+    /// The architecture the snapshot was taken on.
+    pub fn arch(&self) -> u32 {
+        self.arch
+    }
+
+    /// Fails unless the snapshot was taken on this architecture.
+    pub fn check_arch(&self) -> HyperResult<()> {
+        if self.arch != SNAPSHOT_ARCH {
+            return Err(HyperError::NotSupported);
+        }
+        Ok(())
+    }
+
+    /// Skips what is left of the current section and reads the header of the next one. Returns
+    /// `None` at the end of the snapshot.
+    pub fn next_section(&mut self) -> HyperResult<Option<SectionHeader>> {
+        let mut chunk = [0; 256];
+        while self.remaining > 0 {
+            let len = self.remaining.min(chunk.len() as u64) as usize;
+            self.read_raw(&mut chunk[..len])?;
+        }
+        let mut header = [0; SECTION_HEADER_SIZE];
+        self.source.read(&mut header)?;
+        let section = SectionHeader {
+            tag: header[0..4].try_into().unwrap(),
+            version: u32::from_le_bytes(header[4..8].try_into().unwrap()),
+            len: u64::from_le_bytes(header[8..16].try_into().unwrap()),
+        };
+        if section.tag == END_TAG {
+            return Ok(None);
+        }
+        self.remaining = section.len;
+        Ok(Some(section))
+    }
+
+    /// Reads the whole payload of the current section, which must not be longer than
+    /// [`MAX_SECTION_LEN`].
+    pub fn read_payload(&mut self) -> HyperResult<Vec<u8>> {
+        let len = usize::try_from(self.remaining)
+            .ok()
+            .filter(|&len| len <= MAX_SECTION_LEN)
+            .ok_or(HyperError::DecodeError)?;
+        let mut payload = vec![0; len];
+        self.read_raw(&mut payload)?;
+        Ok(payload)
+    }
+
+    /// Fills `buf` with the next bytes of the current section's payload.
+    pub fn read_raw(&mut self, buf: &mut [u8]) -> HyperResult<()> {
+        if buf.len() as u64 > self.remaining {
+            return Err(HyperError::DecodeError);
+        }
+        self.source.read(buf)?;
+        self.remaining -= buf.len() as u64;
+        Ok(())
+    }
+
+    /// Reads the start of a RAM section, returning the guest physical address and length of the
+    /// RAM bytes that follow, to be read with `read_raw`.
+    pub fn read_ram_header(&mut self) -> HyperResult<(GuestPhysAddr, usize)> {
+        let mut header = [0; RAM_HEADER_SIZE];
+        self.read_raw(&mut header)?;
+        let gpa = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        if len != self.remaining {
+            return Err(HyperError::DecodeError);
+        }
+        let gpa = usize::try_from(gpa).map_err(|_| HyperError::DecodeError)?;
+        let len = usize::try_from(len).map_err(|_| HyperError::DecodeError)?;
+        Ok((gpa, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections_round_trip() {
+        let mut out = Vec::new();
+        let mut writer = SnapshotWriter::with_arch(&mut out, SNAPSHOT_ARCH_RISCV64).unwrap();
+        let mut payload = Encoder::new();
+        payload.put_u32(7);
+        payload.put_u64s(&[1, u64::MAX]);
+        payload.put_bytes(b"hello");
+        writer.write_section(VCPU_TAG, 1, &payload).unwrap();
+        writer.write_ram(0x8000_0000, &[0xaa; 12]).unwrap();
+        writer.finish().unwrap();
+
+        let mut source = &out[..];
+        let mut reader = SnapshotReader::new(&mut source).unwrap();
+        assert_eq!(reader.arch(), SNAPSHOT_ARCH_RISCV64);
+
+        let section = reader.next_section().unwrap().unwrap();
+        assert_eq!((section.tag, section.version), (VCPU_TAG, 1));
+        let payload = reader.read_payload().unwrap();
+        let mut decoder = Decoder::new(&payload);
+        assert_eq!(decoder.get_u32(), Ok(7));
+        let mut vals = [0; 2];
+        decoder.get_u64s(&mut vals).unwrap();
+        assert_eq!(vals, [1, u64::MAX]);
+        assert_eq!(decoder.get_bytes(), Ok(&b"hello"[..]));
+        assert_eq!(decoder.get_u8(), Err(HyperError::DecodeError));
+
+        assert_eq!(reader.next_section().unwrap().unwrap().tag, RAM_TAG);
+        assert_eq!(reader.read_ram_header(), Ok((0x8000_0000, 12)));
+        let mut ram = [0; 12];
+        reader.read_raw(&mut ram).unwrap();
+        assert_eq!(ram, [0xaa; 12]);
+        assert_eq!(reader.next_section(), Ok(None));
+    }
+
+    #[test]
+    fn unknown_sections_are_skipped() {
+        let mut out = Vec::new();
+        let mut writer = SnapshotWriter::new(&mut out).unwrap();
+        let mut payload = Encoder::new();
+        payload.put_u64(1);
+        writer.write_section(*b"NEW!", 3, &payload).unwrap();
+        payload.put_u64(2);
+        writer.write_section(PLIC_TAG, 1, &payload).unwrap();
+        writer.finish().unwrap();
+
+        let mut source = &out[..];
+        let mut reader = SnapshotReader::new(&mut source).unwrap();
+        let section = reader.next_section().unwrap().unwrap();
+        assert_eq!(section.check_version(1), Err(HyperError::NotSupported));
+        // The unread payload of the unknown section is skipped.
+        let section = reader.next_section().unwrap().unwrap();
+        assert_eq!(section.tag, PLIC_TAG);
+        assert_eq!(reader.read_payload().unwrap().len(), 16);
+        assert_eq!(reader.next_section(), Ok(None));
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        let mut source = &b"NOTASNAPSHOT...."[..];
+        assert!(matches!(
+            SnapshotReader::new(&mut source),
+            Err(HyperError::DecodeError)
+        ));
+
+        let mut out = Vec::new();
+        let mut writer = SnapshotWriter::new(&mut out).unwrap();
+        writer.begin_section(VCPU_TAG, 1, 100).unwrap();
+        assert_eq!(writer.write_raw(&[0; 101]), Err(HyperError::OutOfRange));
+        writer.write_raw(&[0; 10]).unwrap();
+        // The snapshot is cut short in the middle of the section.
+        let mut source = &out[..];
+        let mut reader = SnapshotReader::new(&mut source).unwrap();
+        reader.next_section().unwrap();
+        assert_eq!(reader.read_payload(), Err(HyperError::DecodeError));
+
+        // A corrupt length is rejected before anything is allocated for the payload.
+        let mut out = Vec::new();
+        let mut writer = SnapshotWriter::new(&mut out).unwrap();
+        writer.begin_section(VCPU_TAG, 1, u64::MAX).unwrap();
+        let mut source = &out[..];
+        let mut reader = SnapshotReader::new(&mut source).unwrap();
+        reader.next_section().unwrap();
+        assert_eq!(reader.read_payload(), Err(HyperError::DecodeError));
+    }
+}
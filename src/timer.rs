@@ -0,0 +1,106 @@
+//! Software timer queue multiplexing one hardware timer.
+//!
+//! Each physical CPU has a single timer comparator, while the VMM running on it needs many
+//! deadlines at once: the end of the current time slice, the timer of every guest vCPU and those
+//! of emulated devices. They are all kept in a [`TimerQueue`], and the hardware is always
+//! programmed for the earliest one.
+
+use alloc::vec::Vec;
+
+/// Pending deadlines, each identified by an event of type `E`. There is at most one deadline per
+/// event: setting it again moves the deadline.
+pub struct TimerQueue<E> {
+    // Sorted by deadline, earliest first.
+    entries: Vec<(u64, E)>,
+}
+
+impl<E: PartialEq> TimerQueue<E> {
+    /// Creates an empty queue.
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Arms `event` to fire at `deadline`, replacing its previous deadline if it had one.
+    pub fn set(&mut self, event: E, deadline: u64) {
+        self.cancel(&event);
+        let pos = self.entries.partition_point(|(d, _)| *d <= deadline);
+        self.entries.insert(pos, (deadline, event));
+    }
+
+    /// Disarms `event`. Returns its deadline if it was armed.
+    pub fn cancel(&mut self, event: &E) -> Option<u64> {
+        let pos = self.entries.iter().position(|(_, e)| e == event)?;
+        Some(self.entries.remove(pos).0)
+    }
+
+    /// The deadline of `event`, if it is armed.
+    pub fn deadline(&self, event: &E) -> Option<u64> {
+        self.entries.iter().find(|(_, e)| e == event).map(|(d, _)| *d)
+    }
+
+    /// The earliest deadline, the one the hardware timer should be programmed for.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.entries.first().map(|(d, _)| *d)
+    }
+
+    /// Removes and returns the earliest event whose deadline is not after `now`.
+    pub fn pop_expired(&mut self, now: u64) -> Option<E> {
+        match self.entries.first() {
+            Some((deadline, _)) if *deadline <= now => Some(self.entries.remove(0).1),
+            _ => None,
+        }
+    }
+
+    /// Removes every event accepted by `f`.
+    pub fn retain(&mut self, mut f: impl FnMut(&E) -> bool) {
+        self.entries.retain(|(_, e)| f(e));
+    }
+
+    /// Returns true if no event is armed.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<E: PartialEq> Default for TimerQueue<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn earliest_first() {
+        let mut queue = TimerQueue::new();
+        queue.set(1, 300);
+        queue.set(2, 100);
+        queue.set(3, 200);
+        assert_eq!(queue.next_deadline(), Some(100));
+
+        assert_eq!(queue.pop_expired(50), None);
+        assert_eq!(queue.pop_expired(250), Some(2));
+        assert_eq!(queue.pop_expired(250), Some(3));
+        assert_eq!(queue.pop_expired(250), None);
+        assert_eq!(queue.next_deadline(), Some(300));
+    }
+
+    #[test]
+    fn set_replaces_deadline() {
+        let mut queue = TimerQueue::new();
+        queue.set(1, 100);
+        queue.set(2, 200);
+        queue.set(1, 300);
+        assert_eq!(queue.deadline(&1), Some(300));
+        assert_eq!(queue.next_deadline(), Some(200));
+
+        assert_eq!(queue.cancel(&2), Some(200));
+        assert_eq!(queue.cancel(&2), None);
+        assert_eq!(queue.pop_expired(u64::MAX), Some(1));
+        assert!(queue.is_empty());
+    }
+}
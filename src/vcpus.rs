@@ -1,6 +1,6 @@
 use alloc::boxed::Box;
 use arrayvec::ArrayVec;
-use spin::Once;
+use spin::{Mutex, MutexGuard, Once};
 
 use crate::arch::{VCpu, VM};
 use crate::{GuestPageTableTrait, HyperCraftHal, HyperError, HyperResult,};
@@ -14,7 +14,7 @@ pub const VM_CPUS_MAX: usize = MAX_CPUS;
 /// The set of vCPUs in a VM.
 #[derive(Default)]
 pub struct VmCpus<H: HyperCraftHal> {
-    inner: [Once<VCpu<H>>; VM_CPUS_MAX],
+    inner: [Once<Mutex<VCpu<H>>>; VM_CPUS_MAX],
     marker: core::marker::PhantomData<H>,
 }
 
@@ -32,7 +32,7 @@ impl<H: HyperCraftHal> VmCpus<H> {
         let vcpu_id = vcpu.vcpu_id();
         let once_entry = self.inner.get(vcpu_id).ok_or(HyperError::BadState)?;
 
-        once_entry.call_once(|| vcpu);
+        once_entry.call_once(|| Mutex::new(vcpu));
         Ok(())
     }
 
@@ -43,7 +43,27 @@ impl<H: HyperCraftHal> VmCpus<H> {
             .get_mut(vcpu_id)
             .and_then(|once| once.get_mut())
             .ok_or(HyperError::NotFound)?;
-        Ok(vcpu)
+        Ok(vcpu.get_mut())
+    }
+
+    /// Locks the vCPU with `vcpu_id`, so that it can run on this CPU while other vCPUs of the
+    /// same VM run on other CPUs.
+    pub fn lock_vcpu(&self, vcpu_id: usize) -> HyperResult<MutexGuard<'_, VCpu<H>>> {
+        let vcpu = self
+            .inner
+            .get(vcpu_id)
+            .and_then(|once| once.get())
+            .ok_or(HyperError::NotFound)?;
+        Ok(vcpu.lock())
+    }
+
+    /// Returns the ids of the vCPUs that were added, in increasing order.
+    pub fn vcpu_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.inner
+            .iter()
+            .enumerate()
+            .filter(|(_, once)| once.is_completed())
+            .map(|(vcpu_id, _)| vcpu_id)
     }
 }
 
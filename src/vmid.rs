@@ -0,0 +1,246 @@
+//! Allocation of the tags that keep the TLB entries of different guests apart: `hgatp.VMID` on
+//! riscv, `VTTBR_EL2.VMID` on aarch64 and the VPID on x86.
+//!
+//! Tags are handed out from a generation. When a generation runs out of tags, a new one starts,
+//! every owner has to allocate again on its next entry and every CPU flushes all guest TLB
+//! entries once before using a tag of the new generation. Between rollovers guests keep their
+//! TLB entries across switches.
+//!
+//! The tag a CPU last ran with stays reserved across a rollover, like the ASIDs of Linux: its
+//! owner keeps the tag in the new generation, so vCPUs of one guest that are still running on
+//! other CPUs never use a different tag than the one the guest's TLB fences target.
+//!
+//! Entering a guest whose tag is current takes no lock. Each CPU publishes the tag it runs with
+//! in `active`, which a rollover clears; a CPU that finds its entry cleared takes the lock.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::vcpus::MAX_CPUS;
+
+// Bits of a tag in the encoding of a slot in `VmidAllocator::active`, the generation is above.
+const VMID_BITS: u32 = 16;
+
+/// The tag of one guest address space and the generation it was allocated from.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VmidSlot {
+    generation: u64,
+    vmid: usize,
+}
+
+impl VmidSlot {
+    /// Creates a slot without a tag, one is allocated on first use.
+    pub const fn new() -> Self {
+        Self {
+            generation: 0,
+            vmid: 0,
+        }
+    }
+
+    /// The tag last allocated to the slot, only valid while its generation is current.
+    pub fn vmid(&self) -> usize {
+        self.vmid
+    }
+
+    fn encode(&self) -> u64 {
+        self.generation << VMID_BITS | self.vmid as u64
+    }
+
+    fn decode(encoded: u64) -> Self {
+        Self {
+            generation: encoded >> VMID_BITS,
+            vmid: (encoded & ((1 << VMID_BITS) - 1)) as usize,
+        }
+    }
+}
+
+struct AllocatorState {
+    next: usize,
+    // The slots that were active at the last rollover. Their tags are not handed out again and
+    // their owners keep them in the new generation.
+    reserved: [Option<VmidSlot>; MAX_CPUS],
+}
+
+impl AllocatorState {
+    /// Moves `slot` to `generation`, the current one, if its tag is reserved.
+    fn update_reserved(&mut self, slot: &mut VmidSlot, generation: u64) -> bool {
+        let mut hit = false;
+        for reserved in self.reserved.iter_mut().flatten() {
+            if reserved.generation == slot.generation && reserved.vmid == slot.vmid {
+                reserved.generation = generation;
+                hit = true;
+            }
+        }
+        if hit {
+            slot.generation = generation;
+        }
+        hit
+    }
+
+    fn is_reserved(&self, vmid: usize) -> bool {
+        self.reserved.iter().flatten().any(|reserved| reserved.vmid == vmid)
+    }
+}
+
+/// Generation based allocator of the tags `1..=max_id`. Tag 0 is left to the host.
+pub struct VmidAllocator {
+    max_id: usize,
+    // Only changed with `state` locked.
+    generation: AtomicU64,
+    // The encoded slot each CPU last ran with, 0 since a rollover until the CPU takes the lock.
+    active: [AtomicU64; MAX_CPUS],
+    state: Mutex<AllocatorState>,
+    // Set for every CPU on rollover, cleared by each CPU once it flushed its guest TLB entries.
+    flush_pending: [AtomicBool; MAX_CPUS],
+}
+
+impl VmidAllocator {
+    /// Creates an allocator of the tags `1..=max_id`. With `max_id == 0` the hardware has no
+    /// tags, all guests share tag 0 and every entry requires a flush. The same holds if there
+    /// are no more tags than CPUs, as the reserved tags could use up a whole generation.
+    pub const fn new(max_id: usize) -> Self {
+        assert!(max_id < 1 << VMID_BITS);
+        let max_id = if max_id > MAX_CPUS { max_id } else { 0 };
+        Self {
+            max_id,
+            generation: AtomicU64::new(1),
+            active: [const { AtomicU64::new(0) }; MAX_CPUS],
+            state: Mutex::new(AllocatorState {
+                next: 1,
+                reserved: [None; MAX_CPUS],
+            }),
+            flush_pending: [const { AtomicBool::new(false) }; MAX_CPUS],
+        }
+    }
+
+    /// The highest tag handed out.
+    pub fn max_id(&self) -> usize {
+        self.max_id
+    }
+
+    /// Returns the tag to run the owner of `slot` with on CPU `cpu_id`, allocating a new one if
+    /// the slot's generation is stale. The returned flag is true if the CPU must flush the
+    /// guest TLB entries of all tags before entering the guest.
+    pub fn get(&self, slot: &mut VmidSlot, cpu_id: usize) -> (usize, bool) {
+        if self.max_id == 0 {
+            return (0, true);
+        }
+        // A rollover clears `active` before the CPU could miss its flush, the exchange fails
+        // then. If the exchange wins instead, the rollover reserves the slot's tag.
+        let active = self.active[cpu_id].load(Ordering::Relaxed);
+        if active != 0
+            && slot.generation == self.generation.load(Ordering::Relaxed)
+            && self.active[cpu_id]
+                .compare_exchange(active, slot.encode(), Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            return (slot.vmid, false);
+        }
+        {
+            let mut state = self.state.lock();
+            let mut generation = self.generation.load(Ordering::Relaxed);
+            if slot.generation != generation && !state.update_reserved(slot, generation) {
+                loop {
+                    if state.next > self.max_id {
+                        generation = self.rollover(&mut state);
+                        // The slot may have been active on some CPU and is now reserved.
+                        if state.update_reserved(slot, generation) {
+                            break;
+                        }
+                    }
+                    let vmid = state.next;
+                    state.next += 1;
+                    if !state.is_reserved(vmid) {
+                        slot.vmid = vmid;
+                        slot.generation = generation;
+                        break;
+                    }
+                }
+            }
+            self.active[cpu_id].store(slot.encode(), Ordering::Relaxed);
+        }
+        let flush = self.flush_pending[cpu_id].swap(false, Ordering::AcqRel);
+        (slot.vmid, flush)
+    }
+}
+
+// Private methods implementation
+impl VmidAllocator {
+    /// Starts a new generation, reserving the tags the CPUs last ran with, and returns it.
+    fn rollover(&self, state: &mut AllocatorState) -> u64 {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        state.next = 1;
+        for (active, reserved) in self.active.iter().zip(state.reserved.iter_mut()) {
+            // A CPU that did not run a guest since the last rollover still holds the entries of
+            // the tag reserved back then.
+            match active.swap(0, Ordering::Relaxed) {
+                0 => {}
+                slot => *reserved = Some(VmidSlot::decode(slot)),
+            }
+        }
+        for flag in self.flush_pending.iter() {
+            flag.store(true, Ordering::Release);
+        }
+        debug!("VMID rollover, generation {}", generation);
+        generation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_within_generation() {
+        let allocator = VmidAllocator::new(MAX_CPUS + 1);
+        let mut a = VmidSlot::new();
+        let mut b = VmidSlot::new();
+        assert_eq!(allocator.get(&mut a, 0), (1, false));
+        assert_eq!(allocator.get(&mut b, 0), (2, false));
+        assert_eq!(allocator.get(&mut a, 1), (1, false));
+    }
+
+    #[test]
+    fn too_few_tags_are_not_used() {
+        let allocator = VmidAllocator::new(MAX_CPUS);
+        let mut slot = VmidSlot::new();
+        assert_eq!(allocator.get(&mut slot, 0), (0, true));
+        assert_eq!(allocator.get(&mut slot, 0), (0, true));
+    }
+
+    #[test]
+    fn rollover_flushes_every_cpu_once() {
+        let max_id = MAX_CPUS + 1;
+        let allocator = VmidAllocator::new(max_id);
+        let mut slots = [VmidSlot::new(); MAX_CPUS + 2];
+        for slot in slots[..max_id].iter_mut() {
+            allocator.get(slot, 0);
+        }
+        // The last slot starts a new generation, the tag CPU 0 last ran with stays reserved.
+        assert_eq!(allocator.get(&mut slots[max_id], 0), (1, true));
+        assert_eq!(allocator.get(&mut slots[0], 0), (2, false));
+        assert_eq!(allocator.get(&mut slots[0], 1), (2, true));
+        assert_eq!(allocator.get(&mut slots[max_id - 1], 0), (max_id, false));
+        assert_eq!(allocator.get(&mut slots[1], 0), (3, false));
+    }
+
+    #[test]
+    fn active_tags_survive_rollover() {
+        let max_id = MAX_CPUS + 1;
+        let allocator = VmidAllocator::new(max_id);
+        let mut running = VmidSlot::new();
+        assert_eq!(allocator.get(&mut running, 1), (1, false));
+        let mut slots = [VmidSlot::new(); MAX_CPUS + 1];
+        for slot in slots[..max_id - 1].iter_mut() {
+            allocator.get(slot, 0);
+        }
+        // The rollover skips the tags still in use on CPUs 0 and 1.
+        assert_eq!(allocator.get(&mut slots[max_id - 1], 2), (2, true));
+        assert_eq!(allocator.get(&mut slots[0], 2), (3, false));
+        // Other vCPUs of the running guest enter with the tag CPU 1 is still using.
+        assert_eq!(allocator.get(&mut running, 0), (1, true));
+        assert_eq!(allocator.get(&mut running, 1), (1, true));
+        assert_eq!(allocator.get(&mut slots[max_id - 2], 0), (max_id, false));
+    }
+}